@@ -0,0 +1,220 @@
+use crate::scheduler::{CompileCommand, OBJECT_EXTENSION};
+use crate::toolchain::Toolchain;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// Sources whose last compile took longer than this are compiled on their
+/// own: in a batch they would hold up the objects of the others.
+pub const HEAVY_MS: u64 = 2_000;
+
+/// What the compiles of a batch have in common: the program, the directory
+/// and the arguments other than the source and `/Fo`.  `None` for a compile
+/// that can't join a batch, since only `cl` takes several sources in one
+/// run and compiler wrappers such as sccache want one per run.
+pub fn key(command: &CompileCommand) -> Option<String> {
+    if command.toolchain != Toolchain::Msvc || command.wrapper.is_some() {
+        return None;
+    }
+    let mut key = format!("{}\0{}", command.program, command.directory.display());
+    for arg in shared_args(command) {
+        key.push('\0');
+        key.push_str(arg);
+    }
+    Some(key)
+}
+
+/// The arguments of `command` without its source and object.
+fn shared_args(command: &CompileCommand) -> impl Iterator<Item = &String> {
+    let source = command.source.display().to_string();
+    command
+        .args
+        .iter()
+        .filter(move |arg| **arg != source && !arg.starts_with("/Fo"))
+}
+
+/// Whether `source` can join a batch already holding `names`: `cl` names
+/// each object after its source in the batch's directory, so two sources
+/// of the same name (`a/util.c`, `b/util.cpp`) can't share one.
+pub fn fits(names: &HashSet<String>, source: &Path) -> bool {
+    !names.contains(&stem(source))
+}
+
+/// The name `fits` goes by, lowercased since `cl` runs on Windows.
+pub fn stem(source: &Path) -> String {
+    source
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+/// The compile of all of `members` (with the same `key`) in one `cl` run,
+/// writing the objects to `out_dir` (see `object_in`).  The command
+/// describes the first member apart from its arguments.
+pub fn command(members: &[&CompileCommand], out_dir: &Path) -> CompileCommand {
+    let first = members[0];
+    let mut args: Vec<String> = shared_args(first).cloned().collect();
+    args.extend(members.iter().map(|m| m.source.display().to_string()));
+    args.push(format!(
+        "/Fo{}{}",
+        out_dir.display(),
+        std::path::MAIN_SEPARATOR
+    ));
+    CompileCommand {
+        args,
+        object: object_in(out_dir, &first.source),
+        ..first.clone()
+    }
+}
+
+/// Where a batch writing to `out_dir` puts the object of `source`.
+pub fn object_in(out_dir: &Path, source: &Path) -> PathBuf {
+    let stem = source.file_stem().unwrap_or_default().to_string_lossy();
+    out_dir.join(format!("{}.{}", stem, OBJECT_EXTENSION))
+}
+
+/// Split the output of a batch into what `cl` said about each of
+/// `sources`.  `cl` prints the file name of each source as it starts on it,
+/// so a section runs from one of those lines to the next; the name lines
+/// themselves are left out.  Anything before the first source, such as a
+/// command-line warning, goes to the first.
+pub fn split_output(output: &str, sources: &[&Path]) -> Vec<String> {
+    let names: Vec<String> = sources
+        .iter()
+        .map(|s| {
+            s.file_name()
+                .map(|n| n.to_string_lossy().to_lowercase())
+                .unwrap_or_default()
+        })
+        .collect();
+    let mut sections = vec![String::new(); sources.len()];
+    let mut current = 0;
+    // sources are compiled in order, so only look ahead
+    let mut next = 0;
+    for line in output.lines() {
+        let name = line.trim().to_lowercase();
+        if let Some(i) = names[next..].iter().position(|n| *n == name) {
+            current = next + i;
+            next = current + 1;
+            continue;
+        }
+        let section = &mut sections[current];
+        section.push_str(line);
+        section.push('\n');
+    }
+    sections
+        .into_iter()
+        .map(|s| s.trim_end().to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cl(source: &str, flags: &[&str]) -> CompileCommand {
+        let source = PathBuf::from(source);
+        let object = PathBuf::from(format!("target/debug/obj/{}.obj", source.display()));
+        CompileCommand {
+            program: "cl".into(),
+            wrapper: None,
+            args: Toolchain::Msvc.compile_args(
+                flags.iter().map(|f| f.to_string()).collect(),
+                &source,
+                &object,
+            ),
+            toolchain: Toolchain::Msvc,
+            directory: PathBuf::from("C:/proj"),
+            source,
+            object,
+            dep_file: None,
+        }
+    }
+
+    #[test]
+    fn batches_compiles_with_the_same_flags() {
+        let a = cl("src/a.cpp", &["/EHsc", "/Iinclude"]);
+        let b = cl("src/net/b.cpp", &["/EHsc", "/Iinclude"]);
+        let c = cl("src/c.cpp", &["/EHsc", "/Iinclude", "/DFAST"]);
+        assert!(key(&a).is_some());
+        assert_eq!(key(&a), key(&b));
+        assert_ne!(key(&a), key(&c));
+
+        let mut wrapped = a.clone();
+        wrapped.wrapper = Some("sccache".into());
+        assert_eq!(key(&wrapped), None);
+        let mut gcc = a.clone();
+        gcc.toolchain = Toolchain::Gnu;
+        assert_eq!(key(&gcc), None);
+    }
+
+    #[test]
+    fn sources_of_the_same_name_go_apart() {
+        let names: HashSet<String> = [stem(Path::new("src/util.c"))].into();
+        assert!(!fits(&names, Path::new("lib/Util.cpp")));
+        assert!(fits(&names, Path::new("src/main.c")));
+    }
+
+    #[test]
+    fn one_command_compiles_every_member() {
+        let a = cl("src/a.cpp", &["/EHsc"]);
+        let b = cl("src/net/b.cpp", &["/EHsc"]);
+        let out = Path::new("target/debug/obj/.batch-0");
+        let batch = command(&[&a, &b], out);
+        let sep = std::path::MAIN_SEPARATOR;
+        assert_eq!(
+            batch.args,
+            vec![
+                "/c".to_string(),
+                "/nologo".into(),
+                "/EHsc".into(),
+                "src/a.cpp".into(),
+                "src/net/b.cpp".into(),
+                format!("/Fotarget/debug/obj/.batch-0{}", sep),
+            ]
+        );
+        assert_eq!(batch.program, "cl");
+        assert_eq!(
+            object_in(out, &b.source),
+            out.join(format!("b.{}", OBJECT_EXTENSION))
+        );
+    }
+
+    #[test]
+    fn splits_the_output_by_source() {
+        let output = "cl : Command line warning D9025 : overriding '/W3' with '/W4'\r\n\
+                      a.cpp\r\n\
+                      src\\a.cpp(3): warning C4101: 'x': unreferenced local variable\r\n\
+                      B.cpp\r\n\
+                      c.cpp\r\n\
+                      src\\c.cpp(7): error C2065: 'y': undeclared identifier\r\n\
+                      src\\c.cpp(8): error C2143: syntax error: missing ';'\r\n";
+        let sources = [
+            Path::new("src/a.cpp"),
+            Path::new("src/net/b.cpp"),
+            Path::new("src/c.cpp"),
+        ];
+        assert_eq!(
+            split_output(output, &sources),
+            vec![
+                "cl : Command line warning D9025 : overriding '/W3' with '/W4'\n\
+                 src\\a.cpp(3): warning C4101: 'x': unreferenced local variable"
+                    .to_string(),
+                String::new(),
+                "src\\c.cpp(7): error C2065: 'y': undeclared identifier\n\
+                 src\\c.cpp(8): error C2143: syntax error: missing ';'"
+                    .to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_diagnostic_naming_a_later_source_stays_put() {
+        // only a line that is nothing but the name starts a section
+        let output = "a.cpp\nsrc\\a.cpp(1): note: see b.cpp\nb.cpp\n";
+        let sources = [Path::new("a.cpp"), Path::new("b.cpp")];
+        assert_eq!(
+            split_output(output, &sources),
+            vec!["src\\a.cpp(1): note: see b.cpp".to_string(), String::new()]
+        );
+    }
+}
@@ -0,0 +1,742 @@
+use crate::cache::BuildCache;
+use crate::cancel;
+use crate::clean;
+use crate::compdb;
+use crate::config::Config;
+use crate::error::BuildyError;
+use crate::graph::BuildGraph;
+use crate::headers;
+use crate::includes;
+use crate::lang::{Language, is_source};
+use crate::lock::TargetLock;
+use crate::messages::{self, Message, MessageFormat};
+use crate::plan::{self, DirtyPlan, Invalidated};
+use crate::profile::{self, Profile};
+use crate::report::BuildReport;
+use crate::scheduler::{self, BuildOptions};
+use crate::session::BuildSession;
+use crate::stamp::{self, Stamp};
+use crate::store::ObjectStore;
+use crate::testing;
+use crate::timings::Timings;
+use crate::toolenv;
+use crate::unity::UnityBuild;
+use std::collections::HashSet;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// Bring the graph up to date with the tree.  With `--force` the graph and
+/// the recorded dependency scans are thrown away and everything is scanned
+/// again.
+pub fn update_graph(
+    graph: &mut BuildGraph,
+    root: &Path,
+    config: &Config,
+    opts: &BuildOptions,
+    cache: &mut BuildCache,
+) -> std::io::Result<()> {
+    let extra_flags = opts.scan_args(root, config.toolchain());
+    if opts.force {
+        cache.scans.clear();
+        return graph.scan(root, config, &extra_flags, opts.profile_name(), cache);
+    }
+    graph.update(root, config, &extra_flags, opts.profile_name(), cache)
+}
+
+/// Load the configuration of `root` with the compilers given on the command
+/// line applied on top, and return it with `opts` whose profile is resolved
+/// against it (see `Profile::resolve`).
+pub fn load_config(root: &Path, opts: &BuildOptions) -> Result<(Config, BuildOptions), String> {
+    let mut config = Config::load(root);
+    if let Some(cc) = &opts.compiler {
+        config.compiler = cc.clone();
+    }
+    if let Some(cxx) = &opts.cxx {
+        config.cxx = Some(cxx.clone());
+    }
+    config.libs.extend(opts.libs.iter().cloned());
+    if let Some(name) = &opts.name {
+        config.name = Some(name.clone());
+    }
+    if let Some(wrapper) = &opts.wrapper {
+        config.compiler_wrapper = Some(wrapper.clone());
+    }
+    config.hash_always |= opts.paranoid || opts.force;
+    config.compiler_wrapper = config.resolve_wrapper()?;
+    let mut opts = opts.clone();
+    opts.profile = Profile::resolve(opts.profile_name(), &config)?;
+    Ok((config, opts))
+}
+
+/// What a successful build produced.
+pub struct Built {
+    /// The programs or library linked (see `scheduler::link_targets`).
+    pub outputs: Vec<PathBuf>,
+    pub report: BuildReport,
+    /// The configuration the build used, with the command line applied,
+    /// so what runs the outputs needn't load it again.
+    pub config: Config,
+}
+
+/// Build the project.  Ends with the summary line, unless the build failed
+/// before compiling, and a `build-finished` message in JSON mode.
+pub fn run_build(session: &mut BuildSession, opts: &BuildOptions) -> Result<Built, Box<dyn Error>> {
+    let started = Instant::now();
+    let mut result = build_and_link(session, opts, Goal::Project);
+    let success = result.is_ok();
+    let (artifacts, mut report) = match &mut result {
+        Ok(built) => (
+            built
+                .outputs
+                .iter()
+                .map(|p| p.display().to_string())
+                .collect(),
+            Some(&mut built.report),
+        ),
+        Err(e) => (
+            Vec::new(),
+            e.downcast_mut::<BuildyError>()
+                .and_then(BuildyError::report_mut),
+        ),
+    };
+    if let Some(report) = report.as_deref_mut() {
+        report.duration = started.elapsed();
+        opts.message_format.summary(report);
+    }
+    opts.message_format.emit(&Message::BuildFinished {
+        success,
+        artifacts,
+        report: report.as_deref(),
+    });
+    result
+}
+
+/// What `build_and_link` builds.
+#[derive(Clone, Copy)]
+pub enum Goal<'a> {
+    /// The project's programs or library, without the sources of `tests/`.
+    Project,
+    /// The test programs whose path below `tests/` contains the filter.
+    Tests(&'a str),
+}
+
+impl Goal<'_> {
+    /// Whether the build leaves `source` out.
+    pub fn skips(self, graph: &BuildGraph, root: &Path, source: &Path) -> bool {
+        match self {
+            Goal::Project => testing::is_test(source, root),
+            Goal::Tests(filter) => testing::skips(graph, root, source, filter),
+        }
+    }
+}
+
+/// Build `goal` holding the `TargetLock`, and save the caches before
+/// letting go of it, also when the build fails so scan failure counts
+/// persist.
+pub fn build_and_link(
+    session: &mut BuildSession,
+    opts: &BuildOptions,
+    goal: Goal,
+) -> Result<Built, Box<dyn Error>> {
+    cancel::reset();
+    let _lock = TargetLock::acquire(&session.root)?;
+    // another process may have built since the caches were loaded
+    session.forget_changed();
+    let result = build_locked(session, opts, goal);
+    session.flush()?;
+    result
+}
+
+fn build_locked(
+    session: &mut BuildSession,
+    opts: &BuildOptions,
+    goal: Goal,
+) -> Result<Built, Box<dyn Error>> {
+    let started = Instant::now();
+    // re-read the config on every build so edits are picked up in watch mode
+    let loaded = load_config(&session.root, opts)?;
+    let (config, opts) = (&loaded.0, &loaded.1);
+    let pool = &*session.pool(opts.jobs.or(config.jobs).unwrap_or(0))?;
+    let BuildSession {
+        root,
+        caches,
+        graph,
+        ..
+    } = session;
+    let root = root.as_path();
+    let profile = opts.profile_name();
+    let cache = caches
+        .entry(profile.to_string())
+        .or_insert_with(|| BuildCache::load(root, profile));
+    let messages = opts.message_format;
+    messages.status(format!("scanning sources in {}", root.display()));
+
+    // scans killed by Ctrl-C aren't failures worth reporting
+    update_graph(graph, root, config, opts, cache).map_err(|e| match e.kind() {
+        std::io::ErrorKind::Interrupted => BuildyError::Interrupted,
+        _ => BuildyError::ScanError(e.to_string()),
+    })?;
+    if cancel::requested() {
+        return Err(BuildyError::Interrupted.into());
+    }
+    if compdb::wanted(root, config)
+        && let Err(e) = compdb::emit(graph, root, config, opts)
+    {
+        messages::warn(format!(
+            "warning: could not update compile_commands.json: {}",
+            e
+        ));
+    }
+    report_scan_failures(graph, cache, root, opts)?;
+    // remove cache entries for files that no longer exist
+    // existing files are tracked in the graph with absolute paths. The
+    // cache stores its keys relative to `root`, so when we are filtering we can
+    // convert each stored key back to an absolute path for comparison.
+    let existing: HashSet<std::path::PathBuf> = graph.nodes.keys().cloned().collect();
+    let removed: Vec<PathBuf> = cache
+        .iter_absolute_paths(root)
+        .filter(|p| !existing.contains(p))
+        .collect();
+    for path in &removed {
+        cache.files.remove(&BuildCache::make_relative(path, root));
+    }
+    // and the objects of sources no longer built, which would otherwise
+    // linger in target/ (they aren't linked, the link only takes the
+    // graph's sources)
+    match scheduler::remove_orphans(graph.nodes.keys(), root, profile) {
+        Ok(0) => {}
+        Ok(n) => messages.status(format!("removed {} orphaned object file(s)", n)),
+        Err(e) => messages::warn(format!("warning: could not remove orphaned objects: {}", e)),
+    }
+    cache
+        .scans
+        .retain(|key, _| existing.contains(&BuildCache::make_absolute(key, root)));
+    cache
+        .includes
+        .retain(|key, _| existing.contains(&BuildCache::make_absolute(key, root)));
+
+    // if compiler or flags changed since last cache, invalidate all
+    let (current_compiler, current_flags) = fingerprint(config, opts);
+    let invalidated = invalidated(cache, config, opts);
+    if invalidated.config {
+        messages.status("compiler or flags changed, invalidating cache");
+        print_config_changes(cache, &current_compiler, &current_flags, messages);
+    } else {
+        for language in &invalidated.languages {
+            messages.status(format!(
+                "{} changed, recompiling the {} sources",
+                language.flags_key(),
+                language.as_str()
+            ));
+        }
+    }
+
+    graph.refresh(cache, root, config.hash_always);
+    report_unreadable(graph, root)?;
+    let commands =
+        scheduler::compile_commands(graph, root, config, opts).map_err(BuildyError::compile)?;
+    plan::migrate_command_hashes(graph, cache, root, &commands);
+    let plan = DirtyPlan::compute(graph, cache, root, profile, &invalidated, &commands);
+    plan.apply(graph);
+    if opts.verbose > 1 {
+        print_decisions(graph, &plan, root, messages);
+    }
+    cache.compiler = Some(current_compiler);
+    cache.flags = current_flags.clone();
+    cache.language_flags = config.language_fingerprint();
+
+    let resolution = includes::check(graph, cache, root, config, opts);
+    report_moved_includes(&resolution.moved, opts)?;
+    resolution.commit(cache);
+
+    // a unity build compiles its members through the buckets, and only the
+    // rest one by one
+    let unity = opts.unity.map(|count| {
+        let count = if count == 0 {
+            pool.current_num_threads()
+        } else {
+            count
+        };
+        UnityBuild::new(graph, root, config, profile, count)
+    });
+    let clean = graph
+        .nodes
+        .keys()
+        .filter(|p| is_source(p) && !plan.reasons.contains_key(*p) && !goal.skips(graph, root, p))
+        .count();
+    let plan = plan.excluding(|path| {
+        goal.skips(graph, root, path) || unity.as_ref().is_some_and(|u| u.is_member(path))
+    });
+    // a failed compile reports the counts of both steps, like a good one
+    let mut report = BuildReport {
+        clean,
+        ..BuildReport::default()
+    };
+    let merge_into = |report: &BuildReport, mut e: BuildyError| {
+        if let Some(partial) = e.report_mut() {
+            let mut merged = report.clone();
+            merged.merge(std::mem::take(partial));
+            *partial = merged;
+        }
+        e
+    };
+    report.merge(
+        scheduler::build(graph, cache, root, config, opts, pool, &plan)
+            .map_err(|e| merge_into(&report, e))?,
+    );
+    if let Some(unity) = &unity {
+        report.merge(
+            scheduler::build_unity(unity, graph, cache, root, config, opts, pool)
+                .map_err(|e| merge_into(&report, e))?,
+        );
+    }
+    let output_dir = root.join("target").join(opts.profile_name());
+    std::fs::create_dir_all(&output_dir)?;
+    let targets = match goal {
+        Goal::Project => {
+            scheduler::link_targets(graph, root, config, &output_dir, &exe_name(root, config))?
+        }
+        Goal::Tests(filter) => testing::link_targets(graph, root, &output_dir, filter),
+    };
+
+    let mut extra_objects = Vec::new();
+    cache.stamp = None;
+    if config.version_stamp {
+        let stamp = Stamp::compute(graph, root);
+        extra_objects
+            .push(stamp::prepare(&stamp, root, config, opts).map_err(BuildyError::compile)?);
+        cache.stamp = Some(stamp);
+    }
+
+    cache.libs = config.lib_args(root);
+    cache.link_settings = config.link_fingerprint();
+    if cancel::requested() {
+        return Err(BuildyError::Interrupted.into());
+    }
+    let linking = Instant::now();
+    let store = ObjectStore::new(root);
+    for target in &targets {
+        // tests are recorded as `tests/<name>`
+        let name = BuildCache::make_relative(&target.output, &output_dir);
+        let digest = scheduler::link_digest(target, cache, root, opts, unity.as_ref())
+            .map_err(BuildyError::LinkFailed)?;
+        if opts.force
+            || opts.force_link
+            || !target.output.exists()
+            || cache.links.get(&name) != Some(&digest)
+        {
+            let file_name = target.output.file_name().unwrap_or_default();
+            let file_name = file_name.to_string_lossy().to_string();
+            if !opts.force && !opts.force_link && store.fetch_link(&digest, &target.output) {
+                messages.status(format!("{} is up to date (linked before)", file_name));
+            } else {
+                scheduler::link(target, root, config, opts, &extra_objects, unity.as_ref())?;
+                store.insert_link(&digest, &target.output);
+                report.linked.push(file_name);
+            }
+            cache.links.insert(name, digest);
+        }
+    }
+    let link_time = linking.elapsed();
+    if let Goal::Project = goal {
+        match headers::stage(root, config, profile, cache) {
+            Ok(0) => {}
+            Ok(n) => messages.status(format!(
+                "staged {} public header(s) in {}",
+                n,
+                headers::staging_dir(root, profile).display()
+            )),
+            Err(e) => {
+                return Err(BuildyError::Other(format!(
+                    "could not stage the public headers: {}",
+                    e
+                ))
+                .into());
+            }
+        }
+    }
+
+    if let Some(age) = config
+        .auto_clean_stale
+        .as_deref()
+        .and_then(|a| clean::parse_age(a).ok())
+    {
+        match clean::auto_clean(root, &profile::names(config), age) {
+            Ok(removed) => {
+                for dir in removed {
+                    opts.message_format.status(dir);
+                }
+            }
+            Err(e) => messages::warn(format!("warning: automatic stale clean failed: {}", e)),
+        }
+    }
+
+    if opts.stats {
+        print_wrapper_stats(config);
+    }
+    if opts.timings {
+        let timings = Timings::new(
+            &report.compiled,
+            graph,
+            cache,
+            root,
+            link_time,
+            started.elapsed(),
+        );
+        timings.print(messages);
+        match timings.write(root, profile) {
+            Ok(path) => messages.status(format!("  report: {}", path.display())),
+            Err(e) => messages::warn(format!("warning: could not write the timing report: {}", e)),
+        }
+    }
+
+    report.duration = started.elapsed();
+    Ok(Built {
+        outputs: targets.into_iter().map(|t| t.output).collect(),
+        report,
+        config: loaded.0,
+    })
+}
+
+/// Run `<wrapper> --show-stats` for `--stats`, which ccache and sccache
+/// both understand.
+fn print_wrapper_stats(config: &Config) {
+    let Some(wrapper) = &config.compiler_wrapper else {
+        messages::warn(
+            "warning: --stats needs a compiler wrapper (`compiler_wrapper` or --wrapper)",
+        );
+        return;
+    };
+    let status = std::process::Command::new(wrapper)
+        .arg("--show-stats")
+        .status();
+    if !status.is_ok_and(|s| s.success()) {
+        messages::warn(format!("warning: `{} --show-stats` failed", wrapper));
+    }
+}
+
+/// Print for every source whether it is compiled and why, for `-vv`.
+fn print_decisions(graph: &BuildGraph, plan: &DirtyPlan, root: &Path, messages: MessageFormat) {
+    let mut sources: Vec<&PathBuf> = graph.nodes.keys().filter(|p| is_source(p)).collect();
+    sources.sort();
+    for path in sources {
+        let name = BuildCache::make_relative(path, root);
+        match plan.reasons.get(path) {
+            Some(reason) => messages.status(format!("dirty: {} ({})", name, reason.describe(root))),
+            None => messages.status(format!("clean: {} (hash match)", name)),
+        }
+    }
+}
+
+/// Name of the program or library built from `root`: the configured
+/// `name`, else the directory name.  Path separators become `_` so the
+/// output stays in the profile directory.
+pub fn exe_name(root: &Path, config: &Config) -> String {
+    config
+        .name
+        .as_deref()
+        .map(|name| name.trim().replace(['/', '\\'], "_"))
+        .filter(|name| !name.is_empty() && name != "." && name != "..")
+        .or_else(|| root.file_name().map(|s| s.to_string_lossy().to_string()))
+        .unwrap_or_else(|| "a.out".into())
+}
+
+/// The compilers and the flattened settings recorded in the cache, which
+/// make every source dirty when they change.
+pub fn fingerprint(config: &Config, opts: &BuildOptions) -> (String, Vec<String>) {
+    let mut flags: Vec<String> = opts
+        .profile
+        .compile_flags(config.toolchain())
+        .iter()
+        .map(|f| format!("profile={}", f))
+        .collect();
+    flags.extend(config.fingerprint());
+    flags.extend(
+        opts.include_dirs
+            .iter()
+            .map(|d| format!("--include={}", d.display())),
+    );
+    flags.extend(toolenv::fingerprint());
+    let mut compilers = vec![config.c_compiler().to_string(), config.cxx_compiler()];
+    compilers.dedup();
+    for compiler in compilers {
+        let version = toolenv::compiler_version(&compiler);
+        if !version.is_empty() {
+            flags.push(format!("version:{}={}", compiler, version));
+        }
+    }
+    (config.compilers(), flags)
+}
+
+/// What changed in the configuration since `cache` was written.  A cache
+/// that was never used has nothing to compare against; its sources are
+/// dirty anyway.
+pub fn invalidated(cache: &BuildCache, config: &Config, opts: &BuildOptions) -> Invalidated {
+    let (compiler, flags) = fingerprint(config, opts);
+    let current = config.language_fingerprint();
+    Invalidated {
+        config: !cache.config_matches(&compiler, &flags),
+        languages: [Language::C, Language::Cxx, Language::Asm]
+            .into_iter()
+            .filter(|language| {
+                let key = language.flags_key();
+                cache.compiler.is_some() && cache.language_flags.get(key) != current.get(key)
+            })
+            .collect(),
+        force: opts.force,
+        check_mtimes: config.check_object_mtime,
+    }
+}
+
+/// Scan the project and work out what a build with `opts` would compile,
+/// without compiling anything or updating the cache beyond the scan.
+pub fn plan_build(
+    session: &mut BuildSession,
+    opts: &BuildOptions,
+) -> Result<DirtyPlan, Box<dyn Error>> {
+    let BuildSession {
+        root,
+        caches,
+        graph,
+        ..
+    } = session;
+    let root = root.as_path();
+    let profile = opts.profile_name();
+    let cache = caches
+        .entry(profile.to_string())
+        .or_insert_with(|| BuildCache::load(root, profile));
+    let (config, opts) = &load_config(root, opts)?;
+    update_graph(graph, root, config, opts, cache).map_err(|e| e.to_string())?;
+    graph.refresh(cache, root, config.hash_always);
+    report_unreadable(graph, root)?;
+    let invalidated = invalidated(cache, config, opts);
+    let commands = scheduler::compile_commands(graph, root, config, opts)?;
+    plan::migrate_command_hashes(graph, cache, root, &commands);
+    let plan = DirtyPlan::compute(graph, cache, root, profile, &invalidated, &commands);
+    plan.apply(graph);
+    // what `build` compiles, which leaves the tests to `test`
+    Ok(plan.excluding(|path| testing::is_test(path, root)))
+}
+
+/// List what differs between the configuration recorded in `cache` and the
+/// current one.  Nothing is printed for a cache that has never been used.
+pub fn print_config_changes(
+    cache: &BuildCache,
+    compiler: &str,
+    flags: &[String],
+    messages: MessageFormat,
+) {
+    let Some(old_compiler) = &cache.compiler else {
+        return;
+    };
+    if old_compiler != compiler {
+        messages.status(format!("  compiler: {} -> {}", old_compiler, compiler));
+    }
+    for removed in cache.flags.iter().filter(|f| !flags.contains(f)) {
+        messages.status(format!("  - {}", removed));
+    }
+    for added in flags.iter().filter(|f| !cache.flags.contains(f)) {
+        messages.status(format!("  + {}", added));
+    }
+}
+
+/// Print the dependency scan failures of `graph` as one grouped warning and
+/// update the per-file failure counts in `cache`.  Under `--strict-deps` any
+/// failure aborts the build.
+fn report_scan_failures(
+    graph: &BuildGraph,
+    cache: &mut BuildCache,
+    root: &Path,
+    opts: &BuildOptions,
+) -> Result<(), Box<dyn Error>> {
+    for path in graph.nodes.keys() {
+        let failed = graph.scan_failures.iter().any(|f| &f.path == path);
+        cache.record_scan(path, root, !failed);
+    }
+    if graph.scan_failures.is_empty() || (messages::quiet() && !opts.strict_deps) {
+        return Ok(());
+    }
+
+    let level = if opts.strict_deps { "error" } else { "warning" };
+    messages::warn(format!(
+        "{}: dependency scan failed for {} file(s), incremental accuracy reduced:",
+        level,
+        graph.scan_failures.len()
+    ));
+    for failure in &graph.scan_failures {
+        messages::warn(format!(
+            "  {}: {}",
+            BuildCache::make_relative(&failure.path, root),
+            failure.first_line()
+        ));
+    }
+    if opts.strict_deps {
+        return Err(BuildyError::ScanError("dependency scan failed (--strict-deps)".into()).into());
+    }
+    Ok(())
+}
+
+/// Print the files the scan couldn't read as one grouped warning, with
+/// the reason for each.  Only when no source is left does the build fail.
+fn report_unreadable(graph: &BuildGraph, root: &Path) -> Result<(), Box<dyn Error>> {
+    if graph.unreadable.is_empty() {
+        return Ok(());
+    }
+    let names: Vec<String> = graph
+        .unreadable
+        .iter()
+        .map(|u| {
+            format!(
+                "  {}: {}",
+                BuildCache::make_relative(&u.path, root),
+                u.error
+            )
+        })
+        .collect();
+    if !graph.nodes.keys().any(|p| is_source(p)) {
+        return Err(BuildyError::ScanError(format!(
+            "no readable sources, skipped {} unreadable file(s):\n{}",
+            names.len(),
+            names.join("\n")
+        ))
+        .into());
+    }
+    if !messages::quiet() {
+        messages::warn(format!(
+            "warning: skipped {} unreadable file(s):\n{}",
+            names.len(),
+            names.join("\n")
+        ));
+    }
+    Ok(())
+}
+
+/// Warn about includes that now pick a different file because the include
+/// directories changed; with `--strict-includes` refuse to build.
+fn report_moved_includes(
+    moved: &[includes::Moved],
+    opts: &BuildOptions,
+) -> Result<(), Box<dyn Error>> {
+    if moved.is_empty() {
+        return Ok(());
+    }
+    if messages::quiet() && !opts.strict_includes {
+        return Ok(());
+    }
+    let level = if opts.strict_includes {
+        "error"
+    } else {
+        "warning"
+    };
+    messages::warn(format!(
+        "{}: {} include(s) resolve to a different file since the include directories changed:",
+        level,
+        moved.len()
+    ));
+    for m in moved {
+        let via = if m.includer == m.unit {
+            String::new()
+        } else {
+            format!(" (via {})", m.includer)
+        };
+        messages::warn(format!(
+            "  {}{}: \"{}\" now resolves to {} instead of {}",
+            m.unit, via, m.spelling, m.after, m.before
+        ));
+    }
+    if opts.strict_includes {
+        return Err(BuildyError::ScanError(
+            "include resolution changed (--strict-includes)".into(),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A cache recording a build of `config` with `opts`.
+    fn built_with(root: &Path, config: &Config, opts: &BuildOptions) -> BuildCache {
+        let mut cache = BuildCache::load(root, "debug");
+        let (compiler, flags) = fingerprint(config, opts);
+        cache.compiler = Some(compiler);
+        cache.flags = flags;
+        cache.language_flags = config.language_fingerprint();
+        cache
+    }
+
+    #[test]
+    fn nothing_changed_invalidates_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let (config, opts) = (Config::default(), BuildOptions::default());
+        let cache = built_with(dir.path(), &config, &opts);
+        let invalidated = invalidated(&cache, &config, &opts);
+        assert!(!invalidated.config);
+        assert!(invalidated.languages.is_empty());
+    }
+
+    #[test]
+    fn another_compiler_invalidates_every_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let opts = BuildOptions::default();
+        let cache = built_with(dir.path(), &Config::default(), &opts);
+        // what `CC=clang` does to the loaded config
+        let config = Config {
+            compiler: "clang".into(),
+            ..Config::default()
+        };
+        assert!(invalidated(&cache, &config, &opts).config);
+    }
+
+    #[test]
+    fn other_flags_invalidate_their_language() {
+        let dir = tempfile::tempdir().unwrap();
+        let opts = BuildOptions::default();
+        let cache = built_with(dir.path(), &Config::default(), &opts);
+        let config = Config {
+            cflags: vec!["-O3".into()],
+            ..Config::default()
+        };
+        let invalidated = invalidated(&cache, &config, &opts);
+        assert_eq!(invalidated.languages, [Language::C]);
+
+        let config = Config {
+            include_dirs: vec![PathBuf::from("include")],
+            ..Config::default()
+        };
+        assert!(super::invalidated(&cache, &config, &opts).config);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_new_compiler_version_invalidates_every_source() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let cc = dir.path().join("cc");
+        std::fs::write(&cc, "#!/bin/sh\necho 'fake cc 13.2'\n").unwrap();
+        std::fs::set_permissions(&cc, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let cc = cc.display().to_string();
+        let config = Config {
+            compiler: cc.clone(),
+            cxx: Some(cc.clone()),
+            ..Config::default()
+        };
+        let opts = BuildOptions::default();
+        let mut cache = built_with(dir.path(), &config, &opts);
+        let recorded = format!("version:{}=fake cc 13.2", cc);
+        assert!(cache.flags.contains(&recorded), "{:?}", cache.flags);
+        assert!(!invalidated(&cache, &config, &opts).config);
+
+        // the cache of a build before the upgrade
+        for flag in &mut cache.flags {
+            if *flag == recorded {
+                *flag = format!("version:{}=fake cc 12.1", cc);
+            }
+        }
+        assert!(invalidated(&cache, &config, &opts).config);
+    }
+}
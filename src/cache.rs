@@ -1,86 +1,530 @@
+use crate::hasher::{HashAlgorithm, hash_file};
+use crate::includes::IncludeRecord;
+use crate::messages;
+use crate::stamp::Stamp;
 use crate::target::FileMeta;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-const CACHE_FILENAME: &str = "target/.buildy_cache.json";
+const CACHE_FILENAME: &str = ".buildy_cache.json";
 
+/// Format of the cache file, stored in it as `version`.  Bump it when a
+/// change needs more than `#[serde(default)]` on a new field for older
+/// caches to load, and add the step to `BuildCache::migrate`:
+///
+/// - 0: caches from before the format was versioned, whose keys may be
+///   absolute paths
+/// - 1: keys are relative to the root where possible
+pub const CURRENT_CACHE_VERSION: u32 = 1;
+
+/// Build state of one profile, stored in `target/<profile>/`.  Each profile
+/// has its own cache since its objects are compiled with different flags.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BuildCache {
+    /// Format the cache was written in, see `CURRENT_CACHE_VERSION`.
+    #[serde(default)]
+    pub version: u32,
     /// Entries keyed by source path string.
     pub files: HashMap<String, CachedEntry>,
     /// Compiler (gcc/g++) used for last build.
     pub compiler: Option<String>,
     /// Flags used for compilation.
     pub flags: Vec<String>,
+    /// Configured flags of each language, see `Config::language_fingerprint`.
+    #[serde(default)]
+    pub language_flags: BTreeMap<String, Vec<String>>,
+    /// Library arguments (`-L`/`-l`) of the last link.  Kept apart from
+    /// `flags` so changing them relinks without recompiling.
+    #[serde(default)]
+    pub libs: Vec<String>,
+    /// The other link-only settings of the last link, see
+    /// `Config::link_fingerprint`.
+    #[serde(default)]
+    pub link_settings: Vec<String>,
+    /// Digest of the inputs of the last successful link of each output, by
+    /// path below the profile directory, see `scheduler::link_digest`.
+    #[serde(default)]
+    pub links: BTreeMap<String, String>,
+    /// Hash of the binary each test program last passed with, by name
+    /// (`tests/<name>`), see `testing::run`.
+    #[serde(default)]
+    pub tests: BTreeMap<String, String>,
+    /// Key of each unity file as last compiled, by file name, see
+    /// `scheduler::build_unity`.
+    #[serde(default)]
+    pub unity: BTreeMap<String, String>,
+    /// Hash of the source of each public header last staged, by path below
+    /// `headers::staging_dir`, see `headers::stage`.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Version stamp compiled into the last build, if enabled.
+    #[serde(default)]
+    pub stamp: Option<Stamp>,
+    /// Dependency scan results by source, so an interrupted or repeated scan
+    /// doesn't run the compiler again for files that haven't changed.
+    #[serde(default)]
+    pub scans: HashMap<String, ScanRecord>,
+    /// How each source's quoted includes resolved, see `includes::check`.
+    #[serde(default)]
+    pub includes: HashMap<String, IncludeRecord>,
+    /// Name of the `HashAlgorithm` the file hashes were made with; `None`
+    /// in caches older than the choice, which all used sha256.
+    #[serde(default)]
+    pub hash_algorithm: Option<String>,
     /// When saved, store timestamp.
     pub saved_at: DateTime<Utc>,
+    /// File the cache was loaded from and is saved to.
+    #[serde(skip)]
+    path: PathBuf,
+    /// Modification time of `path` when it was last loaded or saved, to
+    /// notice another process saving it in the meantime.
+    #[serde(skip)]
+    modified: Option<SystemTime>,
+    /// Hashes replaced by `migrate_hashes` on load, by key, which the
+    /// combined hashes in `files` were made from (see
+    /// `plan::migrate_command_hashes`).
+    #[serde(skip)]
+    previous_hashes: HashMap<String, String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CachedEntry {
     pub hash: String,
     pub last_modified: DateTime<Utc>,
+    /// Size of the file when it was hashed.  Together with `last_modified`
+    /// it lets `unchanged_hash` skip reading the file; caches written before
+    /// it was recorded have `None`, and their files are hashed once more.
+    #[serde(default)]
+    pub size: Option<u64>,
+    /// Number of consecutive builds in which the `-MM` dependency scan of
+    /// this file failed.  Reset once a scan succeeds.
+    #[serde(default)]
+    pub scan_failures: u32,
+    /// Hash of the object file last compiled from this source.
+    #[serde(default)]
+    pub object_hash: Option<String>,
+    /// Combined hash of the compile command and the contents of this source
+    /// and its headers when it last compiled (see `DirtyPlan`).
+    #[serde(default)]
+    pub command_hash: Option<String>,
+    /// How long the compiler took the last time it compiled this source.
+    /// Kept when the source changes, as an estimate for the next compile.
+    #[serde(default)]
+    pub last_compile_ms: Option<u64>,
+}
+
+/// Modification time and size of a file, cheap to compare without reading
+/// it, and its content hash for when only the time changed (a checkout,
+/// `touch`, an editor saving without edits).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileStamp {
+    secs: u64,
+    nanos: u32,
+    size: u64,
+    #[serde(default)]
+    hash: String,
+}
+
+impl FileStamp {
+    fn of(path: &Path) -> Option<Self> {
+        let meta = fs::metadata(path).ok()?;
+        let mtime = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(FileStamp {
+            secs: mtime.as_secs(),
+            nanos: mtime.subsec_nanos(),
+            size: meta.len(),
+            hash: hash_file(path).ok()?,
+        })
+    }
+
+    /// Whether `path` still has the contents the stamp was taken of.  The
+    /// file is only read when its time changed, and then the stamp takes
+    /// the new time so the next check is cheap again.
+    fn still_matches(&mut self, path: &Path) -> bool {
+        let Ok(meta) = fs::metadata(path) else {
+            return false;
+        };
+        let Some(mtime) = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        else {
+            return false;
+        };
+        if meta.len() != self.size {
+            return false;
+        }
+        if (mtime.as_secs(), mtime.subsec_nanos()) == (self.secs, self.nanos) {
+            return true;
+        }
+        if self.hash.is_empty() || hash_file(path).ok().as_ref() != Some(&self.hash) {
+            return false;
+        }
+        self.secs = mtime.as_secs();
+        self.nanos = mtime.subsec_nanos();
+        true
+    }
+}
+
+/// How a cache entry disagrees with its file, see `BuildCache::verify`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mismatch {
+    /// The file no longer exists.
+    Missing,
+    /// The file can't be read.
+    Unreadable,
+    /// The contents changed along with the modification time or size, which
+    /// the next build notices.
+    Changed,
+    /// The contents changed but the modification time and size are the
+    /// recorded ones, so only a build with `--paranoid` notices.
+    Stale,
+}
+
+impl Mismatch {
+    pub fn describe(self) -> &'static str {
+        match self {
+            Mismatch::Missing => "missing",
+            Mismatch::Unreadable => "unreadable",
+            Mismatch::Changed => "changed since the last build, which the next one notices",
+            Mismatch::Stale => {
+                "changed without a new modification time or size, which only --paranoid notices"
+            }
+        }
+    }
+}
+
+/// The dependencies the compiler reported for a source, valid as long as
+/// the scan command and the contents of the source and every dependency are
+/// unchanged.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScanRecord {
+    command: String,
+    stamp: FileStamp,
+    /// Dependencies, relative to the root where possible, with their stamps.
+    deps: Vec<(String, FileStamp)>,
 }
 
 impl Default for BuildCache {
     fn default() -> Self {
         BuildCache {
+            version: CURRENT_CACHE_VERSION,
             files: HashMap::new(),
             compiler: None,
             flags: Vec::new(),
+            language_flags: BTreeMap::new(),
+            libs: Vec::new(),
+            link_settings: Vec::new(),
+            links: BTreeMap::new(),
+            tests: BTreeMap::new(),
+            unity: BTreeMap::new(),
+            headers: BTreeMap::new(),
+            stamp: None,
+            scans: HashMap::new(),
+            includes: HashMap::new(),
+            hash_algorithm: Some(HashAlgorithm::DEFAULT.name().to_string()),
             saved_at: Utc::now(),
+            path: PathBuf::new(),
+            modified: None,
+            previous_hashes: HashMap::new(),
         }
     }
 }
 
 impl BuildCache {
-    /// Load cache from disk, normalizing any stored paths relative to the
-    /// provided project `root`.  Older caches may contain absolute paths;
-    /// those are converted during load so that the in-memory representation
-    /// always uses paths relative to `root`.
-    pub fn load(root: &std::path::Path) -> Self {
-        if let Ok(s) = fs::read_to_string(CACHE_FILENAME) {
-            if let Ok(mut c) = serde_json::from_str::<BuildCache>(&s) {
-                c.normalize_paths(root);
-                return c;
-            }
+    /// Load cache from disk, migrating one written in an older format (see
+    /// `CURRENT_CACHE_VERSION`) so that the in-memory representation is
+    /// always current.  A cache that can't be read, or was written by a
+    /// newer Buildy in a format this one doesn't know, is reported and
+    /// replaced by an empty one.
+    pub fn load(root: &Path, profile: &str) -> Self {
+        let path = BuildCache::path(root, profile);
+        match fs::read_to_string(&path) {
+            Ok(s) => match BuildCache::parse(&s, root) {
+                Ok(mut c) => {
+                    c.migrate_hashes(root);
+                    c.modified = modified(&path);
+                    c.path = path;
+                    return c;
+                }
+                Err(e) => messages::warn(format!(
+                    "warning: {}, rebuilding from scratch ({})",
+                    e,
+                    path.display()
+                )),
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => messages::warn(format!(
+                "warning: cache unreadable, rebuilding from scratch ({}: {})",
+                path.display(),
+                e
+            )),
+        }
+        BuildCache {
+            path,
+            ..BuildCache::default()
+        }
+    }
+
+    /// Deserialize the cache file contents `text` and migrate them to
+    /// `CURRENT_CACHE_VERSION`.  The version is read first, so the fields
+    /// of a newer format are never taken for those of this one.
+    fn parse(text: &str, root: &Path) -> Result<BuildCache, String> {
+        #[derive(Deserialize)]
+        struct Versioned {
+            #[serde(default)]
+            version: u32,
+        }
+
+        let unreadable = |e: serde_json::Error| format!("cache unreadable: {}", e);
+        let Versioned { version } = serde_json::from_str(text).map_err(unreadable)?;
+        if version > CURRENT_CACHE_VERSION {
+            return Err(format!(
+                "cache written by a newer Buildy (format {}, this one reads up to {})",
+                version, CURRENT_CACHE_VERSION
+            ));
+        }
+        let mut cache: BuildCache = serde_json::from_str(text).map_err(unreadable)?;
+        cache.migrate(root);
+        Ok(cache)
+    }
+
+    /// Upgrade a cache loaded in format `self.version` to the current one,
+    /// one format at a time.
+    fn migrate(&mut self, root: &Path) {
+        if self.version < 1 {
+            self.normalize_paths(root);
         }
-        BuildCache::default()
+        self.version = CURRENT_CACHE_VERSION;
+    }
+
+    /// Whether another process saved the cache since it was loaded or last
+    /// saved here.
+    pub fn changed_on_disk(&self) -> bool {
+        modified(&self.path) != self.modified
+    }
+
+    /// Location of the cache file for `profile` of the project at `root`.
+    pub fn path(root: &Path, profile: &str) -> PathBuf {
+        root.join("target").join(profile).join(CACHE_FILENAME)
     }
 
     pub fn save(&mut self) -> io::Result<()> {
         self.saved_at = Utc::now();
 
-        if let Some(parent) = std::path::Path::new(CACHE_FILENAME).parent() {
+        if let Some(parent) = self.path.parent() {
             fs::create_dir_all(parent)?;
         }
 
+        // write next to the cache and rename over it, so a crash or Ctrl-C
+        // (e.g. while checkpointing a long scan) leaves either the old or the
+        // new cache, never half a file; the data is synced before the rename
+        // so a power loss can't leave the new name pointing at nothing
         let s = serde_json::to_string_pretty(self)?;
-        let mut f = fs::File::create(CACHE_FILENAME)?;
-        f.write_all(s.as_bytes())?;
+        let tmp = self
+            .path
+            .with_extension(format!("json.tmp.{}", std::process::id()));
+        let written = fs::File::create(&tmp).and_then(|mut f| {
+            f.write_all(s.as_bytes())?;
+            f.sync_all()
+        });
+        if let Err(e) = written.and_then(|_| fs::rename(&tmp, &self.path)) {
+            let _ = fs::remove_file(&tmp);
+            return Err(e);
+        }
+        self.modified = modified(&self.path);
+        // make the rename itself durable; directories can't be opened (or
+        // synced) like this everywhere, so this is best effort
+        if let Some(parent) = self.path.parent()
+            && let Ok(dir) = fs::File::open(parent)
+        {
+            let _ = dir.sync_all();
+        }
         Ok(())
     }
 
     /// Update a cache entry for `meta`.  Internally the key is stored as a
     /// path _relative_ to the project root so that the cache file is
     /// transportable across machines or workspace relocations.
-    pub fn update_file(&mut self, meta: &FileMeta, root: &std::path::Path) {
+    pub fn update_file(&mut self, meta: &FileMeta, root: &Path) {
         let key = BuildCache::make_relative(&meta.path, root);
-        self.files.insert(
-            key,
-            CachedEntry {
-                hash: meta.hash.clone(),
-                last_modified: meta.last_modified,
+        match self.files.get_mut(&key) {
+            Some(entry) => {
+                entry.hash = meta.hash.clone();
+                entry.last_modified = meta.last_modified;
+                entry.size = Some(meta.size);
+            }
+            None => {
+                self.files.insert(
+                    key,
+                    CachedEntry {
+                        hash: meta.hash.clone(),
+                        last_modified: meta.last_modified,
+                        size: Some(meta.size),
+                        scan_failures: 0,
+                        object_hash: None,
+                        command_hash: None,
+                        last_compile_ms: None,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Make `path` mismatch its cache entry so the next build compiles it.
+    pub fn invalidate(&mut self, path: &Path, root: &Path) {
+        if let Some(entry) = self.files.get_mut(&BuildCache::make_relative(path, root)) {
+            entry.hash.clear();
+            entry.command_hash = None;
+        }
+    }
+
+    /// Remember the hash of the object just compiled from `source`.
+    pub fn record_object(&mut self, source: &Path, root: &Path, hash: String) {
+        let key = BuildCache::make_relative(source, root);
+        if let Some(entry) = self.files.get_mut(&key) {
+            entry.object_hash = Some(hash);
+        }
+    }
+
+    /// Remember the combined hash `source` was just compiled with.
+    pub fn record_command(&mut self, source: &Path, root: &Path, hash: String) {
+        let key = BuildCache::make_relative(source, root);
+        if let Some(entry) = self.files.get_mut(&key) {
+            entry.command_hash = Some(hash);
+        }
+    }
+
+    /// Remember how long compiling `source` just took.
+    pub fn record_duration(&mut self, source: &Path, root: &Path, ms: u64) {
+        let key = BuildCache::make_relative(source, root);
+        if let Some(entry) = self.files.get_mut(&key) {
+            entry.last_compile_ms = Some(ms);
+        }
+    }
+
+    /// How long `source` took to compile the last time, if it ever did.
+    pub fn compile_ms(&self, source: &Path, root: &Path) -> Option<u64> {
+        self.files
+            .get(&BuildCache::make_relative(source, root))
+            .and_then(|e| e.last_compile_ms)
+    }
+
+    /// Whether `source` last compiled with the combined hash `hash`.
+    pub fn command_matches(&self, source: &Path, root: &Path, hash: &str) -> bool {
+        self.files
+            .get(&BuildCache::make_relative(source, root))
+            .is_some_and(|e| e.command_hash.as_deref() == Some(hash))
+    }
+
+    /// Hash of the object last compiled from `source`, if known.
+    pub fn object_hash(&self, source: &Path, root: &Path) -> Option<&str> {
+        self.files
+            .get(&BuildCache::make_relative(source, root))
+            .and_then(|e| e.object_hash.as_deref())
+    }
+
+    /// Record the outcome of a dependency scan of `path`, counting
+    /// consecutive failures so chronic ones can be reported by `doctor`.  A
+    /// failing file without an entry gets one with an empty hash, which keeps
+    /// it dirty.
+    pub fn record_scan(&mut self, path: &Path, root: &Path, ok: bool) {
+        let key = BuildCache::make_relative(path, root);
+        match self.files.get_mut(&key) {
+            Some(entry) if ok => entry.scan_failures = 0,
+            Some(entry) => entry.scan_failures += 1,
+            None if ok => {}
+            None => {
+                self.files.insert(
+                    key,
+                    CachedEntry {
+                        hash: String::new(),
+                        last_modified: Utc::now(),
+                        size: None,
+                        scan_failures: 1,
+                        object_hash: None,
+                        command_hash: None,
+                        last_compile_ms: None,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Dependencies of `source` from an earlier scan with the same `command`,
+    /// if neither the source nor any of its dependencies changed since.
+    pub fn scanned_deps(
+        &mut self,
+        source: &Path,
+        root: &Path,
+        command: &str,
+    ) -> Option<Vec<PathBuf>> {
+        let record = self
+            .scans
+            .get_mut(&BuildCache::make_relative(source, root))?;
+        if record.command != command || !record.stamp.still_matches(source) {
+            return None;
+        }
+        let mut deps = Vec::with_capacity(record.deps.len());
+        for (dep, stamp) in &mut record.deps {
+            let path = BuildCache::make_absolute(dep, root);
+            if !stamp.still_matches(&path) {
+                return None;
+            }
+            deps.push(path);
+        }
+        Some(deps)
+    }
+
+    /// Remember the result of scanning `source` with `command`.
+    pub fn record_deps(&mut self, source: &Path, root: &Path, command: String, deps: &[PathBuf]) {
+        let Some(stamp) = FileStamp::of(source) else {
+            return;
+        };
+        let deps = deps
+            .iter()
+            .filter_map(|d| Some((BuildCache::make_relative(d, root), FileStamp::of(d)?)))
+            .collect();
+        self.scans.insert(
+            BuildCache::make_relative(source, root),
+            ScanRecord {
+                command,
+                stamp,
+                deps,
             },
         );
     }
 
+    /// The cached hash of the file of `meta` if its modification time and
+    /// size, as last read into `meta`, are those it was hashed with, so it
+    /// needn't be read again.
+    pub fn unchanged_hash(&self, meta: &FileMeta, root: &Path) -> Option<&str> {
+        let entry = self
+            .files
+            .get(&BuildCache::make_relative(&meta.path, root))?;
+        let unchanged = !entry.hash.is_empty()
+            && entry.last_modified == meta.last_modified
+            && entry.size == Some(meta.size);
+        unchanged.then_some(entry.hash.as_str())
+    }
+
+    /// The hash `path` had in the cache as loaded, if `migrate_hashes`
+    /// replaced it.
+    pub fn previous_hash(&self, path: &Path, root: &Path) -> Option<&str> {
+        self.previous_hashes
+            .get(&BuildCache::make_relative(path, root))
+            .map(String::as_str)
+    }
+
     /// Check whether a given file matches the cached hash.  `meta.path` is
     /// converted to the corresponding relative key before lookup.
-    pub fn file_matches(&self, meta: &FileMeta, root: &std::path::Path) -> bool {
+    pub fn file_matches(&self, meta: &FileMeta, root: &Path) -> bool {
         let key = BuildCache::make_relative(&meta.path, root);
         if let Some(entry) = self.files.get(&key) {
             entry.hash == meta.hash
@@ -89,17 +533,90 @@ impl BuildCache {
         }
     }
 
+    /// Hash the file of every entry and return the entries that don't match
+    /// it, sorted by key.  Entries a failed build left unhashed are dirty
+    /// anyway and aren't checked.
+    pub fn verify(&self, root: &Path) -> Vec<(String, Mismatch)> {
+        let mut mismatches: Vec<(String, Mismatch)> = self
+            .files
+            .iter()
+            .filter(|(_, entry)| !entry.hash.is_empty())
+            .filter_map(|(key, entry)| {
+                let path = BuildCache::make_absolute(key, root);
+                let mismatch = match hash_file(&path) {
+                    Ok(hash) if hash == entry.hash => return None,
+                    Ok(_) => {
+                        let recorded = fs::metadata(&path).is_ok_and(|m| {
+                            entry.size == Some(m.len())
+                                && m.modified()
+                                    .is_ok_and(|t| DateTime::<Utc>::from(t) == entry.last_modified)
+                        });
+                        if recorded {
+                            Mismatch::Stale
+                        } else {
+                            Mismatch::Changed
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => Mismatch::Missing,
+                    Err(_) => Mismatch::Unreadable,
+                };
+                Some((key.clone(), mismatch))
+            })
+            .collect();
+        mismatches.sort_by(|a, b| a.0.cmp(&b.0));
+        mismatches
+    }
+
+    /// Bring the entries `verify` found out of step with their files: drop
+    /// those of missing files, and forget the hashes of the others, so the
+    /// next build hashes them again and recompiles what they affect.
+    pub fn fix(&mut self, mismatches: &[(String, Mismatch)]) {
+        for (key, mismatch) in mismatches {
+            if *mismatch == Mismatch::Missing {
+                self.files.remove(key);
+            } else if let Some(entry) = self.files.get_mut(key) {
+                entry.hash.clear();
+                entry.command_hash = None;
+            }
+        }
+    }
+
+    /// Drop the entries, dependency scans and include records of files that
+    /// no longer exist.  Returns how many file entries were dropped.
+    pub fn prune_missing(&mut self, root: &Path) -> usize {
+        let exists = |key: &String| BuildCache::make_absolute(key, root).exists();
+        let before = self.files.len();
+        self.files.retain(|key, _| exists(key));
+        self.scans.retain(|key, _| exists(key));
+        self.includes.retain(|key, _| exists(key));
+        before - self.files.len()
+    }
+
+    /// Short digest of the compiler and flags the cache was last built with,
+    /// to compare profiles or machines at a glance.
+    pub fn fingerprint(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.compiler.as_deref().unwrap_or_default().as_bytes());
+        for flag in &self.flags {
+            hasher.update(b"\0");
+            hasher.update(flag.as_bytes());
+        }
+        for (language, flags) in &self.language_flags {
+            hasher.update(format!("\n{}={}", language, flags.join("\0")).as_bytes());
+        }
+        format!("{:x}", hasher.finalize())[..12].to_string()
+    }
+
     pub fn config_matches(&self, compiler: &str, flags: &[String]) -> bool {
         self.compiler.as_deref() == Some(compiler) && self.flags == flags
     }
 
     /// Iterate over the cached file paths as absolute `PathBuf`s, converting
     /// each stored relative key into an absolute path joined with `root`.
-    pub fn iter_absolute_paths<'a>(
-        &'a self,
-        root: &'a std::path::Path,
-    ) -> impl Iterator<Item = std::path::PathBuf> + 'a {
-        self.files.keys().map(move |k| BuildCache::make_absolute(k, root))
+    pub fn iter_absolute_paths<'a>(&'a self, root: &'a Path) -> impl Iterator<Item = PathBuf> + 'a {
+        self.files
+            .keys()
+            .map(move |k| BuildCache::make_absolute(k, root))
     }
 
     /// Convert an absolute path to one relative to the project root.  If the
@@ -109,7 +626,7 @@ impl BuildCache {
     /// original path if it cannot be made relative).  This helper is public
     /// because callers (e.g. `main.rs`) need to generate relative keys when
     /// comparing the set of existing files.
-    pub fn make_relative(path: &std::path::Path, root: &std::path::Path) -> String {
+    pub fn make_relative(path: &Path, root: &Path) -> String {
         if let Ok(rel) = path.strip_prefix(root) {
             rel.to_string_lossy().to_string()
         } else {
@@ -119,22 +636,18 @@ impl BuildCache {
 
     /// Given a stored (relative) path string, return an absolute path by
     /// joining it with `root` when appropriate.
-    pub fn make_absolute(rel: &str, root: &std::path::Path) -> std::path::PathBuf {
-        let p = std::path::PathBuf::from(rel);
-        if p.is_absolute() {
-            p
-        } else {
-            root.join(p)
-        }
+    pub fn make_absolute(rel: &str, root: &Path) -> PathBuf {
+        let p = PathBuf::from(rel);
+        if p.is_absolute() { p } else { root.join(p) }
     }
 
     /// Normalize any existing keys stored in `self.files` so they are all
-    /// relative to `root`.  This is used when loading a cache that may have
-    /// been written with absolute paths in older versions of the tool.
-    fn normalize_paths(&mut self, root: &std::path::Path) {
+    /// relative to `root`: the migration from format 0, which may have been
+    /// written with absolute paths.
+    fn normalize_paths(&mut self, root: &Path) {
         let mut newfiles = HashMap::new();
         for (k, v) in self.files.drain() {
-            let p = std::path::PathBuf::from(&k);
+            let p = PathBuf::from(&k);
             let key = if p.is_absolute() {
                 if let Ok(rel) = p.strip_prefix(root) {
                     rel.to_string_lossy().to_string()
@@ -148,4 +661,192 @@ impl BuildCache {
         }
         self.files = newfiles;
     }
+
+    /// Bring the file hashes of a cache written with another algorithm
+    /// than `HashAlgorithm::DEFAULT` up to date, so the first build after
+    /// the switch compiles only what changed.  A file whose contents still
+    /// give its recorded hash under the old algorithm gets the new one, and
+    /// the old one is kept in `previous_hashes`; any other file is left
+    /// unhashed, which makes it dirty.
+    ///
+    /// Object and test binary hashes only detect changes and are kept: the
+    /// link and test run after the first recompile of each don't skip on
+    /// identical bytes.
+    fn migrate_hashes(&mut self, root: &Path) {
+        let written_with = match &self.hash_algorithm {
+            Some(name) => HashAlgorithm::from_name(name),
+            None => Some(HashAlgorithm::Sha256),
+        };
+        if written_with == Some(HashAlgorithm::DEFAULT) {
+            return;
+        }
+        for (key, entry) in &mut self.files {
+            if entry.hash.is_empty() {
+                continue;
+            }
+            let path = BuildCache::make_absolute(key, root);
+            let unchanged = written_with
+                .is_some_and(|old| old.hash_file(&path).is_ok_and(|hash| hash == entry.hash));
+            match HashAlgorithm::DEFAULT.hash_file(&path) {
+                Ok(hash) if unchanged => {
+                    let old = std::mem::replace(&mut entry.hash, hash);
+                    self.previous_hashes.insert(key.clone(), old);
+                }
+                _ => entry.hash.clear(),
+            }
+        }
+        self.hash_algorithm = Some(HashAlgorithm::DEFAULT.name().to_string());
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A project with `src/main.c` and `src/util.c`, and the path of its
+    /// debug cache.
+    fn project() -> (tempfile::TempDir, PathBuf, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::write(root.join("src/main.c"), "int main(void) { return 0; }\n").unwrap();
+        fs::write(root.join("src/util.c"), "int util;\n").unwrap();
+        let path = BuildCache::path(&root, "debug");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        (dir, root, path)
+    }
+
+    fn sha256(path: &Path) -> String {
+        HashAlgorithm::Sha256.hash_file(path).unwrap()
+    }
+
+    /// A cache as Buildy wrote it before formats were versioned: no
+    /// `version` or `hash_algorithm`, absolute keys and SHA-256 hashes.
+    /// `src/util.c` was edited after it was written.
+    fn v0_fixture(root: &Path) -> String {
+        let main = root.join("src/main.c");
+        let util = root.join("src/util.c");
+        serde_json::json!({
+            "files": {
+                main.display().to_string(): {
+                    "hash": sha256(&main),
+                    "last_modified": "2024-03-01T12:00:00Z",
+                    "command_hash": "0123abcd"
+                },
+                util.display().to_string(): {
+                    "hash": "0000000000000000000000000000000000000000000000000000000000000000",
+                    "last_modified": "2024-03-01T12:00:00Z"
+                },
+                "/elsewhere/include/lib.h": {
+                    "hash": "",
+                    "last_modified": "2024-03-01T12:00:00Z"
+                }
+            },
+            "compiler": "gcc",
+            "flags": ["-g"],
+            "saved_at": "2024-03-01T12:00:00Z"
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn migrates_an_unversioned_cache() {
+        let (_dir, root, path) = project();
+        fs::write(&path, v0_fixture(&root)).unwrap();
+
+        let cache = BuildCache::load(&root, "debug");
+        assert_eq!(cache.version, CURRENT_CACHE_VERSION);
+        assert_eq!(cache.compiler.as_deref(), Some("gcc"));
+        assert_eq!(cache.flags, vec!["-g".to_string()]);
+        let mut keys: Vec<&str> = cache.files.keys().map(String::as_str).collect();
+        keys.sort();
+        assert_eq!(
+            keys,
+            vec!["/elsewhere/include/lib.h", "src/main.c", "src/util.c"]
+        );
+        assert_eq!(cache.hash_algorithm.as_deref(), Some("xxh64"));
+    }
+
+    #[test]
+    fn rehashes_files_hashed_with_sha256() {
+        let (_dir, root, path) = project();
+        fs::write(&path, v0_fixture(&root)).unwrap();
+        let main = root.join("src/main.c");
+
+        let cache = BuildCache::load(&root, "debug");
+        // unchanged since: the new hash, with the old one remembered
+        let entry = &cache.files["src/main.c"];
+        assert_eq!(entry.hash, hash_file(&main).unwrap());
+        assert_eq!(entry.command_hash.as_deref(), Some("0123abcd"));
+        assert_eq!(
+            cache.previous_hash(&main, &root),
+            Some(sha256(&main).as_str())
+        );
+        // edited since: no hash, so the next build compiles it
+        assert_eq!(cache.files["src/util.c"].hash, "");
+        assert_eq!(cache.previous_hash(&root.join("src/util.c"), &root), None);
+    }
+
+    #[test]
+    fn round_trips_the_current_format() {
+        let (_dir, root, _) = project();
+        let main = root.join("src/main.c");
+        let mut cache = BuildCache::load(&root, "debug");
+        assert!(cache.files.is_empty());
+        cache.compiler = Some("clang".into());
+        cache.flags = vec!["-O2".into(), "-DX=1".into()];
+        cache.libs = vec!["-lm".into()];
+        let meta = FileMeta::new(main.clone()).unwrap();
+        cache.update_file(&meta, &root);
+        cache.record_command(&main, &root, "feed".into());
+        cache.record_duration(&main, &root, 250);
+        cache.save().unwrap();
+
+        let loaded = BuildCache::load(&root, "debug");
+        assert_eq!(loaded.version, CURRENT_CACHE_VERSION);
+        assert_eq!(loaded.compiler, cache.compiler);
+        assert_eq!(loaded.flags, cache.flags);
+        assert_eq!(loaded.libs, cache.libs);
+        assert_eq!(loaded.hash_algorithm, cache.hash_algorithm);
+        assert_eq!(loaded.files.len(), 1);
+        let (saved, entry) = (&cache.files["src/main.c"], &loaded.files["src/main.c"]);
+        assert_eq!(entry.hash, saved.hash);
+        assert_eq!(entry.size, Some(meta.size));
+        assert_eq!(entry.last_modified, saved.last_modified);
+        assert!(loaded.command_matches(&main, &root, "feed"));
+        assert_eq!(loaded.compile_ms(&main, &root), Some(250));
+        assert!(loaded.file_matches(&meta, &root));
+        assert!(!loaded.changed_on_disk());
+    }
+
+    #[test]
+    fn rejects_a_cache_from_a_newer_buildy() {
+        let (_dir, root, path) = project();
+        let text = serde_json::json!({
+            "version": CURRENT_CACHE_VERSION + 1,
+            "files": { "src/main.c": { "hash": 42 } },
+            "renamed_field": true
+        })
+        .to_string();
+        let error = BuildCache::parse(&text, &root).unwrap_err();
+        assert!(error.contains("newer Buildy"), "{}", error);
+        assert!(error.contains(&format!("format {}", CURRENT_CACHE_VERSION + 1)));
+
+        // loading starts over instead of misreading the fields
+        fs::write(&path, text).unwrap();
+        let cache = BuildCache::load(&root, "debug");
+        assert!(cache.files.is_empty());
+        assert_eq!(cache.version, CURRENT_CACHE_VERSION);
+    }
+
+    #[test]
+    fn rejects_a_corrupt_cache() {
+        let (_dir, root, _) = project();
+        let error = BuildCache::parse("{\"files\": {", &root).unwrap_err();
+        assert!(error.starts_with("cache unreadable"), "{}", error);
+    }
 }
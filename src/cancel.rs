@@ -0,0 +1,110 @@
+use std::io::{self, Read};
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// Set by Ctrl-C, see `install`.
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Set while a program that handles Ctrl-C itself has the terminal, see
+/// `hand_over`.
+static HANDED_OVER: AtomicBool = AtomicBool::new(false);
+
+/// How often a running compile checks whether the build was cancelled.
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Turn Ctrl-C into a cancellation request the build checks instead of
+/// letting it kill the process, so the compiles that finished are kept in
+/// the cache.  A second Ctrl-C while the first is pending exits right away.
+/// Without Unix signals Ctrl-C keeps its default behaviour.
+pub fn install() {
+    #[cfg(unix)]
+    {
+        extern "C" fn on_interrupt(_: libc::c_int) {
+            if HANDED_OVER.load(Ordering::SeqCst) {
+                return;
+            }
+            if REQUESTED.swap(true, Ordering::SeqCst) {
+                // only async-signal-safe calls here
+                unsafe { libc::_exit(130) };
+            }
+        }
+        let handler: extern "C" fn(libc::c_int) = on_interrupt;
+        unsafe {
+            libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+        }
+    }
+}
+
+/// Run `f` with Ctrl-C left to the program it starts, such as a debugger
+/// that stops the debuggee on Ctrl-C: the terminal sends it to Buildy too,
+/// and it must neither cancel anything nor, pressed twice, exit and leave
+/// the program without its terminal.
+pub fn hand_over<T>(f: impl FnOnce() -> T) -> T {
+    HANDED_OVER.store(true, Ordering::SeqCst);
+    let result = f();
+    HANDED_OVER.store(false, Ordering::SeqCst);
+    result
+}
+
+/// Whether Ctrl-C was pressed since the last `reset`.
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Forget an earlier Ctrl-C; each build in watch mode starts afresh.
+pub fn reset() {
+    REQUESTED.store(false, Ordering::SeqCst);
+}
+
+/// The error of a command killed because the build was cancelled.
+pub fn interrupted() -> io::Error {
+    io::Error::new(io::ErrorKind::Interrupted, "build interrupted")
+}
+
+/// Like `Command::output`, except that the program is killed as soon as the
+/// build is cancelled rather than left running.
+pub fn output(command: &mut Command) -> io::Result<Output> {
+    if requested() {
+        return Err(interrupted());
+    }
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // read both pipes while waiting, or a chatty program blocks on a full one
+    let stdout = drain(child.stdout.take());
+    let stderr = drain(child.stderr.take());
+    let status = wait(&mut child)?;
+    Ok(Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+fn wait(child: &mut Child) -> io::Result<std::process::ExitStatus> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        if requested() {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(interrupted());
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn drain(pipe: Option<impl Read + Send + 'static>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut bytes = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut bytes);
+        }
+        bytes
+    })
+}
@@ -0,0 +1,177 @@
+use crate::cancel;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::{Command, ExitStatus, Output};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// Longest delay a `Fault::Delay` adds to a command.
+const MAX_DELAY: Duration = Duration::from_secs(2);
+
+/// A failure `Chaos` can inject into a command, each with the probability
+/// from the environment variable named in `RATES`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The program can't be started.
+    Spawn,
+    /// The program exits with an error without doing anything.
+    Exit,
+    /// The program finishes, but late.
+    Delay,
+    /// The program dies halfway through writing its output file and its
+    /// captured output.
+    Truncate,
+    /// The build is cancelled: this and every later command fail.
+    Cancel,
+}
+
+const RATES: [(Fault, &str); 5] = [
+    (Fault::Spawn, "BUILDY_CHAOS_SPAWN"),
+    (Fault::Exit, "BUILDY_CHAOS_EXIT"),
+    (Fault::Delay, "BUILDY_CHAOS_DELAY"),
+    (Fault::Truncate, "BUILDY_CHAOS_TRUNCATE"),
+    (Fault::Cancel, "BUILDY_CHAOS_CANCEL"),
+];
+
+/// Probability of each fault unless its variable says otherwise.
+const DEFAULT_RATE: f64 = 0.05;
+
+/// Developer mode behind the hidden `--chaos <seed>` flag that runs compiles
+/// through `output`, which randomly injects faults to exercise the error
+/// paths of the scheduler and the caches.  Whether a command gets a fault
+/// only depends on the seed and the command's key, not on timing, so a
+/// failing seed reproduces regardless of `--jobs`.
+pub struct Chaos {
+    seed: u64,
+    rates: Vec<(Fault, f64)>,
+    cancelled: AtomicBool,
+}
+
+impl Chaos {
+    /// Chaos for `seed`, with the fault probabilities read from the
+    /// environment.
+    pub fn new(seed: u64) -> Self {
+        let rates = RATES
+            .iter()
+            .map(|&(fault, var)| {
+                let rate = match env::var(var) {
+                    Ok(value) => match value.parse::<f64>() {
+                        Ok(rate) if (0.0..=1.0).contains(&rate) => rate,
+                        _ => {
+                            eprintln!(
+                                "warning: ignoring {}={}: not a probability between 0 and 1",
+                                var, value
+                            );
+                            DEFAULT_RATE
+                        }
+                    },
+                    Err(_) => DEFAULT_RATE,
+                };
+                (fault, rate)
+            })
+            .collect();
+        Chaos {
+            seed,
+            rates,
+            cancelled: AtomicBool::new(false),
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// The fault injected into the command identified by `key`, if any,
+    /// and a number to derive its details from.
+    fn draw(&self, key: &str) -> (Option<Fault>, u64) {
+        // FNV-1a of the key, mixed with the seed
+        let mut state = key.bytes().fold(0xcbf29ce484222325u64, |h, b| {
+            (h ^ b as u64).wrapping_mul(0x100000001b3)
+        }) ^ self.seed;
+        let roll = unit(splitmix64(&mut state));
+        let detail = splitmix64(&mut state);
+        let mut threshold = 0.0;
+        for &(fault, rate) in &self.rates {
+            threshold += rate;
+            if roll < threshold {
+                return (Some(fault), detail);
+            }
+        }
+        (None, detail)
+    }
+
+    /// Run `command` like `Command::output`, unless the draw for `key` says
+    /// to fail it.  `product` is the file the command writes, truncated by
+    /// `Fault::Truncate`.
+    pub fn output(&self, key: &str, command: &mut Command, product: &Path) -> io::Result<Output> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            return Err(cancelled());
+        }
+        let (fault, detail) = self.draw(key);
+        match fault {
+            None => cancel::output(command),
+            Some(Fault::Spawn) => Err(io::Error::other("injected spawn failure (chaos)")),
+            Some(Fault::Exit) => Ok(Output {
+                status: failed_status(),
+                stdout: Vec::new(),
+                stderr: b"injected failure (chaos)".to_vec(),
+            }),
+            Some(Fault::Delay) => {
+                let output = cancel::output(command);
+                thread::sleep(MAX_DELAY.mul_f64(unit(detail)));
+                output
+            }
+            Some(Fault::Truncate) => {
+                let mut output = cancel::output(command)?;
+                if let Ok(len) = fs::metadata(product).map(|m| m.len()) {
+                    let _ = fs::File::options()
+                        .write(true)
+                        .open(product)
+                        .and_then(|f| f.set_len(len / 2));
+                }
+                output.stdout.truncate(output.stdout.len() / 2);
+                output.stderr.truncate(output.stderr.len() / 2);
+                output.status = failed_status();
+                Ok(output)
+            }
+            Some(Fault::Cancel) => {
+                self.cancelled.store(true, Ordering::Relaxed);
+                Err(cancelled())
+            }
+        }
+    }
+}
+
+fn cancelled() -> io::Error {
+    io::Error::new(io::ErrorKind::Interrupted, "build cancelled (chaos)")
+}
+
+/// Exit status of a program that exited with 1.
+fn failed_status() -> ExitStatus {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        ExitStatus::from_raw(1 << 8)
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::ExitStatusExt;
+        ExitStatus::from_raw(1)
+    }
+}
+
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// `bits` as a number in `[0, 1)`.
+fn unit(bits: u64) -> f64 {
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
@@ -0,0 +1,342 @@
+use crate::cache::BuildCache;
+use crate::store::ObjectStore;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use walkdir::WalkDir;
+
+/// Remove build outputs of the project at `root`: the directories of
+/// `profile` (or of every one of `profiles`, the profiles the project
+/// knows), which also hold their build caches.  With
+/// `cache_only` the objects are kept and only the caches are dropped,
+/// forcing the next build to recompile everything.  Cleaning every profile
+/// also empties the shared object store.
+pub fn clean(
+    root: &Path,
+    profile: Option<&str>,
+    profiles: &[String],
+    cache_only: bool,
+) -> io::Result<()> {
+    let profiles: Vec<&str> = match profile {
+        Some(p) => vec![p],
+        None => profiles.iter().map(String::as_str).collect(),
+    };
+    let mut removed = 0;
+    for p in profiles {
+        let target = if cache_only {
+            BuildCache::path(root, p)
+        } else {
+            root.join("target").join(p)
+        };
+        removed += remove(&target)? as usize;
+    }
+    if profile.is_none() {
+        removed += remove(ObjectStore::new(root).dir())? as usize;
+    }
+    if removed == 0 {
+        println!("nothing to clean");
+    }
+    Ok(())
+}
+
+/// A directory `clean_stale` removed.
+#[derive(Debug)]
+pub struct Removed {
+    pub path: PathBuf,
+    /// Size of the files it held.
+    pub bytes: u64,
+    /// How long ago its newest file was modified.
+    pub age: Duration,
+}
+
+impl fmt::Display for Removed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "removed {} ({}, last used {} days ago)",
+            self.path.display(),
+            format_bytes(self.bytes),
+            self.age.as_secs() / 86400
+        )
+    }
+}
+
+/// Remove directories under `target/` that don't belong to the current
+/// configuration (one of `profiles`) and whose newest file is older than
+/// `older_than`.  Returns what was removed, for the caller to report.
+pub fn clean_stale(
+    root: &Path,
+    profiles: &[String],
+    older_than: Duration,
+) -> io::Result<Vec<Removed>> {
+    let entries = match fs::read_dir(root.join("target")) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let now = SystemTime::now();
+    let mut removed = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        // hidden directories hold state shared by every configuration, such
+        // as the object store
+        if !path.is_dir() || profiles.iter().any(|p| p == name) || name.starts_with('.') {
+            continue;
+        }
+        let (bytes, newest) = usage(&path);
+        let age = now.duration_since(newest).unwrap_or_default();
+        if age < older_than {
+            continue;
+        }
+        fs::remove_dir_all(&path)?;
+        removed.push(Removed { path, bytes, age });
+    }
+    Ok(removed)
+}
+
+/// Stamp file recording when `auto_clean_stale` last ran.
+const AUTO_CLEAN_STAMP: &str = ".buildy_stale_check";
+const AUTO_CLEAN_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Run `clean_stale` after a successful build when `auto_clean_stale` is
+/// configured, at most once a day.  Returns what was removed, nothing when
+/// it didn't run.
+pub fn auto_clean(
+    root: &Path,
+    profiles: &[String],
+    older_than: Duration,
+) -> io::Result<Vec<Removed>> {
+    let stamp = root.join("target").join(AUTO_CLEAN_STAMP);
+    let last = fs::metadata(&stamp).and_then(|m| m.modified());
+    if let Ok(last) = last
+        && SystemTime::now().duration_since(last).unwrap_or_default() < AUTO_CLEAN_INTERVAL
+    {
+        return Ok(Vec::new());
+    }
+    fs::write(&stamp, b"")?;
+    clean_stale(root, profiles, older_than)
+}
+
+/// Total size of the files below `dir` and the newest modification time
+/// among them (or of `dir` itself when empty).
+fn usage(dir: &Path) -> (u64, SystemTime) {
+    let mut bytes = 0;
+    let mut newest = fs::metadata(dir)
+        .and_then(|m| m.modified())
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+    for entry in WalkDir::new(dir).into_iter().filter_map(|e| e.ok()) {
+        if let Ok(meta) = entry.metadata()
+            && meta.is_file()
+        {
+            bytes += meta.len();
+            if let Ok(modified) = meta.modified() {
+                newest = newest.max(modified);
+            }
+        }
+    }
+    (bytes, newest)
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Parse an age such as `30d`, `12h`, `2w` or `90m`.
+pub fn parse_age(text: &str) -> Result<Duration, String> {
+    let text = text.trim();
+    let split = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid age '{}', expected e.g. 30d", text))?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" | "" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => {
+            return Err(format!(
+                "invalid age unit '{}' in '{}', use s, m, h, d or w",
+                unit, text
+            ));
+        }
+    };
+    Ok(Duration::from_secs(number * seconds))
+}
+
+/// Delete a file or directory tree, returning false if it didn't exist.
+fn remove(path: &Path) -> io::Result<bool> {
+    let result = if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    };
+    match result {
+        Ok(()) => {
+            println!("removed {}", path.display());
+            Ok(true)
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+// directories are opened to set their mtime, which Windows doesn't allow
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    /// Write `bytes` bytes to `path` below `target/`.
+    fn output(root: &Path, path: &str, bytes: usize) {
+        let path = root.join("target").join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, vec![b'o'; bytes]).unwrap();
+    }
+
+    /// Make everything below `target/<dir>`, and the directory itself, last
+    /// modified `days` ago.
+    fn age(root: &Path, dir: &str, days: u32) {
+        let time = SystemTime::now() - DAY * days;
+        let dir = root.join("target").join(dir);
+        for entry in WalkDir::new(dir).contents_first(true) {
+            File::open(entry.unwrap().path())
+                .unwrap()
+                .set_modified(time)
+                .unwrap();
+        }
+    }
+
+    /// The bytes `removed` reclaimed.
+    fn bytes(removed: io::Result<Vec<Removed>>) -> u64 {
+        removed.unwrap().iter().map(|dir| dir.bytes).sum()
+    }
+
+    fn exists(root: &Path, dir: &str) -> bool {
+        root.join("target").join(dir).exists()
+    }
+
+    #[test]
+    fn removes_old_directories_of_other_configurations() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        output(root, "debug/app", 100);
+        output(root, "old-variant/obj/main.c.o", 1000);
+        output(root, "old-variant/app", 24);
+        output(root, "recent-variant/app", 10);
+        output(root, "retired/app", 7);
+        output(root, ".buildy/store/x.o", 5);
+        age(root, "debug", 90);
+        age(root, "old-variant", 45);
+        age(root, "recent-variant", 3);
+        age(root, "retired", 31);
+        age(root, ".buildy", 90);
+
+        let profiles = ["debug".to_string(), "release".to_string()];
+        let removed = clean_stale(root, &profiles, 30 * DAY).unwrap();
+        let mut names: Vec<String> = removed
+            .iter()
+            .map(|dir| dir.path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["old-variant", "retired"]);
+        assert_eq!(removed.iter().map(|dir| dir.bytes).sum::<u64>(), 1031);
+        let retired = removed
+            .iter()
+            .find(|dir| dir.path.ends_with("retired"))
+            .unwrap();
+        assert_eq!(retired.age.as_secs() / DAY.as_secs(), 31);
+        assert!(
+            retired
+                .to_string()
+                .ends_with("(7 B, last used 31 days ago)")
+        );
+        assert!(!exists(root, "old-variant"));
+        assert!(!exists(root, "retired"));
+        // the current configuration, what is recent and shared state stay
+        assert!(exists(root, "debug/app"));
+        assert!(exists(root, "recent-variant/app"));
+        assert!(exists(root, ".buildy/store/x.o"));
+    }
+
+    #[test]
+    fn a_directory_is_as_old_as_its_newest_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        output(root, "variant/obj/a.o", 10);
+        output(root, "variant/obj/b.o", 10);
+        age(root, "variant", 60);
+        let recent = root.join("target/variant/obj/b.o");
+        File::open(&recent)
+            .unwrap()
+            .set_modified(SystemTime::now() - DAY * 2)
+            .unwrap();
+        assert_eq!(bytes(clean_stale(root, &[], 30 * DAY)), 0);
+        assert!(recent.exists());
+        assert_eq!(bytes(clean_stale(root, &[], DAY)), 20);
+        assert!(!exists(root, "variant"));
+    }
+
+    #[test]
+    fn nothing_to_remove_without_target() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(clean_stale(dir.path(), &[], DAY).unwrap().is_empty());
+    }
+
+    #[test]
+    fn auto_clean_runs_at_most_once_a_day() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        output(root, "variant/app", 10);
+        age(root, "variant", 60);
+        fs::write(root.join("target").join(AUTO_CLEAN_STAMP), b"").unwrap();
+        assert!(auto_clean(root, &[], 30 * DAY).unwrap().is_empty());
+        assert!(exists(root, "variant"), "ran again within a day");
+
+        File::open(root.join("target").join(AUTO_CLEAN_STAMP))
+            .unwrap()
+            .set_modified(SystemTime::now() - DAY * 2)
+            .unwrap();
+        assert_eq!(bytes(auto_clean(root, &[], 30 * DAY)), 10);
+        assert!(!exists(root, "variant"));
+        assert!(exists(root, AUTO_CLEAN_STAMP));
+    }
+
+    #[test]
+    fn ages() {
+        assert_eq!(parse_age("30d").unwrap(), 30 * DAY);
+        assert_eq!(parse_age("30").unwrap(), 30 * DAY);
+        assert_eq!(parse_age("2w").unwrap(), 14 * DAY);
+        assert_eq!(parse_age(" 12h ").unwrap(), DAY / 2);
+        assert_eq!(parse_age("90m").unwrap(), Duration::from_secs(90 * 60));
+        assert!(parse_age("d").is_err());
+        assert!(parse_age("3y").unwrap_err().contains("unit 'y'"));
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(5 * 1024 * 1024 * 1024), "5.0 GiB");
+    }
+}
@@ -0,0 +1,57 @@
+use crate::config::Config;
+use crate::graph::BuildGraph;
+use crate::scheduler::{self, BuildOptions};
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const COMPDB_FILENAME: &str = "compile_commands.json";
+
+/// One entry of a JSON compilation database as understood by clangd and
+/// clang-tidy.
+#[derive(Serialize)]
+struct Entry {
+    directory: PathBuf,
+    file: PathBuf,
+    arguments: Vec<String>,
+    output: PathBuf,
+}
+
+/// Whether builds should keep `compile_commands.json` up to date: when asked
+/// to in `buildy.toml`, or once the file exists (e.g. after running
+/// `buildy compile-commands`).
+pub fn wanted(root: &Path, config: &Config) -> bool {
+    config.compile_commands || root.join(COMPDB_FILENAME).exists()
+}
+
+/// Write `compile_commands.json` into `root` with one entry per source in
+/// the graph, using the same commands the scheduler runs.  The file is only
+/// rewritten when its contents change so editors don't reindex needlessly.
+/// Returns the path of the database.
+pub fn emit(
+    graph: &BuildGraph,
+    root: &Path,
+    config: &Config,
+    opts: &BuildOptions,
+) -> Result<PathBuf, String> {
+    let entries: Vec<Entry> = scheduler::compile_commands(graph, root, config, opts)?
+        .into_iter()
+        .map(|c| {
+            let mut arguments = vec![c.program];
+            arguments.extend(c.args);
+            Entry {
+                directory: c.directory,
+                file: c.source,
+                arguments,
+                output: c.object,
+            }
+        })
+        .collect();
+
+    let path = root.join(COMPDB_FILENAME);
+    let json = serde_json::to_string_pretty(&entries).map_err(|e| e.to_string())?;
+    if fs::read_to_string(&path).ok().as_deref() != Some(json.as_str()) {
+        fs::write(&path, json).map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
+    Ok(path)
+}
@@ -0,0 +1,70 @@
+use crate::config::Config;
+use crate::profile;
+use clap::Command;
+use clap_complete::engine::{ArgValueCandidates, CompletionCandidate};
+use std::env;
+use std::path::Path;
+
+/// Shells `completions` writes a script for.
+pub use clap_complete::Shell;
+
+/// The completion script of `cmd`, named `bin`, for `shell`: the
+/// subcommands, their flags and the values of flags that take a fixed set.
+pub fn generate(mut cmd: Command, bin: &str, shell: Shell) -> String {
+    let mut script = Vec::new();
+    clap_complete::generate(shell, &mut cmd, bin, &mut script);
+    String::from_utf8_lossy(&script).into_owned()
+}
+
+/// Completer of `--profile` for the completion registered with
+/// `COMPLETE=<shell> buildy`: the profiles of the project in the current
+/// directory, read each time so new `[profiles]` show up at once.
+pub fn profiles() -> ArgValueCandidates {
+    ArgValueCandidates::new(|| {
+        let root = env::current_dir().unwrap_or_default();
+        profile_candidates(&root)
+    })
+}
+
+fn profile_candidates(root: &Path) -> Vec<CompletionCandidate> {
+    profile::names(&Config::load(root))
+        .into_iter()
+        .map(CompletionCandidate::new)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Arg;
+    use std::fs;
+
+    #[test]
+    fn the_script_names_every_subcommand() {
+        let cmd = Command::new("buildy")
+            .subcommand(Command::new("build").arg(Arg::new("release").long("release")))
+            .subcommand(Command::new("clean"));
+        for shell in [Shell::Bash, Shell::Zsh, Shell::Fish] {
+            let script = generate(cmd.clone(), "buildy", shell);
+            for word in ["build", "clean", "release"] {
+                assert!(script.contains(word), "{:?} lacks {}", shell, word);
+            }
+        }
+    }
+
+    #[test]
+    fn profiles_include_the_configured_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("buildy.toml"),
+            "[profiles.fast]\ncflags = [\"-O1\"]\n",
+        )
+        .unwrap();
+        let names: Vec<String> = profile_candidates(dir.path())
+            .iter()
+            .map(|c| c.get_value().to_string_lossy().into_owned())
+            .collect();
+        assert!(names.contains(&"fast".to_string()), "{:?}", names);
+        assert!(names.contains(&"release".to_string()), "{:?}", names);
+    }
+}
@@ -0,0 +1,778 @@
+use crate::lang::Language;
+use crate::matcher::{IGNORE_FILENAME, IgnoreFile, PathMatcher};
+use crate::messages;
+use crate::toolchain::{self, Toolchain};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const CONFIG_FILENAME: &str = "buildy.toml";
+
+/// The wrappers `compiler_wrapper = "auto"` looks for, in order.
+const AUTO_WRAPPERS: [&str; 2] = ["sccache", "ccache"];
+
+/// Project configuration read from `buildy.toml` in the project root.  Every
+/// field is optional; anything left out keeps the built-in default.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// C compiler.  The C++ compiler is derived from it (`gcc` -> `g++`,
+    /// `clang` -> `clang++`).
+    pub compiler: String,
+    /// C++ compiler, when it can't be derived from `compiler`.
+    pub cxx: Option<String>,
+    /// Program every compile runs through, such as `"ccache"` or
+    /// `"sccache"`, with the compiler and its arguments after it; `"auto"`
+    /// uses whichever of those is on PATH.  Dependency scans run the
+    /// compiler directly.  Overridden by `--wrapper`.
+    pub compiler_wrapper: Option<String>,
+    /// `"gnu"` or `"msvc"`, for compilers whose name doesn't tell (see
+    /// `Toolchain::of_compiler`).  Also picks `cl` as the default compiler.
+    pub toolchain: Option<Toolchain>,
+    /// Flags used when compiling C and C++ sources, before `cflags` or
+    /// `cxxflags`.
+    pub common_flags: Vec<String>,
+    /// Flags used when compiling C sources.
+    pub cflags: Vec<String>,
+    /// Flags used when compiling C++ sources.
+    pub cxxflags: Vec<String>,
+    /// Flags used when assembling `.s` and `.S` sources, which don't get
+    /// `common_flags`.
+    pub asflags: Vec<String>,
+    /// Warning groups enabled for C and C++ sources, without the `-W`:
+    /// `["all", "extra"]` compiles with `-Wall -Wextra`.  They come before
+    /// the configured flags, so a `-Wno-` flag there still wins.
+    pub warnings: Vec<String>,
+    /// Treat warnings as errors (`-Werror`, `/WX` with MSVC).  Overridden
+    /// by `--deny-warnings` and `--allow-warnings`.  Like `warnings` it is
+    /// only part of the compile commands, so changing it recompiles
+    /// without rescanning the tree.
+    pub deny_warnings: bool,
+    /// Flags appended to the link command after the object files.
+    pub ldflags: Vec<String>,
+    /// Linker to link with instead of the compiler driver's default, e.g.
+    /// `"mold"` or `"lld"` (passed as `-fuse-ld=`); with MSVC the program
+    /// replacing `link.exe`, e.g. `"lld-link"`.
+    pub linker: Option<String>,
+    /// Arguments passed to the link right after the object files, before
+    /// the libraries, e.g. `["-Wl,--gc-sections"]`.  Unlike `ldflags`,
+    /// changing them only relinks.
+    pub link_args: Vec<String>,
+    /// Include directories, relative to the project root unless absolute.
+    pub include_dirs: Vec<PathBuf>,
+    /// Libraries to link against, e.g. `["m", "pthread"]` for `-lm -lpthread`.
+    pub libs: Vec<String>,
+    /// Library search directories, relative to the project root unless
+    /// absolute.
+    pub lib_dirs: Vec<PathBuf>,
+    /// Name of the program or library, instead of the name of the project
+    /// directory.  Overridden by `--name`.
+    pub name: Option<String>,
+    /// What the build produces: a program, or a library other projects link
+    /// against.
+    pub kind: TargetKind,
+    /// Glob patterns (see `PathMatcher`) of the headers a library build
+    /// copies to `target/<profile>/include/` for the projects using it,
+    /// e.g. `["include/**"]`.
+    pub public_headers: Vec<String>,
+    /// Compiled form of `public_headers`, built by `load`.
+    #[serde(skip)]
+    pub public: PathMatcher,
+    /// Glob patterns (see `PathMatcher`) of files and directories to leave
+    /// out of the build, e.g. `["vendor/", "**/*_test.c"]`, on top of those
+    /// in `.buildyignore`.
+    pub exclude: Vec<String>,
+    /// Compiled form of `exclude`, built by `load`.
+    #[serde(skip)]
+    pub excluded: PathMatcher,
+    /// The project's `.buildyignore`, read by `load`.
+    #[serde(skip)]
+    pub ignore_file: IgnoreFile,
+    /// Also drop headers under excluded paths from the dependencies.  By
+    /// default only excluded sources are left out: a header there that a
+    /// source includes is still tracked, so editing it recompiles the
+    /// source.
+    pub exclude_headers: bool,
+    /// Follow symlinks while looking for sources, so a directory linked
+    /// into the tree (even from outside the root) is built too.  A file
+    /// reachable through several links is still one source.
+    pub follow_symlinks: bool,
+    /// Experimental C++20 named-module support (clang, or gcc >= 14).
+    pub modules: bool,
+    /// Keep `compile_commands.json` in the project root up to date on every
+    /// build.
+    pub compile_commands: bool,
+    /// Link a generated `buildy_version.c` defining `buildy_build_id` and
+    /// `buildy_git_describe` into the program.
+    pub version_stamp: bool,
+    /// Also recompile sources whose object file is older than the source,
+    /// for trees where something other than Buildy writes to `target/`.
+    /// Off by default, since touching or checking out a file recompiles it.
+    pub check_object_mtime: bool,
+    /// Read and hash every file on every build, instead of reusing the
+    /// cached hash of a file whose modification time and size didn't
+    /// change.  For trees whose times can't be trusted, such as some
+    /// container mounts or with clock skew.  Also set by `--paranoid`.
+    pub hash_always: bool,
+    /// Remove unused directories under `target/` older than this age (e.g.
+    /// `"30d"`) after successful builds, checked at most once a day.
+    pub auto_clean_stale: Option<String>,
+    /// In the watch repl with automatic rebuilds off, compile changed files
+    /// in the background so the next `build` can reuse the objects.
+    pub speculative: bool,
+    /// Milliseconds the tree has to stay quiet after a change before the
+    /// watch repl reacts to it (300 by default).  Overridden by
+    /// `--watch-delay`.
+    pub watch_delay: Option<u64>,
+    /// Parallel compile jobs, 0 for one per CPU (the default).  Overridden by
+    /// `BUILDY_JOBS` and `--jobs`.
+    pub jobs: Option<usize>,
+    /// With MSVC, compile up to this many sources with the same flags in
+    /// one run of `cl`, which saves starting a process for each.  Off by
+    /// default; GNU compilers always get one source per run.
+    pub batch_size: Option<usize>,
+    /// Make `__FILE__` and similar macros expand to paths relative to the
+    /// project root instead of absolute ones.  On by default for profiles
+    /// that optimize, such as release.
+    pub relative_file_macro: Option<bool>,
+    /// Settings of the build profiles by name, see `Profile::resolve`.
+    pub profiles: BTreeMap<String, ProfileConfig>,
+    /// Settings applied to the files matching a set of patterns.
+    pub overrides: Vec<Override>,
+    /// Flags for the sources below a directory, by directory relative to
+    /// the root, e.g. `[dir."vendor/"]`.
+    pub dir: BTreeMap<String, DirConfig>,
+    /// Flags for single sources, by path relative to the root, e.g.
+    /// `[file."src/legacy.c"]`.
+    pub file: BTreeMap<String, FileConfig>,
+    /// Settings for running the built program.
+    pub run: RunConfig,
+}
+
+/// The `kind` of output a project builds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TargetKind {
+    Executable,
+    /// `lib<name>.a`, archived with `ar` (`<name>.lib` and `lib.exe` with
+    /// MSVC).
+    Staticlib,
+    /// `lib<name>.so` (`.dylib`, `.dll`), every object compiled with `-fPIC`.
+    /// MSVC builds `<name>.dll`.
+    Sharedlib,
+}
+
+impl TargetKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TargetKind::Executable => "executable",
+            TargetKind::Staticlib => "staticlib",
+            TargetKind::Sharedlib => "sharedlib",
+        }
+    }
+
+    /// File name of the output for a project called `name`.
+    pub fn file_name(self, name: &str, toolchain: Toolchain) -> String {
+        match self {
+            TargetKind::Executable => format!("{}{}", name, env::consts::EXE_SUFFIX),
+            TargetKind::Staticlib => toolchain.staticlib_name(name),
+            TargetKind::Sharedlib => toolchain.sharedlib_name(name),
+        }
+    }
+}
+
+/// One `[[overrides]]` entry.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Override {
+    /// Glob patterns (see `PathMatcher`) selecting the files.
+    pub files: Vec<String>,
+    /// Warnings to disable for these files, e.g. `-Wdeprecated-declarations`.
+    /// Unlike a `-Wno-` flag in `cflags` they are listed in the build output
+    /// and checked by `buildy doctor`.
+    pub suppress: Vec<String>,
+    /// Compiled form of `files`, built by `Config::load`.
+    #[serde(skip)]
+    pub matcher: PathMatcher,
+}
+
+impl Override {
+    /// The `-Wno-` flags for `suppress`.
+    pub fn suppress_flags(&self) -> impl Iterator<Item = String> + '_ {
+        self.suppress.iter().map(|w| {
+            let name = w.strip_prefix("-W").unwrap_or(w);
+            format!("-Wno-{}", name.strip_prefix("no-").unwrap_or(name))
+        })
+    }
+}
+
+/// One `[dir."<path>"]` table.  Its flags come after the configured ones
+/// of the same kind, and after those of the tables of enclosing
+/// directories, so the deepest directory wins.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DirConfig {
+    /// Flags for C and C++ sources.
+    pub common_flags: Vec<String>,
+    /// Flags for C sources.
+    pub cflags: Vec<String>,
+    /// Flags for C++ sources.
+    pub cxxflags: Vec<String>,
+    /// Flags for assembly sources.
+    pub asflags: Vec<String>,
+}
+
+impl DirConfig {
+    /// The lists applying to sources of `language`, by name.
+    fn flags_for(&self, language: Language) -> [(&'static str, &[String]); 2] {
+        let common: &[String] = match language {
+            Language::Asm => &[],
+            _ => &self.common_flags,
+        };
+        let own = match language {
+            Language::C => &self.cflags,
+            Language::Cxx => &self.cxxflags,
+            Language::Asm => &self.asflags,
+        };
+        [("common_flags", common), (language.flags_key(), own)]
+    }
+}
+
+/// One `[file."<path>"]` table.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+    /// Flags appended to the file's compile command, after those of the
+    /// profile, the configuration and its `[dir]` tables, e.g. `["-O0"]`
+    /// to work around a compiler bug even in release builds.
+    pub extra_flags: Vec<String>,
+}
+
+/// One `[profiles.<name>]` table.  Settings left out keep the value of the
+/// built-in profile of that name, if there is one.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProfileConfig {
+    /// Compile flags, replacing the profile's own.
+    pub cflags: Option<Vec<String>>,
+    /// Link flags, replacing the profile's own.
+    pub ldflags: Option<Vec<String>>,
+    /// Optimization level, e.g. `2` or `"s"`.
+    pub opt: Option<OptLevel>,
+    /// Macros to define, e.g. `["NDEBUG", "FEATURE_X=1"]`, replacing the
+    /// profile's own.
+    pub defines: Option<Vec<String>>,
+}
+
+/// An optimization level, written as a number or a string.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum OptLevel {
+    Number(u32),
+    Name(String),
+}
+
+impl fmt::Display for OptLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OptLevel::Number(n) => write!(f, "{}", n),
+            OptLevel::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// The `[run]` table.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RunConfig {
+    /// Extra environment variables for the launched program.
+    pub env: BTreeMap<String, String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            compiler: toolchain::detect().default_compiler().into(),
+            cxx: None,
+            compiler_wrapper: None,
+            toolchain: None,
+            common_flags: Vec::new(),
+            cflags: Vec::new(),
+            cxxflags: Vec::new(),
+            asflags: Vec::new(),
+            warnings: Vec::new(),
+            deny_warnings: false,
+            ldflags: Vec::new(),
+            linker: None,
+            link_args: Vec::new(),
+            include_dirs: Vec::new(),
+            libs: Vec::new(),
+            lib_dirs: Vec::new(),
+            name: None,
+            kind: TargetKind::Executable,
+            public_headers: Vec::new(),
+            public: PathMatcher::default(),
+            exclude: Vec::new(),
+            excluded: PathMatcher::default(),
+            ignore_file: IgnoreFile::default(),
+            exclude_headers: false,
+            follow_symlinks: false,
+            modules: false,
+            compile_commands: false,
+            version_stamp: false,
+            check_object_mtime: false,
+            hash_always: false,
+            auto_clean_stale: None,
+            speculative: false,
+            watch_delay: None,
+            jobs: None,
+            batch_size: None,
+            relative_file_macro: None,
+            profiles: BTreeMap::new(),
+            overrides: Vec::new(),
+            dir: BTreeMap::new(),
+            file: BTreeMap::new(),
+            run: RunConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Load `buildy.toml` from `root`, then let the `CC` and `CXX`
+    /// environment variables override the compilers and `BUILDY_JOBS` the
+    /// number of jobs.
+    pub fn load(root: &Path) -> Self {
+        let mut config = Config::load_file(root);
+        config.ignore_file = IgnoreFile::read(root).unwrap_or_else(|e| {
+            messages::warn(format!("warning: {}; ignoring {}", e, IGNORE_FILENAME));
+            IgnoreFile::default()
+        });
+        let missing = config.missing_paths(root);
+        if !missing.is_empty() {
+            messages::warn(format!(
+                "warning: {} has tables for paths that don't exist (typos?): {}",
+                CONFIG_FILENAME,
+                missing.join(", ")
+            ));
+        }
+        if let Some(toolchain) = config.toolchain
+            && config.compiler == Config::default().compiler
+        {
+            config.compiler = toolchain.default_compiler().into();
+        }
+        if let Ok(cc) = env::var("CC")
+            && !cc.is_empty()
+        {
+            config.compiler = cc;
+        }
+        if let Ok(cxx) = env::var("CXX")
+            && !cxx.is_empty()
+        {
+            config.cxx = Some(cxx);
+        }
+        if let Ok(jobs) = env::var("BUILDY_JOBS")
+            && !jobs.is_empty()
+        {
+            match jobs.parse() {
+                Ok(n) => config.jobs = Some(n),
+                Err(_) => messages::warn(format!(
+                    "warning: ignoring BUILDY_JOBS={}: not a number",
+                    jobs
+                )),
+            }
+        }
+        config
+    }
+
+    /// Read `buildy.toml`.  A missing file silently yields the defaults; an
+    /// unreadable or malformed one prints a warning and also falls back to
+    /// the defaults so a typo never blocks a build.
+    fn load_file(root: &Path) -> Self {
+        let path = root.join(CONFIG_FILENAME);
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Config::default(),
+            Err(e) => {
+                messages::warn(format!(
+                    "warning: could not read {}: {}; using defaults",
+                    path.display(),
+                    e
+                ));
+                return Config::default();
+            }
+        };
+        match toml::from_str::<Config>(&text) {
+            Ok(mut config) => {
+                config.excluded =
+                    PathMatcher::new("exclude", &config.exclude).unwrap_or_else(|e| {
+                        messages::warn(format!(
+                            "warning: {}: {}; ignoring `exclude`",
+                            path.display(),
+                            e
+                        ));
+                        PathMatcher::default()
+                    });
+                config.public = PathMatcher::new("public_headers", &config.public_headers)
+                    .unwrap_or_else(|e| {
+                        messages::warn(format!(
+                            "warning: {}: {}; ignoring `public_headers`",
+                            path.display(),
+                            e
+                        ));
+                        PathMatcher::default()
+                    });
+                if let Some(age) = &config.auto_clean_stale
+                    && let Err(e) = crate::clean::parse_age(age)
+                {
+                    messages::warn(format!(
+                        "warning: {}: `auto_clean_stale`: {}; ignoring it",
+                        path.display(),
+                        e
+                    ));
+                    config.auto_clean_stale = None;
+                }
+                for (i, entry) in config.overrides.iter_mut().enumerate() {
+                    let key = format!("overrides[{}].files", i);
+                    entry.matcher = PathMatcher::new(&key, &entry.files).unwrap_or_else(|e| {
+                        messages::warn(format!(
+                            "warning: {}: {}; ignoring the entry",
+                            path.display(),
+                            e
+                        ));
+                        PathMatcher::default()
+                    });
+                }
+                config
+            }
+            Err(e) => {
+                messages::warn(format!(
+                    "warning: ignoring malformed {}: {}; using defaults",
+                    path.display(),
+                    e.message()
+                ));
+                Config::default()
+            }
+        }
+    }
+
+    /// Compiler used for C sources.
+    pub fn c_compiler(&self) -> &str {
+        &self.compiler
+    }
+
+    /// Compiler used for C++ sources (and for linking mixed projects):
+    /// `cxx` when set, otherwise derived from the C compiler.
+    pub fn cxx_compiler(&self) -> String {
+        if let Some(cxx) = &self.cxx {
+            return cxx.clone();
+        }
+        let c = self.compiler.as_str();
+        // versioned binaries such as `gcc-12` or `clang-17`
+        if let Some((base, version)) = c.rsplit_once('-')
+            && !version.is_empty()
+            && version.chars().all(|ch| ch.is_ascii_digit() || ch == '.')
+        {
+            return format!("{}-{}", derive_cxx(base), version);
+        }
+        derive_cxx(c)
+    }
+
+    /// The toolchain of the C compiler, unless `toolchain` is set.
+    pub fn toolchain(&self) -> Toolchain {
+        self.toolchain
+            .unwrap_or_else(|| Toolchain::of_compiler(&self.compiler))
+    }
+
+    /// Both compilers, as recorded in the cache so switching either one
+    /// invalidates it.
+    pub fn compilers(&self) -> String {
+        format!("{} {}", self.c_compiler(), self.cxx_compiler())
+    }
+
+    /// The program `compiler_wrapper` names, `"none"` meaning no wrapper
+    /// and `"auto"` the first of sccache and ccache on PATH, if either is.
+    /// Errors when the named wrapper isn't installed.
+    pub fn resolve_wrapper(&self) -> Result<Option<String>, String> {
+        match self.compiler_wrapper.as_deref() {
+            None | Some("none" | "") => Ok(None),
+            Some("auto") => Ok(AUTO_WRAPPERS
+                .into_iter()
+                .find(|w| toolchain::on_path(w))
+                .map(String::from)),
+            Some(wrapper) => {
+                let found = if wrapper.contains(['/', '\\']) {
+                    Path::new(wrapper).is_file()
+                } else {
+                    toolchain::on_path(wrapper)
+                };
+                if found {
+                    Ok(Some(wrapper.to_string()))
+                } else {
+                    Err(format!(
+                        "compiler wrapper `{}` not found on PATH; install it, or build \
+                         without it by removing `compiler_wrapper` from buildy.toml or \
+                         passing --wrapper none",
+                        wrapper
+                    ))
+                }
+            }
+        }
+    }
+
+    /// Pick the compiler for a source file based on its extension.  The C
+    /// compiler also drives the assembler.
+    pub fn compiler_for(&self, path: &Path) -> String {
+        match Language::of(path) {
+            Some(Language::C | Language::Asm) => self.c_compiler().to_string(),
+            _ => self.cxx_compiler(),
+        }
+    }
+
+    /// Name of the flag list that applies to `path`: `cflags` for C,
+    /// `asflags` for assembly, `cxxflags` otherwise.
+    pub fn language_key(path: &Path) -> &'static str {
+        Language::of(path).unwrap_or(Language::Cxx).flags_key()
+    }
+
+    /// `cflags`, `cxxflags` or `asflags`.
+    pub fn flags_for(&self, language: Language) -> &[String] {
+        match language {
+            Language::C => &self.cflags,
+            Language::Cxx => &self.cxxflags,
+            Language::Asm => &self.asflags,
+        }
+    }
+
+    /// `common_flags`, except for assembly.
+    pub fn common_flags_for(&self, language: Language) -> &[String] {
+        match language {
+            Language::Asm => &[],
+            _ => &self.common_flags,
+        }
+    }
+
+    /// Language flags for `path`, see `language_key`.
+    pub fn language_flags(&self, path: &Path) -> &[String] {
+        self.flags_for(Language::of(path).unwrap_or(Language::Cxx))
+    }
+
+    /// Every configured flag `path` is compiled with: `common_flags`, then
+    /// its language flags, then those of its `[dir]` tables.
+    pub fn source_flags(&self, path: &Path, root: &Path) -> Vec<&String> {
+        let language = Language::of(path).unwrap_or(Language::Cxx);
+        self.common_flags_for(language)
+            .iter()
+            .chain(self.flags_for(language))
+            .chain(self.dir_flags(path, root).into_iter().map(|(_, flag)| flag))
+            .chain(
+                self.file_flags(path, root)
+                    .into_iter()
+                    .map(|(_, flag)| flag),
+            )
+            .collect()
+    }
+
+    /// The `extra_flags` of the `[file]` table of `path`, each with the key
+    /// it came from (`file."src/legacy.c".extra_flags`).
+    pub fn file_flags(&self, path: &Path, root: &Path) -> Vec<(String, &String)> {
+        let Some(rel) = relative_to(path, root) else {
+            return Vec::new();
+        };
+        self.file
+            .iter()
+            .filter(|(file, _)| Path::new(file.trim_start_matches("./")) == rel)
+            .flat_map(|(file, table)| {
+                let origin = format!("file.\"{}\".extra_flags", file);
+                table
+                    .extra_flags
+                    .iter()
+                    .map(move |flag| (origin.clone(), flag))
+            })
+            .collect()
+    }
+
+    /// Whether `path` gets flags of its own from a `[dir]` or `[file]`
+    /// table.
+    pub fn has_local_flags(&self, path: &Path, root: &Path) -> bool {
+        !self.dir_flags(path, root).is_empty() || !self.file_flags(path, root).is_empty()
+    }
+
+    /// The `[dir]` and `[file]` keys naming nothing under `root`.
+    fn missing_paths(&self, root: &Path) -> Vec<String> {
+        let dirs = self.dir.keys().map(|dir| (dir, "dir"));
+        let files = self.file.keys().map(|file| (file, "file"));
+        dirs.chain(files)
+            .filter(|(path, _)| !root.join(path).exists())
+            .map(|(path, table)| format!("{}.\"{}\"", table, path))
+            .collect()
+    }
+
+    /// The flags the `[dir]` tables add for `path`, outermost directory
+    /// first, each with the key it came from (`dir."vendor/".cflags`).
+    pub fn dir_flags(&self, path: &Path, root: &Path) -> Vec<(String, &String)> {
+        let Some(rel) = relative_to(path, root) else {
+            return Vec::new();
+        };
+        let mut tables: Vec<(usize, &String, &DirConfig)> = self
+            .dir
+            .iter()
+            .filter_map(|(dir, table)| {
+                let prefix = Path::new(dir.trim_start_matches("./").trim_end_matches('/'));
+                rel.starts_with(prefix)
+                    .then(|| (prefix.components().count(), dir, table))
+            })
+            .collect();
+        tables.sort_by_key(|(depth, _, _)| *depth);
+        let language = Language::of(path).unwrap_or(Language::Cxx);
+        let mut flags = Vec::new();
+        for (_, dir, table) in tables {
+            for (key, list) in table.flags_for(language) {
+                let origin = format!("dir.\"{}\".{}", dir, key);
+                flags.extend(list.iter().map(|flag| (origin.clone(), flag)));
+            }
+        }
+        flags
+    }
+
+    /// The configured flags of each language by flag list name, recorded in
+    /// the cache so that editing `cflags` only recompiles the C sources.
+    /// Kept out of `fingerprint`, which invalidates every source.
+    pub fn language_fingerprint(&self) -> BTreeMap<String, Vec<String>> {
+        [Language::C, Language::Cxx, Language::Asm]
+            .into_iter()
+            .map(|language| {
+                let flags = self
+                    .common_flags_for(language)
+                    .iter()
+                    .chain(self.flags_for(language));
+                (language.flags_key().to_string(), flags.cloned().collect())
+            })
+            .collect()
+    }
+
+    /// Whether `path` is left out of the build by `exclude` or
+    /// `.buildyignore`.
+    pub fn is_excluded(&self, path: &Path, root: &Path) -> bool {
+        self.excluded.is_match(path, root) || self.ignore_file.is_match(path, root)
+    }
+
+    /// Override entries whose patterns match `path`.
+    pub fn overrides_for<'a>(
+        &'a self,
+        path: &'a Path,
+        root: &'a Path,
+    ) -> impl Iterator<Item = &'a Override> + 'a {
+        self.overrides
+            .iter()
+            .filter(move |o| o.matcher.is_match(path, root))
+    }
+
+    /// `-I` arguments for the configured include directories, resolved
+    /// against `root` (`/I` with MSVC).
+    pub fn include_args(&self, root: &Path) -> Vec<String> {
+        let toolchain = self.toolchain();
+        self.include_dirs
+            .iter()
+            .map(|dir| toolchain.include_flag(&root.join(dir)))
+            .collect()
+    }
+
+    /// `-L` arguments for `lib_dirs` followed by `-l` arguments for `libs`,
+    /// in the order they were given (`/LIBPATH:` and `<lib>.lib` with MSVC).
+    pub fn lib_args(&self, root: &Path) -> Vec<String> {
+        self.toolchain().lib_args(root, &self.lib_dirs, &self.libs)
+    }
+
+    /// The settings only the link uses besides the libraries, recorded in
+    /// the cache so changing them relinks without recompiling.
+    pub fn link_fingerprint(&self) -> Vec<String> {
+        self.linker
+            .iter()
+            .map(|linker| format!("linker={}", linker))
+            .chain(
+                self.link_args
+                    .iter()
+                    .map(|arg| format!("link_args={}", arg)),
+            )
+            .collect()
+    }
+
+    /// Flattened representation of every setting that affects compilation or
+    /// linking.  Stored in the cache so editing `buildy.toml` invalidates it.
+    pub fn fingerprint(&self) -> Vec<String> {
+        let mut out = Vec::new();
+        out.extend(self.ldflags.iter().map(|f| format!("ldflags={}", f)));
+        out.extend(
+            self.include_dirs
+                .iter()
+                .map(|d| format!("include_dirs={}", d.display())),
+        );
+        if let Some(toolchain) = self.toolchain {
+            out.push(format!("toolchain={}", toolchain.as_str()));
+        }
+        if let Some(wrapper) = &self.compiler_wrapper {
+            out.push(format!("compiler_wrapper={}", wrapper));
+        }
+        if self.kind != TargetKind::Executable {
+            out.push(format!("kind={}", self.kind.as_str()));
+        }
+        if self.modules {
+            out.push("modules=true".into());
+        }
+        if let Some(relative) = self.relative_file_macro {
+            out.push(format!("relative_file_macro={}", relative));
+        }
+        for entry in &self.overrides {
+            out.push(format!(
+                "overrides={}:suppress={}",
+                entry.files.join(","),
+                entry.suppress.join(",")
+            ));
+        }
+        out
+    }
+}
+
+/// `path` relative to `root`, which may not be canonical when `path` is.
+/// Relative paths are taken as relative to `root` already.
+fn relative_to(path: &Path, root: &Path) -> Option<PathBuf> {
+    if !path.is_absolute() {
+        return Some(path.to_path_buf());
+    }
+    if let Ok(rel) = path.strip_prefix(root) {
+        return Some(rel.to_path_buf());
+    }
+    let root = root.canonicalize().ok()?;
+    path.strip_prefix(root).ok().map(Path::to_path_buf)
+}
+
+/// The C++ driver matching the C compiler `c` (`gcc` -> `g++`, `clang` ->
+/// `clang++`, `cc` -> `c++`).
+fn derive_cxx(c: &str) -> String {
+    if let Some(prefix) = c.strip_suffix("gcc") {
+        format!("{}g++", prefix)
+    } else if c.ends_with("clang") {
+        format!("{}++", c)
+    } else if c == "cc" {
+        "c++".into()
+    } else {
+        c.to_string()
+    }
+}
+
+/// Describe a failure to start `program`, pointing out how to pick another
+/// compiler when it isn't installed.
+pub fn spawn_error(program: &str, e: &std::io::Error) -> String {
+    if e.kind() == std::io::ErrorKind::NotFound {
+        format!(
+            "compiler `{}` not found on PATH; install it or choose another one with \
+             `compiler` in buildy.toml, CC/CXX, or --compiler/--cxx",
+            program
+        )
+    } else {
+        format!("could not run {}: {}", program, e)
+    }
+}
@@ -0,0 +1,234 @@
+use std::path::{Path, PathBuf};
+
+/// One rule of a make dependency file: `targets: prerequisites`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Rule {
+    pub targets: Vec<PathBuf>,
+    pub prerequisites: Vec<PathBuf>,
+}
+
+/// The dependency file the compile writing `object` produces next to it
+/// (`util.c.o` gets `util.c.d`).
+pub fn path_for(object: &Path) -> PathBuf {
+    object.with_extension("d")
+}
+
+/// Parse the make rules gcc and clang print for `-M`/`-MM` and write with
+/// `-MD -MF`, undoing their escaping:
+///
+/// - `\ ` and `\#` are a literal space and `#`, `$$` a literal `$`
+/// - a backslash before a newline continues the line; any other backslash
+///   is part of the path, so `C:\include\foo.h` survives
+/// - a `:` only separates targets from prerequisites when followed by
+///   whitespace or the line end, so a drive letter (`C:/foo.o`) is not one
+/// - several targets may share a rule, and the phony rules of `-MP` are
+///   rules without prerequisites
+/// - an unescaped `#` starts a comment
+pub fn parse(text: &str) -> Vec<Rule> {
+    let mut rules = Vec::new();
+    let mut rule = Rule::default();
+    let mut in_prerequisites = false;
+    let mut token = String::new();
+    let mut chars = text.chars().peekable();
+
+    // ends the current token, adding it to the side of the rule being read
+    let flush = |token: &mut String, rule: &mut Rule, in_prerequisites: bool| {
+        if token.is_empty() {
+            return;
+        }
+        let path = PathBuf::from(std::mem::take(token));
+        if in_prerequisites {
+            rule.prerequisites.push(path);
+        } else {
+            rule.targets.push(path);
+        }
+    };
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.peek() {
+                Some('\n') => {
+                    chars.next();
+                    flush(&mut token, &mut rule, in_prerequisites);
+                }
+                Some('\r') => {
+                    chars.next();
+                    if chars.peek() == Some(&'\n') {
+                        chars.next();
+                    }
+                    flush(&mut token, &mut rule, in_prerequisites);
+                }
+                Some(&escaped @ (' ' | '#')) => {
+                    chars.next();
+                    token.push(escaped);
+                }
+                _ => token.push('\\'),
+            },
+            '$' if chars.peek() == Some(&'$') => {
+                chars.next();
+                token.push('$');
+            }
+            ':' if !in_prerequisites && chars.peek().is_none_or(|next| next.is_whitespace()) => {
+                flush(&mut token, &mut rule, in_prerequisites);
+                in_prerequisites = true;
+            }
+            '#' if token.is_empty() => {
+                for next in chars.by_ref() {
+                    if next == '\n' {
+                        break;
+                    }
+                }
+                end_rule(&mut rules, &mut rule, &mut in_prerequisites);
+            }
+            '\n' => {
+                flush(&mut token, &mut rule, in_prerequisites);
+                end_rule(&mut rules, &mut rule, &mut in_prerequisites);
+            }
+            c if c.is_whitespace() => flush(&mut token, &mut rule, in_prerequisites),
+            c => token.push(c),
+        }
+    }
+    flush(&mut token, &mut rule, in_prerequisites);
+    end_rule(&mut rules, &mut rule, &mut in_prerequisites);
+    rules
+}
+
+/// Every prerequisite of the rules in `text`, in order and without
+/// duplicates.  For the output of one compile the source comes first.
+pub fn prerequisites(text: &str) -> Vec<PathBuf> {
+    let mut seen = std::collections::HashSet::new();
+    parse(text)
+        .into_iter()
+        .flat_map(|rule| rule.prerequisites)
+        .filter(|path| seen.insert(path.clone()))
+        .collect()
+}
+
+fn end_rule(rules: &mut Vec<Rule>, rule: &mut Rule, in_prerequisites: &mut bool) {
+    if *in_prerequisites || !rule.targets.is_empty() {
+        rules.push(std::mem::take(rule));
+    }
+    *in_prerequisites = false;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(paths: &[&str]) -> Vec<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    fn rule(targets: &[&str], prerequisites: &[&str]) -> Rule {
+        Rule {
+            targets: paths(targets),
+            prerequisites: paths(prerequisites),
+        }
+    }
+
+    #[test]
+    fn parses_a_gcc_depfile() {
+        let text = "target/debug/obj/src/main.c.o: src/main.c src/util.h \\\n \
+                    /usr/include/stdio.h\n";
+        assert_eq!(
+            parse(text),
+            vec![rule(
+                &["target/debug/obj/src/main.c.o"],
+                &["src/main.c", "src/util.h", "/usr/include/stdio.h"]
+            )]
+        );
+    }
+
+    #[test]
+    fn undoes_escaping() {
+        let text = "out/my\\ file.o: src/my\\ file.c inc/dollar$$sign.h inc/\\#hash.h\n";
+        assert_eq!(
+            parse(text),
+            vec![rule(
+                &["out/my file.o"],
+                &["src/my file.c", "inc/dollar$sign.h", "inc/#hash.h"]
+            )]
+        );
+    }
+
+    #[test]
+    fn follows_continuations() {
+        let unix = "a.o: a.c \\\n  b.h \\\n  c.h\n";
+        let windows = "a.o: a.c \\\r\n  b.h \\\r\n  c.h\r\n";
+        // a continuation right after a path still ends it
+        let tight = "a.o: a.c\\\nb.h\\\nc.h";
+        for text in [unix, windows, tight] {
+            assert_eq!(
+                parse(text),
+                vec![rule(&["a.o"], &["a.c", "b.h", "c.h"])],
+                "{:?}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_windows_paths() {
+        let text = "C:/proj/target/a.o: C:\\proj\\src\\a.c C:\\x.h \\\n \
+                    C:\\Program\\ Files\\sdk\\include\\y.h\n";
+        assert_eq!(
+            parse(text),
+            vec![rule(
+                &["C:/proj/target/a.o"],
+                &[
+                    "C:\\proj\\src\\a.c",
+                    "C:\\x.h",
+                    "C:\\Program Files\\sdk\\include\\y.h"
+                ]
+            )]
+        );
+    }
+
+    #[test]
+    fn reads_several_targets_and_rules() {
+        // `-MT` twice and the phony rules of `-MP`
+        let text = "a.o a.d: a.c a.h b.h\n\na.h:\n\nb.h:\n";
+        assert_eq!(
+            parse(text),
+            vec![
+                rule(&["a.o", "a.d"], &["a.c", "a.h", "b.h"]),
+                rule(&["a.h"], &[]),
+                rule(&["b.h"], &[]),
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_comments() {
+        let text = "# generated\na.o: a.c # the source\n  # indented\nb.o: b.c\n";
+        assert_eq!(
+            parse(text),
+            vec![rule(&["a.o"], &["a.c"]), rule(&["b.o"], &["b.c"])]
+        );
+    }
+
+    #[test]
+    fn handles_empty_and_unterminated_input() {
+        assert!(parse("").is_empty());
+        assert!(parse("\n\n").is_empty());
+        assert_eq!(parse("a.o: a.c"), vec![rule(&["a.o"], &["a.c"])]);
+        assert_eq!(parse("a.o:"), vec![rule(&["a.o"], &[])]);
+    }
+
+    #[test]
+    fn prerequisites_are_unique_and_in_order() {
+        let text = "a.o: a.c a.h common.h\nb.o: b.c common.h a.h\n";
+        assert_eq!(
+            prerequisites(text),
+            paths(&["a.c", "a.h", "common.h", "b.c"])
+        );
+    }
+
+    #[test]
+    fn depfiles_sit_next_to_their_object() {
+        assert_eq!(
+            path_for(Path::new("target/debug/obj/src/util.c.o")),
+            PathBuf::from("target/debug/obj/src/util.c.d")
+        );
+    }
+}
@@ -0,0 +1,449 @@
+use crate::cache::BuildCache;
+use crate::config::{Config, TargetKind};
+use crate::executor::{self, ExecError, ExecRequest};
+use crate::graph;
+use crate::lang::Language;
+use crate::profile;
+use crate::toolchain::{self, Toolchain};
+use crate::toolenv;
+use notify::{RecursiveMode, Watcher};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+use walkdir::WalkDir;
+
+/// Files failing their dependency scan this many builds in a row are
+/// reported as chronic.
+const CHRONIC_SCAN_FAILURES: u32 = 3;
+
+/// How long a probed program may take to answer.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the file watcher may take to report a file being created.
+const WATCH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Check the tools and the environment builds of `root` need, then report
+/// problems recorded in its build cache and in its configuration.  Returns
+/// whether everything a build needs is there.
+pub fn run(root: &Path) -> bool {
+    let config = Config::load(root);
+    let files = project_files(root, &config);
+    let mut checks = tools(&config, &files);
+    checks.push(dependency_scan(&config));
+    checks.push(target_writable(root));
+    checks.push(file_watching(root));
+    print_table(&checks);
+    let ok = checks.iter().all(|c| c.outcome != Outcome::Fail);
+
+    let mut found = scan_failures(root);
+    found |= stale_overrides(root, &config, &files);
+    if !found && ok {
+        println!("no problems found");
+    }
+    compiler_env();
+    ok
+}
+
+/// Outcome of one of the checks of `run`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Pass,
+    /// Builds work, but not everything does (or could be checked).
+    Warn,
+    /// Builds will fail.
+    Fail,
+}
+
+/// A row of the table `run` prints.
+struct Check {
+    name: String,
+    outcome: Outcome,
+    /// What was found, such as a version, or what went wrong.
+    detail: String,
+    /// What to do about a warning or failure.
+    hint: Option<String>,
+}
+
+impl Check {
+    fn pass(name: impl Into<String>, detail: impl Into<String>) -> Self {
+        Check {
+            name: name.into(),
+            outcome: Outcome::Pass,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn problem(
+        name: impl Into<String>,
+        outcome: Outcome,
+        detail: impl Into<String>,
+        hint: impl Into<String>,
+    ) -> Self {
+        Check {
+            name: name.into(),
+            outcome,
+            detail: detail.into(),
+            hint: Some(hint.into()),
+        }
+    }
+}
+
+fn print_table(checks: &[Check]) {
+    let width = checks.iter().map(|c| c.name.len()).max().unwrap_or(0);
+    for check in checks {
+        let status = match check.outcome {
+            Outcome::Pass => "ok",
+            Outcome::Warn => "warn",
+            Outcome::Fail => "FAIL",
+        };
+        println!(
+            "{:<4}  {:<width$}  {}",
+            status,
+            check.name,
+            check.detail,
+            width = width
+        );
+        if let Some(hint) = &check.hint {
+            println!("{:<4}  {:<width$}  hint: {}", "", "", hint, width = width);
+        }
+    }
+    println!();
+}
+
+/// The files a build would look at, for the checks that depend on what the
+/// project contains.
+fn project_files(root: &Path, config: &Config) -> Vec<PathBuf> {
+    WalkDir::new(root)
+        .into_iter()
+        .filter_entry(|e| !graph::is_ignored(e.path(), root) && !config.is_excluded(e.path(), root))
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .map(|e| e.into_path())
+        .collect()
+}
+
+/// The compilers, the compiler wrapper, the linker and the archiver the
+/// configuration uses.  Those a build can't do without fail when missing;
+/// the C++ compiler only counts when there are C++ sources.
+fn tools(config: &Config, files: &[PathBuf]) -> Vec<Check> {
+    let mut checks = Vec::new();
+    let cc = config.c_compiler().to_string();
+    let cxx = config.cxx_compiler();
+    let has_cxx = files.iter().any(|f| Language::of(f) == Some(Language::Cxx));
+    checks.push(program(
+        "C compiler",
+        &cc,
+        Outcome::Fail,
+        "install it, or set `compiler` in buildy.toml or CC",
+    ));
+    checks.push(program(
+        "C++ compiler",
+        &cxx,
+        if has_cxx {
+            Outcome::Fail
+        } else {
+            Outcome::Warn
+        },
+        "install it, or set `cxx` in buildy.toml or CXX (only C++ sources need it)",
+    ));
+    // the defaults, for switching back to them
+    if config.toolchain() == Toolchain::Gnu {
+        for default in ["gcc", "g++"] {
+            if default != cc && default != cxx {
+                checks.push(program(
+                    default,
+                    default,
+                    Outcome::Warn,
+                    format!("only needed to build with {}", default),
+                ));
+            }
+        }
+    }
+
+    match config.resolve_wrapper() {
+        Ok(Some(wrapper)) => checks.push(program(
+            "compiler wrapper",
+            &wrapper,
+            Outcome::Fail,
+            "install it, or pass --wrapper none",
+        )),
+        Ok(None) => {}
+        Err(e) => checks.push(Check::problem(
+            "compiler wrapper",
+            Outcome::Fail,
+            format!(
+                "{} not found",
+                config.compiler_wrapper.as_deref().unwrap_or_default()
+            ),
+            e,
+        )),
+    }
+
+    if let Some(linker) = &config.linker {
+        // gcc and clang run `ld.<name>` for `-fuse-ld=<name>`
+        let found = match config.toolchain() {
+            Toolchain::Gnu => on_path(&format!("ld.{}", linker)) || on_path(linker),
+            Toolchain::Msvc => on_path(linker),
+        };
+        checks.push(if found {
+            Check::pass("linker", linker.as_str())
+        } else {
+            Check::problem(
+                "linker",
+                Outcome::Fail,
+                format!("{} not found", linker),
+                "install it, or remove `linker` from buildy.toml",
+            )
+        });
+    }
+
+    if config.kind == TargetKind::Staticlib {
+        let (archiver, _) = config.toolchain().archive_command(Path::new("probe"));
+        let found = on_path(&archiver);
+        checks.push(if found {
+            Check::pass("archiver", archiver)
+        } else {
+            Check::problem(
+                "archiver",
+                Outcome::Fail,
+                format!("{} not found", archiver),
+                "install binutils (or set AR) to build a static library",
+            )
+        });
+    }
+    checks
+}
+
+/// Whether `program` can be run, either a path or a name on PATH.
+fn on_path(program: &str) -> bool {
+    if program.contains(['/', '\\']) {
+        Path::new(program).is_file()
+    } else {
+        toolchain::on_path(program)
+    }
+}
+
+/// Probe `program` with `--version`, reporting the first line it prints.
+/// One that can't be run gets `missing`.
+fn program(name: &str, program: &str, missing: Outcome, hint: impl Into<String>) -> Check {
+    let request = ExecRequest {
+        program: program.to_string(),
+        args: vec!["--version".into()],
+        timeout: Some(PROBE_TIMEOUT),
+        capture: true,
+        ..ExecRequest::default()
+    };
+    let first_line = |stdout: String, stderr: String| {
+        let text = if stdout.trim().is_empty() {
+            stderr
+        } else {
+            stdout
+        };
+        let line = text.lines().next().unwrap_or_default().trim().to_string();
+        format!("{}: {}", program, line)
+    };
+    match executor::execute(&request) {
+        Ok(output) => Check::pass(name, first_line(output.stdout, output.stderr)),
+        // `cl` prints its banner and complains about the option
+        Err(ExecError::Failed { stdout, stderr, .. }) => {
+            Check::pass(name, first_line(stdout, stderr))
+        }
+        Err(ExecError::TimedOut { .. }) => Check::problem(
+            name,
+            Outcome::Warn,
+            format!("{}: no answer to --version", program),
+            "check that it doesn't hang waiting for input or a license server",
+        ),
+        Err(_) => Check::problem(name, missing, format!("{} not found", program), hint),
+    }
+}
+
+/// Whether the C compiler lists the headers a source includes, which every
+/// dependency scan relies on: a source including a header is scanned in a
+/// temporary directory.
+fn dependency_scan(config: &Config) -> Check {
+    let toolchain = config.toolchain();
+    let name = match toolchain {
+        Toolchain::Gnu => "dependency scan (-MM)",
+        Toolchain::Msvc => "dependency scan (/showIncludes)",
+    };
+    let dir = std::env::temp_dir().join(format!("buildy-doctor-{}", std::process::id()));
+    let written = fs::create_dir_all(&dir)
+        .and_then(|_| fs::write(dir.join("probe.h"), "int probe(void);\n"))
+        .and_then(|_| fs::write(dir.join("probe.c"), "#include \"probe.h\"\n"));
+    if let Err(e) = written {
+        let _ = fs::remove_dir_all(&dir);
+        return Check::problem(
+            name,
+            Outcome::Warn,
+            format!("could not write a test source: {}", e),
+            "check the temporary directory (TMPDIR)",
+        );
+    }
+    let mut args: Vec<String> = toolchain
+        .dep_scan_args()
+        .iter()
+        .map(|a| a.to_string())
+        .collect();
+    args.push("probe.c".into());
+    let request = ExecRequest {
+        program: config.c_compiler().to_string(),
+        args,
+        cwd: Some(dir.clone()),
+        timeout: Some(PROBE_TIMEOUT),
+        capture: true,
+        ..ExecRequest::default()
+    };
+    let result = executor::execute(&request);
+    let _ = fs::remove_dir_all(&dir);
+    match result {
+        Ok(output) => {
+            let deps = toolchain.parse_deps(&output.stdout);
+            if deps.iter().any(|d| d.ends_with("probe.h")) {
+                Check::pass(name, "lists included headers")
+            } else {
+                Check::problem(
+                    name,
+                    Outcome::Fail,
+                    "the compiler ran but listed no headers",
+                    "use a compiler that supports the scan, or set `toolchain` in buildy.toml",
+                )
+            }
+        }
+        Err(e) => Check::problem(
+            name,
+            Outcome::Fail,
+            e.to_string().lines().next().unwrap_or_default().to_string(),
+            "fix the C compiler first; without the scan no build can start",
+        ),
+    }
+}
+
+/// Whether Buildy can create `target/` and write to it.
+fn target_writable(root: &Path) -> Check {
+    let dir = root.join("target");
+    let probe = dir.join(format!(".buildy-doctor-{}", std::process::id()));
+    match fs::create_dir_all(&dir).and_then(|_| fs::write(&probe, "")) {
+        Ok(()) => {
+            let _ = fs::remove_file(&probe);
+            Check::pass("target directory", "writable")
+        }
+        Err(e) => Check::problem(
+            "target directory",
+            Outcome::Fail,
+            format!("{}: {}", dir.display(), e),
+            "fix the permissions of target/, or build from a writable copy",
+        ),
+    }
+}
+
+/// Whether the file watcher the watch repl relies on notices a file being
+/// created, tried in `target/`.  Only the watch repl needs it.
+fn file_watching(root: &Path) -> Check {
+    let name = "file watching";
+    let hint = "`buildy watch` won't notice changes; on Linux, check \
+                fs.inotify.max_user_watches and max_user_instances";
+    let dir = root
+        .join("target")
+        .join(format!(".buildy-doctor-watch-{}", std::process::id()));
+    if let Err(e) = fs::create_dir_all(&dir) {
+        return Check::problem(name, Outcome::Warn, format!("not checked: {}", e), hint);
+    }
+    let (tx, rx) = mpsc::channel();
+    let noticed = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if event.is_ok_and(|e| e.kind.is_create()) {
+            let _ = tx.send(());
+        }
+    })
+    .and_then(|mut watcher| {
+        watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+        Ok(watcher)
+    })
+    .map_err(|e| e.to_string())
+    .and_then(|watcher| {
+        fs::write(dir.join("probe"), "").map_err(|e| e.to_string())?;
+        let noticed = rx.recv_timeout(WATCH_TIMEOUT).is_ok();
+        drop(watcher);
+        Ok(noticed)
+    });
+    let _ = fs::remove_dir_all(&dir);
+    match noticed {
+        Ok(true) => Check::pass(name, "works"),
+        Ok(false) => Check::problem(
+            name,
+            Outcome::Warn,
+            format!("a new file went unnoticed for {}s", WATCH_TIMEOUT.as_secs()),
+            hint,
+        ),
+        Err(e) => Check::problem(name, Outcome::Warn, e, hint),
+    }
+}
+
+/// Not a problem in itself, but set variables change what the compiler
+/// sees without appearing in any command line.
+fn compiler_env() {
+    let vars = toolenv::snapshot();
+    if vars.is_empty() {
+        return;
+    }
+    println!("compiler environment (part of the build fingerprint):");
+    for (name, value) in vars {
+        println!("  {}={}", name, value);
+    }
+}
+
+/// Files whose dependency scan keeps failing.
+fn scan_failures(root: &Path) -> bool {
+    // a failing scan is recorded in whichever profile was being built
+    let mut counts: BTreeMap<String, u32> = BTreeMap::new();
+    for profile in profile::names(&Config::load(root)) {
+        for (path, entry) in BuildCache::load(root, &profile).files {
+            if entry.scan_failures > 0 {
+                let count = counts.entry(path).or_default();
+                *count = (*count).max(entry.scan_failures);
+            }
+        }
+    }
+    let mut failing: Vec<(String, u32)> = counts.into_iter().collect();
+    if failing.is_empty() {
+        return false;
+    }
+
+    failing.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    println!("dependency scan failing for {} file(s):", failing.len());
+    for (path, count) in failing {
+        let note = if count >= CHRONIC_SCAN_FAILURES {
+            " (chronic)"
+        } else {
+            ""
+        };
+        println!("  {}: {} consecutive build(s){}", path, count, note);
+    }
+    println!("incremental rebuilds of these files are unreliable; run `buildy build` for details");
+    true
+}
+
+/// `[[overrides]]` entries with suppressions that match no file, most likely
+/// because the files were moved or the pattern has a typo.
+fn stale_overrides(root: &Path, config: &Config, files: &[PathBuf]) -> bool {
+    if config.overrides.is_empty() {
+        return false;
+    }
+    let mut found = false;
+    for (i, entry) in config.overrides.iter().enumerate() {
+        if entry.suppress.is_empty() || files.iter().any(|f| entry.matcher.is_match(f, root)) {
+            continue;
+        }
+        found = true;
+        println!(
+            "overrides[{}] suppresses {} but its files ({}) match nothing",
+            i,
+            entry.suppress.join(", "),
+            entry.files.join(", ")
+        );
+    }
+    found
+}
@@ -0,0 +1,105 @@
+use crate::report::BuildReport;
+use std::error::Error;
+use std::fmt;
+use std::path::PathBuf;
+
+/// Why a build or `run` failed, as far as the exit status tells scripts
+/// apart (see `exit_code`).
+#[derive(Debug)]
+pub enum BuildyError {
+    /// The sources couldn't be scanned or ordered, so nothing was compiled:
+    /// a failed scan under `--strict-deps`, a dependency cycle, ...
+    ScanError(String),
+    /// These sources failed to compile (none when the compiles were refused
+    /// up front, as with `--strict-flags`).  `report` counts what compiled
+    /// before the build stopped.
+    CompileFailed {
+        files: Vec<PathBuf>,
+        message: String,
+        report: Box<BuildReport>,
+    },
+    LinkFailed(String),
+    /// The program to `run` doesn't exist.
+    ExecutableMissing(PathBuf),
+    /// The program started by `run` exited with `code`.
+    ChildFailed {
+        code: i32,
+    },
+    /// `failed` of the `total` programs run by `test` failed.
+    TestsFailed {
+        failed: usize,
+        total: usize,
+    },
+    /// Ctrl-C stopped the build.  What compiled before is kept.
+    Interrupted,
+    /// Anything else, such as an unreadable config or a cache that couldn't
+    /// be written.
+    Other(String),
+}
+
+/// Exit status of each failure, listed in `--help`.
+pub const EXIT_CODES: &str = "\
+Exit status:
+  0    success
+  1    any other error (bad arguments, unreadable cache or config, ...)
+  101  a source failed to compile
+  102  linking or archiving failed
+  103  the sources couldn't be scanned or ordered (e.g. a dependency cycle)
+  104  the program to run is missing
+  105  a test failed
+  130  the build was interrupted with Ctrl-C
+  run exits with the status of the program it started";
+
+impl BuildyError {
+    /// A compile that failed without a source to blame, such as a missing
+    /// module toolchain or conflicting flags under `--strict-flags`.
+    pub fn compile(message: String) -> Self {
+        BuildyError::CompileFailed {
+            files: Vec::new(),
+            message,
+            report: Box::default(),
+        }
+    }
+
+    /// The report of a failed compile, to complete or print.
+    pub fn report_mut(&mut self) -> Option<&mut BuildReport> {
+        match self {
+            BuildyError::CompileFailed { report, .. } => Some(report),
+            _ => None,
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            BuildyError::CompileFailed { .. } => 101,
+            BuildyError::LinkFailed(_) => 102,
+            BuildyError::ScanError(_) => 103,
+            BuildyError::ExecutableMissing(_) => 104,
+            BuildyError::ChildFailed { code } => *code,
+            BuildyError::TestsFailed { .. } => 105,
+            BuildyError::Interrupted => 130,
+            BuildyError::Other(_) => 1,
+        }
+    }
+}
+
+impl fmt::Display for BuildyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildyError::ScanError(message)
+            | BuildyError::CompileFailed { message, .. }
+            | BuildyError::LinkFailed(message)
+            | BuildyError::Other(message) => write!(f, "{}", message),
+            BuildyError::ExecutableMissing(path) => {
+                write!(f, "executable {} not found, build first", path.display())
+            }
+            BuildyError::ChildFailed { code } => write!(f, "program exited with status {}", code),
+            BuildyError::TestsFailed { failed, total } => {
+                write!(f, "{} of {} test(s) failed", failed, total)
+            }
+            BuildyError::Interrupted => write!(f, "build interrupted, finished compiles are kept"),
+        }
+    }
+}
+
+impl Error for BuildyError {}
@@ -0,0 +1,299 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often a command with a timeout is checked for having exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A program to run, see `execute`.
+#[derive(Debug, Default)]
+pub struct ExecRequest {
+    pub program: String,
+    pub args: Vec<String>,
+    /// Working directory, the current one when `None`.
+    pub cwd: Option<PathBuf>,
+    /// Variables added to the inherited environment.
+    pub env: BTreeMap<String, String>,
+    /// Kill the program if it hasn't exited after this long.
+    pub timeout: Option<Duration>,
+    /// Collect stdout and stderr instead of passing them through.  Stdin
+    /// is closed either way when capturing.
+    pub capture: bool,
+}
+
+/// What a program that exited with status 0 printed.  Empty unless
+/// `capture` was set.
+#[derive(Debug)]
+pub struct ExecResult {
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Why `execute` failed.
+#[derive(Debug)]
+pub enum ExecError {
+    /// The request names no program.
+    Empty,
+    /// The program couldn't be started.
+    Spawn { program: String, source: io::Error },
+    /// The program exited with a non-zero status (or was killed by a
+    /// signal).
+    Failed {
+        program: String,
+        status: ExitStatus,
+        /// Empty unless `capture` was set, like `stderr`.
+        stdout: String,
+        stderr: String,
+    },
+    /// The program was killed after running for `timeout`.
+    TimedOut { program: String, timeout: Duration },
+}
+
+impl fmt::Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecError::Empty => write!(f, "no program to run"),
+            ExecError::Spawn { program, source } => {
+                write!(f, "could not run {}: {}", program, source)
+            }
+            ExecError::Failed {
+                program,
+                status,
+                stderr,
+                ..
+            } => {
+                write!(f, "{} failed with {}", program, status)?;
+                match stderr.trim_end() {
+                    "" => Ok(()),
+                    stderr => write!(f, ":\n{}", stderr),
+                }
+            }
+            ExecError::TimedOut { program, timeout } => write!(
+                f,
+                "{} did not finish within {:.1}s and was killed",
+                program,
+                timeout.as_secs_f64()
+            ),
+        }
+    }
+}
+
+impl Error for ExecError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ExecError::Spawn { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Run `request` to completion.  Captured output is read on threads of its
+/// own so a chatty program can't block on a full pipe while we wait for it.
+pub fn execute(request: &ExecRequest) -> Result<ExecResult, ExecError> {
+    if request.program.is_empty() {
+        return Err(ExecError::Empty);
+    }
+    let mut command = Command::new(&request.program);
+    command.args(&request.args).envs(&request.env);
+    if let Some(cwd) = &request.cwd {
+        command.current_dir(cwd);
+    }
+    if request.capture {
+        command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+    }
+    let mut child = command.spawn().map_err(|source| ExecError::Spawn {
+        program: request.program.clone(),
+        source,
+    })?;
+    let stdout = collect(child.stdout.take());
+    let stderr = collect(child.stderr.take());
+
+    let wait = match request.timeout {
+        None => child.wait().map(Some),
+        Some(timeout) => {
+            let deadline = Instant::now() + timeout;
+            loop {
+                match child.try_wait() {
+                    Ok(Some(status)) => break Ok(Some(status)),
+                    Ok(None) if Instant::now() < deadline => thread::sleep(POLL_INTERVAL),
+                    Ok(None) => {
+                        let _ = child.kill();
+                        let _ = child.wait();
+                        break Ok(None);
+                    }
+                    Err(e) => break Err(e),
+                }
+            }
+        }
+    };
+    let status = match wait {
+        Ok(Some(status)) => status,
+        Ok(None) => {
+            return Err(ExecError::TimedOut {
+                program: request.program.clone(),
+                timeout: request.timeout.unwrap_or_default(),
+            });
+        }
+        Err(source) => {
+            return Err(ExecError::Spawn {
+                program: request.program.clone(),
+                source,
+            });
+        }
+    };
+    let stdout = stdout.join().unwrap_or_default();
+    let stderr = stderr.join().unwrap_or_default();
+    if !status.success() {
+        return Err(ExecError::Failed {
+            program: request.program.clone(),
+            status,
+            stdout,
+            stderr,
+        });
+    }
+    Ok(ExecResult { stdout, stderr })
+}
+
+/// Read `pipe` to the end on a new thread.
+fn collect(pipe: Option<impl Read + Send + 'static>) -> thread::JoinHandle<String> {
+    thread::spawn(move || {
+        let mut bytes = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut bytes);
+        }
+        String::from_utf8_lossy(&bytes).into_owned()
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    /// `/bin/sh -c script`, captured.
+    fn sh(script: &str) -> ExecRequest {
+        ExecRequest {
+            program: "/bin/sh".into(),
+            args: vec!["-c".into(), script.into()],
+            capture: true,
+            ..ExecRequest::default()
+        }
+    }
+
+    #[test]
+    fn captures_stdout_and_stderr() {
+        let result = execute(&sh("echo out; echo err >&2")).unwrap();
+        assert_eq!(result.stdout, "out\n");
+        assert_eq!(result.stderr, "err\n");
+    }
+
+    #[test]
+    fn runs_in_cwd_with_env_added() {
+        let dir = tempfile::tempdir().unwrap();
+        let request = ExecRequest {
+            cwd: Some(dir.path().to_path_buf()),
+            env: BTreeMap::from([("BUILDY_TEST_VALUE".to_string(), "x y".to_string())]),
+            ..sh("pwd; echo \"$BUILDY_TEST_VALUE\"; test -n \"$PATH\" && echo inherited")
+        };
+        let result = execute(&request).unwrap();
+        let cwd = dir.path().canonicalize().unwrap();
+        assert_eq!(
+            result.stdout,
+            format!("{}\nx y\ninherited\n", cwd.display())
+        );
+    }
+
+    #[test]
+    fn a_non_zero_exit_is_a_failure_with_its_output() {
+        match execute(&sh("echo partial; echo broken >&2; exit 3")) {
+            Err(ExecError::Failed {
+                program,
+                status,
+                stdout,
+                stderr,
+            }) => {
+                assert_eq!(program, "/bin/sh");
+                assert_eq!(status.code(), Some(3));
+                assert_eq!(stdout, "partial\n");
+                assert_eq!(stderr, "broken\n");
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn a_killed_program_is_a_failure_without_a_code() {
+        use std::os::unix::process::ExitStatusExt;
+        match execute(&sh("kill -TERM $$")) {
+            Err(ExecError::Failed { status, .. }) => {
+                assert_eq!(status.code(), None);
+                assert_eq!(status.signal(), Some(libc::SIGTERM));
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn an_empty_program_or_a_missing_one_doesnt_start() {
+        assert!(matches!(
+            execute(&ExecRequest::default()),
+            Err(ExecError::Empty)
+        ));
+        let request = ExecRequest {
+            program: "/nonexistent/buildy-test-program".into(),
+            ..ExecRequest::default()
+        };
+        match execute(&request) {
+            Err(ExecError::Spawn { program, source }) => {
+                assert_eq!(program, request.program);
+                assert_eq!(source.kind(), io::ErrorKind::NotFound);
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn a_program_running_past_its_timeout_is_killed() {
+        let request = ExecRequest {
+            timeout: Some(Duration::from_millis(100)),
+            ..sh("exec sleep 10")
+        };
+        let started = Instant::now();
+        match execute(&request) {
+            Err(ExecError::TimedOut { timeout, .. }) => {
+                assert_eq!(timeout, Duration::from_millis(100));
+            }
+            other => panic!("{:?}", other),
+        }
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let request = ExecRequest {
+            timeout: Some(Duration::from_secs(10)),
+            ..sh("echo quick")
+        };
+        assert_eq!(execute(&request).unwrap().stdout, "quick\n");
+    }
+
+    #[test]
+    fn a_chatty_program_doesnt_block_on_its_pipes() {
+        // far more than a pipe buffer on both streams
+        let script = "i=0; while [ $i -lt 20000 ]; do \
+                      echo 0123456789012345678901234567890123456789; \
+                      echo 0123456789012345678901234567890123456789 >&2; \
+                      i=$((i + 1)); done";
+        let request = ExecRequest {
+            timeout: Some(Duration::from_secs(60)),
+            ..sh(script)
+        };
+        let result = execute(&request).unwrap();
+        assert_eq!(result.stdout.lines().count(), 20000);
+        assert_eq!(result.stderr.lines().count(), 20000);
+    }
+}
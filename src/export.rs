@@ -0,0 +1,153 @@
+use crate::cache::BuildCache;
+use crate::graph::BuildGraph;
+use crate::lang::is_source;
+use crate::plan::DirtyPlan;
+use crate::protocol::{CollapsedHeader, Graph, GraphEdge, GraphNode, NodeKind, Versioned};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Output formats of `buildy graph`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    Dot,
+    Json,
+}
+
+/// Render the graph in `format`.  With `dirty_only` only dirty files and
+/// the edges between them are kept.  Headers that include nothing and have
+/// more than `max_fanout` dependents (typically a project-wide config or
+/// common header) are collapsed into one summary node so large graphs stay
+/// readable.
+pub fn render(
+    graph: &BuildGraph,
+    plan: &DirtyPlan,
+    root: &Path,
+    format: Format,
+    dirty_only: bool,
+    max_fanout: Option<usize>,
+) -> String {
+    let export = collect(graph, plan, root, dirty_only, max_fanout);
+    match format {
+        Format::Json => {
+            let mut json =
+                serde_json::to_string_pretty(&Versioned::new(&export)).unwrap_or_default();
+            json.push('\n');
+            json
+        }
+        Format::Dot => to_dot(&export, max_fanout),
+    }
+}
+
+fn collect(
+    graph: &BuildGraph,
+    plan: &DirtyPlan,
+    root: &Path,
+    dirty_only: bool,
+    max_fanout: Option<usize>,
+) -> Graph {
+    let key = |p: &Path| BuildCache::make_relative(p, root);
+    let mut nodes = BTreeMap::new();
+    let mut collapsed = Vec::new();
+    for (path, meta) in &graph.nodes {
+        let reason = plan.reasons.get(path);
+        if dirty_only && reason.is_none() {
+            continue;
+        }
+        let fanout = meta.dependents.len();
+        if !is_source(path) && meta.deps.is_empty() && max_fanout.is_some_and(|max| fanout > max) {
+            collapsed.push(CollapsedHeader {
+                path: key(path),
+                dependents: fanout,
+            });
+            continue;
+        }
+        nodes.insert(
+            key(path),
+            GraphNode {
+                path: key(path),
+                kind: if is_source(path) {
+                    NodeKind::Source
+                } else {
+                    NodeKind::Header
+                },
+                dirty: reason.is_some(),
+                reason: reason.map(|r| r.describe(root)),
+            },
+        );
+    }
+    let mut edges = Vec::new();
+    for (path, meta) in &graph.nodes {
+        let from = key(path);
+        if !nodes.contains_key(&from) {
+            continue;
+        }
+        for dep in &meta.deps {
+            let to = key(dep);
+            if nodes.contains_key(&to) {
+                edges.push(GraphEdge {
+                    from: from.clone(),
+                    to,
+                });
+            }
+        }
+    }
+    edges.sort();
+    edges.dedup();
+    collapsed.sort();
+    Graph {
+        nodes: nodes.into_values().collect(),
+        edges,
+        collapsed,
+    }
+}
+
+fn to_dot(export: &Graph, max_fanout: Option<usize>) -> String {
+    let mut out = String::from("digraph buildy {\n    rankdir=LR;\n    node [style=filled];\n");
+    for node in &export.nodes {
+        let shape = if node.kind == NodeKind::Source {
+            "box"
+        } else {
+            "ellipse"
+        };
+        let color = if node.dirty { "#f4a6a6" } else { "#c8e6c9" };
+        let tooltip = node.reason.as_deref().unwrap_or("clean");
+        out.push_str(&format!(
+            "    {} [shape={}, fillcolor=\"{}\", tooltip={}];\n",
+            quote(&node.path),
+            shape,
+            color,
+            quote(tooltip)
+        ));
+    }
+    for edge in &export.edges {
+        out.push_str(&format!(
+            "    {} -> {};\n",
+            quote(&edge.from),
+            quote(&edge.to)
+        ));
+    }
+    if !export.collapsed.is_empty() {
+        let names: Vec<&str> = export.collapsed.iter().map(|c| c.path.as_str()).collect();
+        out.push_str(&format!(
+            "    \"*collapsed*\" [shape=note, fillcolor=\"#eeeeee\", label={}, tooltip={}];\n",
+            quote(&format!(
+                "{} header(s) with more than {} dependents",
+                export.collapsed.len(),
+                max_fanout.unwrap_or_default()
+            )),
+            quote(&names.join("\n"))
+        ));
+    }
+    out.push_str("}\n");
+    out
+}
+
+/// `text` as a DOT string literal.
+fn quote(text: &str) -> String {
+    format!(
+        "\"{}\"",
+        text.replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    )
+}
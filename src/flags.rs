@@ -0,0 +1,441 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// Where a compiler flag came from.  Flags are gathered from several layers
+/// before a file is compiled; keeping the origin around lets us explain
+/// conflicts in terms the user can act on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FlagOrigin {
+    /// Built-in flags of the named build profile (e.g. `-g` for debug).
+    Profile(String),
+    /// A key in `buildy.toml` (e.g. `cflags`).
+    Config(String),
+    /// A command-line option (e.g. `--include`).
+    Cli(String),
+    /// A `[file]` table of `buildy.toml`, which overrides the flags before
+    /// it on purpose: conflicts it wins are reported but never fail the
+    /// build, even under `--strict-flags`.
+    FileOverride(String),
+}
+
+impl fmt::Display for FlagOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlagOrigin::Profile(name) => write!(f, "profile '{}'", name),
+            FlagOrigin::Config(key) => write!(f, "buildy.toml '{}'", key),
+            FlagOrigin::Cli(option) => write!(f, "command line '{}'", option),
+            FlagOrigin::FileOverride(key) => write!(f, "buildy.toml '{}'", key),
+        }
+    }
+}
+
+/// A single compiler argument together with its provenance.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Flag {
+    pub value: String,
+    pub origin: FlagOrigin,
+}
+
+impl fmt::Display for Flag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} from {}", self.value, self.origin)
+    }
+}
+
+/// Two flags from the same mutually-exclusive family that disagree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlagConflict {
+    pub family: String,
+    pub first: Flag,
+    pub second: Flag,
+    /// The last flag of the family on the command line, the one the
+    /// compiler goes by.
+    pub winner: Flag,
+}
+
+impl FlagConflict {
+    /// Whether a `[file]` override wins the conflict, which it does on
+    /// purpose (see `FlagOrigin::FileOverride`).
+    pub fn is_override(&self) -> bool {
+        matches!(self.winner.origin, FlagOrigin::FileOverride(_))
+    }
+}
+
+impl fmt::Display for FlagConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "conflicting {} flags: {}, {}; the compiler uses {}",
+            self.family, self.first, self.second, self.winner.value
+        )
+    }
+}
+
+/// The ordered list of flags used to compile one file.
+#[derive(Debug, Clone, Default)]
+pub struct FlagSet {
+    flags: Vec<Flag>,
+}
+
+impl FlagSet {
+    pub fn new() -> Self {
+        FlagSet { flags: Vec::new() }
+    }
+
+    pub fn push(&mut self, value: impl Into<String>, origin: FlagOrigin) {
+        self.flags.push(Flag {
+            value: value.into(),
+            origin,
+        });
+    }
+
+    /// The flag values in command-line order.
+    pub fn args(&self) -> Vec<String> {
+        self.flags.iter().map(|f| f.value.clone()).collect()
+    }
+
+    /// Drop repeated `-I`/`-D` flags (`/I`/`/D` with MSVC) with identical
+    /// values, keeping the first occurrence.  A `-D` repeated after a `-U`
+    /// of the same macro is kept, since it defines the macro again.
+    pub fn dedup(&mut self) {
+        let mut seen = HashSet::new();
+        self.flags.retain(|f| {
+            if let Some(name) = strip_any(&f.value, &["-U", "/U"]) {
+                seen.retain(|flag: &String| {
+                    strip_any(flag, &["-D", "/D"]).map(macro_name) != Some(name)
+                });
+                return true;
+            }
+            let dedupable = strip_any(&f.value, &["-I", "-D", "/I", "/D"]).is_some();
+            !dedupable || seen.insert(f.value.clone())
+        });
+    }
+
+    /// Find flags from the same mutually-exclusive family that disagree.
+    /// Each flag is only reported against the first flag of its family, so
+    /// `-O0 -O2 -O3` yields two conflicts rather than three, both naming
+    /// `-O3` as the winner.
+    pub fn conflicts(&self) -> Vec<FlagConflict> {
+        let mut first_seen: Vec<(String, String, &Flag)> = Vec::new();
+        let mut last: HashMap<String, &Flag> = HashMap::new();
+        let mut conflicts = Vec::new();
+        for flag in &self.flags {
+            for (family, setting) in classify(&flag.value) {
+                last.insert(family.clone(), flag);
+                match first_seen.iter().find(|(fam, _, _)| *fam == family) {
+                    Some((_, first_setting, first)) => {
+                        if *first_setting != setting {
+                            conflicts.push(FlagConflict {
+                                family: family.clone(),
+                                first: (*first).clone(),
+                                second: flag.clone(),
+                                winner: flag.clone(),
+                            });
+                        }
+                    }
+                    None => first_seen.push((family, setting, flag)),
+                }
+            }
+        }
+        for conflict in &mut conflicts {
+            conflict.winner = last[&conflict.family].clone();
+        }
+        conflicts
+    }
+}
+
+/// `flag` without the first of `prefixes` it starts with.
+fn strip_any<'a>(flag: &'a str, prefixes: &[&str]) -> Option<&'a str> {
+    prefixes.iter().find_map(|prefix| flag.strip_prefix(prefix))
+}
+
+/// The macro of a `-D` argument written `NAME` or `NAME=VALUE`.
+fn macro_name(define: &str) -> &str {
+    define.split_once('=').map_or(define, |(name, _)| name)
+}
+
+/// Sanitizers that each bring their own runtime and cannot be combined.
+const EXCLUSIVE_SANITIZERS: [&str; 4] = ["address", "hwaddress", "thread", "memory"];
+
+/// Map a flag onto the mutually-exclusive families it belongs to, returning
+/// `(family, setting)` pairs.  Two flags conflict when they share a family
+/// but not a setting.
+fn classify(flag: &str) -> Vec<(String, String)> {
+    let one = |family: &str, setting: &str| vec![(family.to_string(), setting.to_string())];
+
+    if let Some(level) = flag.strip_prefix("-O") {
+        // plain `-O` is an alias for `-O1`
+        let level = if level.is_empty() { "1" } else { level };
+        return one("optimization level", level);
+    }
+    if let Some(std) = flag.strip_prefix("-std=") {
+        return one("language standard", std);
+    }
+    match flag {
+        "-fexceptions" => return one("exceptions", "on"),
+        "-fno-exceptions" => return one("exceptions", "off"),
+        "-frtti" => return one("RTTI", "on"),
+        "-fno-rtti" => return one("RTTI", "off"),
+        "-fPIC" | "-fpic" => return one("position-independent code", "on"),
+        "-fno-PIC" | "-fno-pic" => return one("position-independent code", "off"),
+        _ => {}
+    }
+
+    let mut out = Vec::new();
+    if let Some(list) = flag.strip_prefix("-fsanitize=") {
+        for san in list.split(',') {
+            if EXCLUSIVE_SANITIZERS.contains(&san) {
+                out.push(("sanitizer runtime".to_string(), san.to_string()));
+            }
+            out.push((format!("sanitizer '{}'", san), "on".to_string()));
+        }
+    } else if let Some(list) = flag.strip_prefix("-fno-sanitize=") {
+        for san in list.split(',') {
+            out.push((format!("sanitizer '{}'", san), "off".to_string()));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cli() -> FlagOrigin {
+        FlagOrigin::Cli("--test".into())
+    }
+
+    fn set(flags: &[&str]) -> FlagSet {
+        let mut set = FlagSet::new();
+        for flag in flags {
+            set.push(*flag, cli());
+        }
+        set
+    }
+
+    /// `(family, first, second, winner)` of each conflict.
+    fn conflicts(flags: &[&str]) -> Vec<(String, String, String, String)> {
+        set(flags)
+            .conflicts()
+            .into_iter()
+            .map(|c| (c.family, c.first.value, c.second.value, c.winner.value))
+            .collect()
+    }
+
+    fn conflict(
+        family: &str,
+        first: &str,
+        second: &str,
+        winner: &str,
+    ) -> (String, String, String, String) {
+        (family.into(), first.into(), second.into(), winner.into())
+    }
+
+    #[test]
+    fn classifies_each_family() {
+        let table: &[(&str, &[(&str, &str)])] = &[
+            ("-O2", &[("optimization level", "2")]),
+            ("-O", &[("optimization level", "1")]),
+            ("-Os", &[("optimization level", "s")]),
+            ("-std=c++20", &[("language standard", "c++20")]),
+            ("-fexceptions", &[("exceptions", "on")]),
+            ("-fno-exceptions", &[("exceptions", "off")]),
+            ("-frtti", &[("RTTI", "on")]),
+            ("-fno-rtti", &[("RTTI", "off")]),
+            ("-fPIC", &[("position-independent code", "on")]),
+            ("-fpic", &[("position-independent code", "on")]),
+            ("-fno-PIC", &[("position-independent code", "off")]),
+            (
+                "-fsanitize=address,undefined",
+                &[
+                    ("sanitizer runtime", "address"),
+                    ("sanitizer 'address'", "on"),
+                    ("sanitizer 'undefined'", "on"),
+                ],
+            ),
+            (
+                "-fno-sanitize=undefined",
+                &[("sanitizer 'undefined'", "off")],
+            ),
+            ("-Wall", &[]),
+            ("-g", &[]),
+            ("-march=native", &[]),
+        ];
+        for (flag, expected) in table {
+            let expected: Vec<(String, String)> = expected
+                .iter()
+                .map(|(family, setting)| (family.to_string(), setting.to_string()))
+                .collect();
+            assert_eq!(classify(flag), expected, "{}", flag);
+        }
+    }
+
+    #[test]
+    fn the_last_flag_of_each_family_wins() {
+        assert_eq!(
+            conflicts(&["-O0", "-O2", "-O3"]),
+            vec![
+                conflict("optimization level", "-O0", "-O2", "-O3"),
+                conflict("optimization level", "-O0", "-O3", "-O3"),
+            ]
+        );
+        assert_eq!(
+            conflicts(&["-std=c++17", "-std=c++20"]),
+            vec![conflict(
+                "language standard",
+                "-std=c++17",
+                "-std=c++20",
+                "-std=c++20"
+            )]
+        );
+        assert_eq!(
+            conflicts(&["-fno-exceptions", "-fexceptions"]),
+            vec![conflict(
+                "exceptions",
+                "-fno-exceptions",
+                "-fexceptions",
+                "-fexceptions"
+            )]
+        );
+        assert_eq!(
+            conflicts(&["-frtti", "-fno-rtti"]),
+            vec![conflict("RTTI", "-frtti", "-fno-rtti", "-fno-rtti")]
+        );
+        assert_eq!(
+            conflicts(&["-fPIC", "-fno-pic"]),
+            vec![conflict(
+                "position-independent code",
+                "-fPIC",
+                "-fno-pic",
+                "-fno-pic"
+            )]
+        );
+        assert_eq!(
+            conflicts(&["-fsanitize=address", "-fsanitize=thread"]),
+            vec![conflict(
+                "sanitizer runtime",
+                "-fsanitize=address",
+                "-fsanitize=thread",
+                "-fsanitize=thread"
+            )]
+        );
+        assert_eq!(
+            conflicts(&["-fsanitize=undefined", "-fno-sanitize=undefined"]),
+            vec![conflict(
+                "sanitizer 'undefined'",
+                "-fsanitize=undefined",
+                "-fno-sanitize=undefined",
+                "-fno-sanitize=undefined"
+            )]
+        );
+        // back and forth: the conflicts are reported against the first, the
+        // last one still wins
+        assert_eq!(
+            conflicts(&["-O2", "-O0", "-O2"]),
+            vec![conflict("optimization level", "-O2", "-O0", "-O2")]
+        );
+    }
+
+    #[test]
+    fn agreeing_flags_dont_conflict() {
+        assert!(conflicts(&["-O2", "-O2", "-fPIC", "-fpic", "-std=c11", "-std=c11"]).is_empty());
+        assert!(conflicts(&["-O", "-O1"]).is_empty());
+        // combinable sanitizers
+        assert!(conflicts(&["-fsanitize=address", "-fsanitize=undefined"]).is_empty());
+        assert!(conflicts(&["-Wall", "-Wextra", "-g", "-DNDEBUG"]).is_empty());
+    }
+
+    #[test]
+    fn a_file_override_wins_its_conflicts() {
+        let mut flags = FlagSet::new();
+        flags.push("-O3", FlagOrigin::Profile("release".into()));
+        flags.push("-Wall", FlagOrigin::Config("cflags".into()));
+        flags.push(
+            "-O0",
+            FlagOrigin::FileOverride("file.\"src/slow.cpp\".extra_flags".into()),
+        );
+        let conflicts = flags.conflicts();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(
+            conflicts[0].to_string(),
+            "conflicting optimization level flags: -O3 from profile 'release', \
+             -O0 from buildy.toml 'file.\"src/slow.cpp\".extra_flags'; the compiler uses -O0"
+        );
+        assert!(conflicts[0].is_override());
+
+        // overridden again on the command line: no longer the override's doing
+        flags.push("-O2", cli());
+        let conflicts = flags.conflicts();
+        assert_eq!(conflicts.len(), 2);
+        assert!(conflicts.iter().all(|c| !c.is_override()));
+    }
+
+    #[test]
+    fn conflicts_name_where_each_flag_came_from() {
+        let mut flags = FlagSet::new();
+        flags.push("-O0", FlagOrigin::Profile("debug".into()));
+        flags.push("-O3", FlagOrigin::Config("cflags".into()));
+        let conflicts = flags.conflicts();
+        assert_eq!(
+            conflicts[0].to_string(),
+            "conflicting optimization level flags: -O0 from profile 'debug', \
+             -O3 from buildy.toml 'cflags'; the compiler uses -O3"
+        );
+    }
+
+    #[test]
+    fn dedup_drops_repeated_includes_and_defines_in_order() {
+        let mut flags = set(&[
+            "-Iinclude",
+            "-DX=1",
+            "-O2",
+            "-Ivendor",
+            "-Iinclude",
+            "-DX=1",
+            "-DX=2",
+            "-DY",
+            "-O2",
+            "-DY",
+        ]);
+        flags.dedup();
+        assert_eq!(
+            flags.args(),
+            vec![
+                "-Iinclude",
+                "-DX=1",
+                "-O2",
+                "-Ivendor",
+                "-DX=2",
+                "-DY",
+                "-O2"
+            ]
+        );
+
+        // defined again after -U, which dropping it would undo
+        let mut flags = set(&["-DX=1", "-UX", "-DX=1", "-DX=1", "-DY", "-UX", "-DY"]);
+        flags.dedup();
+        assert_eq!(flags.args(), ["-DX=1", "-UX", "-DX=1", "-DY", "-UX"]);
+        let mut flags = set(&["/DX", "/UX", "/DX"]);
+        flags.dedup();
+        assert_eq!(flags.args(), ["/DX", "/UX", "/DX"]);
+    }
+
+    #[test]
+    fn unknown_flags_pass_through_unchanged() {
+        let args = [
+            "-Wall",
+            "-Wall",
+            "-march=native",
+            "-fno-strict-aliasing",
+            "--param=max-inline-insns-single=500",
+            "-isystem",
+            "/opt/include",
+            "-isystem",
+            "/opt/include",
+        ];
+        let mut flags = set(&args);
+        flags.dedup();
+        assert_eq!(flags.args(), args);
+        assert!(flags.conflicts().is_empty());
+    }
+}
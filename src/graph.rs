@@ -1,206 +1,758 @@
 use crate::cache::BuildCache;
+use crate::cancel;
+use crate::config::{self, Config};
+use crate::depfile;
 use crate::hasher::hash_file;
+use crate::lang::{self, Language, is_source};
+use crate::modules::{self, ModuleGraph};
+use crate::scheduler;
 use crate::target::FileMeta;
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::io;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::time::{Duration, Instant};
 use walkdir::WalkDir;
 
+/// A source whose `-MM` dependency scan failed.  Its dependency list is
+/// unknown, so it is treated as always dirty until a scan succeeds.
+#[derive(Debug, Clone)]
+pub struct ScanFailure {
+    pub path: PathBuf,
+    /// The compiler's stderr.
+    pub message: String,
+}
+
+impl ScanFailure {
+    /// First line of the compiler output, for compact reports.
+    pub fn first_line(&self) -> &str {
+        self.message.lines().next().unwrap_or("(no output)")
+    }
+}
+
+/// A file the scan had to skip because it couldn't be read: a dangling
+/// symlink, a file or directory without read permission, ...
+#[derive(Debug, Clone)]
+pub struct Unreadable {
+    pub path: PathBuf,
+    pub error: String,
+}
+
+/// Whether `path` is somewhere Buildy never looks for sources: the `target/`
+/// output directory or a hidden directory such as `.git/`.  The watcher uses
+/// this too, so build outputs don't trigger rebuilds.
+pub fn is_ignored(path: &Path, root: &Path) -> bool {
+    let Ok(rel) = path.strip_prefix(root) else {
+        return false;
+    };
+    rel.components().enumerate().any(|(i, c)| {
+        let name = c.as_os_str().to_string_lossy();
+        (i == 0 && name == "target") || (name.starts_with('.') && name != "." && name != "..")
+    })
+}
+
+/// Whether the source at `path` defines `main`: a quick textual check for a
+/// line that declares `int main(` (or `auto`/`void main(`) outside a line
+/// comment.  Good enough to tell programs from the code they share.
+fn defines_main(path: &Path) -> bool {
+    let Ok(text) = std::fs::read_to_string(path) else {
+        return false;
+    };
+    let is_ident = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    text.lines().any(|line| {
+        let code = line.split("//").next().unwrap_or("");
+        code.match_indices("main").any(|(i, _)| {
+            let (before, after) = (&code[..i], &code[i + 4..]);
+            !after.starts_with(is_ident)
+                && after.trim_start().starts_with('(')
+                && ["int", "auto", "void"].iter().any(|ty| {
+                    before
+                        .trim_end()
+                        .strip_suffix(ty)
+                        .is_some_and(|rest| !rest.ends_with(is_ident))
+                })
+        })
+    })
+}
+
+/// Saves the cache every `FILES` freshly scanned sources or `INTERVAL`,
+/// whichever comes first.
+struct Checkpoint {
+    last: Instant,
+    pending: usize,
+    recorded: usize,
+}
+
+impl Checkpoint {
+    const FILES: usize = 500;
+    const INTERVAL: Duration = Duration::from_secs(5);
+
+    fn new() -> Self {
+        Checkpoint {
+            last: Instant::now(),
+            pending: 0,
+            recorded: 0,
+        }
+    }
+
+    /// Called after each source; saves when enough new results piled up.
+    fn tick(&mut self, cache: &mut BuildCache) -> io::Result<()> {
+        let recorded = cache.scans.len();
+        self.pending += recorded.saturating_sub(self.recorded);
+        self.recorded = recorded;
+        if self.pending > 0
+            && (self.pending >= Self::FILES || self.last.elapsed() >= Self::INTERVAL)
+        {
+            cache.save()?;
+            self.pending = 0;
+            self.last = Instant::now();
+        }
+        Ok(())
+    }
+}
+
 /// BuildGraph keeps metadata for every source/header file we know about.
 #[derive(Debug)]
 pub struct BuildGraph {
     pub nodes: HashMap<PathBuf, FileMeta>,
+    /// C++20 module declarations; empty unless `modules` is enabled.
+    pub modules: ModuleGraph,
+    /// Sources whose dependency scan failed during the last `scan`.
+    pub scan_failures: Vec<ScanFailure>,
+    /// Files skipped or left unhashed since the graph was last brought up
+    /// to date, see `Unreadable`.
+    pub unreadable: Vec<Unreadable>,
+    /// Sources defining `main`, see `defines_main`.
+    pub mains: BTreeSet<PathBuf>,
+    /// Walked path -> canonical path, kept across scans so a long-lived
+    /// graph doesn't canonicalize every file again on each build.
+    canonical: HashMap<PathBuf, PathBuf>,
+    /// Settings of the last full scan (see `scan_key`), or `None` when the
+    /// next `update` has to scan everything.
+    scanned_with: Option<String>,
+    /// Files reported changed since the graph was last brought up to date.
+    changed: BTreeSet<PathBuf>,
+}
+
+/// What a scan's result depends on besides the files: which files are
+/// walked and the `-MM` command lines.
+fn scan_key(config: &Config, extra_flags: &[String]) -> String {
+    format!(
+        "{}|{:?}|{:?}|{:?}|{:?}|{:?}|{:?}|{:?}|{:?}|{}|{}|{:?}",
+        config.compilers(),
+        config.common_flags,
+        config.cflags,
+        config.cxxflags,
+        config.dir,
+        config.file,
+        config.include_dirs,
+        config.exclude,
+        config.ignore_file.lines,
+        config.exclude_headers,
+        config.follow_symlinks,
+        extra_flags
+    )
+}
+
+/// Whether `scan` picks up files with the name of `path`.
+fn is_scanned(path: &Path, config: &Config) -> bool {
+    lang::is_header(path) || (is_source(path) && (config.modules || !lang::is_interface(path)))
 }
 
 impl BuildGraph {
     pub fn new() -> Self {
         BuildGraph {
             nodes: HashMap::new(),
+            modules: ModuleGraph::default(),
+            scan_failures: Vec::new(),
+            unreadable: Vec::new(),
+            mains: BTreeSet::new(),
+            canonical: HashMap::new(),
+            scanned_with: None,
+            changed: BTreeSet::new(),
         }
     }
 
+    /// Remember that `path` was created, modified or removed, for the next
+    /// `update`.
+    pub fn note_change(&mut self, path: PathBuf) {
+        self.changed.insert(path);
+    }
+
+    /// Make the next `update` scan everything, e.g. after a directory moved.
+    pub fn invalidate(&mut self) {
+        self.scanned_with = None;
+    }
+
+    /// Bring the graph up to date: only the files reported through
+    /// `note_change` when the last full scan used the same settings, with a
+    /// full `scan` otherwise.  Module support always scans everything.
+    pub fn update(
+        &mut self,
+        root: &Path,
+        config: &Config,
+        extra_flags: &[String],
+        profile: &str,
+        cache: &mut BuildCache,
+    ) -> io::Result<()> {
+        if !config.modules
+            && self.scanned_with.as_deref() == Some(scan_key(config, extra_flags).as_str())
+        {
+            let paths: Vec<PathBuf> = std::mem::take(&mut self.changed).into_iter().collect();
+            return self.apply_changes(&paths, root, config, extra_flags, profile, cache);
+        }
+        self.scan(root, config, extra_flags, profile, cache)
+    }
+
     /// Scan the filesystem for C/C++ sources and headers and populate the
-    /// graph. `extra_flags` are forwarded to the compiler when querying
-    /// dependencies.
-    pub fn scan(&mut self, root: &Path, extra_flags: &[String]) -> io::Result<()> {
-        let exts = ["c", "cpp", "cc", "cxx", "h", "hpp"];
-        for entry in WalkDir::new(root).into_iter().filter_map(|e| e.ok()) {
-            if let Some(ext) = entry.path().extension().and_then(|e| e.to_str()) {
-                if exts.contains(&ext) {
-                    let path = entry.path().canonicalize()?;
-                    let meta = FileMeta::new(path.clone())?;
-                    self.nodes.entry(path.clone()).or_insert(meta);
+    /// graph, replacing the result of any previous scan.  The configured
+    /// language flags and include directories, plus `extra_flags`, are
+    /// forwarded to the compiler when querying dependencies.
+    ///
+    /// Dependencies are recorded in `cache`, which is saved periodically
+    /// during the scan.  Files whose earlier results are still valid are not
+    /// scanned again, so an interrupted first scan of a large tree resumes
+    /// where it stopped.  Files and directories that can't be read are
+    /// skipped and listed in `unreadable`.
+    ///
+    /// With `follow_symlinks` linked directories are walked too, each only
+    /// once however many links lead to it, and links back to a directory
+    /// being walked (cycles) are skipped.
+    pub fn scan(
+        &mut self,
+        root: &Path,
+        config: &Config,
+        extra_flags: &[String],
+        profile: &str,
+        cache: &mut BuildCache,
+    ) -> io::Result<()> {
+        self.nodes.clear();
+        self.scan_failures.clear();
+        self.unreadable.clear();
+        self.mains.clear();
+        self.scanned_with = None;
+        self.changed.clear();
+        let follow = config.follow_symlinks;
+        let mut visited = HashSet::new();
+        let walker = WalkDir::new(root)
+            .follow_links(follow)
+            .into_iter()
+            .filter_entry(|e| {
+                !is_ignored(e.path(), root)
+                    && !config.is_excluded(e.path(), root)
+                    && (!follow || !e.file_type().is_dir() || {
+                        let dir = e.path().canonicalize();
+                        dir.map_or(true, |dir| visited.insert(dir))
+                    })
+            });
+        for entry in walker {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) if e.loop_ancestor().is_some() => continue,
+                Err(e) => {
+                    let path = e.path().unwrap_or(root).to_path_buf();
+                    self.skip(path, e);
+                    continue;
                 }
+            };
+            if is_scanned(entry.path(), config) {
+                self.add(entry.path());
             }
         }
 
-        let keys: Vec<PathBuf> = self.nodes.keys().cloned().collect();
+        let mut keys: Vec<PathBuf> = self.nodes.keys().cloned().collect();
+        keys.sort();
+        let mut checkpoint = Checkpoint::new();
         for path in keys {
-            if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
-                if ["c", "cpp", "cc", "cxx"].contains(&ext) {
-                    let deps = self.parse_deps(&path, extra_flags)?;
-                    if let Some(node) = self.nodes.get_mut(&path) {
-                        node.deps = deps.clone();
-                    }
-                    for d in deps {
-                        self.nodes.entry(d.clone()).or_insert_with(|| FileMeta {
-                            path: d.clone(),
-                            hash: String::new(),
-                            last_modified: chrono::Utc::now(),
-                            deps: Vec::new(),
-                            dependents: Vec::new(),
-                            dirty: true,
-                        });
-                        if let Some(depnode) = self.nodes.get_mut(&d) {
-                            depnode.dependents.push(path.clone());
-                        }
-                    }
-                }
+            if is_source(&path) {
+                self.scan_source(&path, root, config, extra_flags, profile, cache)?;
+                checkpoint.tick(cache)?;
+            }
+            // keep what was scanned; without `scanned_with` the next build
+            // scans again and resumes from the cache
+            if cancel::requested() {
+                cache.save()?;
+                return Err(cancel::interrupted());
             }
         }
 
+        if config.modules {
+            self.scan_modules()?;
+        }
+
+        self.scanned_with = Some(scan_key(config, extra_flags));
         Ok(())
     }
 
-    fn parse_deps(&self, file: &Path, extra_flags: &[String]) -> io::Result<Vec<PathBuf>> {
-        let compiler = if file
-            .extension()
-            .and_then(|e| e.to_str())
-            .map(|e| e == "c")
-            .unwrap_or(false)
-        {
-            "gcc"
-        } else {
-            "g++"
-        };
-        let mut cmd = Command::new(compiler);
-        cmd.arg("-MM");
-        for f in extra_flags {
-            cmd.arg(f);
+    /// Add a node for the file at `path` unless there is one, under its
+    /// canonical path, which is returned.  Unreadable files are skipped and
+    /// listed in `unreadable`.
+    fn add(&mut self, path: &Path) -> Option<PathBuf> {
+        let added = self.canonicalize(path).and_then(|canonical| {
+            if !self.nodes.contains_key(&canonical) {
+                let meta = FileMeta::new(canonical.clone())?;
+                // a file can be stat'ed without read permission
+                std::fs::File::open(&canonical)?;
+                self.nodes.insert(canonical.clone(), meta);
+            }
+            Ok(canonical)
+        });
+        match added {
+            Ok(canonical) => Some(canonical),
+            Err(e) => {
+                self.skip(path.to_path_buf(), e);
+                None
+            }
         }
-        cmd.arg(file);
-        let output = cmd.output()?;
-        if !output.status.success() {
-            return Ok(Vec::new());
+    }
+
+    /// Record that `path` couldn't be read.
+    fn skip(&mut self, path: PathBuf, error: impl std::fmt::Display) {
+        if !self.unreadable.iter().any(|u| u.path == path) {
+            let error = error.to_string();
+            self.unreadable.push(Unreadable { path, error });
         }
-        let text = String::from_utf8_lossy(&output.stdout);
-        let mut deps = Vec::new();
-        for token in text.split_whitespace().skip(1) {
-            let tok = token.trim_end_matches(['\\', ':'].as_ref());
-            if tok.is_empty() {
+    }
+
+    /// Update the graph of an earlier `scan` for `paths`, which were
+    /// created, modified or removed since (a rename is the removal of one
+    /// name and the creation of the other).  Removed files are dropped with
+    /// their edges, new ones are added, and the sources among them, plus
+    /// the sources including a changed or removed header, are scanned
+    /// again.  Changed files are hashed again by the next `refresh`.
+    pub fn apply_changes(
+        &mut self,
+        paths: &[PathBuf],
+        root: &Path,
+        config: &Config,
+        extra_flags: &[String],
+        profile: &str,
+        cache: &mut BuildCache,
+    ) -> io::Result<()> {
+        self.unreadable.clear();
+        let mut rescan = BTreeSet::new();
+        for path in paths {
+            if !path.exists() {
+                let known = self.canonical.remove(path).unwrap_or_else(|| path.clone());
+                if let Some(meta) = self.remove(&known) {
+                    rescan.extend(meta.dependents.into_iter().filter(|p| is_source(p)));
+                }
+                continue;
+            }
+            if is_ignored(path, root) || !is_scanned(path, config) {
+                continue;
+            }
+            let excluded = config.is_excluded(path, root);
+            if excluded && (config.exclude_headers || !lang::is_header(path)) {
                 continue;
             }
-            if tok.starts_with('/') || tok.starts_with('<') {
+            let path = match self.canonicalize(path) {
+                Ok(canonical) => canonical,
+                Err(e) => {
+                    self.skip(path.clone(), e);
+                    continue;
+                }
+            };
+            // an excluded header only matters to the sources including it
+            if excluded && !self.nodes.contains_key(&path) {
                 continue;
             }
-            let candidate = PathBuf::from(tok);
-            if candidate.exists() {
-                deps.push(candidate);
+            match self.nodes.get_mut(&path) {
+                Some(node) => node.hash.clear(),
+                None => {
+                    if self.add(&path).is_none() {
+                        continue;
+                    }
+                    // it may be the header a failed scan was missing
+                    rescan.extend(self.scan_failures.iter().map(|f| f.path.clone()));
+                }
+            }
+            if is_source(&path) {
+                rescan.insert(path);
+            } else if let Some(node) = self.nodes.get(&path) {
+                rescan.extend(node.dependents.iter().filter(|p| is_source(p)).cloned());
             }
         }
-        Ok(deps)
+        for source in rescan {
+            if self.nodes.contains_key(&source) {
+                self.scan_source(&source, root, config, extra_flags, profile, cache)?;
+            }
+        }
+        Ok(())
     }
 
-    pub fn update_dirty(&mut self, cache: &BuildCache, root: &std::path::Path) {
-        for meta in self.nodes.values_mut() {
-            let _ = meta.refresh(|p| hash_file(p));
-            if !cache.file_matches(meta, root) {
-                meta.dirty = true;
+    /// Drop `path` and the edges to and from it.
+    fn remove(&mut self, path: &Path) -> Option<FileMeta> {
+        let meta = self.nodes.remove(path)?;
+        self.mains.remove(path);
+        self.scan_failures.retain(|f| f.path != path);
+        for dep in &meta.deps {
+            if let Some(node) = self.nodes.get_mut(dep) {
+                node.dependents.retain(|d| d != path);
             }
         }
-        let mut queue: VecDeque<PathBuf> = self
+        for dependent in &meta.dependents {
+            if let Some(node) = self.nodes.get_mut(dependent) {
+                node.deps.retain(|d| d != path);
+            }
+        }
+        Some(meta)
+    }
+
+    /// (Re)scan the dependencies of `source`, replacing the edges of an
+    /// earlier scan.
+    fn scan_source(
+        &mut self,
+        source: &Path,
+        root: &Path,
+        config: &Config,
+        extra_flags: &[String],
+        profile: &str,
+        cache: &mut BuildCache,
+    ) -> io::Result<()> {
+        self.scan_failures.retain(|f| f.path != source);
+        self.mains.remove(source);
+        if defines_main(source) {
+            self.mains.insert(source.to_path_buf());
+        }
+
+        let deps = if lang::is_preprocessed(source) {
+            self.parse_deps(source, root, config, extra_flags, profile, cache)?
+        } else {
+            Vec::new()
+        };
+        self.set_deps(source, deps);
+        Ok(())
+    }
+
+    /// Take the dependencies of each of `sources` from the dependency file
+    /// its compile just wrote, so the graph knows about headers an edit
+    /// started including.  Sources without a current dependency file keep
+    /// the edges they have.
+    pub fn reload_deps(
+        &mut self,
+        sources: &[PathBuf],
+        root: &Path,
+        config: &Config,
+        profile: &str,
+    ) {
+        for source in sources {
+            if self.nodes.contains_key(source)
+                && let Some(deps) = dep_file_deps(source, root, config, profile, &self.nodes)
+            {
+                self.set_deps(source, deps);
+            }
+        }
+    }
+
+    /// Replace the edges from `source` with edges to `deps`, adding nodes
+    /// for dependencies not seen yet.  Dependencies are keyed by their
+    /// canonical paths like the walked files, so a header the cache lists
+    /// below a root reached through a symlink, or as `src/../foo.h`, is
+    /// the node whose hash the scan refreshes.  One that no longer exists
+    /// keeps its path, and so its source dirty.
+    fn set_deps(&mut self, source: &Path, deps: Vec<PathBuf>) {
+        let mut canonical: Vec<PathBuf> = Vec::with_capacity(deps.len());
+        for dep in deps {
+            let dep = self.canonicalize(&dep).unwrap_or(dep);
+            if !canonical.contains(&dep) {
+                canonical.push(dep);
+            }
+        }
+        let deps = canonical;
+        let old = self
             .nodes
-            .iter()
-            .filter(|(_, m)| m.dirty)
-            .map(|(p, _)| p.clone())
-            .collect();
-        let mut seen = HashSet::new();
-        while let Some(p) = queue.pop_front() {
-            if !seen.insert(p.clone()) {
-                continue;
+            .get_mut(source)
+            .map(|node| std::mem::take(&mut node.deps))
+            .unwrap_or_default();
+        for dep in &old {
+            if let Some(node) = self.nodes.get_mut(dep) {
+                node.dependents.retain(|d| d != source);
             }
-            // copy dependents list to avoid borrowing conflict when mutably accessing nodes later
-            let dependents = if let Some(node) = self.nodes.get(&p) {
-                node.dependents.clone()
-            } else {
-                Vec::new()
-            };
-            for dep in dependents {
-                if let Some(dnode) = self.nodes.get_mut(&dep) {
-                    if !dnode.dirty {
-                        dnode.dirty = true;
-                        queue.push_back(dep.clone());
-                    }
-                }
+        }
+        if let Some(node) = self.nodes.get_mut(source) {
+            node.deps = deps.clone();
+        }
+        for d in deps {
+            self.nodes.entry(d.clone()).or_insert_with(|| FileMeta {
+                path: d.clone(),
+                hash: String::new(),
+                last_modified: chrono::Utc::now(),
+                size: 0,
+                deps: Vec::new(),
+                dependents: Vec::new(),
+                dirty: true,
+            });
+            if let Some(depnode) = self.nodes.get_mut(&d) {
+                depnode.dependents.push(source.to_path_buf());
             }
         }
     }
 
-    pub fn topo_sort_dirty(&self) -> Vec<PathBuf> {
-        // determining the set of files we actually care about (dirty or dependent on dirty)
-        let mut dirty_set: HashSet<PathBuf> = self
+    fn canonicalize(&mut self, path: &Path) -> io::Result<PathBuf> {
+        if let Some(canonical) = self.canonical.get(path) {
+            return Ok(canonical.clone());
+        }
+        let canonical = path.canonicalize()?;
+        self.canonical.insert(path.to_path_buf(), canonical.clone());
+        Ok(canonical)
+    }
+
+    /// Read the module declarations of every C++ source and add an edge from
+    /// each importer to the unit providing the imported module, so interface
+    /// units are ordered (and dirtied) like headers.
+    fn scan_modules(&mut self) -> io::Result<()> {
+        self.modules = ModuleGraph::default();
+        let sources: Vec<PathBuf> = self
             .nodes
-            .iter()
-            .filter(|(_, m)| m.dirty)
-            .map(|(p, _)| p.clone())
+            .keys()
+            .filter(|p| Language::of(p) == Some(Language::Cxx))
+            .cloned()
             .collect();
-        let mut queue: Vec<PathBuf> = dirty_set.iter().cloned().collect();
-        while let Some(p) = queue.pop() {
-            if let Some(node) = self.nodes.get(&p) {
-                for dep in &node.dependents {
-                    if dirty_set.insert(dep.clone()) {
-                        queue.push(dep.clone());
-                    }
+        for path in &sources {
+            match modules::scan_unit(path) {
+                Ok(unit) => self.modules.insert(path.clone(), unit),
+                Err(e) => self.skip(path.clone(), e),
+            }
+        }
+        for path in &sources {
+            for provider in self.modules.providers_of(path) {
+                if let Some(node) = self.nodes.get_mut(path)
+                    && !node.deps.contains(&provider)
+                {
+                    node.deps.push(provider.clone());
+                }
+                if let Some(node) = self.nodes.get_mut(&provider)
+                    && !node.dependents.contains(path)
+                {
+                    node.dependents.push(path.clone());
                 }
             }
         }
+        Ok(())
+    }
 
-        // compute in-degrees restricted to dirty_set
-        let mut indeg: HashMap<PathBuf, usize> = HashMap::new();
-        for path in &dirty_set {
-            indeg.insert(path.clone(), 0);
+    /// The user headers `file` includes: from the dependency file of its
+    /// last compile while that is current (see `dep_file_deps`), from
+    /// `cache` while it holds a valid answer, and otherwise from the
+    /// compiler.  A failing scan is recorded in `scan_failures` and yields
+    /// no dependencies.
+    fn parse_deps(
+        &mut self,
+        file: &Path,
+        root: &Path,
+        config: &Config,
+        extra_flags: &[String],
+        profile: &str,
+        cache: &mut BuildCache,
+    ) -> io::Result<Vec<PathBuf>> {
+        if let Some(deps) = dep_file_deps(file, root, config, profile, &self.nodes) {
+            return Ok(deps);
         }
-        for path in &dirty_set {
-            if let Some(node) = self.nodes.get(path) {
-                for dep in &node.deps {
-                    if dirty_set.contains(dep) {
-                        *indeg.get_mut(path).unwrap() += 1;
-                    }
-                }
+        let compiler = config.compiler_for(file);
+        let toolchain = config.toolchain();
+        let mut args = vec![compiler.clone()];
+        args.extend(toolchain.dep_scan_args().iter().map(|a| a.to_string()));
+        args.extend(config.source_flags(file, root).into_iter().cloned());
+        args.extend(config.include_args(root));
+        args.extend(extra_flags.iter().cloned());
+        if lang::is_interface(file) {
+            args.extend(toolchain.cxx_language_args().iter().map(|a| a.to_string()));
+        }
+        let key = args.join(" ");
+        if let Some(deps) = cache.scanned_deps(file, root, &key) {
+            return Ok(deps);
+        }
+
+        let output = cancel::output(Command::new(&compiler).args(&args[1..]).arg(file)).map_err(
+            |e| match e.kind() {
+                io::ErrorKind::Interrupted => e,
+                kind => io::Error::new(kind, config::spawn_error(&compiler, &e)),
+            },
+        )?;
+        if !output.status.success() {
+            // cl reports errors on stdout
+            let message = match String::from_utf8_lossy(&output.stderr).trim_end() {
+                "" => String::from_utf8_lossy(&output.stdout)
+                    .trim_end()
+                    .to_string(),
+                stderr => stderr.to_string(),
+            };
+            self.scan_failures.push(ScanFailure {
+                path: file.to_path_buf(),
+                message,
+            });
+            return Ok(Vec::new());
+        }
+        let text = String::from_utf8_lossy(&output.stdout);
+        let deps = project_deps(file, root, config, &self.nodes, toolchain.parse_deps(&text));
+        cache.record_deps(file, root, key, &deps);
+        Ok(deps)
+    }
+
+    /// `source` and every header it includes, directly or indirectly, sorted.
+    pub fn inputs(&self, source: &Path) -> Vec<PathBuf> {
+        let mut seen = HashSet::new();
+        let mut queue = vec![source.to_path_buf()];
+        while let Some(path) = queue.pop() {
+            if seen.insert(path.clone())
+                && let Some(meta) = self.nodes.get(&path)
+            {
+                queue.extend(meta.deps.iter().cloned());
             }
         }
+        let mut inputs: Vec<PathBuf> = seen.into_iter().collect();
+        inputs.sort();
+        inputs
+    }
 
-        // Kahn's algorithm
-        let mut q: VecDeque<PathBuf> = indeg
-            .iter()
-            .filter_map(|(p, &d)| if d == 0 { Some(p.clone()) } else { None })
-            .collect();
-        let mut order = Vec::new();
-        while let Some(n) = q.pop_front() {
-            order.push(n.clone());
-            if let Some(node) = self.nodes.get(&n) {
-                for dep in &node.dependents {
-                    if dirty_set.contains(dep) {
-                        let e = indeg.get_mut(dep).unwrap();
-                        *e -= 1;
-                        if *e == 0 {
-                            q.push_back(dep.clone());
-                        }
-                    }
-                }
+    /// Read the modification time and hash of every node not hashed yet:
+    /// all of them after a `scan`, the changed ones after `apply_changes`.
+    /// `DirtyPlan` compares them against the cache.  Files that can't be
+    /// read stay unhashed, and so dirty, and are listed in `unreadable`.
+    ///
+    /// A file whose modification time and size are still those in `cache`
+    /// takes its hash from there without being read, unless `hash_always`
+    /// (see `Config::hash_always`).
+    pub fn refresh(&mut self, cache: &BuildCache, root: &Path, hash_always: bool) {
+        let mut failed = Vec::new();
+        for meta in self.nodes.values_mut() {
+            if !meta.hash.is_empty() {
+                continue;
+            }
+            if !hash_always
+                && meta.stat().is_ok()
+                && let Some(hash) = cache.unchanged_hash(meta, root)
+            {
+                meta.hash = hash.to_string();
+                continue;
+            }
+            if let Err(e) = meta.refresh(hash_file) {
+                meta.dirty = true;
+                failed.push((meta.path.clone(), e));
             }
         }
+        for (path, e) in failed {
+            self.skip(path, e);
+        }
+    }
+}
 
-        // filter to sources
-        order
-            .into_iter()
-            .filter(|p| {
-                p.extension()
-                    .and_then(|e| e.to_str())
-                    .map(|ext| matches!(ext, "c" | "cpp" | "cc" | "cxx"))
-                    .unwrap_or(false)
-            })
-            .collect()
+impl Default for BuildGraph {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The dependencies of `source` listed in the dependency file its last
+/// compile wrote (see `depfile::path_for`), if it has an object and the
+/// file is at least as new as the source.  Only GNU compiles without
+/// modules write one.
+fn dep_file_deps(
+    source: &Path,
+    root: &Path,
+    config: &Config,
+    profile: &str,
+    nodes: &HashMap<PathBuf, FileMeta>,
+) -> Option<Vec<PathBuf>> {
+    if !config.toolchain().is_gnu() || config.modules {
+        return None;
+    }
+    let object = scheduler::object_path_for(source, root, profile);
+    let dep_file = depfile::path_for(&object);
+    let written = std::fs::metadata(&dep_file).ok()?.modified().ok()?;
+    let changed = std::fs::metadata(source).ok()?.modified().ok()?;
+    if written < changed || !object.exists() {
+        return None;
+    }
+    let text = std::fs::read_to_string(&dep_file).ok()?;
+    // compiles run in the root, so relative paths are relative to it
+    let base = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+    let listed = depfile::prerequisites(&text)
+        .into_iter()
+        .map(|path| base.join(path));
+    Some(project_deps(source, root, config, nodes, listed))
+}
+
+/// The `candidates` a dependency scan or file reported for `source` that
+/// belong to the project, by their canonical paths: not the source itself,
+/// and not files outside the project, which are system headers, unless the
+/// scan found them through a followed symlink (they are among `nodes`).
+/// With `exclude_headers`, excluded headers don't belong to it either.
+fn project_deps(
+    source: &Path,
+    root: &Path,
+    config: &Config,
+    nodes: &HashMap<PathBuf, FileMeta>,
+    candidates: impl IntoIterator<Item = PathBuf>,
+) -> Vec<PathBuf> {
+    let canonical_root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+    let mut deps = Vec::new();
+    for candidate in candidates {
+        // a missing file is a header gcc only guessed for a failed include
+        let Ok(canonical) = candidate.canonicalize() else {
+            continue;
+        };
+        if canonical != source
+            && (canonical.starts_with(&canonical_root) || nodes.contains_key(&canonical))
+            && !(config.exclude_headers
+                && (config.is_excluded(&candidate, root) || config.is_excluded(&canonical, root)))
+            && !deps.contains(&canonical)
+        {
+            deps.push(canonical);
+        }
+    }
+    deps
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+
+    /// A project of `src/main.c` and `src/util.c`, scanned.
+    fn scanned() -> (tempfile::TempDir, PathBuf, BuildGraph, BuildCache) {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::write(root.join("src/main.c"), "int main(void) { return 0; }\n").unwrap();
+        fs::write(root.join("src/util.c"), "int util(void) { return 1; }\n").unwrap();
+        symlink(root.join("src/gone.c"), root.join("src/dangling.c")).unwrap();
+        let config = Config::load(&root);
+        let mut cache = BuildCache::load(&root, "debug");
+        let mut graph = BuildGraph::new();
+        graph
+            .scan(&root, &config, &[], "debug", &mut cache)
+            .unwrap();
+        (dir, root, graph, cache)
+    }
+
+    #[test]
+    fn a_dangling_symlink_is_skipped_and_listed() {
+        let (_dir, root, graph, _) = scanned();
+        let mut nodes: Vec<&PathBuf> = graph.nodes.keys().collect();
+        nodes.sort();
+        assert_eq!(nodes, [&root.join("src/main.c"), &root.join("src/util.c")]);
+        assert_eq!(graph.unreadable.len(), 1, "{:?}", graph.unreadable);
+        assert_eq!(graph.unreadable[0].path, root.join("src/dangling.c"));
+        assert!(!graph.unreadable[0].error.is_empty());
+    }
+
+    #[test]
+    fn a_file_that_cant_be_hashed_stays_dirty() {
+        let (_dir, root, mut graph, cache) = scanned();
+        let util = root.join("src/util.c");
+        fs::remove_file(&util).unwrap();
+        graph.refresh(&cache, &root, false);
+        let meta = &graph.nodes[&util];
+        assert!(meta.dirty);
+        assert!(meta.hash.is_empty());
+        assert!(
+            graph.unreadable.iter().any(|u| u.path == util),
+            "{:?}",
+            graph.unreadable
+        );
+        assert!(!graph.nodes[&root.join("src/main.c")].hash.is_empty());
     }
 }
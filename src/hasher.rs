@@ -1,20 +1,293 @@
 use sha2::{Digest, Sha256};
 use std::fs::File;
-use std::io::Read;
+use std::io::{self, Read};
 use std::path::Path;
 
-pub fn hash_file(path: &Path) -> std::io::Result<String> {
-    let mut file = File::open(path)?;
-    let mut hasher = Sha256::new();
-    let mut buffer = [0u8; 8192];
+/// How file contents are hashed.  The cache records the algorithm its
+/// hashes were made with (see `BuildCache::hash_algorithm`), and one made
+/// with another is migrated when loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    /// SHA-256, used by every cache before the algorithm was recorded.
+    Sha256,
+    /// XXH64: not cryptographic, but many times faster, and the hashes only
+    /// need to tell the versions of a file apart.
+    Xxh64,
+}
+
+impl HashAlgorithm {
+    /// The algorithm new hashes are made with.
+    pub const DEFAULT: HashAlgorithm = HashAlgorithm::Xxh64;
+
+    /// Name as recorded in the cache.
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Xxh64 => "xxh64",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sha256" => Some(HashAlgorithm::Sha256),
+            "xxh64" => Some(HashAlgorithm::Xxh64),
+            _ => None,
+        }
+    }
+
+    /// Hex-encoded hash of the contents of `path`.
+    pub fn hash_file(self, path: &Path) -> io::Result<String> {
+        let file = File::open(path)?;
+        match self {
+            HashAlgorithm::Sha256 => digest(file, Sha256::new()),
+            HashAlgorithm::Xxh64 => digest(file, Xxh64::new(0)),
+        }
+    }
+}
+
+/// Hex-encoded hash of the contents of `path`, with `HashAlgorithm::DEFAULT`.
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    HashAlgorithm::DEFAULT.hash_file(path)
+}
+
+/// A hash computed over data fed to it in pieces.
+trait Backend {
+    fn update(&mut self, data: &[u8]);
+    /// The hash, hex-encoded.
+    fn finish(self) -> String;
+}
 
+impl Backend for Sha256 {
+    fn update(&mut self, data: &[u8]) {
+        Digest::update(self, data);
+    }
+
+    fn finish(self) -> String {
+        format!("{:x}", self.finalize())
+    }
+}
+
+fn digest(mut file: File, mut backend: impl Backend) -> io::Result<String> {
+    let mut buffer = vec![0u8; 64 * 1024];
     loop {
-        let n = file.read(&mut buffer).unwrap();
-        if n == 0 {
-            break;
+        let n = match file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        backend.update(&buffer[..n]);
+    }
+    Ok(backend.finish())
+}
+
+const PRIME64_1: u64 = 0x9E37_79B1_85EB_CA87;
+const PRIME64_2: u64 = 0xC2B2_AE3D_27D4_EB4F;
+const PRIME64_3: u64 = 0x1656_67B1_9E37_79F9;
+const PRIME64_4: u64 = 0x85EB_CA77_C2B2_AE63;
+const PRIME64_5: u64 = 0x27D4_EB2F_1656_67C5;
+
+/// Streaming XXH64, as specified in the xxHash repository
+/// (`doc/xxhash_spec.md`).
+struct Xxh64 {
+    seed: u64,
+    /// The four lane accumulators, used once 32 bytes have been fed.
+    lanes: [u64; 4],
+    /// Bytes fed that don't fill a 32-byte stripe yet.
+    pending: [u8; 32],
+    pending_len: usize,
+    total_len: u64,
+}
+
+impl Xxh64 {
+    fn new(seed: u64) -> Self {
+        Xxh64 {
+            seed,
+            lanes: [
+                seed.wrapping_add(PRIME64_1).wrapping_add(PRIME64_2),
+                seed.wrapping_add(PRIME64_2),
+                seed,
+                seed.wrapping_sub(PRIME64_1),
+            ],
+            pending: [0; 32],
+            pending_len: 0,
+            total_len: 0,
+        }
+    }
+
+    fn stripe(lanes: &mut [u64; 4], stripe: &[u8]) {
+        for (lane, word) in lanes.iter_mut().zip(stripe.chunks_exact(8)) {
+            *lane = round(*lane, read_u64(word));
+        }
+    }
+}
+
+impl Backend for Xxh64 {
+    fn update(&mut self, mut data: &[u8]) {
+        self.total_len += data.len() as u64;
+        if self.pending_len > 0 {
+            let take = data.len().min(32 - self.pending_len);
+            self.pending[self.pending_len..self.pending_len + take].copy_from_slice(&data[..take]);
+            self.pending_len += take;
+            data = &data[take..];
+            if self.pending_len < 32 {
+                return;
+            }
+            Xxh64::stripe(&mut self.lanes, &self.pending);
+            self.pending_len = 0;
+        }
+        let mut stripes = data.chunks_exact(32);
+        for stripe in &mut stripes {
+            Xxh64::stripe(&mut self.lanes, stripe);
+        }
+        let rest = stripes.remainder();
+        self.pending[..rest.len()].copy_from_slice(rest);
+        self.pending_len = rest.len();
+    }
+
+    fn finish(self) -> String {
+        let mut hash = if self.total_len >= 32 {
+            let [v1, v2, v3, v4] = self.lanes;
+            let mut hash = v1
+                .rotate_left(1)
+                .wrapping_add(v2.rotate_left(7))
+                .wrapping_add(v3.rotate_left(12))
+                .wrapping_add(v4.rotate_left(18));
+            for lane in self.lanes {
+                hash = (hash ^ round(0, lane))
+                    .wrapping_mul(PRIME64_1)
+                    .wrapping_add(PRIME64_4);
+            }
+            hash
+        } else {
+            self.seed.wrapping_add(PRIME64_5)
+        };
+        hash = hash.wrapping_add(self.total_len);
+
+        let mut rest = &self.pending[..self.pending_len];
+        while rest.len() >= 8 {
+            hash ^= round(0, read_u64(rest));
+            hash = hash
+                .rotate_left(27)
+                .wrapping_mul(PRIME64_1)
+                .wrapping_add(PRIME64_4);
+            rest = &rest[8..];
+        }
+        if rest.len() >= 4 {
+            let word = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]);
+            hash ^= u64::from(word).wrapping_mul(PRIME64_1);
+            hash = hash
+                .rotate_left(23)
+                .wrapping_mul(PRIME64_2)
+                .wrapping_add(PRIME64_3);
+            rest = &rest[4..];
+        }
+        for &byte in rest {
+            hash ^= u64::from(byte).wrapping_mul(PRIME64_5);
+            hash = hash.rotate_left(11).wrapping_mul(PRIME64_1);
+        }
+
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(PRIME64_2);
+        hash ^= hash >> 29;
+        hash = hash.wrapping_mul(PRIME64_3);
+        hash ^= hash >> 32;
+        format!("{:016x}", hash)
+    }
+}
+
+fn round(acc: u64, input: u64) -> u64 {
+    acc.wrapping_add(input.wrapping_mul(PRIME64_2))
+        .rotate_left(31)
+        .wrapping_mul(PRIME64_1)
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut word = [0u8; 8];
+    word.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(word)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 100 bytes, so three full stripes and an 8-, 4- and 1-byte tail.
+    fn long_input() -> Vec<u8> {
+        (0..100u32).map(|i| (i * 7 + 3) as u8).collect()
+    }
+
+    fn xxh64(seed: u64, data: &[u8]) -> String {
+        let mut hasher = Xxh64::new(seed);
+        hasher.update(data);
+        hasher.finish()
+    }
+
+    #[test]
+    fn xxh64_matches_the_reference() {
+        assert_eq!(xxh64(0, b""), "ef46db3751d8e999");
+        assert_eq!(xxh64(0, b"abc"), "44bc2cf5ad770999");
+        assert_eq!(
+            xxh64(0, b"The quick brown fox jumps over the lazy dog"),
+            "0b242d361fda71bc"
+        );
+        assert_eq!(xxh64(0, &long_input()[..32]), "23c3c17ef790fd97");
+        assert_eq!(xxh64(0, &long_input()), "a61f8d4c170fe531");
+    }
+
+    #[test]
+    fn xxh64_uses_the_seed() {
+        let seed = 0x9e37_79b9_7f4a_7c15;
+        assert_eq!(xxh64(seed, b"abc"), "2ed0f59d6b43ac8b");
+        assert_eq!(xxh64(seed, &long_input()), "f6d8f65c625abb4f");
+    }
+
+    #[test]
+    fn xxh64_streaming_matches_one_shot() {
+        let data = long_input();
+        let expected = xxh64(0, &data);
+        for first in [1, 3, 5, 7, 31, 33] {
+            let mut hasher = Xxh64::new(0);
+            let mut rest = &data[..];
+            let mut size = first;
+            while !rest.is_empty() {
+                let n = size.min(rest.len());
+                hasher.update(&rest[..n]);
+                rest = &rest[n..];
+                size += 2;
+            }
+            assert_eq!(hasher.finish(), expected, "chunks from {} bytes", first);
         }
-        hasher.update(&buffer[..n]);
+
+        let mut hasher = Xxh64::new(0);
+        hasher.update(&data[..50]);
+        hasher.update(&[]);
+        hasher.update(&data[50..]);
+        assert_eq!(hasher.finish(), expected);
     }
 
-    Ok(format!("{:x}", hasher.finalize()))
+    #[test]
+    fn hashes_files_with_either_algorithm() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("abc.txt");
+        std::fs::write(&path, "abc").unwrap();
+        assert_eq!(
+            HashAlgorithm::Xxh64.hash_file(&path).unwrap(),
+            "44bc2cf5ad770999"
+        );
+        assert_eq!(
+            HashAlgorithm::Sha256.hash_file(&path).unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(hash_file(&path).unwrap(), "44bc2cf5ad770999");
+        assert!(hash_file(&dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn algorithm_names_round_trip() {
+        for algorithm in [HashAlgorithm::Sha256, HashAlgorithm::Xxh64] {
+            assert_eq!(HashAlgorithm::from_name(algorithm.name()), Some(algorithm));
+        }
+        assert_eq!(HashAlgorithm::from_name("md5"), None);
+    }
 }
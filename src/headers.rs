@@ -0,0 +1,108 @@
+use crate::cache::BuildCache;
+use crate::config::{Config, TargetKind};
+use crate::graph::is_ignored;
+use crate::hasher;
+use crate::lang;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Where a library build of `profile` stages its public headers, for the
+/// projects using the library to put on their include path.
+pub fn staging_dir(root: &Path, profile: &str) -> PathBuf {
+    root.join("target").join(profile).join("include")
+}
+
+/// Copy the headers `public_headers` selects to `staging_dir`.  Each keeps
+/// its path below the deepest of `include_dirs` holding it (below the root
+/// when none does), so `#include "net/socket.h"` finds the staged copy the
+/// way the library's own sources find the original.  The staged headers are
+/// recorded in `cache.headers` with the hash of their source, and a header
+/// is copied again only when that hash changed (or the copy is gone).
+/// Staged headers no longer selected are removed.  Returns how many were
+/// copied.
+pub fn stage(
+    root: &Path,
+    config: &Config,
+    profile: &str,
+    cache: &mut BuildCache,
+) -> io::Result<usize> {
+    let dir = staging_dir(root, profile);
+    let mut staged = HashSet::new();
+    let mut copied = 0;
+    for (header, relative) in public_headers(root, config) {
+        let copy = dir.join(&relative);
+        // of two headers at the same place the first one wins
+        if !staged.insert(copy.clone()) {
+            continue;
+        }
+        let key = relative.to_string_lossy().to_string();
+        let hash = hasher::hash_file(&header)?;
+        if copy.is_file() && cache.headers.get(&key) == Some(&hash) {
+            continue;
+        }
+        fs::create_dir_all(copy.parent().unwrap_or(&dir))?;
+        fs::copy(&header, &copy)?;
+        cache.headers.insert(key, hash);
+        copied += 1;
+    }
+    cache
+        .headers
+        .retain(|key, _| staged.contains(&dir.join(key)));
+    if dir.is_dir() {
+        prune(&dir, &staged)?;
+    }
+    Ok(copied)
+}
+
+/// The headers `public_headers` selects with where they go below
+/// `staging_dir`, sorted.  None for a program.
+fn public_headers(root: &Path, config: &Config) -> Vec<(PathBuf, PathBuf)> {
+    if config.kind == TargetKind::Executable || config.public.is_empty() {
+        return Vec::new();
+    }
+    let include_dirs: Vec<PathBuf> = config.include_dirs.iter().map(|d| root.join(d)).collect();
+    let mut headers: Vec<(PathBuf, PathBuf)> = WalkDir::new(root)
+        .follow_links(config.follow_symlinks)
+        .into_iter()
+        .filter_entry(|e| !is_ignored(e.path(), root))
+        .filter_map(|e| e.ok())
+        .filter(|e| {
+            e.file_type().is_file()
+                && lang::is_header(e.path())
+                && config.public.is_match(e.path(), root)
+        })
+        .map(|e| {
+            let header = e.into_path();
+            let base = include_dirs
+                .iter()
+                .filter(|dir| header.starts_with(dir))
+                .max_by_key(|dir| dir.components().count())
+                .map_or(root, PathBuf::as_path);
+            let relative = header.strip_prefix(base).unwrap_or(&header).to_path_buf();
+            (header, relative)
+        })
+        .collect();
+    headers.sort();
+    headers
+}
+
+/// Remove the files below `dir` other than `keep`, then the directories
+/// left empty.
+fn prune(dir: &Path, keep: &HashSet<PathBuf>) -> io::Result<()> {
+    let entries = WalkDir::new(dir).min_depth(1).contents_first(true);
+    for entry in entries.into_iter().filter_map(|e| e.ok()) {
+        if entry.file_type().is_dir() {
+            // fails, as it should, while something is left inside
+            let _ = fs::remove_dir(entry.path());
+        } else if !keep.contains(entry.path()) {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    if keep.is_empty() {
+        let _ = fs::remove_dir(dir);
+    }
+    Ok(())
+}
@@ -0,0 +1,152 @@
+use crate::cache::BuildCache;
+use crate::config::Config;
+use crate::graph::BuildGraph;
+use crate::lang::is_source;
+use crate::scheduler::BuildOptions;
+use crate::toolchain::Toolchain;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Where the quoted `#include`s of one translation unit (and of the headers
+/// it pulls in) resolved, together with the search path that was in effect.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IncludeRecord {
+    /// `-I` directories in the order the compiler searches them.
+    search: Vec<String>,
+    /// `(including file, spelling, resolved file)`, paths relative to the
+    /// root where possible.
+    resolved: Vec<(String, String, String)>,
+}
+
+/// An include that resolves to a different file than it did before the
+/// search path changed.
+pub struct Moved {
+    pub unit: String,
+    pub includer: String,
+    pub spelling: String,
+    pub before: String,
+    pub after: String,
+}
+
+/// The outcome of `check`: includes that moved, and the current resolution
+/// to record with `commit`.
+pub struct Resolution {
+    pub moved: Vec<Moved>,
+    records: Vec<(String, IncludeRecord)>,
+}
+
+impl Resolution {
+    /// Remember the current resolution.  Skipped when the build is refused,
+    /// so the next attempt reports the same includes.
+    pub fn commit(self, cache: &mut BuildCache) {
+        cache.includes.extend(self.records);
+    }
+}
+
+/// Compare each source's quoted includes against the files they resolved to
+/// under the include directories recorded for it.  Sources whose search
+/// path didn't change are only resolved again when they are dirty.
+pub fn check(
+    graph: &BuildGraph,
+    cache: &BuildCache,
+    root: &Path,
+    config: &Config,
+    opts: &BuildOptions,
+) -> Resolution {
+    let mut moved = Vec::new();
+    let mut records = Vec::new();
+    let mut sources: Vec<&PathBuf> = graph.nodes.keys().filter(|p| is_source(p)).collect();
+    sources.sort();
+    for source in sources {
+        let key = BuildCache::make_relative(source, root);
+        let search = search_path(source, root, config, opts);
+        let previous = cache.includes.get(&key);
+        let changed = previous.is_some_and(|r| r.search != search);
+        if previous.is_some() && !changed && !graph.nodes[source].dirty {
+            continue;
+        }
+        let record = resolve(graph, source, root, search);
+        if changed && let Some(previous) = previous {
+            for (includer, spelling, before) in &previous.resolved {
+                if let Some((_, _, after)) = record
+                    .resolved
+                    .iter()
+                    .find(|(i, s, _)| i == includer && s == spelling)
+                    && after != before
+                {
+                    moved.push(Moved {
+                        unit: key.clone(),
+                        includer: includer.clone(),
+                        spelling: spelling.clone(),
+                        before: before.clone(),
+                        after: after.clone(),
+                    });
+                }
+            }
+        }
+        records.push((key, record));
+    }
+    Resolution { moved, records }
+}
+
+/// Directories a quoted include of `source` is looked up in after the
+/// including file's own directory: `-I` flags from `common_flags`,
+/// `cflags`/`cxxflags` and the `[dir]` tables, then `include_dirs`, then
+/// `--include`, as on the compile command line.
+fn search_path(source: &Path, root: &Path, config: &Config, opts: &BuildOptions) -> Vec<String> {
+    let mut dirs = Vec::new();
+    let mut flags = config.source_flags(source, root).into_iter();
+    while let Some(flag) = flags.next() {
+        if flag == "-I" || flag == "/I" {
+            dirs.extend(flags.next().map(|d| root.join(d).display().to_string()));
+        } else if let Some(dir) = Toolchain::include_dir(flag) {
+            dirs.push(root.join(dir).display().to_string());
+        }
+    }
+    for arg in config
+        .include_args(root)
+        .into_iter()
+        .chain(opts.include_args(root, config.toolchain()))
+    {
+        dirs.extend(Toolchain::include_dir(&arg).map(str::to_string));
+    }
+    dirs
+}
+
+/// Resolve the quoted includes of `source` and its headers like the
+/// compiler does.  Includes that only a system directory satisfies aren't
+/// recorded.
+fn resolve(graph: &BuildGraph, source: &Path, root: &Path, search: Vec<String>) -> IncludeRecord {
+    let mut resolved = Vec::new();
+    for includer in graph.inputs(source) {
+        let Ok(text) = fs::read_to_string(&includer) else {
+            continue;
+        };
+        let own_dir = includer.parent().map(Path::to_path_buf).unwrap_or_default();
+        for spelling in quoted_includes(&text) {
+            let found = std::iter::once(own_dir.clone())
+                .chain(search.iter().map(PathBuf::from))
+                .map(|dir| dir.join(spelling))
+                .find(|candidate| candidate.is_file());
+            if let Some(found) = found {
+                resolved.push((
+                    BuildCache::make_relative(&includer, root),
+                    spelling.to_string(),
+                    BuildCache::make_relative(&found, root),
+                ));
+            }
+        }
+    }
+    IncludeRecord { search, resolved }
+}
+
+/// Names in `#include "..."` directives, ignoring conditionals.
+fn quoted_includes(text: &str) -> impl Iterator<Item = &str> {
+    text.lines().filter_map(|line| {
+        let rest = line.trim_start().strip_prefix('#')?.trim_start();
+        let rest = rest.strip_prefix("include")?.trim_start();
+        let name = rest.strip_prefix('"')?;
+        name.split_once('"').map(|(name, _)| name)
+    })
+}
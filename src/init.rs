@@ -0,0 +1,101 @@
+use crate::config::CONFIG_FILENAME;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const C_MAIN: &str = r#"#include <stdio.h>
+
+int main(void) {
+    printf("Hello, world!\n");
+    return 0;
+}
+"#;
+
+const CXX_MAIN: &str = r#"#include <iostream>
+
+int main() {
+    std::cout << "Hello, world!\n";
+    return 0;
+}
+"#;
+
+/// Ignored by git in a new project.
+const GITIGNORE_ENTRY: &str = "target/";
+
+/// Scaffold a project in `dir`, creating it if needed: a hello-world
+/// `src/main.c` (`src/main.cpp` with `cpp`), a starter `buildy.toml` and a
+/// `.gitignore` entry for `target/`.  Existing files are only overwritten
+/// with `force`; otherwise nothing is written.  An existing `.gitignore` is
+/// added to, never replaced.  Returns the files written.
+pub fn init(dir: &Path, cpp: bool, force: bool) -> Result<Vec<PathBuf>, String> {
+    let name = dir
+        .canonicalize()
+        .unwrap_or_else(|_| dir.to_path_buf())
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_else(|| "app".into());
+    let main = if cpp { "src/main.cpp" } else { "src/main.c" };
+    let files = [
+        (main, if cpp { CXX_MAIN } else { C_MAIN }.to_string()),
+        (CONFIG_FILENAME, starter_config(&name, cpp)),
+    ];
+    let existing: Vec<&str> = files
+        .iter()
+        .map(|(path, _)| *path)
+        .filter(|path| dir.join(path).exists())
+        .collect();
+    if !existing.is_empty() && !force {
+        return Err(format!(
+            "{} already has {}; pass --force to overwrite",
+            dir.display(),
+            existing.join(" and ")
+        ));
+    }
+
+    let mut written = Vec::new();
+    for (path, content) in files {
+        let path = dir.join(path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| format!("{}: {}", parent.display(), e))?;
+        }
+        fs::write(&path, content).map_err(|e| format!("{}: {}", path.display(), e))?;
+        written.push(path);
+    }
+    let gitignore = dir.join(".gitignore");
+    let ignored = fs::read_to_string(&gitignore).unwrap_or_default();
+    let listed = ignored
+        .lines()
+        .any(|line| matches!(line.trim(), "target" | "target/" | "/target" | "/target/"));
+    if !listed {
+        let mut content = ignored;
+        if !content.is_empty() && !content.ends_with('\n') {
+            content.push('\n');
+        }
+        content.push_str(GITIGNORE_ENTRY);
+        content.push('\n');
+        fs::write(&gitignore, content).map_err(|e| format!("{}: {}", gitignore.display(), e))?;
+        written.push(gitignore);
+    }
+    Ok(written)
+}
+
+/// `buildy.toml` for a new project called `name`, with the common settings
+/// commented out.
+fn starter_config(name: &str, cpp: bool) -> String {
+    let flags = if cpp {
+        "cxxflags = [\"-std=c++17\"]"
+    } else {
+        "# cflags = [\"-std=c11\"]"
+    };
+    format!(
+        r#"# Buildy configuration, see the README for every setting.
+
+# name = "{name}"  # output name; defaults to the directory name
+# compiler = "clang"        # C compiler; the C++ one is derived (clang++)
+# warnings = ["all", "extra"] # -Wall -Wextra
+# deny_warnings = true      # treat warnings as errors
+{flags}
+# include_dirs = ["include"]
+# libs = ["m"]              # linked as -lm
+"#
+    )
+}
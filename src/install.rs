@@ -0,0 +1,59 @@
+use crate::build::{self, Built};
+use crate::cache::BuildCache;
+use crate::config::TargetKind;
+use crate::headers;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Copy the library `built` linked to `<prefix>/lib`, and the public headers
+/// its build staged, as recorded in `cache.headers`, to
+/// `<prefix>/include/<name>` at their paths below `headers::staging_dir`.
+/// Returns the files installed.  A program isn't installed.
+pub fn install(
+    root: &Path,
+    built: &Built,
+    cache: &BuildCache,
+    profile: &str,
+    prefix: &Path,
+) -> Result<Vec<PathBuf>, String> {
+    if built.config.kind == TargetKind::Executable {
+        return Err(
+            "install only installs libraries; set `kind = \"staticlib\"` or \
+             `kind = \"sharedlib\"` in buildy.toml"
+                .to_string(),
+        );
+    }
+    let mut installed = Vec::new();
+    let lib = prefix.join("lib");
+    for output in &built.outputs {
+        let to = lib.join(output.file_name().unwrap_or_default());
+        copy(output, &to)?;
+        installed.push(to);
+    }
+    let staged = headers::staging_dir(root, profile);
+    let include = prefix
+        .join("include")
+        .join(build::exe_name(root, &built.config));
+    for header in cache.headers.keys() {
+        let to = include.join(header);
+        copy(&staged.join(header), &to)?;
+        installed.push(to);
+    }
+    Ok(installed)
+}
+
+/// Copy `from` to `to` through a file next to it, so a program using the
+/// installed shared library never loads it half written.
+fn copy(from: &Path, to: &Path) -> Result<(), String> {
+    let fail = |e: std::io::Error| format!("could not install {}: {}", to.display(), e);
+    let mut partial = to.as_os_str().to_os_string();
+    partial.push(".partial");
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent).map_err(fail)?;
+    }
+    fs::copy(from, &partial).map_err(fail)?;
+    fs::rename(&partial, to).map_err(|e| {
+        let _ = fs::remove_file(&partial);
+        fail(e)
+    })
+}
@@ -0,0 +1,84 @@
+use crate::modules;
+use std::path::Path;
+
+/// Extensions of C sources.
+const C_EXTS: [&str; 1] = ["c"];
+/// Extensions of C++ sources besides the module interface units
+/// (`modules::INTERFACE_EXTS`), which only count when modules are enabled.
+const CXX_EXTS: [&str; 5] = ["cpp", "cc", "cxx", "C", "c++"];
+/// Extensions of assembly sources: `.S` goes through the C preprocessor,
+/// `.s` straight to the assembler.
+const ASM_EXTS: [&str; 2] = ["s", "S"];
+/// Extensions of headers, scanned for changes but never compiled on their
+/// own.
+const HEADER_EXTS: [&str; 5] = ["h", "hpp", "hh", "hxx", "inl"];
+
+/// The language a source is compiled as, which picks the compiler and the
+/// configured flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Language {
+    C,
+    Cxx,
+    /// Assembled by the C compiler driver.
+    Asm,
+}
+
+impl Language {
+    /// The language of the source `path`; `None` for headers and anything
+    /// else that isn't compiled.
+    pub fn of(path: &Path) -> Option<Language> {
+        let ext = extension(path);
+        if C_EXTS.contains(&ext) {
+            Some(Language::C)
+        } else if CXX_EXTS.contains(&ext) || modules::INTERFACE_EXTS.contains(&ext) {
+            Some(Language::Cxx)
+        } else if ASM_EXTS.contains(&ext) {
+            Some(Language::Asm)
+        } else {
+            None
+        }
+    }
+
+    /// Name of the configured flag list of the language.
+    pub fn flags_key(self) -> &'static str {
+        match self {
+            Language::C => "cflags",
+            Language::Cxx => "cxxflags",
+            Language::Asm => "asflags",
+        }
+    }
+
+    /// How the language is named in messages.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Language::C => "C",
+            Language::Cxx => "C++",
+            Language::Asm => "assembly",
+        }
+    }
+}
+
+fn extension(path: &Path) -> &str {
+    path.extension().and_then(|e| e.to_str()).unwrap_or("")
+}
+
+/// Whether `path` is compiled into an object file.
+pub fn is_source(path: &Path) -> bool {
+    Language::of(path).is_some()
+}
+
+/// Whether `path` can include headers: everything but `.s` assembly, which
+/// isn't preprocessed.
+pub fn is_preprocessed(path: &Path) -> bool {
+    extension(path) != "s"
+}
+
+/// Whether `path` is a header.
+pub fn is_header(path: &Path) -> bool {
+    HEADER_EXTS.contains(&extension(path))
+}
+
+/// Whether `path` is a module interface unit.
+pub fn is_interface(path: &Path) -> bool {
+    modules::INTERFACE_EXTS.contains(&extension(path))
+}
@@ -0,0 +1,140 @@
+pub mod batch;
+pub mod build;
+pub mod cache;
+pub mod cancel;
+pub mod chaos;
+pub mod clean;
+pub mod compdb;
+pub mod completions;
+pub mod config;
+pub mod depfile;
+pub mod doctor;
+pub mod error;
+pub mod executor;
+pub mod export;
+pub mod flags;
+pub mod graph;
+pub mod hasher;
+pub mod headers;
+pub mod includes;
+pub mod init;
+pub mod install;
+pub mod lang;
+pub mod lock;
+pub mod matcher;
+pub mod messages;
+pub mod modules;
+pub mod plan;
+pub mod profile;
+pub mod progress;
+pub mod prompt;
+pub mod protocol;
+pub mod report;
+pub mod scheduler;
+pub mod session;
+pub mod session_env;
+pub mod speculate;
+pub mod stamp;
+pub mod store;
+pub mod supervisor;
+pub mod target;
+pub mod testing;
+pub mod timings;
+pub mod toolchain;
+pub mod toolenv;
+pub mod unity;
+
+pub use cache::BuildCache;
+pub use error::BuildyError;
+pub use graph::BuildGraph;
+pub use messages::Reporter;
+pub use report::BuildReport;
+pub use target::FileMeta;
+
+use build::Goal;
+use messages::Silent;
+use scheduler::BuildOptions;
+use session::BuildSession;
+use std::env;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Builds a project from another program, as `buildy build` does.  What
+/// the build prints goes to the reporter, which drops it unless one is
+/// given.
+///
+/// ```no_run
+/// let report = buildy::Builder::new("path/to/project")
+///     .profile("release")
+///     .jobs(4)
+///     .build()?;
+/// println!("{}", report);
+/// # Ok::<(), buildy::BuildyError>(())
+/// ```
+pub struct Builder {
+    root: PathBuf,
+    opts: BuildOptions,
+    reporter: Arc<dyn Reporter>,
+}
+
+impl Builder {
+    /// A debug build of the project at `root`, relative to the current
+    /// directory.
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        let root = env::current_dir().unwrap_or_default().join(root);
+        Builder {
+            root,
+            opts: BuildOptions::default(),
+            reporter: Arc::new(Silent),
+        }
+    }
+
+    /// Build the profile called `name`, built in or from `buildy.toml`.
+    pub fn profile(mut self, name: &str) -> Self {
+        self.opts.profile = profile::Profile::named(name);
+        self
+    }
+
+    /// Run at most `jobs` compiles at once, 0 meaning one per CPU.
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        self.opts.jobs = Some(jobs);
+        self
+    }
+
+    /// Compile every dirty source even after one fails, like `--keep-going`.
+    pub fn keep_going(mut self, keep_going: bool) -> Self {
+        self.opts.keep_going = keep_going;
+        self
+    }
+
+    /// Rebuild everything, like `--force`.
+    pub fn force(mut self, force: bool) -> Self {
+        self.opts.force = force;
+        self
+    }
+
+    /// Pass what the build prints to `reporter`.
+    pub fn reporter(mut self, reporter: impl Reporter + 'static) -> Self {
+        self.reporter = Arc::new(reporter);
+        self
+    }
+
+    /// Build the project's programs or library.  The reporter is set for
+    /// the whole process while building (see `messages::set_reporter`).
+    pub fn build(self) -> Result<BuildReport, BuildyError> {
+        messages::set_reporter(Some(self.reporter.clone()));
+        let result = self.run();
+        messages::set_reporter(None);
+        result.map_err(|e| match e.downcast::<BuildyError>() {
+            Ok(e) => *e,
+            Err(e) => BuildyError::Other(e.to_string()),
+        })
+    }
+
+    fn run(&self) -> Result<BuildReport, Box<dyn Error>> {
+        let mut session = BuildSession::new(&self.root);
+        let built = build::build_and_link(&mut session, &self.opts, Goal::Project)?;
+        Ok(built.report)
+    }
+}
@@ -0,0 +1,124 @@
+use crate::cancel;
+use crate::messages;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// Lock file in `target/`, holding the PID of the process that owns it.
+const LOCK_FILENAME: &str = ".buildy.lock";
+
+/// How often a waiting process checks whether the lock was released.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Age after which a lock without a PID counts as left by a process that
+/// died before writing it.
+const UNWRITTEN_LOCK_AGE: Duration = Duration::from_secs(10);
+
+/// Set by `--no-lock`, see `disable`.
+static DISABLED: AtomicBool = AtomicBool::new(false);
+
+/// Skip locking altogether, for `--no-lock`: for file systems where the
+/// lock misbehaves, at the risk of concurrent builds overwriting each
+/// other's cache.
+pub fn disable() {
+    DISABLED.store(true, Ordering::SeqCst);
+}
+
+/// Exclusive use of a project's `target/` directory by this process, held
+/// while building, cleaning or pruning so two Buildy processes (a one-off
+/// build and the watch repl, say) never write objects or caches at the
+/// same time.  Released when dropped, also while unwinding from a panic; a
+/// lock left by a process that died is taken over.
+pub struct TargetLock {
+    /// `None` when locking is disabled.
+    path: Option<PathBuf>,
+}
+
+impl TargetLock {
+    /// Take the lock of the project at `root`, waiting for the process
+    /// holding it to finish.  Ctrl-C stops the wait.
+    pub fn acquire(root: &Path) -> io::Result<TargetLock> {
+        if DISABLED.load(Ordering::SeqCst) {
+            return Ok(TargetLock { path: None });
+        }
+        let dir = root.join("target");
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(LOCK_FILENAME);
+        let mut waiting_for = None;
+        loop {
+            match fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(mut file) => {
+                    write!(file, "{}", std::process::id())?;
+                    return Ok(TargetLock { path: Some(path) });
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e),
+            }
+            // a lock being written has no PID yet; give it a moment
+            let owner = fs::read_to_string(&path)
+                .ok()
+                .and_then(|pid| pid.trim().parse::<u32>().ok());
+            if owner.is_none() && age(&path).is_some_and(|age| age > UNWRITTEN_LOCK_AGE) {
+                let _ = fs::remove_file(&path);
+                continue;
+            }
+            match owner {
+                Some(pid) if !is_running(pid) => {
+                    messages::warn(format!(
+                        "warning: removing the lock of buildy process {}, which no longer runs",
+                        pid
+                    ));
+                    let _ = fs::remove_file(&path);
+                    continue;
+                }
+                Some(pid) if waiting_for != Some(pid) => {
+                    messages::warn(format!("waiting for other buildy process (pid {})", pid));
+                    waiting_for = Some(pid);
+                }
+                _ => {}
+            }
+            if cancel::requested() {
+                return Err(cancel::interrupted());
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+impl Drop for TargetLock {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+fn age(path: &Path) -> Option<Duration> {
+    fs::metadata(path).ok()?.modified().ok()?.elapsed().ok()
+}
+
+/// Whether the process `pid` still exists.  Without a way to tell, it is
+/// assumed to, so a stale lock has to be removed by hand (or skipped with
+/// `--no-lock`).
+fn is_running(pid: u32) -> bool {
+    #[cfg(unix)]
+    {
+        let Ok(pid) = libc::pid_t::try_from(pid) else {
+            return false;
+        };
+        // signal 0 only checks; EPERM means it exists but isn't ours
+        let alive = unsafe { libc::kill(pid, 0) } == 0;
+        alive || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = pid;
+        true
+    }
+}
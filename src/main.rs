@@ -1,227 +1,2246 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use clap_complete::CompleteEnv;
 use colored::Colorize;
+use notify::event::{CreateKind, EventKind, ModifyKind, RemoveKind};
 use notify::{RecommendedWatcher, RecursiveMode, Watcher};
 use rustyline::Editor;
 use rustyline::error::ReadlineError;
-use std::collections::HashSet;
+use rustyline::history::FileHistory;
+use std::collections::{BTreeMap, HashSet};
 use std::env;
 use std::error::Error;
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::channel;
-
-mod cache;
-mod graph;
-mod hasher;
-mod scheduler;
-mod target;
+use std::process::ExitStatus;
+use std::sync::mpsc::{RecvTimeoutError, channel};
+use std::time::{Duration, Instant};
 
+use buildy::build::{
+    Built, Goal, build_and_link, exe_name, fingerprint, load_config, plan_build,
+    print_config_changes, run_build,
+};
+use buildy::{
+    cache, cancel, clean, compdb, completions, config, doctor, error, executor, export, graph,
+    init, install, lang, lock, matcher, messages, plan, profile, prompt, protocol, scheduler,
+    session, session_env, speculate, stamp, store, supervisor, testing, toolchain,
+};
 use cache::BuildCache;
-use graph::BuildGraph;
+use config::{CONFIG_FILENAME, Config, TargetKind};
+use error::BuildyError;
+use executor::{ExecError, ExecRequest};
+use lang::{Language, is_source};
+use lock::TargetLock;
+use matcher::IGNORE_FILENAME;
+use messages::{ColorChoice, Message, MessageFormat};
+use plan::{DirtyPlan, DirtyReason, Invalidated};
+use profile::Profile;
+use prompt::ReplHelper;
+use scheduler::BuildOptions;
+use session::BuildSession;
+use session_env::SessionEnv;
+use speculate::{Speculator, Staged};
+use store::ObjectStore;
+use supervisor::Supervisor;
 
 /// CLI for the buildy daemon/tool.
 #[derive(Parser)]
+#[command(after_help = error::EXIT_CODES)]
 struct Cli {
     /// Root directory of the project (defaults to current working directory)
-    #[arg(long, default_value = ".")]
+    #[arg(long, default_value = ".", global = true)]
     root: PathBuf,
 
+    /// Only print errors; compiler warnings and progress are hidden
+    #[arg(long, short = 'q', global = true)]
+    quiet: bool,
+
+    /// Print every compile and link command before running it; twice (`-vv`)
+    /// also print why each source is or isn't compiled
+    #[arg(long, short = 'v', action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// When to color the output (`auto` colors a terminal unless NO_COLOR
+    /// is set)
+    #[arg(long, value_enum, value_name = "WHEN", global = true)]
+    color: Option<ColorChoice>,
+
+    /// Don't lock target/ against other buildy processes while building
+    #[arg(long, global = true)]
+    no_lock: bool,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+impl Cli {
+    /// The command, with the `-v` given anywhere on the line handed to its
+    /// build options.
+    fn into_command(self) -> Commands {
+        let mut command = self.command;
+        if let Some(build) = command.build_args_mut() {
+            build.verbose = self.verbose;
+        }
+        command
+    }
+}
+
+/// Options shared by every subcommand that performs a build.
+#[derive(Args, Default)]
+struct BuildArgs {
+    /// Build in release mode, short for `--profile release`
+    #[arg(long)]
+    release: bool,
+
+    /// Build in debug mode, the default (in the watch repl it overrides
+    /// `set profile release`)
+    #[arg(long, conflicts_with = "release")]
+    debug: bool,
+
+    /// Build with this profile: debug, release, asan, coverage or one
+    /// defined under `[profiles]` in buildy.toml
+    #[arg(
+        long,
+        value_name = "NAME",
+        conflicts_with_all = ["release", "debug"],
+        add = completions::profiles()
+    )]
+    profile: Option<String>,
+
+    /// Treat conflicting compiler flags (e.g. `-O0` and `-O3`) as errors
+    #[arg(long)]
+    strict_flags: bool,
+
+    /// Treat failing dependency scans as errors instead of warnings
+    #[arg(long)]
+    strict_deps: bool,
+
+    /// Treat includes that resolve to a different file after the include
+    /// directories changed as errors instead of warnings
+    #[arg(long)]
+    strict_includes: bool,
+
+    /// C compiler to use instead of the configured one or `CC`
+    #[arg(long, value_name = "CC")]
+    compiler: Option<String>,
+
+    /// C++ compiler to use instead of the configured one or `CXX`
+    #[arg(long, value_name = "CXX")]
+    cxx: Option<String>,
+
+    /// Run every compile through this wrapper, e.g. `ccache` or `sccache`,
+    /// instead of the configured `compiler_wrapper`; `auto` picks one on
+    /// PATH and `none` compiles directly
+    #[arg(long, value_name = "WRAPPER")]
+    wrapper: Option<String>,
+
+    /// After building, print the compiler wrapper's statistics
+    #[arg(long)]
+    stats: bool,
+
+    /// Add an include directory for dependency scanning and compilation
+    /// (repeatable)
+    #[arg(long = "include", short = 'I', value_name = "DIR")]
+    include: Vec<PathBuf>,
+
+    /// Define a macro when scanning and compiling, `NAME` or `NAME=VALUE`
+    /// (repeatable)
+    #[arg(long = "define", short = 'D', value_name = "NAME[=VALUE]")]
+    defines: Vec<String>,
+
+    /// Link against a library, e.g. `--lib m` for `-lm` (repeatable)
+    #[arg(long = "lib", value_name = "NAME")]
+    libs: Vec<String>,
+
+    /// Name of the program or library instead of the configured one or the
+    /// project directory's
+    #[arg(long, value_name = "NAME")]
+    name: Option<String>,
+
+    /// `-v` of the command line, see `Cli::verbose`.
+    #[arg(skip)]
+    verbose: u8,
+
+    /// Keep compiling after a file fails and report every error
+    #[arg(long, short = 'k')]
+    keep_going: bool,
+
+    /// Number of parallel compile jobs; 0 uses every CPU
+    #[arg(long, short = 'j', value_name = "N")]
+    jobs: Option<usize>,
+
+    /// Start compiles in path order instead of the slowest first
+    #[arg(long)]
+    no_reorder: bool,
+
+    /// Rescan and recompile every file and relink, ignoring the cache
+    #[arg(long)]
+    force: bool,
+
+    /// Relink even when no object changed since the last link
+    #[arg(long)]
+    force_link: bool,
+
+    /// Hash every file instead of trusting unchanged modification times and
+    /// sizes, like `hash_always = true` in buildy.toml
+    #[arg(long)]
+    paranoid: bool,
+
+    /// Treat compiler warnings as errors, like `deny_warnings = true` in
+    /// buildy.toml
+    #[arg(long, conflicts_with = "allow_warnings")]
+    deny_warnings: bool,
+
+    /// Don't treat warnings as errors, even with `deny_warnings = true`
+    #[arg(long)]
+    allow_warnings: bool,
+
+    /// Compile the C and C++ sources in N generated unity files that
+    /// include them, instead of one by one; N defaults to the job count
+    #[arg(long, value_name = "N", num_args = 0..=1, default_missing_value = "0")]
+    unity: Option<usize>,
+
+    /// Print the slowest compiles and how long linking took, and write a
+    /// report to target/.buildy/timings/
+    #[arg(long)]
+    timings: bool,
+
+    /// Inject random compile failures derived from SEED (developer mode)
+    #[arg(long, value_name = "SEED", hide = true)]
+    chaos: Option<u64>,
+}
+
+impl BuildArgs {
+    /// The profile the flags select, debug unless told otherwise.
+    fn profile_name(&self) -> &str {
+        match &self.profile {
+            Some(name) => name,
+            None if self.release => "release",
+            None => "debug",
+        }
+    }
+
+    fn options(&self) -> BuildOptions {
+        BuildOptions {
+            profile: Profile::named(self.profile_name()),
+            strict_flags: self.strict_flags,
+            strict_deps: self.strict_deps,
+            strict_includes: self.strict_includes,
+            compiler: self.compiler.clone(),
+            cxx: self.cxx.clone(),
+            wrapper: self.wrapper.clone(),
+            stats: self.stats,
+            include_dirs: self.include.clone(),
+            defines: self.defines.clone(),
+            libs: self.libs.clone(),
+            name: self.name.clone(),
+            keep_going: self.keep_going,
+            jobs: self.jobs,
+            chaos: self.chaos,
+            message_format: MessageFormat::Human,
+            timings: self.timings,
+            no_reorder: self.no_reorder,
+            force: self.force,
+            force_link: self.force_link,
+            paranoid: self.paranoid,
+            deny_warnings: match (self.deny_warnings, self.allow_warnings) {
+                (true, _) => Some(true),
+                (_, true) => Some(false),
+                _ => None,
+            },
+            unity: self.unity,
+            verbose: if self.verbose > 0 {
+                self.verbose
+            } else {
+                env_verbosity()
+            },
+        }
+    }
+}
+
+/// Verbosity from `BUILDY_VERBOSE` (`1`, `2`), for builds without `-v`.
+fn env_verbosity() -> u8 {
+    match env::var("BUILDY_VERBOSE") {
+        Ok(level) if !level.is_empty() => level.parse().unwrap_or_else(|_| {
+            eprintln!("warning: ignoring BUILDY_VERBOSE={}: not a number", level);
+            0
+        }),
+        _ => 0,
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Perform a build and exit
     Build {
+        #[command(flatten)]
+        build: BuildArgs,
+
+        /// Report progress as JSON lines on stdout
+        #[arg(long, value_enum, default_value = "human")]
+        message_format: MessageFormat,
+
+        /// Print the compile and link commands the build would run, in
+        /// order, without running them or writing anything
         #[arg(long)]
-        release: bool,
+        dry_run: bool,
+    },
+    /// Check that the dirty sources compile, without writing objects or
+    /// linking
+    Check {
+        #[command(flatten)]
+        build: BuildArgs,
+
+        /// Report progress as JSON lines on stdout
+        #[arg(long, value_enum, default_value = "human")]
+        message_format: MessageFormat,
     },
     /// Start the watch daemon with an interactive repl
-    Watch,
+    Watch {
+        /// Rebuild automatically whenever sources change
+        #[arg(long)]
+        auto: bool,
+
+        /// Rebuild automatically and restart the program after every
+        /// successful build (like `autorun on`)
+        #[arg(long)]
+        exec: bool,
+
+        /// Milliseconds without changes before the tree counts as settled
+        /// and a rebuild starts [default: `watch_delay` from buildy.toml,
+        /// or 300]
+        #[arg(long, value_name = "MS")]
+        watch_delay: Option<u64>,
+    },
 
+    /// Build, then run the executable
     Run {
-        /// Build in release mode
+        #[command(flatten)]
+        build: BuildArgs,
+
+        /// Program to run when several sources define main()
+        #[arg(long, value_name = "NAME")]
+        bin: Option<String>,
+
+        /// Report progress as JSON lines on stdout
+        #[arg(long, value_enum, default_value = "human")]
+        message_format: MessageFormat,
+
+        /// Run the program under another one, such as `gdb` or `"valgrind
+        /// --leak-check=full"`, which gets the executable and its arguments
+        /// appended
+        #[arg(long, value_name = "CMD")]
+        wrap: Option<String>,
+
+        /// Arguments passed to the program (after `--`)
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+    /// Build, then run the executable under gdb (or lldb)
+    Debug {
+        #[command(flatten)]
+        build: BuildArgs,
+
+        /// Program to debug when several sources define main()
+        #[arg(long, value_name = "NAME")]
+        bin: Option<String>,
+
+        /// Arguments passed to the program (after `--`)
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+    /// Build the library, then install it and its public headers
+    Install {
+        #[command(flatten)]
+        build: BuildArgs,
+
+        /// Install to PREFIX/lib and PREFIX/include/<name>
+        #[arg(long, value_name = "PREFIX", default_value = "/usr/local")]
+        prefix: PathBuf,
+    },
+    /// Write compile_commands.json for clangd and other tools
+    #[command(name = "compile-commands")]
+    CompileDb {
+        #[command(flatten)]
+        build: BuildArgs,
+    },
+    /// Check the toolchain and report problems recorded in the build cache
+    Doctor,
+    /// Remove build outputs and the build cache
+    Clean {
+        #[command(flatten)]
+        clean: CleanArgs,
+    },
+    /// Show the build cache and what a build would compile now
+    Status {
+        #[command(flatten)]
+        build: BuildArgs,
+    },
+    /// Explain why the next build would compile a file
+    Explain {
+        /// File to explain, relative to the project root
+        path: PathBuf,
+
+        #[command(flatten)]
+        build: BuildArgs,
+    },
+    /// Build the programs in tests/ against the project's sources and run
+    /// them
+    Test {
+        #[command(flatten)]
+        build: BuildArgs,
+
+        /// Only build and run the tests whose path below tests/ contains
+        /// this
+        #[arg(long, value_name = "SUBSTRING")]
+        filter: Option<String>,
+    },
+    /// Create a new project with a hello-world program
+    Init {
+        /// Directory to create it in, relative to the root [default: the
+        /// root itself]
+        name: Option<PathBuf>,
+
+        /// Scaffold a C++ program instead of a C one
+        #[arg(long)]
+        cpp: bool,
+
+        /// Overwrite an existing src/main.c or buildy.toml
         #[arg(long)]
-        release: bool,
+        force: bool,
+    },
+    /// Print a shell completion script, e.g. `buildy completions bash >
+    /// /etc/bash_completion.d/buildy`
+    #[command(hide = true)]
+    Completions {
+        #[arg(value_enum)]
+        shell: completions::Shell,
+    },
+    /// Print the dependency graph as DOT or JSON
+    Graph {
+        #[command(flatten)]
+        graph: GraphArgs,
+    },
+    /// Print the JSON Schema of a document buildy writes, for tools that
+    /// read it
+    Schema {
+        #[arg(value_enum)]
+        document: protocol::Document,
     },
+    /// Inspect and repair the build caches, and manage the object store
+    /// shared by all profiles and branches
+    Cache {
+        #[command(subcommand)]
+        action: CacheCommand,
+    },
+}
+
+impl Commands {
+    /// The build options of a command that builds.
+    fn build_args_mut(&mut self) -> Option<&mut BuildArgs> {
+        match self {
+            Commands::Build { build, .. }
+            | Commands::Check { build, .. }
+            | Commands::Run { build, .. }
+            | Commands::Debug { build, .. }
+            | Commands::Install { build, .. }
+            | Commands::CompileDb { build, .. }
+            | Commands::Status { build, .. }
+            | Commands::Explain { build, .. }
+            | Commands::Test { build, .. } => Some(build),
+            Commands::Graph { graph, .. } => Some(&mut graph.build),
+            _ => None,
+        }
+    }
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
+#[derive(Args)]
+struct GraphArgs {
+    #[command(flatten)]
+    build: BuildArgs,
+
+    /// Output format
+    #[arg(long, value_enum, default_value = "dot")]
+    format: export::Format,
+
+    /// Only show dirty files
+    #[arg(long)]
+    dirty_only: bool,
+
+    /// Write to this file instead of stdout
+    #[arg(long, short = 'o', value_name = "FILE")]
+    output: Option<PathBuf>,
+
+    /// Collapse headers included by more than N files into one node
+    #[arg(long, value_name = "N")]
+    max_fanout: Option<usize>,
+}
+
+#[derive(Subcommand)]
+enum CacheCommand {
+    /// Summarize the build cache of each profile
+    Stats {
+        #[command(flatten)]
+        profiles: CacheProfiles,
+    },
+    /// Hash every file in the build caches and report the entries that no
+    /// longer match, without building
+    Verify {
+        #[command(flatten)]
+        profiles: CacheProfiles,
+
+        /// Update the entries that don't match, so the next build
+        /// recompiles what they affect
+        #[arg(long)]
+        fix: bool,
+    },
+    /// Drop the cache entries of deleted files and remove the objects no
+    /// cached source compiles to
+    Prune {
+        #[command(flatten)]
+        profiles: CacheProfiles,
+
+        /// Also remove the least recently used objects from the object
+        /// store until it fits this size, e.g. 2G or 500M
+        #[arg(long, value_name = "SIZE", value_parser = store::parse_size)]
+        max_size: Option<u64>,
+    },
+}
+
+#[derive(Args)]
+struct CacheProfiles {
+    /// Only this profile's cache [default: every profile built so far]
+    #[arg(
+        long,
+        value_name = "NAME",
+        value_parser = parse_profile_name,
+        add = completions::profiles()
+    )]
+    profile: Option<String>,
+}
+
+impl CacheProfiles {
+    /// The profiles to look at, with their caches as they are on disk.
+    fn load(&self, root: &Path) -> Vec<(String, BuildCache)> {
+        let names = match &self.profile {
+            Some(name) => vec![name.clone()],
+            None => profile::names(&Config::load(root)),
+        };
+        let caches: Vec<(String, BuildCache)> = names
+            .into_iter()
+            .filter(|name| BuildCache::path(root, name).exists())
+            .map(|name| {
+                let cache = BuildCache::load(root, &name);
+                (name, cache)
+            })
+            .collect();
+        if caches.is_empty() {
+            match &self.profile {
+                Some(name) => println!("no build cache for profile {}", name),
+                None => println!("no build cache yet"),
+            }
+        }
+        caches
+    }
+}
+
+impl CacheCommand {
+    fn run(&self, root: &Path) -> Result<(), Box<dyn Error>> {
+        match self {
+            CacheCommand::Stats { profiles } => {
+                for (name, cache) in profiles.load(root) {
+                    print_cache_stats(root, &name, &cache);
+                }
+                Ok(())
+            }
+            CacheCommand::Verify { profiles, fix } => {
+                let _lock = fix.then(|| TargetLock::acquire(root)).transpose()?;
+                let mut stale = 0;
+                for (name, mut cache) in profiles.load(root) {
+                    let mismatches = cache.verify(root);
+                    if mismatches.is_empty() {
+                        println!("{}: all {} entries match", name, cache.files.len());
+                        continue;
+                    }
+                    println!(
+                        "{}: {} of {} entries don't match:",
+                        name,
+                        mismatches.len(),
+                        cache.files.len()
+                    );
+                    for (key, mismatch) in &mismatches {
+                        println!("  {}: {}", key, mismatch.describe());
+                    }
+                    if *fix {
+                        cache.fix(&mismatches);
+                        cache.save()?;
+                        println!("  fixed them");
+                    } else {
+                        stale += mismatches
+                            .iter()
+                            .filter(|(_, m)| *m == cache::Mismatch::Stale)
+                            .count();
+                    }
+                }
+                if stale > 0 {
+                    return Err(format!(
+                        "{} file(s) changed unnoticed; run `buildy cache verify --fix` or build with --paranoid",
+                        stale
+                    )
+                    .into());
+                }
+                Ok(())
+            }
+            CacheCommand::Prune { profiles, max_size } => {
+                let _lock = TargetLock::acquire(root)?;
+                for (name, mut cache) in profiles.load(root) {
+                    let dropped = cache.prune_missing(root);
+                    let sources: Vec<PathBuf> = cache.iter_absolute_paths(root).collect();
+                    let removed = scheduler::remove_orphans(&sources, root, &name)?;
+                    if dropped > 0 {
+                        cache.save()?;
+                    }
+                    match (dropped, removed) {
+                        (0, 0) => println!("{}: nothing to prune", name),
+                        _ => println!(
+                            "{}: dropped the entries of {} deleted file(s), removed {} orphaned object file(s)",
+                            name, dropped, removed
+                        ),
+                    }
+                }
+                if let Some(max_size) = max_size {
+                    match ObjectStore::new(root).prune(*max_size)? {
+                        (0, _) => println!(
+                            "object store already within {}",
+                            clean::format_bytes(*max_size)
+                        ),
+                        (removed, bytes) => println!(
+                            "removed {} object(s) from the store, reclaimed {}",
+                            removed,
+                            clean::format_bytes(bytes)
+                        ),
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// What `cache stats` prints for the cache of `profile`.
+fn print_cache_stats(root: &Path, profile: &str, cache: &BuildCache) {
+    let path = BuildCache::path(root, profile);
+    println!("{} ({})", profile, BuildCache::make_relative(&path, root));
+    let sources = cache
+        .files
+        .keys()
+        .filter(|k| is_source(Path::new(k)))
+        .count();
+    // entries from before sizes were recorded have none
+    let size: u64 = cache
+        .files
+        .iter()
+        .map(|(key, entry)| {
+            entry.size.unwrap_or_else(|| {
+                std::fs::metadata(BuildCache::make_absolute(key, root)).map_or(0, |m| m.len())
+            })
+        })
+        .sum();
+    println!(
+        "  entries:     {} files ({} sources), {} tracked",
+        cache.files.len(),
+        sources,
+        clean::format_bytes(size)
+    );
+    println!(
+        "  format:      {}, hashed with {}",
+        cache.version,
+        cache.hash_algorithm.as_deref().unwrap_or("sha256")
+    );
+    println!(
+        "  last saved:  {}",
+        cache.saved_at.format("%Y-%m-%d %H:%M:%S UTC")
+    );
+    println!(
+        "  compiler:    {}",
+        cache.compiler.as_deref().unwrap_or("(none yet)")
+    );
+    println!(
+        "  flags:       {} (fingerprint {})",
+        cache.flags.join(" "),
+        cache.fingerprint()
+    );
+    println!(
+        "  also:        {} dependency scans, {} linked outputs, {} passed tests",
+        cache.scans.len(),
+        cache.links.len(),
+        cache.tests.len()
+    );
+}
+
+#[derive(Args)]
+struct CleanArgs {
+    /// Only clean this profile's outputs
+    #[arg(
+        long,
+        value_name = "NAME",
+        value_parser = parse_profile_name,
+        add = completions::profiles()
+    )]
+    profile: Option<String>,
+
+    /// Keep object files, only drop the build cache
+    #[arg(long)]
+    cache_only: bool,
+
+    /// Only remove unused directories older than `--older-than`
+    #[arg(long, conflicts_with_all = ["profile", "cache_only"])]
+    stale: bool,
+
+    /// Minimum age for `--stale`, e.g. 30d, 12h or 2w
+    #[arg(long, value_name = "AGE", requires = "stale", value_parser = clean::parse_age)]
+    older_than: Option<Duration>,
+}
+
+impl CleanArgs {
+    fn run(&self, root: &Path) -> std::io::Result<()> {
+        let _lock = TargetLock::acquire(root)?;
+        let profiles = profile::names(&Config::load(root));
+        if self.stale {
+            let older_than = self.older_than.unwrap_or(DEFAULT_STALE_AGE);
+            let removed = clean::clean_stale(root, &profiles, older_than)?;
+            for dir in &removed {
+                println!("{}", dir);
+            }
+            if removed.is_empty() {
+                println!("nothing to clean");
+            } else {
+                let bytes = removed.iter().map(|dir| dir.bytes).sum();
+                println!("reclaimed {}", clean::format_bytes(bytes));
+            }
+            Ok(())
+        } else {
+            clean::clean(root, self.profile.as_deref(), &profiles, self.cache_only)
+        }
+    }
+}
+
+/// A valid `--profile` for `clean`, see `profile::check_name`.
+fn parse_profile_name(name: &str) -> Result<String, String> {
+    profile::check_name(name).map(|_| name.to_string())
+}
+
+/// Default `--older-than` for `clean --stale`.
+const DEFAULT_STALE_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+fn main() {
+    // `COMPLETE=<shell> buildy` prints or answers the dynamic completion
+    CompleteEnv::with_factory(Cli::command)
+        .bin("buildy")
+        .complete();
+    cancel::install();
+    // errors can span several lines (compiler diagnostics), so print them
+    // as they are rather than through `Debug`
+    if let Err(e) = try_main() {
+        let code = match e.downcast_ref::<BuildyError>() {
+            // the program speaks for itself
+            Some(BuildyError::ChildFailed { code }) => *code,
+            Some(e @ BuildyError::CompileFailed { files, .. }) if files.len() > 1 => {
+                // after several failures the first is far up, so end with
+                // the list
+                let cwd = env::current_dir().unwrap_or_default();
+                let names: Vec<String> = files
+                    .iter()
+                    .map(|f| f.strip_prefix(&cwd).unwrap_or(f).display().to_string())
+                    .collect();
+                eprintln!("Error: {}", e);
+                eprintln!("could not compile {}", names.join(", "));
+                e.exit_code()
+            }
+            Some(e) => {
+                eprintln!("Error: {}", e);
+                e.exit_code()
+            }
+            None => {
+                eprintln!("Error: {}", e);
+                1
+            }
+        };
+        std::process::exit(code);
+    }
+}
+
+fn try_main() -> Result<(), Box<dyn Error>> {
     let cli = Cli::parse();
+    messages::configure(cli.quiet, cli.color);
+    if cli.no_lock {
+        lock::disable();
+    }
+    // compilers run from the root, so object paths below a relative root
+    // would resolve twice
     let cwd = if cli.root.as_os_str() == "." {
         env::current_dir()?
     } else {
-        cli.root.clone()
+        env::current_dir()?.join(&cli.root)
     };
 
-    match cli.command {
-        Commands::Build { release } => {
-            let mut cache = BuildCache::load(&cwd);
-            let is_debug = !release;
-            run_build(&cwd, &mut cache, is_debug)?;
-            cache.save()?;
+    let (quiet, color, verbose) = (cli.quiet, cli.color, cli.verbose);
+    if let Commands::Watch {
+        auto,
+        exec,
+        watch_delay,
+    } = cli.command
+    {
+        let watch = WatchOptions {
+            auto: auto || exec,
+            autorun: exec,
+            delay: watch_delay,
+            quiet,
+            color,
+            verbose,
+        };
+        return watch_mode(cwd, watch);
+    }
+    run_once(&cwd, cli.into_command())
+}
+
+/// Run a command other than `watch` with a session of its own.
+fn run_once(root: &Path, command: Commands) -> Result<(), Box<dyn Error>> {
+    match command {
+        Commands::Build {
+            build,
+            dry_run: true,
+            ..
+        } => {
+            let mut session = BuildSession::new(root);
+            dry_run(&mut session, &build.options())?;
+        }
+        Commands::Build {
+            build,
+            message_format,
+            ..
+        } => {
+            let mut session = BuildSession::new(root);
+            let mut opts = build.options();
+            opts.message_format = message_format;
+            run_build(&mut session, &opts)?;
+        }
+        Commands::Check {
+            build,
+            message_format,
+        } => {
+            let mut opts = build.options();
+            opts.message_format = message_format;
+            run_check(&mut BuildSession::new(root), &opts)?;
+        }
+        Commands::Run {
+            build,
+            bin,
+            message_format,
+            wrap,
+            args,
+        } => {
+            let wrapper = match wrap {
+                Some(wrap) => split_wrapper(&wrap)?,
+                None => Vec::new(),
+            };
+            let mut session = BuildSession::new(root);
+            let mut opts = build.options();
+            opts.message_format = message_format;
+            let built = run_build(&mut session, &opts)?;
+            let exe_path = pick_program(&built.config, built.outputs, bin.as_deref())?;
+            message_format.status(format!("executable path: {}", exe_path.display()));
+            run_executable(&exe_path, &wrapper, &args, &built.config.run.env)?;
         }
-        Commands::Run { release } => {
-            let mut cache = BuildCache::load(&cwd);
-            let is_debug = !release;
-            let exe_path = run_build(&cwd, &mut cache, is_debug)?;
-            println!("executable path: {}", exe_path.display());
-            cache.save()?;
-            run_executable(&exe_path)?;
+        Commands::Debug { build, bin, args } => {
+            let wrapper = debugger()?;
+            let mut session = BuildSession::new(root);
+            let built = run_build(&mut session, &build.options())?;
+            let exe_path = pick_program(&built.config, built.outputs, bin.as_deref())?;
+            run_executable(&exe_path, &wrapper, &args, &built.config.run.env)?;
         }
-        Commands::Watch => {
-            watch_mode(cwd)?;
+        Commands::Install { build, prefix } => {
+            run_install(&mut BuildSession::new(root), &build.options(), &prefix)?;
+        }
+        Commands::Test { build, filter } => {
+            let mut session = BuildSession::new(root);
+            let env = SessionEnv::default();
+            run_tests(&mut session, &build.options(), filter.as_deref(), &env)?;
+        }
+        Commands::Init { name, cpp, force } => {
+            scaffold(root, name.as_deref(), cpp, force)?;
+        }
+        Commands::Completions { shell } => print_completions(shell),
+        Commands::Watch { .. } => return Err("already in watch mode".into()),
+        Commands::CompileDb { build } => {
+            write_compile_commands(&mut BuildSession::new(root), &build.options())?;
+        }
+        Commands::Doctor => {
+            if !doctor::run(root) {
+                return Err(
+                    "some of the tools builds need are missing or broken, see above".into(),
+                );
+            }
+        }
+        Commands::Clean { clean } => {
+            clean.run(root)?;
+        }
+        Commands::Graph { graph } => {
+            export_graph(&mut BuildSession::new(root), &graph)?;
+        }
+        Commands::Schema { document } => print!("{}", document.schema_json()),
+        Commands::Explain { path, build } => {
+            explain(&mut BuildSession::new(root), &build.options(), &path)?;
+        }
+        Commands::Status { build } => {
+            print_status(&mut BuildSession::new(root), &build.options())?;
+        }
+        Commands::Cache { action } => {
+            action.run(root)?;
         }
     }
+    Ok(())
+}
 
+/// Print the completion script for `shell`.
+fn print_completions(shell: completions::Shell) {
+    print!("{}", completions::generate(Cli::command(), "buildy", shell));
+}
+
+/// Create a project in `name` below `root`, or in `root` itself, for
+/// `buildy init`.
+fn scaffold(root: &Path, name: Option<&Path>, cpp: bool, force: bool) -> Result<(), String> {
+    let dir = match name {
+        Some(name) => root.join(name),
+        None => root.to_path_buf(),
+    };
+    for path in init::init(&dir, cpp, force)? {
+        println!("created {}", path.display());
+    }
+    match name {
+        Some(name) => println!(
+            "run it with `buildy --root {} run`",
+            shell_words::quote(&name.display().to_string())
+        ),
+        None => println!("run it with `buildy run`"),
+    }
     Ok(())
 }
 
-/// Build the project and return the path to the executable if linking occurred.
-fn run_build(
-    root: &Path,
-    cache: &mut BuildCache,
-    is_debug: bool,
-) -> Result<PathBuf, Box<dyn Error>> {
-    println!("scanning sources in {}", root.display());
-
-    let mut graph = BuildGraph::new();
-    graph.scan(root, &[])?;
-    // remove cache entries for files that no longer exist
-    // existing files are tracked in the graph with absolute paths. The
-    // cache stores its keys relative to `root`, so when we are filtering we can
-    // convert each stored key back to an absolute path for comparison.
-    let existing: HashSet<std::path::PathBuf> = graph
-        .nodes
-        .keys()
-        .cloned()
-        .collect();
-    cache.files.retain(|k, _| {
-        let abs = BuildCache::make_absolute(k, root);
-        existing.contains(&abs)
+/// Scan the project and write its compilation database.
+fn write_compile_commands(
+    session: &mut BuildSession,
+    opts: &BuildOptions,
+) -> Result<(), Box<dyn Error>> {
+    let BuildSession {
+        root,
+        caches,
+        graph,
+        ..
+    } = session;
+    let root = root.as_path();
+    let profile = opts.profile_name();
+    let cache = caches
+        .entry(profile.to_string())
+        .or_insert_with(|| BuildCache::load(root, profile));
+    let (config, opts) = &load_config(root, opts)?;
+    graph
+        .update(
+            root,
+            config,
+            &opts.scan_args(root, config.toolchain()),
+            profile,
+            cache,
+        )
+        .map_err(|e| e.to_string())?;
+    let path = compdb::emit(graph, root, config, opts)?;
+    println!("wrote {}", path.display());
+    Ok(())
+}
+
+/// Dirty sources listed by `status`.
+const STATUS_DIRTY_SHOWN: usize = 10;
+
+/// Print what the cache of the `opts` profile holds, the compilers and
+/// flags a build would use, and which sources it would compile, for
+/// `buildy status`.  Like `explain`, nothing is compiled or written.
+fn print_status(session: &mut BuildSession, opts: &BuildOptions) -> Result<(), Box<dyn Error>> {
+    let plan = plan_build(session, opts)?;
+    let (config, opts) = &load_config(&session.root, opts)?;
+    let root = session.root.as_path();
+    let profile = opts.profile_name();
+    let cache = &session.caches[profile];
+
+    let sources = session.graph.nodes.keys().filter(|p| is_source(p)).count();
+    println!(
+        "cache ({}): {} file(s), {} source(s) with objects, {} link(s) recorded",
+        profile,
+        cache.files.len(),
+        session
+            .graph
+            .nodes
+            .keys()
+            .filter(|p| is_source(p) && cache.object_hash(p, root).is_some())
+            .count(),
+        cache.links.len()
+    );
+    println!(
+        "compilers: {} (C), {} (C++)",
+        config.c_compiler(),
+        config.cxx_compiler()
+    );
+    for language in [Language::C, Language::Cxx, Language::Asm] {
+        let profile_flags = opts.profile.compile_flags(config.toolchain());
+        let all: Vec<&str> = profile_flags
+            .iter()
+            .map(String::as_str)
+            .chain(config.common_flags_for(language).iter().map(String::as_str))
+            .chain(config.flags_for(language).iter().map(String::as_str))
+            .collect();
+        println!("{}: {}", language.flags_key(), all.join(" "));
+    }
+    if plan.order.is_empty() {
+        println!("all {} source(s) up to date", sources);
+        return Ok(());
+    }
+    println!(
+        "a build would compile {} of {} source(s):",
+        plan.order.len(),
+        sources
+    );
+    let mut dirty = plan.order.clone();
+    dirty.sort();
+    for path in dirty.iter().take(STATUS_DIRTY_SHOWN) {
+        let reason = plan
+            .reasons
+            .get(path)
+            .map(|r| r.describe(root))
+            .unwrap_or_default();
+        println!("  {} ({})", BuildCache::make_relative(path, root), reason);
+    }
+    if dirty.len() > STATUS_DIRTY_SHOWN {
+        println!("  ({} more)", dirty.len() - STATUS_DIRTY_SHOWN);
+    }
+    Ok(())
+}
+
+/// Check that the sources a build with `opts` would compile still compile,
+/// for `buildy check`.  Like `explain`, nothing but the scan is recorded in
+/// the cache, so checked files stay dirty for the next build.
+fn run_check(session: &mut BuildSession, opts: &BuildOptions) -> Result<(), Box<dyn Error>> {
+    cancel::reset();
+    let (config, opts) = &load_config(&session.root, opts)?;
+    let pool = session.pool(opts.jobs.or(config.jobs).unwrap_or(0))?;
+    let messages = opts.message_format;
+    messages.status(format!("scanning sources in {}", session.root.display()));
+    let plan = plan_build(session, opts)?;
+    let root = session.root.as_path();
+    let cache = &session.caches[opts.profile_name()];
+    let result = scheduler::check(&session.graph, cache, root, config, opts, &pool, &plan);
+    messages.emit(&Message::BuildFinished {
+        success: result.is_ok(),
+        artifacts: Vec::new(),
+        report: None,
     });
+    match result? {
+        0 => messages.status("nothing to check"),
+        n => messages.status(format!("{} file(s) compile", n)),
+    }
+    Ok(())
+}
+
+/// Print what `build` with `opts` would do for `build --dry-run`: the
+/// compile commands of the dirty sources in the order they would start,
+/// then the links.  Nothing is compiled, linked or written, and a build
+/// that would fail is only reported, so the exit status is 0.  The scan
+/// still runs the compiler's dependency scan for sources it hasn't seen.
+fn dry_run(session: &mut BuildSession, opts: &BuildOptions) -> Result<(), Box<dyn Error>> {
+    let plan = plan_build(session, opts)?;
+    let (config, opts) = &load_config(&session.root, opts)?;
+    let root = session.root.as_path();
+    let graph = &session.graph;
+    let cache = &session.caches[opts.profile_name()];
 
-    // if compiler or flags changed since last cache, invalidate all
-    let current_compiler = "gcc".to_string();
-    let current_flags: Vec<String> = vec!["-g".into()];
-    if cache.compiler.as_ref() != Some(&current_compiler) || cache.flags != current_flags {
-        println!("compiler or flags changed, invalidating cache");
-        for meta in graph.nodes.values_mut() {
-            meta.dirty = true;
+    let sources = graph.nodes.keys().filter(|p| is_source(p)).count();
+    println!(
+        "dry run: {} source(s) to compile, {} up to date",
+        plan.order.len(),
+        sources - plan.order.len()
+    );
+    if let Some(cycle) = &plan.cycle {
+        let names: Vec<String> = cycle
+            .iter()
+            .map(|p| BuildCache::make_relative(p, root))
+            .collect();
+        println!(
+            "the build would fail: dependency cycle {}",
+            names.join(" -> ")
+        );
+        return Ok(());
+    }
+    let compiles = match scheduler::planned_compiles(graph, cache, root, config, opts, &plan) {
+        Ok(compiles) => compiles,
+        Err(e) => {
+            println!("the build would fail: {}", e);
+            return Ok(());
         }
+    };
+    for command in &compiles {
+        println!(
+            "compile {}",
+            BuildCache::make_relative(&command.source, root)
+        );
+        println!("  {}", command.shell_line());
     }
-    cache.compiler = Some(current_compiler);
-    cache.flags = current_flags.clone();
 
-    // update_dirty now needs the project root to convert paths as well
-    graph.update_dirty(&cache, root);
+    let output_dir = root.join("target").join(opts.profile_name());
+    let name = exe_name(root, config);
+    let targets = match scheduler::link_targets(graph, root, config, &output_dir, &name) {
+        Ok(targets) => targets,
+        Err(e) => {
+            println!("the build would fail: {}", e);
+            return Ok(());
+        }
+    };
+    let mut extra_objects = Vec::new();
+    if config.version_stamp {
+        extra_objects.push(stamp::object_path(root, opts));
+    }
+    let mut linked_any = false;
+    for target in &targets {
+        let recompiled = target.sources.iter().any(|p| plan.reasons.contains_key(p));
+        let mut objects: Vec<PathBuf> = target
+            .sources
+            .iter()
+            .filter(|p| {
+                plan.reasons.contains_key(*p) || {
+                    scheduler::object_path_for(p, root, opts.profile_name()).exists()
+                }
+            })
+            .map(|p| scheduler::object_path_for(p, root, opts.profile_name()))
+            .collect();
+        objects.sort();
+        let name = target.output.file_name().unwrap_or_default();
+        let name = name.to_string_lossy().to_string();
+        let relink = recompiled
+            || opts.force
+            || opts.force_link
+            || !target.output.exists()
+            || scheduler::link_digest(target, cache, root, opts, None).ok()
+                != cache.links.get(&name).cloned();
+        if objects.is_empty() || !relink {
+            continue;
+        }
+        let command = scheduler::link_command(
+            target,
+            &objects,
+            root,
+            config,
+            &opts.profile,
+            &extra_objects,
+        );
+        println!("link {}", BuildCache::make_relative(&command.output, root));
+        println!("  {}", command.shell_line());
+        linked_any = true;
+    }
+    if !linked_any {
+        println!("nothing to link");
+    }
+    Ok(())
+}
 
-    let need_link = scheduler::build(&mut graph, cache, root, is_debug)?;
-    let exe_name = root
-        .file_name()
-        .map(|s| s.to_string_lossy().to_string())
-        .unwrap_or_else(|| "a.out".into());
+/// Write the dependency graph for `buildy graph`.
+fn export_graph(session: &mut BuildSession, args: &GraphArgs) -> Result<(), Box<dyn Error>> {
+    let plan = plan_build(session, &args.build.options())?;
+    let text = export::render(
+        &session.graph,
+        &plan,
+        &session.root,
+        args.format,
+        args.dirty_only,
+        args.max_fanout,
+    );
+    match &args.output {
+        Some(path) => {
+            std::fs::write(path, text).map_err(|e| format!("{}: {}", path.display(), e))?;
+            println!("wrote {}", path.display());
+        }
+        None => print!("{}", text),
+    }
+    Ok(())
+}
 
-    let profile_dir = if is_debug { "debug" } else { "release" };
-    let output_dir = root.join("target").join(profile_dir);
-    std::fs::create_dir_all(&output_dir)?;
-    let output_path = output_dir.join(&exe_name);
+/// Print why the next build with `opts` would compile `path`, following
+/// the chain of dependencies back to the file that actually changed, or
+/// that it is up to date.
+fn explain(
+    session: &mut BuildSession,
+    opts: &BuildOptions,
+    path: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let plan = plan_build(session, opts)?;
+    let root = session.root.as_path();
+    let target = root.join(path);
+    let target = target
+        .canonicalize()
+        .map_err(|e| format!("{}: {}", target.display(), e))?;
+    let graph = &session.graph;
+    if !graph.nodes.contains_key(&target) {
+        return Err(format!("{} is not part of the build", path.display()).into());
+    }
+    let cache = &session.caches[opts.profile_name()];
+    let rel = |p: &Path| BuildCache::make_relative(p, root);
+    let entry = |p: &Path| cache.files.get(&rel(p));
 
-    if need_link {
-        scheduler::link(&graph, root, is_debug, &output_path)?;
+    let Some(mut reason) = plan.reasons.get(&target) else {
+        match entry(&target) {
+            Some(e) => println!(
+                "{} is up to date (hash {}, modified {})",
+                rel(&target),
+                e.hash,
+                e.last_modified.format("%Y-%m-%d %H:%M:%S")
+            ),
+            None => println!("{} is up to date", rel(&target)),
+        }
+        return Ok(());
+    };
+    if is_source(&target) {
+        println!("{} will be recompiled:", rel(&target));
     } else {
-        println!("nothing to link");
+        println!("{} is dirty:", rel(&target));
+    }
+    let mut current = target.clone();
+    let mut seen = HashSet::new();
+    while let DirtyReason::Dependency(dep) = reason
+        && seen.insert(dep.clone())
+    {
+        if !plan.reasons.contains_key(dep) {
+            // a header whose dependents weren't all rebuilt with it
+            println!("  {} includes {}, which changed", rel(&current), rel(dep));
+            return Ok(());
+        }
+        println!("  {} depends on {}", rel(&current), rel(dep));
+        current = dep.clone();
+        reason = &plan.reasons[dep];
+    }
+    let name = rel(&current);
+    match (reason, entry(&current)) {
+        (DirtyReason::Changed, None) => println!("  {} is not in the cache (new file)", name),
+        (DirtyReason::Changed, Some(e)) if e.hash.is_empty() => {
+            println!("  {} was left out of date by a failed build", name)
+        }
+        (DirtyReason::Changed, Some(e)) => println!(
+            "  {} changed: hash {} differs from the cached {} (modified {})",
+            name,
+            graph.nodes[&current].hash,
+            e.hash,
+            e.last_modified.format("%Y-%m-%d %H:%M:%S")
+        ),
+        (DirtyReason::ConfigChanged, _) => {
+            println!(
+                "  {}: the compiler, flags or compiler environment changed:",
+                name
+            );
+            let (config, opts) = &load_config(root, opts)?;
+            let (compiler, flags) = fingerprint(config, opts);
+            print_config_changes(cache, &compiler, &flags, MessageFormat::Human);
+        }
+        (reason, _) => println!("  {}: {}", name, reason.describe(root)),
+    }
+    Ok(())
+}
+
+/// The program `run` starts among the build's `outputs`: the one named
+/// `bin`, or the only one.  Libraries can't be run.
+fn pick_program(
+    config: &Config,
+    outputs: Vec<PathBuf>,
+    bin: Option<&str>,
+) -> Result<PathBuf, String> {
+    if config.kind != TargetKind::Executable {
+        let built: Vec<String> = outputs.iter().map(|p| p.display().to_string()).collect();
+        return Err(format!(
+            "nothing to run: {} sets kind = \"{}\" (built {})",
+            CONFIG_FILENAME,
+            config.kind.as_str(),
+            built.join(", ")
+        ));
+    }
+    let name = |p: &PathBuf| {
+        p.file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default()
+    };
+    let names: Vec<String> = outputs.iter().map(name).collect();
+    match bin {
+        Some(bin) => outputs
+            .iter()
+            .find(|p| name(p) == bin)
+            .cloned()
+            .ok_or_else(|| format!("no program named `{}`, built: {}", bin, names.join(", "))),
+        None if outputs.len() == 1 => Ok(outputs[0].clone()),
+        None => Err(format!(
+            "several programs were built, pick one with --bin: {}",
+            names.join(", ")
+        )),
+    }
+}
+
+/// Build the library and install it with its public headers below
+/// `prefix`, see `install::install`.
+fn run_install(
+    session: &mut BuildSession,
+    opts: &BuildOptions,
+    prefix: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let built = run_build(session, opts)?;
+    let profile = opts.profile_name();
+    let cache = &session.caches[profile];
+    for path in install::install(&session.root, &built, cache, profile, prefix)? {
+        opts.message_format
+            .status(format!("installed {}", path.display()));
     }
+    Ok(())
+}
 
-    Ok(output_path)
+/// Build the test programs matching `filter` (all of them when `None`) and
+/// run them with the build's `run.env` overridden by `session_env`, one at
+/// a time unless `--jobs` asks for more.
+fn run_tests(
+    session: &mut BuildSession,
+    opts: &BuildOptions,
+    filter: Option<&str>,
+    session_env: &SessionEnv,
+) -> Result<(), Box<dyn Error>> {
+    let filter = filter.unwrap_or("");
+    let built = build_and_link(session, opts, Goal::Tests(filter))?;
+    let (tests, env) = (built.outputs, session_env.resolve(&built.config));
+    if tests.is_empty() {
+        println!(
+            "no tests{} in {}/",
+            match filter {
+                "" => String::new(),
+                filter => format!(" matching `{}`", filter),
+            },
+            testing::TESTS_DIR
+        );
+        return Ok(());
+    }
+    let root = session.root.clone();
+    let profile = opts.profile_name();
+    let mut passed = session
+        .caches
+        .get(profile)
+        .map(|cache| cache.tests.clone())
+        .unwrap_or_default();
+    let jobs = opts.jobs.unwrap_or(1);
+    // the tests run unlocked, so other builds can go on meanwhile
+    let result = testing::run(&tests, &root, &env, jobs, opts.force, &mut passed);
+    let _lock = TargetLock::acquire(&root)?;
+    session.forget_changed();
+    let cache = session
+        .caches
+        .entry(profile.to_string())
+        .or_insert_with(|| BuildCache::load(&root, profile));
+    cache.tests = passed;
+    cache.save()?;
+    Ok(result?)
 }
 
-/// Run an executable from a given path.
-fn run_executable(exe_path: &Path) -> Result<(), Box<dyn Error>> {
-    if exe_path.exists() {
-        std::process::Command::new(exe_path).status()?;
+/// Run the program at `exe_path` with `args` and with `env` added to the
+/// inherited environment, under `wrapper` (e.g. `["gdb"]`) unless that is
+/// empty.  A wrapped program gets the terminal and Ctrl-C to itself, so a
+/// debugger can stop it.  A non-zero exit becomes
+/// `BuildyError::ChildFailed` with the program's exit code, or 128 plus the
+/// signal that killed it.
+fn run_executable(
+    exe_path: &Path,
+    wrapper: &[String],
+    args: &[String],
+    env: &BTreeMap<String, String>,
+) -> Result<(), Box<dyn Error>> {
+    if !exe_path.exists() {
+        return Err(BuildyError::ExecutableMissing(exe_path.to_path_buf()).into());
+    }
+    let exe = exe_path.display().to_string();
+    let request = match wrapper.split_first() {
+        None => ExecRequest {
+            program: exe,
+            args: args.to_vec(),
+            env: env.clone(),
+            ..ExecRequest::default()
+        },
+        Some((program, options)) => {
+            let mut wrapped = options.to_vec();
+            // gdb takes the program's arguments after `--args`, lldb after
+            // `--`; without them they would be taken for its own
+            let separator = match Path::new(program).file_stem().and_then(|s| s.to_str()) {
+                Some("gdb") => Some("--args"),
+                Some("lldb") => Some("--"),
+                _ => None,
+            };
+            if let Some(separator) = separator
+                && !wrapped.iter().any(|arg| arg == separator)
+            {
+                wrapped.push(separator.to_string());
+            }
+            wrapped.push(exe);
+            wrapped.extend_from_slice(args);
+            ExecRequest {
+                program: program.clone(),
+                args: wrapped,
+                env: env.clone(),
+                ..ExecRequest::default()
+            }
+        }
+    };
+    let result = if wrapper.is_empty() {
+        executor::execute(&request)
     } else {
-        println!("executable not found, build first");
+        cancel::hand_over(|| executor::execute(&request))
+    };
+    match result {
+        Ok(_) => Ok(()),
+        Err(ExecError::Failed { status, .. }) => Err(BuildyError::ChildFailed {
+            code: child_exit_code(status),
+        }
+        .into()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The status a shell would give for `status`: the exit code, or 128 plus
+/// the signal that killed the program.
+fn child_exit_code(status: ExitStatus) -> i32 {
+    #[cfg(unix)]
+    if let Some(signal) = std::os::unix::process::ExitStatusExt::signal(&status) {
+        return 128 + signal;
+    }
+    status.code().unwrap_or(1)
+}
+
+/// The command line of `run --wrap CMD`, split like a shell would.
+fn split_wrapper(wrap: &str) -> Result<Vec<String>, String> {
+    match shell_words::split(wrap) {
+        Ok(words) if words.is_empty() => Err("--wrap needs a command".to_string()),
+        Ok(words) => Ok(words),
+        Err(e) => Err(format!("can't parse --wrap {:?}: {}", wrap, e)),
+    }
+}
+
+/// The debugger `debug` runs the program under: gdb, or lldb if there's no
+/// gdb.  On macOS, where gdb is rarely installed and can't debug without
+/// codesigning, lldb comes first.
+fn debugger() -> Result<Vec<String>, String> {
+    let candidates = if cfg!(target_os = "macos") {
+        ["lldb", "gdb"]
+    } else {
+        ["gdb", "lldb"]
+    };
+    candidates
+        .into_iter()
+        .find(|name| toolchain::on_path(name))
+        .map(|name| vec![name.to_string()])
+        .ok_or_else(|| {
+            "no debugger found: install gdb or lldb, or name one with `run --wrap`".to_string()
+        })
+}
+
+/// Everything the watch loop reacts to: lines from the readline thread and
+/// filesystem events from the watcher.
+enum WatchEvent {
+    Line(String),
+    Interrupted,
+    Eof,
+    ReadError(String),
+    /// A file changed, or a directory appeared, vanished or moved (`true`),
+    /// which can add or remove many files without an event for each.
+    Changed(PathBuf, bool),
+}
+
+/// How long the tree has to stay quiet before an automatic rebuild starts,
+/// so an editor saving several files (or `git checkout` touching
+/// thousands) triggers a single build.  `watch_delay` overrides it.
+const DEFAULT_WATCH_DELAY: Duration = Duration::from_millis(300);
+
+/// Whether a change to `path` matters to the build: a C/C++ file or
+/// `buildy.toml`.  Anything else is dropped as soon as the watcher reports
+/// it.
+fn is_watched(path: &Path) -> bool {
+    is_config_file(path) || is_source(path) || lang::is_header(path)
+}
+
+/// Whether `path` is named like `buildy.toml` or `.buildyignore`.
+fn is_config_file(path: &Path) -> bool {
+    matches!(
+        path.file_name().and_then(|n| n.to_str()),
+        Some(CONFIG_FILENAME | IGNORE_FILENAME)
+    )
+}
+
+/// How `buildy watch` was started.
+struct WatchOptions {
+    auto: bool,
+    autorun: bool,
+    /// `--watch-delay` in milliseconds.
+    delay: Option<u64>,
+    quiet: bool,
+    color: Option<ColorChoice>,
+    /// `-v` count, the default verbosity of the builds.
+    verbose: u8,
+}
+
+/// Change the repl setting `key` in `opts` to `value`, for `set`.
+fn set_option(opts: &mut BuildOptions, key: &str, value: &str) -> Result<(), String> {
+    let on_off = |value: &str| match value {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(format!("{}: expected on or off, not `{}`", key, value)),
+    };
+    match key {
+        "profile" => {
+            profile::check_name(value)?;
+            opts.profile = Profile::named(value);
+        }
+        "jobs" => {
+            opts.jobs = match value {
+                "default" => None,
+                n => Some(
+                    n.parse()
+                        .map_err(|_| format!("jobs: expected a number, not `{}`", n))?,
+                ),
+            }
+        }
+        "verbose" => {
+            opts.verbose = match value {
+                "2" => 2,
+                other => on_off(other)? as u8,
+            }
+        }
+        "keep-going" => opts.keep_going = on_off(value)?,
+        _ => {
+            return Err(format!(
+                "unknown setting `{}` (profile, jobs, verbose or keep-going)",
+                key
+            ));
+        }
     }
     Ok(())
 }
 
-fn watch_mode(root: PathBuf) -> Result<(), Box<dyn Error>> {
+/// Print the repl settings, for `show` and after `set`.
+fn show_options(opts: &BuildOptions) {
+    println!("profile     {}", opts.profile_name());
+    match opts.jobs {
+        Some(n) => println!("jobs        {}", n),
+        None => println!("jobs        default (buildy.toml, BUILDY_JOBS or every CPU)"),
+    }
+    println!("verbose     {}", opts.verbose);
+    println!("keep-going  {}", if opts.keep_going { "on" } else { "off" });
+}
+
+/// Changed files listed by the `status` command of the repl.
+const STATUS_PATHS_SHOWN: usize = 5;
+
+/// What the repl keeps track of between builds, shown by `status`.
+#[derive(Default)]
+struct SessionState {
+    /// Relevant files changed since the last build.
+    changed: HashSet<PathBuf>,
+    last: Option<LastBuild>,
+}
+
+/// The last `build` (or `run`) or `check` of the repl.
+struct LastBuild {
+    command: &'static str,
+    profile: String,
+    success: bool,
+    finished: Instant,
+}
+
+impl SessionState {
+    /// Record the outcome of a build with `opts`; a successful one consumes
+    /// the pending changes.
+    fn finished<T>(
+        &mut self,
+        command: &'static str,
+        opts: &BuildOptions,
+        result: &Result<T, Box<dyn Error>>,
+    ) {
+        if result.is_ok() {
+            self.changed.clear();
+        }
+        self.last = Some(LastBuild {
+            command,
+            profile: opts.profile_name().to_string(),
+            success: result.is_ok(),
+            finished: Instant::now(),
+        });
+    }
+
+    fn print(&self, root: &Path) {
+        let mut changed: Vec<&PathBuf> = self.changed.iter().collect();
+        changed.sort();
+        let shown: Vec<String> = changed
+            .iter()
+            .take(STATUS_PATHS_SHOWN)
+            .map(|p| BuildCache::make_relative(p, root))
+            .collect();
+        match changed.len() {
+            0 => println!("no pending changes"),
+            n if n > STATUS_PATHS_SHOWN => {
+                println!("{} pending change(s): {}, ...", n, shown.join(", "))
+            }
+            n => println!("{} pending change(s): {}", n, shown.join(", ")),
+        }
+        match &self.last {
+            Some(last) => println!(
+                "last {}: {}, {} {}s ago",
+                last.command,
+                last.profile,
+                if last.success { "succeeded" } else { "failed" },
+                last.finished.elapsed().as_secs()
+            ),
+            None => println!("nothing built yet in this session"),
+        }
+    }
+}
+
+/// State of the watch repl between commands.
+struct WatchState {
+    root: PathBuf,
+    session: BuildSession,
+    session_env: SessionEnv,
+    /// Pending changes and the outcome of the last build.
+    state: SessionState,
+    /// How long changes have to stop before the repl reacts to them (see
+    /// `watch_delay`), re-read when `buildy.toml` changes.
+    delay: Duration,
+    /// `--watch-delay`, which beats the config.
+    delay_flag: Option<u64>,
+    /// The configuration as of the last change to `buildy.toml` or
+    /// `.buildyignore`, telling which changes are excluded from the build.
+    config: Config,
+    /// Rebuild automatically when files change.
+    auto: bool,
+    /// Restart the program after every successful build.
+    autorun: bool,
+    /// The program started by `autorun`.
+    supervisor: Supervisor,
+    /// `--quiet` and `--color` the repl was started with; a command can
+    /// pass its own for just that command.
+    quiet: bool,
+    color: Option<ColorChoice>,
+    /// Options of the last explicit build, reused by automatic rebuilds.
+    last_build: BuildOptions,
+    /// The last explicit build was a `check`, so automatic rebuilds only
+    /// check too.
+    checking: bool,
+    /// Settings chosen with `set`, used by builds that don't pass the
+    /// matching flag.
+    defaults: BuildOptions,
+    /// Background compiles while automatic rebuilds are off.
+    speculator: Speculator,
+}
+
+impl WatchState {
+    /// Handle one line typed at the prompt.  Returns false when the repl
+    /// should shut down.
+    fn command(&mut self, line: &str) -> Result<bool, Box<dyn Error>> {
+        messages::configure(self.quiet, self.color);
+        let args =
+            shell_words::split(line.trim()).unwrap_or_else(|_| vec![line.trim().to_string()]);
+        if args.is_empty() {
+            return Ok(true);
+        }
+
+        let mut argv = vec!["repl".to_string()];
+        argv.extend(args);
+
+        let trimmed = line.trim();
+        let root = self.root.clone();
+
+        if trimmed == "exit" || trimmed == "close" {
+            println!("shutting down");
+            return Ok(false);
+        } else if trimmed == "help" {
+            println!(
+                "available commands: build, check, run, debug, status, clean, compile-commands, doctor, env, auto, autorun, rescan, close, help"
+            );
+            println!(
+                "flags available are --release, --profile <NAME>, --strict-flags, --strict-deps, --include <DIR>, --quiet, --color <WHEN>"
+            );
+            println!("env set KEY=VALUE | env unset KEY | env show | env save");
+            println!("auto on | auto off (rebuild when sources change)");
+            println!("autorun on | autorun off (restart the program after each successful build)");
+            println!(
+                "set profile NAME, set jobs N|default, set verbose on|off|2, set keep-going on|off"
+            );
+            println!("  (session defaults for builds that don't pass the flag; `show` lists them)");
+            println!("jobs N | jobs default (same as set jobs)");
+            println!("rescan (scan every file again on the next build)");
+            return Ok(true);
+        } else if trimmed == "rescan" {
+            self.session.graph.invalidate();
+            println!("the next build scans every file again");
+            return Ok(true);
+        } else if argv[1] == "env" {
+            if let Err(e) = self.session_env.command(&argv[2..], &root) {
+                println!("{}", e);
+            }
+            return Ok(true);
+        } else if argv[1] == "auto" {
+            match argv.get(2).map(String::as_str) {
+                Some("on") => self.auto = true,
+                Some("off") => self.auto = false,
+                _ => println!("usage: auto on | auto off"),
+            }
+            println!(
+                "automatic rebuilds {}",
+                if self.auto { "on" } else { "off" }
+            );
+            return Ok(true);
+        } else if argv[1] == "autorun" {
+            match argv.get(2).map(String::as_str) {
+                Some("on") => self.autorun = true,
+                Some("off") => {
+                    self.autorun = false;
+                    self.supervisor.stop();
+                }
+                _ => println!("usage: autorun on | autorun off"),
+            }
+            println!(
+                "autorun {}",
+                if self.autorun {
+                    "on (the program restarts after every successful build)"
+                } else {
+                    "off"
+                }
+            );
+            return Ok(true);
+        } else if argv[1] == "set" || argv[1] == "jobs" {
+            // `jobs N` is short for `set jobs N`
+            let (key, value) = match argv[1].as_str() {
+                "jobs" => (Some("jobs"), argv.get(2)),
+                _ => (argv.get(2).map(String::as_str), argv.get(3)),
+            };
+            let (Some(key), Some(value)) = (key, value) else {
+                println!(
+                    "usage: set profile NAME | set jobs N|default | set verbose on|off|2 | set keep-going on|off"
+                );
+                return Ok(true);
+            };
+            // also for automatic rebuilds, which reuse the last build's options
+            match set_option(&mut self.defaults, key, value) {
+                Ok(()) => {
+                    let _ = set_option(&mut self.last_build, key, value);
+                    show_options(&self.defaults);
+                }
+                Err(e) => println!("{}", e),
+            }
+            return Ok(true);
+        } else if trimmed == "show" {
+            show_options(&self.defaults);
+            return Ok(true);
+        }
+
+        let parsed = Cli::try_parse_from(&argv);
+        if let Ok(cli) = &parsed {
+            messages::configure(self.quiet || cli.quiet, cli.color.or(self.color));
+        }
+        match parsed {
+            Ok(cli) if self.session.is_foreign(&cli.root) => {
+                // a one-off command for another project: give it a session of
+                // its own so nothing of it ends up in this session's cache
+                let other = root.join(&cli.root);
+                let other = other.canonicalize().unwrap_or(other);
+                println!(
+                    "running in {}; the session stays in {}",
+                    other.display(),
+                    root.display()
+                );
+                if let Err(e) = run_once(&other, cli.into_command()) {
+                    println!("{}", e);
+                }
+            }
+            Ok(cli) => match cli.into_command() {
+                Commands::Build {
+                    build,
+                    dry_run: true,
+                    ..
+                } => {
+                    let opts = self.options(&build);
+                    dry_run(&mut self.session, &opts)?;
+                }
+                Commands::Build {
+                    build,
+                    message_format,
+                    ..
+                } => {
+                    self.last_build = self.options(&build);
+                    self.last_build.message_format = message_format;
+                    self.checking = false;
+                    let result = run_build(&mut self.session, &self.last_build);
+                    self.state.finished("build", &self.last_build, &result);
+                    self.autorun(result?);
+                }
+                Commands::Run {
+                    build,
+                    bin,
+                    message_format,
+                    wrap,
+                    args,
+                } => {
+                    let wrapper = match wrap.as_deref().map(split_wrapper).transpose() {
+                        Ok(wrapper) => wrapper.unwrap_or_default(),
+                        Err(e) => {
+                            println!("{}", e);
+                            return Ok(true);
+                        }
+                    };
+                    self.last_build = self.options(&build);
+                    self.last_build.message_format = message_format;
+                    self.checking = false;
+                    let result = run_build(&mut self.session, &self.last_build);
+                    self.state.finished("build", &self.last_build, &result);
+                    let built = result?;
+                    let exe_path = pick_program(&built.config, built.outputs, bin.as_deref())?;
+                    let env = self.session_env.resolve(&built.config);
+                    // the loop waits for this, so no automatic rebuild or
+                    // autorun restart can stop a debugger mid-session;
+                    // changes made meanwhile are built once it exits
+                    if let Err(e) = run_executable(&exe_path, &wrapper, &args, &env) {
+                        println!("{}", e);
+                    }
+                }
+                Commands::Debug { build, bin, args } => {
+                    let wrapper = match debugger() {
+                        Ok(wrapper) => wrapper,
+                        Err(e) => {
+                            println!("{}", e);
+                            return Ok(true);
+                        }
+                    };
+                    self.last_build = self.options(&build);
+                    self.checking = false;
+                    let result = run_build(&mut self.session, &self.last_build);
+                    self.state.finished("build", &self.last_build, &result);
+                    let built = result?;
+                    let exe_path = pick_program(&built.config, built.outputs, bin.as_deref())?;
+                    let env = self.session_env.resolve(&built.config);
+                    if let Err(e) = run_executable(&exe_path, &wrapper, &args, &env) {
+                        println!("{}", e);
+                    }
+                }
+                Commands::Check {
+                    build,
+                    message_format,
+                } => {
+                    self.last_build = self.options(&build);
+                    self.last_build.message_format = message_format;
+                    self.checking = true;
+                    let result = run_check(&mut self.session, &self.last_build);
+                    self.state.finished("check", &self.last_build, &result);
+                    result?;
+                }
+                Commands::Test { build, filter } => {
+                    let opts = self.options(&build);
+                    run_tests(
+                        &mut self.session,
+                        &opts,
+                        filter.as_deref(),
+                        &self.session_env,
+                    )?;
+                }
+                Commands::Install { build, prefix } => {
+                    let opts = self.options(&build);
+                    run_install(&mut self.session, &opts, &prefix)?;
+                }
+                Commands::Init { name, cpp, force } => {
+                    scaffold(&root, name.as_deref(), cpp, force)?;
+                }
+                Commands::Completions { shell } => print_completions(shell),
+                Commands::Watch { .. } => println!("Already in watch mode."),
+                Commands::CompileDb { build } => {
+                    write_compile_commands(&mut self.session, &build.options())?;
+                }
+                Commands::Doctor => {
+                    doctor::run(&root);
+                }
+                Commands::Clean { clean } => {
+                    clean.run(&root)?;
+                    self.session.reset_caches();
+                }
+                Commands::Graph { graph } => {
+                    export_graph(&mut self.session, &graph)?;
+                }
+                Commands::Schema { document } => print!("{}", document.schema_json()),
+                Commands::Explain { path, build } => {
+                    let opts = self.options(&build);
+                    explain(&mut self.session, &opts, &path)?;
+                }
+                Commands::Status { .. } => {
+                    self.state.print(&root);
+                    // the profile the session builds, not the flags' default
+                    let opts = self.last_build.clone();
+                    print_status(&mut self.session, &opts)?;
+                }
+                Commands::Cache { action } => {
+                    // a failed `verify` is a finding, not the end of the session
+                    if let Err(e) = action.run(&root) {
+                        println!("{}", e);
+                    }
+                }
+            },
+            Err(e) => println!("{}", e),
+        }
+        Ok(true)
+    }
+
+    /// Options for a build typed at the prompt: the flags it passes, and
+    /// the session's `set` values for the settings it doesn't pass.
+    fn options(&self, build: &BuildArgs) -> BuildOptions {
+        let mut opts = build.options();
+        if build.profile.is_none() && !build.release && !build.debug {
+            opts.profile = self.defaults.profile.clone();
+        }
+        opts.jobs = opts.jobs.or(self.defaults.jobs);
+        if build.verbose == 0 {
+            opts.verbose = self.defaults.verbose;
+        }
+        opts.keep_going |= self.defaults.keep_going;
+        opts
+    }
+
+    /// Remember a filesystem change of a watched file (see `is_watched`) or a
+    /// directory for the next build's graph update.  Returns false, keeping
+    /// nothing, for a file excluded from the build that no source includes.
+    fn file_changed(&mut self, path: PathBuf, directory: bool) -> bool {
+        if !directory && self.config.is_excluded(&path, &self.root) {
+            let canonical = path.canonicalize().unwrap_or_else(|_| path.clone());
+            // before the first scan any header may turn out to be included
+            let graph = &self.session.graph;
+            let tracked = !self.config.exclude_headers
+                && lang::is_header(&path)
+                && (graph.nodes.is_empty() || graph.nodes.contains_key(&canonical));
+            if !tracked {
+                return false;
+            }
+        }
+        if directory {
+            self.session.graph.invalidate();
+        } else {
+            self.session.graph.note_change(path.clone());
+        }
+        if is_config_file(&path) {
+            self.config = Config::load(&self.root);
+            self.delay = watch_delay(&self.root, self.delay_flag);
+        }
+        self.state.changed.insert(path);
+        true
+    }
+
+    /// Files stopped changing: rebuild, or with automatic rebuilds off
+    /// start compiling the affected sources speculatively.
+    fn settled(&mut self) {
+        if self.auto {
+            self.auto_build();
+        } else {
+            self.speculate();
+        }
+    }
+
+    /// Rebuild after files changed.  Errors are printed rather than ending
+    /// the session.
+    fn auto_build(&mut self) {
+        messages::configure(self.quiet, self.color);
+        println!("\n{} file(s) changed, rebuilding", self.state.changed.len());
+        self.state.changed.clear();
+        let result = if self.checking {
+            let result = run_check(&mut self.session, &self.last_build);
+            self.state.finished("check", &self.last_build, &result);
+            result
+        } else {
+            let result = run_build(&mut self.session, &self.last_build);
+            self.state.finished("build", &self.last_build, &result);
+            result.map(|built| self.autorun(built))
+        };
+        if let Err(e) = result {
+            // the program of the last good build keeps running
+            println!("build failed: {}", e);
+        }
+    }
+
+    /// With `autorun on`, (re)start the program a build just produced.
+    fn autorun(&mut self, built: Built) {
+        if !self.autorun {
+            return;
+        }
+        let exe = match pick_program(&built.config, built.outputs, None) {
+            Ok(exe) => exe,
+            Err(e) => {
+                println!("autorun: {}", e);
+                return;
+            }
+        };
+        let env = self.session_env.resolve(&built.config);
+        if let Err(e) = self.supervisor.restart(&exe, &[], &env) {
+            println!("autorun: could not start {}: {}", exe.display(), e);
+        }
+    }
+
+    /// Compile the sources a `build` would compile now into the staging
+    /// area, if `speculative` is enabled.  Nothing is printed; a failing
+    /// scan or compile is left for the real build to report.
+    fn speculate(&mut self) {
+        let opts = &self.last_build;
+        let root = self.root.as_path();
+        let Ok((config, opts)) = &load_config(root, opts) else {
+            return;
+        };
+        // module units depend on each other's BMIs, which only a real build
+        // produces in order
+        if !config.speculative || config.modules {
+            return;
+        }
+        let BuildSession { caches, graph, .. } = &mut self.session;
+        let profile = opts.profile_name();
+        let cache = caches
+            .entry(profile.to_string())
+            .or_insert_with(|| BuildCache::load(root, profile));
+        if graph
+            .update(
+                root,
+                config,
+                &opts.scan_args(root, config.toolchain()),
+                profile,
+                cache,
+            )
+            .is_err()
+        {
+            return;
+        }
+        graph.refresh(cache, root, config.hash_always);
+        let Ok(commands) = scheduler::compile_commands(graph, root, config, opts) else {
+            return;
+        };
+        plan::migrate_command_hashes(graph, cache, root, &commands);
+        let plan = DirtyPlan::compute(
+            graph,
+            cache,
+            root,
+            profile,
+            &Invalidated::default(),
+            &commands,
+        );
+        let store = ObjectStore::new(root);
+        let jobs = commands
+            .into_iter()
+            .filter(|c| plan.is_dirty(&c.source))
+            .filter_map(|command| {
+                let inputs = graph.inputs(&command.source);
+                let key = speculate::job_key(&command, &inputs)?;
+                (Staged::find(root, profile, &key).is_none() && !store.contains(&key)).then_some(
+                    speculate::Job {
+                        command,
+                        inputs,
+                        key,
+                    },
+                )
+            })
+            .collect();
+        self.speculator
+            .start(speculate::staging_dir(root, profile), jobs);
+    }
+}
+
+/// Read lines on a separate thread so filesystem events can be handled while
+/// the prompt is waiting.  After each line the thread waits for the main loop
+/// to acknowledge it, so command output isn't interleaved with the next
+/// prompt; a `false` acknowledgement (or a dropped channel) stops the thread.
+/// The history is loaded from `target/.buildy/history` and saved back when
+/// the thread ends, which the returned handle waits for.
+fn spawn_readline(
+    root: &Path,
+    tx: std::sync::mpsc::Sender<WatchEvent>,
+    ack: std::sync::mpsc::Receiver<bool>,
+) -> Result<std::thread::JoinHandle<()>, Box<dyn Error>> {
+    let config = rustyline::Config::builder()
+        .max_history_size(prompt::HISTORY_SIZE)?
+        .history_ignore_dups(true)?
+        .build();
+    let mut rl: Editor<ReplHelper, FileHistory> = Editor::with_config(config)?;
+    rl.set_helper(Some(ReplHelper::new(Cli::command())));
+    let history = prompt::history_path(root);
+    // no history yet on the first session
+    let _ = rl.load_history(&history);
+    let handle = std::thread::spawn(move || {
+        loop {
+            let prompt = "buildy> ".red().bold().to_string();
+            let event = match rl.readline(&prompt) {
+                Ok(line) => {
+                    if !line.trim().is_empty() {
+                        let _ = rl.add_history_entry(line.as_str());
+                    }
+                    WatchEvent::Line(line)
+                }
+                Err(ReadlineError::Interrupted) => WatchEvent::Interrupted,
+                Err(ReadlineError::Eof) => WatchEvent::Eof,
+                Err(err) => WatchEvent::ReadError(format!("{:?}", err)),
+            };
+            let is_line = matches!(event, WatchEvent::Line(_));
+            if tx.send(event).is_err() || !is_line || !ack.recv().unwrap_or(false) {
+                break;
+            }
+        }
+        if let Some(dir) = history.parent()
+            && std::fs::create_dir_all(dir).is_ok()
+            && let Err(e) = rl.save_history(&history)
+        {
+            eprintln!("warning: could not save the repl history: {}", e);
+        }
+    });
+    Ok(handle)
+}
+
+/// The settle delay of the watch repl: `--watch-delay`, else `watch_delay`
+/// from `buildy.toml`, else `DEFAULT_WATCH_DELAY`.
+fn watch_delay(root: &Path, flag: Option<u64>) -> Duration {
+    flag.or(Config::load(root).watch_delay)
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_WATCH_DELAY)
+}
+
+fn watch_mode(root: PathBuf, watch: WatchOptions) -> Result<(), Box<dyn Error>> {
+    let WatchOptions {
+        auto,
+        autorun,
+        delay,
+        quiet,
+        color,
+        verbose,
+    } = watch;
     println!("starting watch daemon in {}", root.display());
+    if auto {
+        println!("automatic rebuilds on");
+    }
+    if autorun {
+        println!("the program is restarted after every successful build");
+    }
 
     let (tx, rx) = channel();
+    let watch_tx = tx.clone();
+    let watch_root = root.clone();
     let mut watcher: RecommendedWatcher =
         notify::recommended_watcher(move |res: notify::Result<notify::Event>| match res {
             Ok(event) => {
+                // our own objects and cache writes land in target/
                 for path in event.paths {
-                    let _ = tx.send(path);
+                    let ignore_file = path == watch_root.join(IGNORE_FILENAME);
+                    if graph::is_ignored(&path, &watch_root) && !ignore_file {
+                        continue;
+                    }
+                    let directory = match event.kind {
+                        EventKind::Create(CreateKind::Folder)
+                        | EventKind::Remove(RemoveKind::Folder) => true,
+                        EventKind::Modify(ModifyKind::Name(_)) => {
+                            path.is_dir() || path.extension().is_none()
+                        }
+                        _ => false,
+                    };
+                    if directory || is_watched(&path) {
+                        let _ = watch_tx.send(WatchEvent::Changed(path, directory));
+                    }
                 }
             }
             Err(e) => eprintln!("watch error: {:?}", e),
         })?;
     watcher.watch(&root, RecursiveMode::Recursive)?;
 
-    let mut rl: Editor<(), _> = Editor::new()?;
-    let mut cache = BuildCache::load(&root);
-    let mut changed = HashSet::new();
+    let (ack_tx, ack_rx) = channel();
+    let readline = spawn_readline(&root, tx, ack_rx)?;
+
+    let mut state = WatchState {
+        session: BuildSession::new(&root),
+        delay: watch_delay(&root, delay),
+        config: Config::load(&root),
+        delay_flag: delay,
+        root,
+        session_env: SessionEnv::default(),
+        state: SessionState::default(),
+        auto,
+        autorun,
+        supervisor: Supervisor::default(),
+        quiet,
+        color,
+        last_build: BuildArgs::default().options(),
+        checking: false,
+        speculator: Speculator::default(),
+        defaults: BuildOptions {
+            verbose: if verbose > 0 {
+                verbose
+            } else {
+                env_verbosity()
+            },
+            ..BuildArgs::default().options()
+        },
+    };
+    // set while changes are waiting for the tree to settle
+    let mut rebuild_at: Option<Instant> = None;
 
     let result: Result<(), Box<dyn Error>> = (|| {
         loop {
-            // drain filesystem events
-            while let Ok(path) = rx.try_recv() {
-                changed.insert(path);
-            }
-            let prompt = "buildy> ".red().bold().to_string();
-
-            match rl.readline(&prompt) {
-                Ok(line) => {
-                    let args = shell_words::split(line.trim())
-                        .unwrap_or_else(|_| vec![line.trim().to_string()]);
-                    if args.is_empty() {
+            let event = match rebuild_at {
+                Some(at) => match rx.recv_timeout(at.saturating_duration_since(Instant::now())) {
+                    Ok(event) => event,
+                    Err(RecvTimeoutError::Timeout) => {
+                        rebuild_at = None;
+                        state.settled();
                         continue;
                     }
+                    Err(RecvTimeoutError::Disconnected) => break,
+                },
+                None => match rx.recv() {
+                    Ok(event) => event,
+                    Err(_) => break,
+                },
+            };
 
-                    let mut argv = vec!["repl".to_string()];
-                    argv.extend(args);
-
-                    let trimmed = line.trim();
-
-                    if trimmed == "exit" || trimmed == "close" {
-                        println!("shutting down");
-                        break;
-                    } else if trimmed == "help" {
-                        println!("available commands: build, run, close, help");
-                        println!("flags available are --release")
+            match event {
+                WatchEvent::Changed(path, directory) => {
+                    // every event pushes the deadline back, so a burst of
+                    // them is handed to `settled` as one batch
+                    if state.file_changed(path, directory) {
+                        rebuild_at = Some(Instant::now() + state.delay);
                     }
-
-                    match Cli::try_parse_from(&argv) {
-                        Ok(cli) => match cli.command {
-                            Commands::Build { release } => {
-                                let is_debug = !release;
-                                run_build(&root, &mut cache, is_debug)?;
-                                changed.clear();
-                            }
-                            Commands::Run { release } => {
-                                let is_debug = !release;
-                                let exe_path = run_build(&root, &mut cache, is_debug)?;
-                                changed.clear();
-                                run_executable(&exe_path)?;
-                            }
-                            Commands::Watch => println!("Already in watch mode."),
-                        },
-                        Err(e) => println!("{}", e),
+                }
+                WatchEvent::Line(line) => {
+                    // Ctrl-C during a build cancels it, not the session
+                    let keep_going = match state.command(&line) {
+                        Err(e)
+                            if matches!(
+                                e.downcast_ref::<BuildyError>(),
+                                Some(BuildyError::Interrupted)
+                            ) =>
+                        {
+                            println!("{}", e);
+                            true
+                        }
+                        result => result?,
+                    };
+                    let _ = ack_tx.send(keep_going);
+                    if !keep_going {
+                        break;
                     }
                 }
-                Err(ReadlineError::Interrupted) => {
+                WatchEvent::Interrupted => {
                     println!("CTRL-C");
                     break;
                 }
-                Err(ReadlineError::Eof) => {
+                WatchEvent::Eof => {
                     println!("CTRL-D");
                     break;
                 }
-                Err(err) => {
-                    eprintln!("error reading line: {:?}", err);
+                WatchEvent::ReadError(err) => {
+                    eprintln!("error reading line: {}", err);
                     break;
                 }
             }
@@ -229,7 +2248,13 @@ fn watch_mode(root: PathBuf) -> Result<(), Box<dyn Error>> {
         Ok(())
     })();
 
-    cache.save()?;
+    state.speculator.stop();
+    state.supervisor.stop();
+    // the readline thread saves the history once it stops waiting for us
+    drop(ack_tx);
+    let _ = readline.join();
+    let _lock = TargetLock::acquire(&state.session.root)?;
+    state.session.flush()?;
     println!("Cache saved. Goodbye!");
 
     result
@@ -0,0 +1,329 @@
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Glob patterns selecting paths in the project, shared by every option that
+/// matches paths so they all behave the same:
+///
+/// - patterns are relative to the project root and anchored there:
+///   `main.c` (or `/main.c`) only matches the top-level file,
+///   `**/main.c` matches any
+/// - `*`, `?` and `[...]` never cross a `/`; `**` matches any number of
+///   directories
+/// - a trailing `/` selects a directory and everything below it, but not a
+///   file of that name
+/// - matching is case-insensitive on Windows and case-sensitive elsewhere
+#[derive(Debug, Clone, Default)]
+pub struct PathMatcher {
+    set: GlobSet,
+    /// Patterns written with a trailing `/`, which match only directories.
+    dirs: GlobSet,
+}
+
+impl PathMatcher {
+    /// Compile `patterns`.  `key` names the configuration option they came
+    /// from and is included in the error for an invalid pattern.
+    pub fn new(key: &str, patterns: &[String]) -> Result<Self, String> {
+        let (mut builder, mut dirs) = (GlobSetBuilder::new(), GlobSetBuilder::new());
+        for pattern in patterns {
+            let glob = |glob: &str| {
+                GlobBuilder::new(glob)
+                    .literal_separator(true)
+                    .backslash_escape(!cfg!(windows))
+                    .case_insensitive(cfg!(windows))
+                    .build()
+                    .map_err(|e| {
+                        format!("invalid pattern '{}' in `{}`: {}", pattern, key, e.kind())
+                    })
+            };
+            let trimmed = pattern.trim_start_matches("./").trim_start_matches('/');
+            match trimmed.strip_suffix('/') {
+                Some(dir) => {
+                    dirs.add(glob(dir)?);
+                    builder.add(glob(&format!("{}/**", dir))?);
+                }
+                None => {
+                    builder.add(glob(trimmed)?);
+                }
+            }
+        }
+        let build = |builder: GlobSetBuilder| {
+            builder
+                .build()
+                .map_err(|e| format!("invalid patterns in `{}`: {}", key, e))
+        };
+        Ok(PathMatcher {
+            set: build(builder)?,
+            dirs: build(dirs)?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty() && self.dirs.is_empty()
+    }
+
+    /// Whether `path` matches.  Absolute paths are made relative to `root`
+    /// first; paths outside the root never match.
+    pub fn is_match(&self, path: &Path, root: &Path) -> bool {
+        if self.is_empty() {
+            return false;
+        }
+        let rel = if path.is_absolute() {
+            let canonical_root;
+            match path.strip_prefix(root) {
+                Ok(rel) => rel,
+                Err(_) => {
+                    // graph paths are canonical, `root` may not be
+                    canonical_root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+                    match path.strip_prefix(&canonical_root) {
+                        Ok(rel) => rel,
+                        Err(_) => return false,
+                    }
+                }
+            }
+        } else {
+            path
+        };
+        // globs use `/` on every platform
+        let rel = rel.to_string_lossy().replace('\\', "/");
+        let rel = rel.trim_start_matches("./");
+        self.set.is_match(rel) || (self.dirs.is_match(rel) && root.join(path).is_dir())
+    }
+}
+
+/// File in the project root listing paths to leave out of the build, in
+/// gitignore style.
+pub const IGNORE_FILENAME: &str = ".buildyignore";
+
+/// The patterns of a `.buildyignore`, one per line:
+///
+/// - blank lines and lines starting with `#` are skipped
+/// - a pattern without a `/` (other than a trailing one) matches at any
+///   depth, one with a `/` is relative to the root, like `exclude`
+/// - a match also selects everything below it, and a trailing `/` matches
+///   directories only
+/// - `!pattern` takes paths an exclusion matched back into the build;
+///   as with git, nothing below an excluded directory can be
+#[derive(Debug, Clone, Default)]
+pub struct IgnoreFile {
+    /// The lines as written, so a scan can tell the file changed.
+    pub lines: Vec<String>,
+    ignored: PathMatcher,
+    reincluded: PathMatcher,
+}
+
+impl IgnoreFile {
+    /// Read the `.buildyignore` of `root`; a missing file ignores nothing.
+    pub fn read(root: &Path) -> Result<Self, String> {
+        let path = root.join(IGNORE_FILENAME);
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(IgnoreFile::default()),
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        };
+        let lines: Vec<String> = text
+            .lines()
+            .map(str::trim_end)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(String::from)
+            .collect();
+        let (mut ignored, mut reincluded) = (Vec::new(), Vec::new());
+        for line in &lines {
+            match line.strip_prefix('!') {
+                Some(pattern) => reincluded.extend(anchor(pattern)),
+                None => ignored.extend(anchor(line)),
+            }
+        }
+        Ok(IgnoreFile {
+            ignored: PathMatcher::new(IGNORE_FILENAME, &ignored)?,
+            reincluded: PathMatcher::new(IGNORE_FILENAME, &reincluded)?,
+            lines,
+        })
+    }
+
+    /// Whether `path` is excluded by the file, itself or through a directory
+    /// above it.  Paths are taken like `PathMatcher::is_match` takes them.
+    pub fn is_match(&self, path: &Path, root: &Path) -> bool {
+        let excluded = |path: &Path| {
+            self.ignored.is_match(path, root) && !self.reincluded.is_match(path, root)
+        };
+        excluded(path)
+            || path
+                .ancestors()
+                .skip(1)
+                .take_while(|dir| *dir != root && !dir.as_os_str().is_empty())
+                .any(excluded)
+    }
+}
+
+/// A `.buildyignore` pattern in `PathMatcher` form: anchored at the root
+/// when it contains a `/`, at any depth otherwise, and always selecting what
+/// is below it.
+fn anchor(pattern: &str) -> Vec<String> {
+    // `\#` and `\!` start patterns with a literal `#` or `!`
+    let pattern = pattern.strip_prefix('\\').unwrap_or(pattern);
+    let trimmed = pattern.trim_end_matches('/');
+    let anchored = match trimmed.strip_prefix('/') {
+        Some(anchored) => anchored.to_string(),
+        None if trimmed.contains('/') => trimmed.to_string(),
+        None => format!("**/{}", trimmed),
+    };
+    if pattern.ends_with('/') {
+        vec![format!("{}/", anchored)]
+    } else {
+        vec![format!("{}/**", anchored), anchored]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A project with `src/foo.c`, `src/foo/bar.c`, `lib/deep/er/foo.c`,
+    /// a `build` file and a `out/` directory.
+    fn project() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for file in [
+            "foo",
+            "src/foo.c",
+            "src/foo/bar.c",
+            "src/Main.c",
+            "lib/deep/er/foo.c",
+            "lib/foo.h",
+            "build",
+            "out/app.o",
+        ] {
+            let path = dir.path().join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn path_matcher_table() {
+        let dir = project();
+        let root = dir.path();
+        let table = [
+            // anchored at the root, with or without a leading `/` or `./`
+            ("foo", "foo", true),
+            ("foo", "src/foo", false),
+            ("/foo", "foo", true),
+            ("/foo", "src/foo", false),
+            ("./src/foo.c", "src/foo.c", true),
+            ("foo.c", "src/foo.c", false),
+            ("**/foo.c", "src/foo.c", true),
+            ("**/foo.c", "lib/deep/er/foo.c", true),
+            ("**/foo.c", "foo.c", true),
+            // `*` and `?` stay within a directory
+            ("src/*.c", "src/foo.c", true),
+            ("src/*.c", "src/foo/bar.c", false),
+            ("src/fo?.c", "src/foo.c", true),
+            ("*", "src/foo.c", false),
+            // `**` in the middle and at the end
+            ("lib/**/foo.c", "lib/deep/er/foo.c", true),
+            ("lib/**/foo.c", "lib/foo.c", true),
+            ("lib/**/foo.c", "src/foo.c", false),
+            ("lib/**", "lib/deep/er/foo.c", true),
+            ("lib/**", "lib/foo.h", true),
+            ("lib/**", "src/foo.c", false),
+            // a trailing `/` matches a directory and what is below it
+            ("src/foo/", "src/foo", true),
+            ("src/foo/", "src/foo/bar.c", true),
+            ("src/foo/", "src/foo.c", false),
+            ("out/", "out", true),
+            ("out/", "out/app.o", true),
+            ("build/", "build", false),
+            ("build", "build", true),
+            // case
+            ("src/main.c", "src/Main.c", cfg!(windows)),
+            ("src/Main.c", "src/Main.c", true),
+        ];
+        for (pattern, path, expected) in table {
+            let matcher = PathMatcher::new("exclude", &[pattern.to_string()]).unwrap();
+            assert_eq!(
+                matcher.is_match(Path::new(path), root),
+                expected,
+                "'{}' against {}",
+                pattern,
+                path
+            );
+            // absolute paths are taken relative to the root
+            assert_eq!(
+                matcher.is_match(&root.join(path), root),
+                expected,
+                "'{}' against {} (absolute)",
+                pattern,
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn paths_outside_the_root_never_match() {
+        let dir = project();
+        let matcher = PathMatcher::new("exclude", &["**".to_string()]).unwrap();
+        assert!(matcher.is_match(Path::new("src/foo.c"), dir.path()));
+        assert!(!matcher.is_match(Path::new("/elsewhere/src/foo.c"), dir.path()));
+        assert!(!PathMatcher::default().is_match(Path::new("src/foo.c"), dir.path()));
+    }
+
+    #[test]
+    fn invalid_patterns_name_the_option() {
+        let err = PathMatcher::new("exclude", &["src/[".to_string()]).unwrap_err();
+        assert!(
+            err.starts_with("invalid pattern 'src/[' in `exclude`"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn ignore_file_table() {
+        let dir = project();
+        let root = dir.path();
+        fs::write(
+            root.join(IGNORE_FILENAME),
+            "# comment\n\
+             \n\
+             foo.c\n\
+             !src/foo.c\n\
+             /build/\n\
+             out/\n\
+             !out/app.o\n\
+             lib/deep\n\
+             \\#hash.c\n",
+        )
+        .unwrap();
+        let ignore = IgnoreFile::read(root).unwrap();
+        assert_eq!(ignore.lines.len(), 7);
+        let table = [
+            // no `/`: at any depth; `!` takes a path back in
+            ("lib/deep/er/foo.c", true),
+            ("src/foo.c", false),
+            // a directory also selects what is below it
+            ("lib/deep", true),
+            ("lib/deep/er", true),
+            ("lib/foo.h", false),
+            // a trailing `/` matches directories only
+            ("build", false),
+            ("out", true),
+            // nothing below an excluded directory can be taken back in
+            ("out/app.o", true),
+            ("#hash.c", true),
+            ("src/Main.c", false),
+        ];
+        for (path, expected) in table {
+            assert_eq!(ignore.is_match(Path::new(path), root), expected, "{}", path);
+        }
+    }
+
+    #[test]
+    fn a_missing_ignore_file_ignores_nothing() {
+        let dir = project();
+        let ignore = IgnoreFile::read(dir.path()).unwrap();
+        assert!(ignore.lines.is_empty());
+        assert!(!ignore.is_match(Path::new("src/foo.c"), dir.path()));
+    }
+}
@@ -0,0 +1,133 @@
+pub use crate::protocol::Message;
+use crate::report::BuildReport;
+use std::env;
+use std::fmt::Display;
+use std::io::{self, IsTerminal};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+/// Set by `--quiet`, see `configure`.
+static QUIET: AtomicBool = AtomicBool::new(false);
+/// Set by `set_reporter`.
+static REPORTER: RwLock<Option<Arc<dyn Reporter>>> = RwLock::new(None);
+
+/// Receives what builds print, for programs using buildy as a library
+/// (see `set_reporter`).  Without one, status lines go to stdout and
+/// warnings and compiler output to stderr.
+pub trait Reporter: Send + Sync {
+    /// A status line: progress, the commands run with `-v`, the summary.
+    fn status(&self, text: &str);
+    /// A warning, or what the compiler printed for a file.
+    fn warning(&self, text: &str);
+    /// Every `Message`, whatever the message format.
+    fn message(&self, _message: &Message) {}
+}
+
+/// A `Reporter` that drops everything.
+pub struct Silent;
+
+impl Reporter for Silent {
+    fn status(&self, _text: &str) {}
+    fn warning(&self, _text: &str) {}
+}
+
+/// Send everything printed from now on to `reporter`, or back to stdout
+/// and stderr with `None`.  The reporter is global, like `--quiet`.
+pub fn set_reporter(reporter: Option<Arc<dyn Reporter>>) {
+    *REPORTER.write().unwrap_or_else(|e| e.into_inner()) = reporter;
+}
+
+/// The reporter set with `set_reporter`.
+pub fn reporter() -> Option<Arc<dyn Reporter>> {
+    REPORTER.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Print a warning on stderr, or pass it to the reporter.
+pub fn warn(text: impl Display) {
+    match reporter() {
+        Some(reporter) => reporter.warning(&text.to_string()),
+        None => eprintln!("{}", text),
+    }
+}
+
+/// How `build` and `run` report what they do, chosen with
+/// `--message-format`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum MessageFormat {
+    /// Status lines on stdout, compiler output on stderr.
+    #[default]
+    Human,
+    /// One `Message` per line on stdout; everything meant for people goes
+    /// to stderr so stdout can be parsed as is.  `json@1` asks for version
+    /// 1 of the messages, the current one.
+    #[value(alias = "json@1")]
+    Json,
+}
+
+/// When to color the output, chosen with `--color`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ColorChoice {
+    /// Color when stdout is a terminal and `NO_COLOR` isn't set.
+    Auto,
+    Always,
+    Never,
+}
+
+/// Apply `--quiet` and `--color` to everything printed from now on.
+/// Without `--color` the choice is `auto`.
+pub fn configure(quiet: bool, color: Option<ColorChoice>) {
+    QUIET.store(quiet, Ordering::SeqCst);
+    let colored = match color.unwrap_or(ColorChoice::Auto) {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => {
+            env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()) && io::stdout().is_terminal()
+        }
+    };
+    colored::control::set_override(colored);
+}
+
+/// Whether `--quiet` is in effect: only errors and the summary of a build
+/// are printed.
+pub fn quiet() -> bool {
+    QUIET.load(Ordering::SeqCst)
+}
+
+impl MessageFormat {
+    /// Write `message` to stdout in JSON mode.  Each message is a single
+    /// `println!`, so messages from parallel compiles never interleave.
+    /// A reporter gets every message instead.
+    pub fn emit(self, message: &Message) {
+        if let Some(reporter) = reporter() {
+            reporter.message(message);
+        } else if self == MessageFormat::Json
+            && let Ok(line) = message.to_json()
+        {
+            println!("{}", line);
+        }
+    }
+
+    /// Print a status line for people: on stdout, or stderr in JSON mode.
+    /// Nothing is printed with `--quiet`.
+    pub fn status(self, text: impl Display) {
+        if !quiet() {
+            self.print(text);
+        }
+    }
+
+    /// Print the line summing up a build like `status`, also with
+    /// `--quiet`.
+    pub fn summary(self, report: &BuildReport) {
+        self.print(report);
+    }
+
+    fn print(self, text: impl Display) {
+        if let Some(reporter) = reporter() {
+            return reporter.status(&text.to_string());
+        }
+        match self {
+            MessageFormat::Human => println!("{}", text),
+            MessageFormat::Json => eprintln!("{}", text),
+        }
+    }
+}
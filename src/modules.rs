@@ -0,0 +1,222 @@
+use crate::executor::{self, ExecRequest};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Extensions of module interface units.  They are only scanned when
+/// modules are enabled.
+pub const INTERFACE_EXTS: [&str; 2] = ["cppm", "ixx"];
+
+/// Module declarations found in one translation unit.  Only used for the
+/// experimental C++20 named-module support (`modules = true`), where a
+/// lightweight line scanner orders interface units before their importers.
+#[derive(Debug, Clone, Default)]
+pub struct ModuleUnit {
+    /// Module (or partition, as `name:part`) this unit provides a BMI for.
+    pub provides: Option<String>,
+    /// Named modules this unit imports.  Header units are not tracked.
+    pub imports: Vec<String>,
+}
+
+impl ModuleUnit {
+    pub fn is_module_unit(&self) -> bool {
+        self.provides.is_some() || !self.imports.is_empty()
+    }
+}
+
+/// Read `path` and extract its module declarations.
+pub fn scan_unit(path: &Path) -> std::io::Result<ModuleUnit> {
+    Ok(parse_unit(&fs::read_to_string(path)?))
+}
+
+fn parse_unit(text: &str) -> ModuleUnit {
+    let mut unit = ModuleUnit::default();
+    // name of the module this unit belongs to, used to qualify `import :part;`
+    let mut current: Option<String> = None;
+
+    for line in text.lines() {
+        let line = line.split("//").next().unwrap_or("").trim();
+        let Some(decl) = line.strip_suffix(';') else {
+            continue;
+        };
+        let decl = decl.trim();
+
+        if let Some(name) = decl
+            .strip_prefix("export module ")
+            .map(str::trim)
+            .filter(|n| !n.is_empty())
+        {
+            current = Some(base_name(name).to_string());
+            unit.provides = Some(name.to_string());
+        } else if let Some(name) = decl.strip_prefix("module ").map(str::trim) {
+            if name.starts_with(':') {
+                // `module :private;` fragment
+                continue;
+            }
+            current = Some(base_name(name).to_string());
+            if name.contains(':') {
+                // internal partition: provides a BMI for its own name
+                unit.provides = Some(name.to_string());
+            } else {
+                // implementation unit implicitly imports its interface
+                unit.imports.push(name.to_string());
+            }
+        } else if let Some(name) = decl
+            .strip_prefix("export import ")
+            .or_else(|| decl.strip_prefix("import "))
+            .map(str::trim)
+        {
+            if name.starts_with('<') || name.starts_with('"') {
+                continue;
+            }
+            let name = match (name.strip_prefix(':'), &current) {
+                (Some(part), Some(module)) => format!("{}:{}", module, part),
+                _ => name.to_string(),
+            };
+            unit.imports.push(name);
+        }
+    }
+    unit
+}
+
+fn base_name(name: &str) -> &str {
+    name.split(':').next().unwrap_or(name)
+}
+
+/// Module declarations for every scanned source, plus which file provides
+/// each module name.
+#[derive(Debug, Default)]
+pub struct ModuleGraph {
+    units: HashMap<PathBuf, ModuleUnit>,
+    providers: HashMap<String, PathBuf>,
+}
+
+impl ModuleGraph {
+    pub fn insert(&mut self, path: PathBuf, unit: ModuleUnit) {
+        if !unit.is_module_unit() {
+            return;
+        }
+        if let Some(name) = &unit.provides {
+            self.providers.insert(name.clone(), path.clone());
+        }
+        self.units.insert(path, unit);
+    }
+
+    pub fn unit(&self, path: &Path) -> Option<&ModuleUnit> {
+        self.units.get(path)
+    }
+
+    /// Files providing the modules imported by `path`.  Imports not provided
+    /// by the project (e.g. `import std;`) are left for the compiler to
+    /// resolve.
+    pub fn providers_of(&self, path: &Path) -> Vec<PathBuf> {
+        self.unit(path)
+            .map(|u| {
+                u.imports
+                    .iter()
+                    .filter_map(|name| self.providers.get(name))
+                    .filter(|p| p.as_path() != path)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// Directory holding the BMIs for a profile.
+pub fn cache_dir(root: &Path, profile: &str) -> PathBuf {
+    root.join("target").join(profile).join("modules")
+}
+
+/// Compiler families with module support, each needing different flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModuleCompiler {
+    Clang,
+    /// GCC 14 or newer with `-fmodules-ts`.  GCC writes BMIs into
+    /// `gcm.cache/` under its working directory, so module units are compiled
+    /// from inside the module cache directory.
+    Gcc,
+}
+
+/// How long `detect` waits for each answer of the compiler.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// First GCC release whose module implementation is usable in practice.
+const MIN_GCC_MAJOR: u32 = 14;
+
+impl ModuleCompiler {
+    /// Work out which module flavour `cxx` speaks, failing for compilers that
+    /// cannot build named modules.
+    pub fn detect(cxx: &str) -> Result<Self, String> {
+        let probe = |arg: &str| {
+            let request = ExecRequest {
+                program: cxx.to_string(),
+                args: vec![arg.to_string()],
+                timeout: Some(PROBE_TIMEOUT),
+                capture: true,
+                ..ExecRequest::default()
+            };
+            executor::execute(&request)
+                .map(|output| output.stdout)
+                .map_err(|e| e.to_string())
+        };
+        if probe("--version")?.contains("clang") {
+            return Ok(ModuleCompiler::Clang);
+        }
+        let version = probe("-dumpversion")?;
+        let major: u32 = version
+            .trim()
+            .split('.')
+            .next()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0);
+        if major >= MIN_GCC_MAJOR {
+            Ok(ModuleCompiler::Gcc)
+        } else {
+            Err(format!(
+                "C++20 modules need clang or gcc >= {}, but {} is gcc {}; \
+                 set `compiler = \"clang\"` or disable `modules` in buildy.toml",
+                MIN_GCC_MAJOR,
+                cxx,
+                version.trim()
+            ))
+        }
+    }
+
+    /// Extra flags for compiling `path`; the `-x` language selection must
+    /// come before the source file on the command line.
+    pub fn flags(&self, path: &Path, unit: &ModuleUnit, cache_dir: &Path) -> Vec<String> {
+        let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+        let is_interface_ext = INTERFACE_EXTS.contains(&ext);
+        let mut flags = Vec::new();
+        match self {
+            ModuleCompiler::Clang => {
+                if ext == "ixx" {
+                    flags.extend(["-x".to_string(), "c++-module".to_string()]);
+                }
+                if let Some(name) = &unit.provides {
+                    // clang looks partitions up as `module-part.pcm`
+                    let bmi = cache_dir.join(format!("{}.pcm", name.replace(':', "-")));
+                    flags.push(format!("-fmodule-output={}", bmi.display()));
+                }
+                flags.push(format!("-fprebuilt-module-path={}", cache_dir.display()));
+            }
+            ModuleCompiler::Gcc => {
+                flags.push("-fmodules-ts".into());
+                if is_interface_ext {
+                    flags.extend(["-x".to_string(), "c++".to_string()]);
+                }
+            }
+        }
+        flags
+    }
+
+    /// Directory the compiler has to run in, if it matters.
+    pub fn working_dir(&self, cache_dir: &Path) -> Option<PathBuf> {
+        match self {
+            ModuleCompiler::Clang => None,
+            ModuleCompiler::Gcc => Some(cache_dir.to_path_buf()),
+        }
+    }
+}
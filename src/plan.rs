@@ -0,0 +1,645 @@
+use crate::cache::BuildCache;
+use crate::graph::BuildGraph;
+use crate::lang::{Language, is_source};
+use crate::scheduler::{self, CompileCommand};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Why a file has to be rebuilt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DirtyReason {
+    /// Its contents differ from the cache, or it is new.
+    Changed,
+    /// Its dependency scan failed, so what it includes is unknown.
+    ScanFailed,
+    /// The compiler, flags or compiler environment changed.
+    ConfigChanged,
+    /// Its compile command differs from the one it was last compiled with,
+    /// although the configuration as a whole didn't change (or it was
+    /// compiled by a Buildy that didn't record commands).
+    CommandChanged,
+    /// Its language's flags (`common_flags` and `cflags` or `cxxflags`)
+    /// changed.
+    LanguageFlagsChanged(Language),
+    /// Its object file is missing.
+    MissingObject,
+    /// Its object file is older than the source, and `check_object_mtime`
+    /// is set.
+    StaleObject,
+    /// The build was run with `--force`.
+    Forced,
+    /// It includes (directly or indirectly) this file, which is dirty for
+    /// one of the other reasons.
+    Dependency(PathBuf),
+}
+
+impl fmt::Display for DirtyReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DirtyReason::Changed => write!(f, "changed"),
+            DirtyReason::ScanFailed => write!(f, "dependency scan failed"),
+            DirtyReason::ConfigChanged => write!(f, "compiler or flags changed"),
+            DirtyReason::CommandChanged => write!(f, "compile command changed"),
+            DirtyReason::LanguageFlagsChanged(language) => {
+                write!(f, "{} changed", language.flags_key())
+            }
+            DirtyReason::MissingObject => write!(f, "object file missing"),
+            DirtyReason::StaleObject => write!(f, "object file older than the source"),
+            DirtyReason::Forced => write!(f, "rebuild forced"),
+            DirtyReason::Dependency(path) => write!(f, "depends on {}", path.display()),
+        }
+    }
+}
+
+impl DirtyReason {
+    /// Like `Display`, with the path of a dependency relative to `root`.
+    pub fn describe(&self, root: &Path) -> String {
+        match self {
+            DirtyReason::Dependency(path) => {
+                format!("depends on {}", BuildCache::make_relative(path, root))
+            }
+            other => other.to_string(),
+        }
+    }
+}
+
+/// Whether `object` was last written before `source`; `None` when there is
+/// no object.  An unreadable time counts as newer.
+fn object_is_older(object: &Path, source: &Path) -> Option<bool> {
+    let written = fs::metadata(object).ok()?.modified().ok();
+    let edited = fs::metadata(source).and_then(|m| m.modified()).ok();
+    Some(matches!((written, edited), (Some(w), Some(e)) if w < e))
+}
+
+/// Settings that changed since the cache was written, which make sources
+/// dirty whatever their own hashes say.
+#[derive(Debug, Default)]
+pub struct Invalidated {
+    /// The compiler, the flags of every source or the compiler's
+    /// environment: every source is dirty.
+    pub config: bool,
+    /// Languages whose configured flags changed.  Their sources are dirty
+    /// through their compile commands already; this only names the reason.
+    pub languages: Vec<Language>,
+    /// `--force`: every file is dirty.
+    pub force: bool,
+    /// `check_object_mtime`: objects older than their source don't count.
+    pub check_mtimes: bool,
+}
+
+/// Everything a build has to do, decided up front in one pass over the
+/// graph: which files are dirty and why, and the order the dirty sources
+/// are compiled in.  Whether to link is only known after compiling, since a
+/// recompile can produce an identical object (see `scheduler::link_digest`).
+#[derive(Debug, Default)]
+pub struct DirtyPlan {
+    /// Every dirty file, sources and headers.
+    pub reasons: BTreeMap<PathBuf, DirtyReason>,
+    /// Dirty sources in compile order: every source comes after the module
+    /// interface units it imports.
+    pub order: Vec<PathBuf>,
+    /// Combined hash of every source (see `command_hash`), recorded in the
+    /// cache once the source compiles.
+    pub hashes: HashMap<PathBuf, String>,
+    /// A dependency cycle among the dirty files, which makes the order
+    /// impossible: the files walked from the first one caught in it, ending
+    /// with the first file repeated.
+    pub cycle: Option<Vec<PathBuf>>,
+}
+
+impl DirtyPlan {
+    /// Work out the plan from the graph's current hashes and the compile
+    /// `commands` of its sources.  A source is dirty when its combined hash
+    /// differs from the one recorded when it last compiled; headers only
+    /// matter through that hash, so a header edited and then changed back
+    /// before the next build leaves its dependents alone.  See `Invalidated`
+    /// for settings that dirty more.
+    pub fn compute(
+        graph: &BuildGraph,
+        cache: &BuildCache,
+        root: &Path,
+        profile: &str,
+        invalidated: &Invalidated,
+        commands: &[CompileCommand],
+    ) -> Self {
+        let hashes: HashMap<PathBuf, String> = commands
+            .iter()
+            .map(|c| (c.source.clone(), command_hash(c, graph)))
+            .collect();
+        let mut reasons = BTreeMap::new();
+        for (path, meta) in &graph.nodes {
+            let reason = if graph.scan_failures.iter().any(|f| &f.path == path) {
+                DirtyReason::ScanFailed
+            } else if invalidated.force {
+                DirtyReason::Forced
+            } else if !is_source(path) {
+                if cache.file_matches(meta, root) {
+                    continue;
+                }
+                DirtyReason::Changed
+            } else if !hashes
+                .get(path)
+                .is_some_and(|h| cache.command_matches(path, root, h))
+            {
+                // name whatever went into the hash that changed
+                if !cache.file_matches(meta, root) {
+                    DirtyReason::Changed
+                } else if let Some(header) = graph.inputs(path).into_iter().find(|p| {
+                    graph
+                        .nodes
+                        .get(p)
+                        .is_some_and(|m| !cache.file_matches(m, root))
+                }) {
+                    DirtyReason::Dependency(header)
+                } else if invalidated.config {
+                    DirtyReason::ConfigChanged
+                } else if let Some(language) =
+                    Language::of(path).filter(|l| invalidated.languages.contains(l))
+                {
+                    DirtyReason::LanguageFlagsChanged(language)
+                } else {
+                    DirtyReason::CommandChanged
+                }
+            } else if invalidated.config {
+                DirtyReason::ConfigChanged
+            } else {
+                let object = scheduler::object_path_for(path, root, profile);
+                match object_is_older(&object, path) {
+                    None => DirtyReason::MissingObject,
+                    Some(true) if invalidated.check_mtimes => DirtyReason::StaleObject,
+                    Some(_) => continue,
+                }
+            };
+            reasons.insert(path.clone(), reason);
+        }
+
+        // importers of a dirty module interface unit are dirty too, since its
+        // BMI is rebuilt; blamed on the unit they were reached from first
+        let mut queue: VecDeque<(PathBuf, PathBuf)> = reasons
+            .keys()
+            .filter(|path| is_source(path))
+            .map(|path| (path.clone(), path.clone()))
+            .collect();
+        while let Some((path, origin)) = queue.pop_front() {
+            let Some(meta) = graph.nodes.get(&path) else {
+                continue;
+            };
+            for dependent in &meta.dependents {
+                if !reasons.contains_key(dependent) {
+                    reasons.insert(dependent.clone(), DirtyReason::Dependency(origin.clone()));
+                    queue.push_back((dependent.clone(), origin.clone()));
+                }
+            }
+        }
+
+        let (order, cycle) = compile_order(graph, &reasons);
+        let plan = DirtyPlan {
+            reasons,
+            order,
+            hashes,
+            cycle,
+        };
+        if cfg!(debug_assertions) {
+            plan.check_invariants(graph);
+        }
+        plan
+    }
+
+    pub fn is_dirty(&self, path: &Path) -> bool {
+        self.reasons.contains_key(path)
+    }
+
+    /// The plan without the sources `skip` accepts in its compile order.
+    /// They stay dirty, so the build forgets their cache entries.
+    pub fn excluding(&self, skip: impl Fn(&Path) -> bool) -> DirtyPlan {
+        DirtyPlan {
+            reasons: self.reasons.clone(),
+            order: self.order.iter().filter(|p| !skip(p)).cloned().collect(),
+            hashes: self.hashes.clone(),
+            cycle: self.cycle.clone(),
+        }
+    }
+
+    /// Set the graph's dirty flags to match the plan.
+    pub fn apply(&self, graph: &mut BuildGraph) {
+        for (path, meta) in graph.nodes.iter_mut() {
+            meta.dirty = self.is_dirty(path);
+        }
+    }
+
+    /// Panic if the plan contradicts itself or the graph.
+    fn check_invariants(&self, graph: &BuildGraph) {
+        let mut seen = BTreeSet::new();
+        for path in &self.order {
+            assert!(
+                self.is_dirty(path),
+                "{} scheduled but clean",
+                path.display()
+            );
+            assert!(seen.insert(path), "{} scheduled twice", path.display());
+        }
+        for path in self.reasons.keys() {
+            assert!(
+                !is_source(path) || seen.contains(path),
+                "dirty source {} not scheduled",
+                path.display()
+            );
+            if is_source(path)
+                && let Some(meta) = graph.nodes.get(path)
+            {
+                for dependent in &meta.dependents {
+                    assert!(
+                        self.is_dirty(dependent),
+                        "{} is clean but depends on dirty {}",
+                        dependent.display(),
+                        path.display()
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Hash of everything a compile depends on: the command line and the
+/// contents of the source and every header it includes.
+fn command_hash(command: &CompileCommand, graph: &BuildGraph) -> String {
+    let inputs = graph.inputs(&command.source);
+    command.digest(inputs.iter().map(|input| {
+        let hash = graph.nodes.get(input).map_or("", |m| m.hash.as_str());
+        (input.as_path(), hash)
+    }))
+}
+
+/// Carry the combined hashes recorded before the cache switched hash
+/// algorithm (see `BuildCache::previous_hash`) over to the new one: a
+/// source whose recorded hash is what its command and the old hashes of
+/// its inputs give is recorded with the hash the new ones give, so it
+/// isn't recompiled.  Run before `DirtyPlan::compute`.
+pub fn migrate_command_hashes(
+    graph: &BuildGraph,
+    cache: &mut BuildCache,
+    root: &Path,
+    commands: &[CompileCommand],
+) {
+    for command in commands {
+        let inputs = graph.inputs(&command.source);
+        let mut previous = Vec::with_capacity(inputs.len());
+        for input in &inputs {
+            match cache.previous_hash(input, root) {
+                Some(hash) => previous.push((input.as_path(), hash)),
+                None => break,
+            }
+        }
+        if previous.len() == inputs.len()
+            && cache.command_matches(&command.source, root, &command.digest(previous))
+        {
+            let hash = command_hash(command, graph);
+            cache.record_command(&command.source, root, hash);
+        }
+    }
+}
+
+/// Topological order of the dirty sources (Kahn's algorithm over the dirty
+/// subgraph); ties are broken by path so the order is deterministic.
+/// Sources caught in or behind a cycle (module units importing each other,
+/// or sources including each other) come last, and one cycle is returned.
+fn compile_order(
+    graph: &BuildGraph,
+    dirty: &BTreeMap<PathBuf, DirtyReason>,
+) -> (Vec<PathBuf>, Option<Vec<PathBuf>>) {
+    let mut pending: HashMap<&PathBuf, BTreeSet<&PathBuf>> = HashMap::new();
+    let mut dependents: HashMap<&PathBuf, Vec<&PathBuf>> = HashMap::new();
+    for path in dirty.keys() {
+        let deps: BTreeSet<&PathBuf> = graph
+            .nodes
+            .get(path)
+            .map(|node| {
+                node.deps
+                    .iter()
+                    .filter(|d| dirty.contains_key(*d))
+                    .collect()
+            })
+            .unwrap_or_default();
+        for dep in &deps {
+            dependents.entry(*dep).or_default().push(path);
+        }
+        pending.insert(path, deps);
+    }
+
+    let mut ready: BTreeSet<&PathBuf> = pending
+        .iter()
+        .filter(|(_, deps)| deps.is_empty())
+        .map(|(path, _)| *path)
+        .collect();
+    let mut order = Vec::new();
+    while let Some(path) = ready.pop_first() {
+        pending.remove(path);
+        order.push(path.clone());
+        for dependent in dependents.get(path).into_iter().flatten() {
+            if let Some(deps) = pending.get_mut(dependent) {
+                deps.remove(path);
+                if deps.is_empty() {
+                    ready.insert(dependent);
+                }
+            }
+        }
+    }
+    let cycle = find_cycle(&pending);
+    let mut cyclic: Vec<PathBuf> = pending.into_keys().cloned().collect();
+    cyclic.sort();
+    order.extend(cyclic);
+    order.retain(|p| is_source(p));
+    (order, cycle)
+}
+
+/// A cycle among the files Kahn's algorithm couldn't order.  Each of them
+/// still waits on at least one other, so following the first such dependency
+/// from any of them must eventually revisit a file.
+fn find_cycle(pending: &HashMap<&PathBuf, BTreeSet<&PathBuf>>) -> Option<Vec<PathBuf>> {
+    let mut current = *pending.keys().min()?;
+    let mut walk: Vec<&PathBuf> = Vec::new();
+    while !walk.contains(&current) {
+        walk.push(current);
+        current = pending.get(current)?.first()?;
+    }
+    walk.push(current);
+    Some(walk.into_iter().cloned().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::target::FileMeta;
+    use crate::toolchain::Toolchain;
+    use chrono::Utc;
+
+    /// Random graphs checked per property.
+    const CASES: u64 = 300;
+
+    /// A random acyclic graph under a temp root: node `i` may only depend
+    /// on nodes before it.  Headers include headers; sources include
+    /// headers and import other sources, as module units do.  Every file
+    /// is recorded in the cache as compiled with its current hashes, and
+    /// every source has its object.
+    struct Fixture {
+        _dir: tempfile::TempDir,
+        root: PathBuf,
+        graph: BuildGraph,
+        cache: BuildCache,
+        commands: Vec<CompileCommand>,
+    }
+
+    impl Fixture {
+        fn random(rng: &mut fastrand::Rng) -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let root = dir.path().to_path_buf();
+            let paths: Vec<PathBuf> = (0..rng.usize(2..40))
+                .map(|i| match rng.bool() {
+                    true => root.join(format!("src/f{}.c", i)),
+                    false => root.join(format!("include/f{}.h", i)),
+                })
+                .collect();
+            let mut graph = BuildGraph::new();
+            for (i, path) in paths.iter().enumerate() {
+                let deps = paths[..i]
+                    .iter()
+                    .filter(|dep| is_source(path) || !is_source(dep))
+                    .filter(|_| rng.u8(..) < 50)
+                    .cloned()
+                    .collect();
+                graph.nodes.insert(path.clone(), node(path, deps));
+            }
+            link_dependents(&mut graph);
+
+            let commands: Vec<CompileCommand> = paths
+                .iter()
+                .filter(|p| is_source(p))
+                .map(|source| command(source, &root))
+                .collect();
+            let mut cache = BuildCache::default();
+            for meta in graph.nodes.values() {
+                cache.update_file(meta, &root);
+            }
+            for command in &commands {
+                cache.record_command(&command.source, &root, command_hash(command, &graph));
+                fs::create_dir_all(command.object.parent().unwrap()).unwrap();
+                fs::write(&command.object, "").unwrap();
+            }
+            Fixture {
+                _dir: dir,
+                root,
+                graph,
+                cache,
+                commands,
+            }
+        }
+
+        fn plan(&self, invalidated: &Invalidated) -> DirtyPlan {
+            DirtyPlan::compute(
+                &self.graph,
+                &self.cache,
+                &self.root,
+                "debug",
+                invalidated,
+                &self.commands,
+            )
+        }
+    }
+
+    fn node(path: &Path, deps: Vec<PathBuf>) -> FileMeta {
+        FileMeta {
+            path: path.to_path_buf(),
+            hash: format!("hash of {}", path.display()),
+            last_modified: Utc::now(),
+            size: 0,
+            deps,
+            dependents: Vec::new(),
+            dirty: false,
+        }
+    }
+
+    fn link_dependents(graph: &mut BuildGraph) {
+        let edges: Vec<(PathBuf, PathBuf)> = graph
+            .nodes
+            .values()
+            .flat_map(|meta| meta.deps.iter().map(|dep| (dep.clone(), meta.path.clone())))
+            .collect();
+        for meta in graph.nodes.values_mut() {
+            meta.dependents.clear();
+        }
+        for (dep, dependent) in edges {
+            graph
+                .nodes
+                .get_mut(&dep)
+                .unwrap()
+                .dependents
+                .push(dependent);
+        }
+    }
+
+    fn command(source: &Path, root: &Path) -> CompileCommand {
+        let object = scheduler::object_path_for(source, root, "debug");
+        CompileCommand {
+            // not on PATH, so no version is probed
+            program: "buildy-test-cc".into(),
+            wrapper: None,
+            args: vec!["-c".into(), source.display().to_string()],
+            toolchain: Toolchain::Gnu,
+            directory: root.to_path_buf(),
+            source: source.to_path_buf(),
+            object,
+            dep_file: None,
+        }
+    }
+
+    /// Edit a random subset of the files, returning them.
+    fn edit_some(fixture: &mut Fixture, rng: &mut fastrand::Rng) -> BTreeSet<PathBuf> {
+        let mut edited = BTreeSet::new();
+        for meta in fixture.graph.nodes.values_mut() {
+            if rng.u8(..) < 40 {
+                meta.hash = "edited".into();
+                edited.insert(meta.path.clone());
+            }
+        }
+        edited
+    }
+
+    #[test]
+    fn dirty_files_are_those_reaching_an_edit() {
+        for seed in 0..CASES {
+            let mut rng = fastrand::Rng::with_seed(seed);
+            let mut fixture = Fixture::random(&mut rng);
+            let edited = edit_some(&mut fixture, &mut rng);
+            let plan = fixture.plan(&Invalidated::default());
+            let graph = &fixture.graph;
+
+            for (path, meta) in &graph.nodes {
+                let expected = if is_source(path) {
+                    graph.inputs(path).iter().any(|p| edited.contains(p))
+                } else {
+                    edited.contains(path)
+                };
+                assert_eq!(
+                    plan.is_dirty(path),
+                    expected,
+                    "seed {}: {} dirty: {:?}",
+                    seed,
+                    path.display(),
+                    plan.reasons.get(path)
+                );
+                // nothing compiled that depends on a dirty file stays clean
+                if plan.is_dirty(path) {
+                    let mut queue = meta.dependents.clone();
+                    let mut seen = BTreeSet::new();
+                    while let Some(dependent) = queue.pop() {
+                        if seen.insert(dependent.clone()) {
+                            assert!(
+                                !is_source(&dependent) || plan.is_dirty(&dependent),
+                                "seed {}: {} is clean but depends on dirty {}",
+                                seed,
+                                dependent.display(),
+                                path.display()
+                            );
+                            queue.extend(graph.nodes[&dependent].dependents.iter().cloned());
+                        }
+                    }
+                }
+            }
+            assert_eq!(plan.cycle, None, "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn compile_order_is_topological() {
+        for seed in 0..CASES {
+            let mut rng = fastrand::Rng::with_seed(seed);
+            let mut fixture = Fixture::random(&mut rng);
+            edit_some(&mut fixture, &mut rng);
+            let plan = fixture.plan(&Invalidated::default());
+
+            let position: HashMap<&PathBuf, usize> =
+                plan.order.iter().enumerate().map(|(i, p)| (p, i)).collect();
+            assert_eq!(position.len(), plan.order.len(), "seed {}: repeats", seed);
+            let dirty_sources: BTreeSet<&PathBuf> =
+                plan.reasons.keys().filter(|p| is_source(p)).collect();
+            let ordered: BTreeSet<&PathBuf> = plan.order.iter().collect();
+            assert_eq!(ordered, dirty_sources, "seed {}", seed);
+            for source in &plan.order {
+                for input in fixture.graph.inputs(source) {
+                    if &input != source
+                        && let Some(&before) = position.get(&input)
+                    {
+                        assert!(
+                            before < position[source],
+                            "seed {}: {} compiled before its dependency {}",
+                            seed,
+                            source.display(),
+                            input.display()
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn reports_an_injected_cycle() {
+        let mut checked = 0;
+        for seed in 0..CASES {
+            let mut rng = fastrand::Rng::with_seed(seed);
+            let mut fixture = Fixture::random(&mut rng);
+            // a file and something it depends on, which is made to depend
+            // on it in turn
+            let Some((from, to)) = fixture
+                .graph
+                .nodes
+                .values()
+                .filter_map(|meta| Some((meta.path.clone(), meta.deps.first()?.clone())))
+                .min()
+            else {
+                continue;
+            };
+            let deepest = fixture
+                .graph
+                .inputs(&to)
+                .into_iter()
+                .find(|p| p != &to && rng.bool())
+                .unwrap_or(to);
+            fixture
+                .graph
+                .nodes
+                .get_mut(&deepest)
+                .unwrap()
+                .deps
+                .push(from.clone());
+            link_dependents(&mut fixture.graph);
+
+            let force = Invalidated {
+                force: true,
+                ..Invalidated::default()
+            };
+            let plan = fixture.plan(&force);
+            let cycle = plan
+                .cycle
+                .unwrap_or_else(|| panic!("seed {}: no cycle reported", seed));
+            assert!(cycle.len() >= 3, "seed {}: {:?}", seed, cycle);
+            assert_eq!(cycle.first(), cycle.last(), "seed {}", seed);
+            for pair in cycle.windows(2) {
+                assert!(
+                    fixture.graph.nodes[&pair[0]].deps.contains(&pair[1]),
+                    "seed {}: {} doesn't depend on {}",
+                    seed,
+                    pair[0].display(),
+                    pair[1].display()
+                );
+            }
+            // the sources involved are still compiled, last
+            let sources = fixture.graph.nodes.keys().filter(|p| is_source(p)).count();
+            assert_eq!(plan.order.len(), sources, "seed {}", seed);
+            checked += 1;
+        }
+        assert!(checked > CASES / 2);
+    }
+}
@@ -0,0 +1,141 @@
+use crate::config::Config;
+use crate::toolchain::Toolchain;
+
+/// Profiles every project has.  `[profiles.<name>]` in `buildy.toml`
+/// adjusts them or defines more.
+pub const BUILTIN_PROFILES: [&str; 4] = ["debug", "release", "asan", "coverage"];
+
+/// What the objects of a build are compiled and linked with.  Each profile
+/// builds into `target/<name>/`, which also holds its cache, so switching
+/// profiles never invalidates another profile's objects.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    pub name: String,
+    /// Compile flags besides the optimization level.
+    pub cflags: Vec<String>,
+    /// Link flags, after the configured `ldflags`.
+    pub ldflags: Vec<String>,
+    /// Optimization level (`0` to `3`, `s`, ...), see `Toolchain::opt_flag`;
+    /// `None` leaves it to the compiler.
+    pub opt: Option<String>,
+    /// Macros defined when scanning and compiling, `NAME` or `NAME=VALUE`.
+    /// Unlike `cflags` they only go into the compile commands, so changing
+    /// them recompiles the sources without dropping the rest of the cache.
+    pub defines: Vec<String>,
+}
+
+impl Profile {
+    /// The profile called `name` with no flags, to be filled in by
+    /// `resolve` once the configuration is loaded.
+    pub fn named(name: &str) -> Self {
+        Profile {
+            name: name.to_string(),
+            cflags: Vec::new(),
+            ldflags: Vec::new(),
+            opt: None,
+            defines: Vec::new(),
+        }
+    }
+
+    /// The profile called `name`: the built-in one with the settings of
+    /// `[profiles.<name>]` on top, or one defined there from scratch.
+    pub fn resolve(name: &str, config: &Config) -> Result<Self, String> {
+        check_name(name)?;
+        let table = config.profiles.get(name);
+        let mut profile = match (builtin(name, config.toolchain()), table) {
+            (Some(profile), _) => profile,
+            (None, Some(_)) => Profile::named(name),
+            (None, None) => {
+                return Err(format!(
+                    "unknown profile `{}` (known: {})",
+                    name,
+                    names(config).join(", ")
+                ));
+            }
+        };
+        if let Some(table) = table {
+            if let Some(cflags) = &table.cflags {
+                profile.cflags = cflags.clone();
+            }
+            if let Some(ldflags) = &table.ldflags {
+                profile.ldflags = ldflags.clone();
+            }
+            if let Some(opt) = &table.opt {
+                profile.opt = Some(opt.to_string());
+            }
+            if let Some(defines) = &table.defines {
+                profile.defines = defines.clone();
+            }
+        }
+        Ok(profile)
+    }
+
+    /// Flags every source is compiled with: the optimization level, then
+    /// `cflags`.
+    pub fn compile_flags(&self, toolchain: Toolchain) -> Vec<String> {
+        self.opt
+            .iter()
+            .map(|level| toolchain.opt_flag(level))
+            .chain(self.cflags.iter().cloned())
+            .collect()
+    }
+
+    /// Whether the profile optimizes, as release does.
+    pub fn is_optimized(&self) -> bool {
+        self.opt.as_deref().is_some_and(|level| level != "0")
+    }
+}
+
+/// The built-in profile `name` for `toolchain`.  There is no `coverage`
+/// profile for MSVC, which has no `--coverage` equivalent.
+fn builtin(name: &str, toolchain: Toolchain) -> Option<Profile> {
+    let debug = toolchain.debug_flag();
+    let (cflags, ldflags, opt): (Vec<&str>, Vec<&str>, _) = match (name, toolchain) {
+        ("debug", _) => (vec![debug], vec![], None),
+        ("release", _) => (vec![], vec![], Some("3")),
+        ("asan", Toolchain::Gnu) => (
+            vec![debug, "-fsanitize=address", "-fno-omit-frame-pointer"],
+            vec!["-fsanitize=address"],
+            None,
+        ),
+        ("asan", Toolchain::Msvc) => (vec![debug, "/fsanitize=address"], vec![], None),
+        ("coverage", Toolchain::Gnu) => (vec!["--coverage"], vec!["--coverage"], None),
+        _ => return None,
+    };
+    let owned = |flags: Vec<&str>| flags.into_iter().map(String::from).collect();
+    Some(Profile {
+        name: name.to_string(),
+        cflags: owned(cflags),
+        ldflags: owned(ldflags),
+        opt: opt.map(String::from),
+        defines: Vec::new(),
+    })
+}
+
+/// Every profile `config` knows: the built-in ones and those of
+/// `[profiles]`, sorted.
+pub fn names(config: &Config) -> Vec<String> {
+    let mut names: Vec<String> = BUILTIN_PROFILES
+        .iter()
+        .map(|name| name.to_string())
+        .chain(config.profiles.keys().cloned())
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Refuse profile names that wouldn't make a plain directory name under
+/// `target/`.
+pub fn check_name(name: &str) -> Result<(), String> {
+    let valid = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if name.is_empty() || !valid || name.starts_with('-') {
+        return Err(format!(
+            "invalid profile name `{}`: use letters, digits, `-` and `_`",
+            name
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,76 @@
+use crate::messages::{self, MessageFormat};
+use std::io::{self, IsTerminal, Write};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Erases the current terminal line.
+const CLEAR_LINE: &str = "\r\x1b[K";
+
+/// The `[ 37/214] compiling src/foo.c` counter of a build, shared by the
+/// parallel compiles.  On a terminal it is a single line rewritten as each
+/// compile starts; piped, in JSON mode or with `-v` every compile gets a
+/// line of its own.
+pub struct Progress {
+    total: usize,
+    /// What is being done to each file, "compiling" or "checking".
+    verb: &'static str,
+    started: AtomicUsize,
+    live: bool,
+    messages: MessageFormat,
+    /// Held while writing, so the line and compiler output don't mix.
+    lock: Mutex<()>,
+}
+
+impl Progress {
+    pub fn new(total: usize, verb: &'static str, messages: MessageFormat, verbose: u8) -> Self {
+        Progress {
+            total,
+            verb,
+            started: AtomicUsize::new(0),
+            live: messages == MessageFormat::Human
+                && verbose == 0
+                && !messages::quiet()
+                && messages::reporter().is_none()
+                && io::stdout().is_terminal(),
+            messages,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Count the compile of `file` (relative to the root) as started.
+    pub fn start(&self, file: &str) {
+        let n = self.started.fetch_add(1, Ordering::SeqCst) + 1;
+        let width = self.total.to_string().len();
+        let line = format!("[{:>width$}/{}] {} {}", n, self.total, self.verb, file);
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        if self.live {
+            let mut stdout = io::stdout().lock();
+            let _ = write!(stdout, "{}{}", CLEAR_LINE, line);
+            let _ = stdout.flush();
+        } else {
+            self.messages.status(line);
+        }
+    }
+
+    /// Print `text` (compiler output) on stderr, clearing the progress line
+    /// first so the text doesn't start in the middle of it.
+    pub fn print_above(&self, text: &str) {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        self.clear_line();
+        messages::warn(text);
+    }
+
+    /// Remove the progress line once the compiles are done.
+    pub fn finish(&self) {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        self.clear_line();
+    }
+
+    fn clear_line(&self) {
+        if self.live {
+            let mut stdout = io::stdout().lock();
+            let _ = write!(stdout, "{}", CLEAR_LINE);
+            let _ = stdout.flush();
+        }
+    }
+}
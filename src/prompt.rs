@@ -0,0 +1,113 @@
+use rustyline::completion::{Completer, FilenameCompleter, Pair};
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Helper};
+use std::path::{Path, PathBuf};
+
+/// Lines kept in the repl history.
+pub const HISTORY_SIZE: usize = 1000;
+
+/// Commands the repl handles itself rather than through the `Cli` parser.
+const REPL_COMMANDS: [&str; 10] = [
+    "auto", "autorun", "close", "env", "exit", "help", "jobs", "rescan", "set", "show",
+];
+
+/// Where the repl history of the project at `root` is kept.
+pub fn history_path(root: &Path) -> PathBuf {
+    root.join("target").join(".buildy").join("history")
+}
+
+/// Tab completion for the watch repl: command names at the start of the
+/// line, the long flags of the command being typed (taken from the clap
+/// definition of the CLI, so they never go stale), and file paths for
+/// `explain`.
+pub struct ReplHelper {
+    command: clap::Command,
+    files: FilenameCompleter,
+}
+
+impl ReplHelper {
+    /// Completion for the commands and flags of `command`.
+    pub fn new(command: clap::Command) -> Self {
+        ReplHelper {
+            command,
+            files: FilenameCompleter::new(),
+        }
+    }
+
+    fn command_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .command
+            .get_subcommands()
+            .map(|c| c.get_name().to_string())
+            .chain(REPL_COMMANDS.iter().map(|c| c.to_string()))
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// `--flag` spellings accepted by `subcommand`, global flags included.
+    fn flags(&self, subcommand: &str) -> Vec<String> {
+        let global = self.command.get_arguments();
+        let own = self
+            .command
+            .find_subcommand(subcommand)
+            .into_iter()
+            .flat_map(|c| c.get_arguments());
+        let mut flags: Vec<String> = own
+            .chain(global)
+            .filter(|a| !a.is_hide_set())
+            .filter_map(|a| a.get_long())
+            .map(|long| format!("--{}", long))
+            .collect();
+        flags.sort();
+        flags.dedup();
+        flags
+    }
+}
+
+impl Completer for ReplHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let before = &line[..pos];
+        let start = before.rfind(char::is_whitespace).map_or(0, |i| i + 1);
+        let word = &before[start..];
+        let first = before.split_whitespace().next().unwrap_or("");
+        let candidates = if start == 0 || before[..start].trim().is_empty() {
+            self.command_names()
+        } else if word.starts_with('-') {
+            self.flags(first)
+        } else if first == "explain" {
+            return self.files.complete(line, pos, ctx);
+        } else {
+            Vec::new()
+        };
+        let pairs = candidates
+            .into_iter()
+            .filter(|c| c.starts_with(word))
+            .map(|c| Pair {
+                display: c.clone(),
+                replacement: c,
+            })
+            .collect();
+        Ok((start, pairs))
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ReplHelper {}
+
+impl Validator for ReplHelper {}
+
+impl Helper for ReplHelper {}
@@ -0,0 +1,216 @@
+use crate::report::BuildReport;
+use schemars::{JsonSchema, Schema, SchemaGenerator, json_schema, schema_for};
+use serde::Serialize;
+
+/// Version of the JSON that buildy writes for other programs: the lines of
+/// `--message-format json`, `graph --format json` and the timings report.
+/// Every document and event carries it as `schema_version`.  It is bumped
+/// whenever one of the types below changes in a way readers could notice,
+/// and `--message-format json@<previous>` keeps the old shape for one
+/// version.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// A document or event as written: `schema_version` first, then the fields
+/// of `body`.
+#[derive(Serialize, JsonSchema)]
+pub struct Versioned<T> {
+    #[schemars(schema_with = "version_schema")]
+    pub schema_version: u32,
+    #[serde(flatten)]
+    pub body: T,
+}
+
+impl<T: Serialize> Versioned<T> {
+    /// `body` at the current `SCHEMA_VERSION`.
+    pub fn new(body: T) -> Self {
+        Versioned {
+            schema_version: SCHEMA_VERSION,
+            body,
+        }
+    }
+}
+
+fn version_schema(_: &mut SchemaGenerator) -> Schema {
+    json_schema!({ "type": "integer", "const": SCHEMA_VERSION })
+}
+
+/// A machine-readable event of `--message-format json`.  Files are relative
+/// to the project root, artifacts are absolute.
+#[derive(Serialize, JsonSchema)]
+#[serde(tag = "reason", rename_all = "kebab-case")]
+pub enum Message<'a> {
+    CompileStart {
+        file: &'a str,
+    },
+    CompileFinish {
+        file: &'a str,
+        success: bool,
+        duration_ms: u64,
+    },
+    /// What the compiler printed for `file`, warnings or errors.
+    Diagnostic {
+        file: &'a str,
+        stderr: &'a str,
+    },
+    /// The end of a build, with its counts unless it failed before
+    /// compiling anything.
+    BuildFinished {
+        success: bool,
+        artifacts: Vec<String>,
+        #[serde(flatten)]
+        report: Option<&'a BuildReport>,
+    },
+}
+
+impl Message<'_> {
+    /// The JSON line for the message, `schema_version` first.
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(&Versioned::new(self))
+    }
+}
+
+/// The dependency graph of `buildy graph --format json`, with paths
+/// relative to the root and everything sorted so two exports of the same
+/// tree are identical.
+#[derive(Serialize, JsonSchema)]
+pub struct Graph {
+    pub nodes: Vec<GraphNode>,
+    pub edges: Vec<GraphEdge>,
+    /// Headers left out by `--max-fanout`.
+    pub collapsed: Vec<CollapsedHeader>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct GraphNode {
+    pub path: String,
+    pub kind: NodeKind,
+    pub dirty: bool,
+    /// Why the file is dirty, as `buildy explain` puts it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeKind {
+    Source,
+    Header,
+}
+
+/// `from` includes or imports `to`.
+#[derive(Serialize, JsonSchema, PartialEq, Eq, PartialOrd, Ord)]
+pub struct GraphEdge {
+    pub from: String,
+    pub to: String,
+}
+
+/// A header with more dependents than `--max-fanout`, shown as one node.
+#[derive(Serialize, JsonSchema, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CollapsedHeader {
+    pub path: String,
+    pub dependents: usize,
+}
+
+/// Where the time of a build went, printed with `--timings` and written to
+/// `target/.buildy/timings/<profile>.json`.
+#[derive(Serialize, JsonSchema)]
+pub struct Timings {
+    /// Compiles that ran, slowest first.
+    pub compiles: Vec<CompileTime>,
+    pub link_ms: u64,
+    pub total_ms: u64,
+    /// Last known compile time of every source that didn't have to be
+    /// compiled (up to date, or reused from the object store).
+    pub saved_ms: u64,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct CompileTime {
+    /// Relative to the project root.
+    pub file: String,
+    pub ms: u64,
+}
+
+/// The documents `buildy schema` describes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Document {
+    /// A line of `--message-format json`
+    Message,
+    /// The output of `graph --format json`
+    Graph,
+    /// A timings report
+    Timings,
+}
+
+impl Document {
+    /// The JSON Schema of the document, `schema_version` included.
+    pub fn schema(self) -> Schema {
+        let (mut schema, title) = match self {
+            Document::Message => (schema_for!(Versioned<Message<'static>>), "Message"),
+            Document::Graph => (schema_for!(Versioned<Graph>), "Graph"),
+            Document::Timings => (schema_for!(Versioned<Timings>), "Timings"),
+        };
+        // named after the document rather than the envelope
+        schema.insert("title".into(), title.into());
+        schema.remove("description");
+        schema
+    }
+
+    /// `schema` as printed by `buildy schema`.
+    pub fn schema_json(self) -> String {
+        let mut json = serde_json::to_string_pretty(&self.schema()).unwrap_or_default();
+        json.push('\n');
+        json
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    #[test]
+    fn every_line_carries_the_schema_version() {
+        let report = BuildReport {
+            compiled: vec![(PathBuf::from("src/util.c"), Duration::from_millis(41))],
+            clean: 12,
+            linked: vec!["app".into()],
+            duration: Duration::from_millis(230),
+            ..BuildReport::default()
+        };
+        let messages = [
+            Message::CompileStart { file: "src/util.c" },
+            Message::Diagnostic {
+                file: "src/util.c",
+                stderr: "src/util.c:3:5: warning: unused",
+            },
+            Message::CompileFinish {
+                file: "src/util.c",
+                success: true,
+                duration_ms: 41,
+            },
+            Message::BuildFinished {
+                success: true,
+                artifacts: vec!["/p/target/debug/app".into()],
+                report: Some(&report),
+            },
+            Message::BuildFinished {
+                success: false,
+                artifacts: Vec::new(),
+                report: None,
+            },
+        ];
+        let lines: Vec<String> = messages.iter().map(|m| m.to_json().unwrap()).collect();
+        assert_eq!(
+            lines,
+            [
+                r#"{"schema_version":1,"reason":"compile-start","file":"src/util.c"}"#,
+                r#"{"schema_version":1,"reason":"diagnostic","file":"src/util.c","stderr":"src/util.c:3:5: warning: unused"}"#,
+                r#"{"schema_version":1,"reason":"compile-finish","file":"src/util.c","success":true,"duration_ms":41}"#,
+                r#"{"schema_version":1,"reason":"build-finished","success":true,"artifacts":["/p/target/debug/app"],"compiled":1,"from_cache":0,"failed":0,"skipped":0,"clean":12,"warnings":0,"linked":["app"],"duration_ms":230}"#,
+                r#"{"schema_version":1,"reason":"build-finished","success":false,"artifacts":[]}"#,
+            ]
+        );
+    }
+}
@@ -0,0 +1,100 @@
+use schemars::JsonSchema;
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// What a build did: returned by `scheduler::build`, completed by the
+/// caller with the links, and printed as the one-line summary that ends
+/// every `build` and `run`.  `--message-format json` includes the counts in
+/// the `build-finished` message.
+#[derive(Debug, Clone, Default, Serialize, JsonSchema)]
+pub struct BuildReport {
+    /// Sources the compiler ran for, with how long each took.
+    #[serde(serialize_with = "count")]
+    #[schemars(with = "usize")]
+    pub compiled: Vec<(PathBuf, Duration)>,
+    /// Objects taken from the object store or pre-built by the watch repl
+    /// instead of compiling them.
+    pub from_cache: usize,
+    /// Sources that failed to compile.
+    pub failed: usize,
+    /// Sources not compiled because an earlier one failed: with
+    /// `--keep-going` those depending on a failed one, otherwise all that
+    /// hadn't started yet.
+    pub skipped: usize,
+    /// Sources that were up to date.
+    pub clean: usize,
+    /// Lines of the compiler output reporting a warning.  Objects taken
+    /// from the object store bring the output they were compiled with.
+    pub warnings: usize,
+    /// File names of the programs and libraries linked; those already up to
+    /// date aren't included.
+    pub linked: Vec<String>,
+    #[serde(rename = "duration_ms", serialize_with = "millis")]
+    #[schemars(with = "u64")]
+    pub duration: Duration,
+}
+
+impl BuildReport {
+    /// Objects the build produced, compiled or not.
+    pub fn objects(&self) -> usize {
+        self.compiled.len() + self.from_cache
+    }
+
+    /// Add the compiles of `other`, such as the unity buckets built after
+    /// the sources outside them.
+    pub fn merge(&mut self, other: BuildReport) {
+        self.compiled.extend(other.compiled);
+        self.from_cache += other.from_cache;
+        self.failed += other.failed;
+        self.skipped += other.skipped;
+        self.warnings += other.warnings;
+        self.duration += other.duration;
+    }
+}
+
+/// `built 14 objects (3 cached, 197 clean) and linked myproj in 4.2s`, with
+/// the warnings and failures after the counts when there are any.
+impl fmt::Display for BuildReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "built {} ({} cached, {} clean)",
+            plural(self.objects(), "object"),
+            self.from_cache,
+            self.clean
+        )?;
+        if self.warnings > 0 {
+            write!(f, ", {}", plural(self.warnings, "warning"))?;
+        }
+        if self.failed > 0 {
+            write!(f, ", {} failed", self.failed)?;
+        }
+        if self.skipped > 0 {
+            write!(f, ", {} skipped", self.skipped)?;
+        }
+        match self.linked.as_slice() {
+            [] => {}
+            [name] => write!(f, " and linked {}", name)?,
+            names => write!(f, " and linked {}", plural(names.len(), "target"))?,
+        }
+        write!(f, " in {:.1}s", self.duration.as_secs_f64())
+    }
+}
+
+fn plural(n: usize, noun: &str) -> String {
+    if n == 1 {
+        format!("1 {}", noun)
+    } else {
+        format!("{} {}s", n, noun)
+    }
+}
+
+fn count<S: Serializer>(compiled: &[(PathBuf, Duration)], s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_u64(compiled.len() as u64)
+}
+
+fn millis<S: Serializer>(duration: &Duration, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_u64(duration.as_millis() as u64)
+}
@@ -1,197 +1,2194 @@
+use crate::batch;
 use crate::cache::BuildCache;
+use crate::cancel;
+use crate::chaos::Chaos;
+use crate::config::{self, Config, TargetKind};
+use crate::depfile;
+use crate::error::BuildyError;
+use crate::flags::{FlagOrigin, FlagSet};
 use crate::graph::BuildGraph;
+use crate::hasher::hash_file;
+use crate::lang::{Language, is_source};
+use crate::messages::{self, Message, MessageFormat};
+use crate::modules::{self, ModuleCompiler};
+use crate::plan::DirtyPlan;
+use crate::profile::Profile;
+use crate::progress::Progress;
+use crate::report::BuildReport;
+use crate::speculate::{self, Staged};
+use crate::store::ObjectStore;
 use crate::target::FileMeta;
-use num_cpus;
+use crate::testing;
+use crate::toolchain::Toolchain;
+use crate::toolenv;
+use crate::unity::UnityBuild;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use walkdir::WalkDir;
 
-/// Simple scheduler that walks the topologically sorted order and compiles dirty
-/// nodes in parallel but respects dependency order.
+/// Options for a single build invocation, shared by the CLI and the repl.
+#[derive(Debug, Clone)]
+pub struct BuildOptions {
+    /// The profile to build, resolved against the configuration by
+    /// `Profile::resolve` before building.
+    pub profile: Profile,
+    /// Treat conflicting compiler flags as errors instead of warnings.
+    pub strict_flags: bool,
+    /// Fail the build when a dependency scan fails instead of warning.
+    pub strict_deps: bool,
+    /// Fail the build when a change to the include directories makes an
+    /// include resolve to a different file, instead of warning.
+    pub strict_includes: bool,
+    /// C compiler from the command line, overriding `buildy.toml` and `CC`.
+    pub compiler: Option<String>,
+    /// C++ compiler from the command line, overriding `buildy.toml` and
+    /// `CXX`.
+    pub cxx: Option<String>,
+    /// Compiler wrapper from the command line (`--wrapper`), overriding
+    /// `compiler_wrapper` in `buildy.toml`.
+    pub wrapper: Option<String>,
+    /// Print the compiler wrapper's statistics after building.
+    pub stats: bool,
+    /// Include directories given on the command line, in addition to the
+    /// ones from `buildy.toml`.
+    pub include_dirs: Vec<PathBuf>,
+    /// Macros defined on the command line (`--define`), after the
+    /// profile's `defines`.
+    pub defines: Vec<String>,
+    /// Libraries given on the command line, linked after the ones from
+    /// `buildy.toml`.
+    pub libs: Vec<String>,
+    /// Output name from the command line, overriding `name` in
+    /// `buildy.toml`.
+    pub name: Option<String>,
+    /// Compile every dirty file even after one fails, and report all errors.
+    pub keep_going: bool,
+    /// Parallel compile jobs from the command line; `Some(0)` means every
+    /// CPU and `None` defers to `buildy.toml` / `BUILDY_JOBS`.
+    pub jobs: Option<usize>,
+    /// Seed for injecting faults into compiles, see `Chaos`.
+    pub chaos: Option<u64>,
+    /// How progress is reported, see `MessageFormat`.
+    pub message_format: MessageFormat,
+    /// Print how long each compile and the link took, see `Timings`.
+    pub timings: bool,
+    /// Start ready compiles in path order rather than longest first.
+    pub no_reorder: bool,
+    /// Rescan, recompile and relink everything, trusting nothing recorded
+    /// by earlier builds and reusing no stored or staged object.
+    pub force: bool,
+    /// Relink every target even when `link_digest` matches the last link.
+    pub force_link: bool,
+    /// Hash every file, see `Config::hash_always`.
+    pub paranoid: bool,
+    /// `--deny-warnings` (`Some(true)`) or `--allow-warnings`, overriding
+    /// `Config::deny_warnings`.
+    pub deny_warnings: Option<bool>,
+    /// Compile in this many unity files, see `UnityBuild`; `Some(0)` means
+    /// one per compile job.
+    pub unity: Option<usize>,
+    /// 1 prints every compile and link command before running it, 2 also
+    /// why each source is or isn't compiled.
+    pub verbose: u8,
+}
+
+/// A debug build with nothing given on the command line.
+impl Default for BuildOptions {
+    fn default() -> Self {
+        BuildOptions {
+            profile: Profile::named("debug"),
+            strict_flags: false,
+            strict_deps: false,
+            strict_includes: false,
+            compiler: None,
+            cxx: None,
+            wrapper: None,
+            stats: false,
+            include_dirs: Vec::new(),
+            defines: Vec::new(),
+            libs: Vec::new(),
+            name: None,
+            keep_going: false,
+            jobs: None,
+            chaos: None,
+            message_format: MessageFormat::Human,
+            timings: false,
+            no_reorder: false,
+            force: false,
+            force_link: false,
+            paranoid: false,
+            deny_warnings: None,
+            unity: None,
+            verbose: 0,
+        }
+    }
+}
+
+impl BuildOptions {
+    /// Name of the active profile, which doubles as its directory under
+    /// `target/`.
+    pub fn profile_name(&self) -> &str {
+        &self.profile.name
+    }
+
+    /// `-I` arguments for the command-line include directories.
+    pub fn include_args(&self, root: &Path, toolchain: Toolchain) -> Vec<String> {
+        self.include_dirs
+            .iter()
+            .map(|dir| toolchain.include_flag(&root.join(dir)))
+            .collect()
+    }
+
+    /// `-D` arguments for the profile's `defines` and `--define`.
+    pub fn define_args(&self, toolchain: Toolchain) -> Vec<String> {
+        self.profile
+            .defines
+            .iter()
+            .chain(&self.defines)
+            .map(|define| toolchain.define_flag(define))
+            .collect()
+    }
+
+    /// What the dependency scan passes besides the configured flags: the
+    /// command-line include directories and the defines, since `#if` can
+    /// change which headers are included.
+    pub fn scan_args(&self, root: &Path, toolchain: Toolchain) -> Vec<String> {
+        let mut args = self.include_args(root, toolchain);
+        args.extend(self.define_args(toolchain));
+        args
+    }
+}
+
+/// The exact compiler invocation for one source file.  Shared by the
+/// scheduler and the `compile_commands.json` emitter so both always agree.
+#[derive(Debug, Clone)]
+pub struct CompileCommand {
+    pub program: String,
+    /// Compiler wrapper `program` runs under (see `compiler_wrapper`); not
+    /// part of `digest`, since it leaves the object as it is.
+    pub wrapper: Option<String>,
+    /// Arguments after `program`, including the source and `-o <object>`.
+    pub args: Vec<String>,
+    /// How `args` are spelled.
+    pub toolchain: Toolchain,
+    /// Directory the compiler runs in.
+    pub directory: PathBuf,
+    pub source: PathBuf,
+    pub object: PathBuf,
+    /// Dependency file the compile writes next to the object, see
+    /// `depfile::path_for`.
+    pub dep_file: Option<PathBuf>,
+}
+
+impl CompileCommand {
+    /// Hash of the command line and the compiler's version together with
+    /// the content hashes of its `inputs`, the source and the headers it
+    /// includes.
+    pub fn digest<'a>(&self, inputs: impl IntoIterator<Item = (&'a Path, &'a str)>) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.program.as_bytes());
+        hasher.update(b"\0");
+        hasher.update(toolenv::compiler_version(&self.program).as_bytes());
+        for arg in &self.args {
+            hasher.update(b"\0");
+            hasher.update(arg.as_bytes());
+        }
+        hasher.update(b"\0");
+        hasher.update(self.directory.to_string_lossy().as_bytes());
+        for (input, hash) in inputs {
+            hasher.update(format!("\n{}={}", input.display(), hash).as_bytes());
+        }
+        format!("{:x}", hasher.finalize())
+    }
+
+    fn to_command(&self) -> Command {
+        let mut cmd = match &self.wrapper {
+            Some(wrapper) => {
+                let mut cmd = Command::new(wrapper);
+                cmd.arg(&self.program);
+                cmd
+            }
+            None => Command::new(&self.program),
+        };
+        cmd.current_dir(&self.directory);
+        cmd.args(&self.args);
+        cmd
+    }
+
+    /// The command line, quoted so it can be pasted into a shell.
+    pub fn shell_line(&self) -> String {
+        shell_words::join(
+            self.wrapper
+                .iter()
+                .chain(std::iter::once(&self.program))
+                .chain(&self.args),
+        )
+    }
+
+    /// The same compile with `-fsyntax-only` instead of `-o <object>` (`/Zs`
+    /// with MSVC), so nothing is written.
+    fn syntax_only(&self) -> CompileCommand {
+        CompileCommand {
+            args: self.toolchain.syntax_only(&self.args),
+            // nothing for a cache to keep
+            wrapper: None,
+            ..self.clone()
+        }
+    }
+}
+
+/// A dirty file together with the command that compiles it.
+struct CompileJob {
+    meta: FileMeta,
+    command: CompileCommand,
+    /// Matching object compiled ahead of time by the watch repl.
+    staged: Option<Staged>,
+    /// Combined hash the object is kept under in the object store, with the
+    /// inputs it covers; `None` for module units, whose BMIs aren't stored.
+    key: Option<(String, Vec<PathBuf>)>,
+}
+
+/// How the compile jobs of a build were satisfied, other than by running
+/// the compiler.
+#[derive(Default)]
+struct Reused {
+    /// Objects pre-built by the watch repl.
+    promoted: AtomicUsize,
+    /// Objects linked from the object store.
+    stored: AtomicUsize,
+}
+
+/// Compile the sources `plan` lists, in parallel where its order allows, and
+/// record the results in `cache`.  The graph's dirty flags must already
+/// match the plan (see `DirtyPlan::apply`).  Returns what was compiled and
+/// reused, for the caller to complete with the links; a failed compile
+/// carries the counts in its error.
 pub fn build(
     graph: &mut BuildGraph,
     cache: &mut BuildCache,
     root: &std::path::Path,
-    is_debug: bool,
-) -> Result<bool, String> {
-    let mut need_link = false;
+    config: &Config,
+    opts: &BuildOptions,
+    pool: &rayon::ThreadPool,
+    plan: &DirtyPlan,
+) -> Result<BuildReport, BuildyError> {
+    let started = Instant::now();
+    let work: Vec<FileMeta> = plan
+        .order
+        .iter()
+        .filter_map(|path| graph.nodes.get(path).cloned())
+        .collect();
 
-    // compute a build order for the dirty subset; if nothing is dirty just return
-    let order = graph.topo_sort_dirty();
-    if order.is_empty() {
-        return Ok(false);
+    if cancel::requested() {
+        return Err(BuildyError::Interrupted);
     }
-
-    // gather metadata clones for the dirty ones
-    let mut work: Vec<FileMeta> = Vec::new();
-    for path in &order {
-        if let Some(meta) = graph.nodes.get(path) {
-            if meta.dirty {
-                work.push(meta.clone());
-            }
-        }
+    if let Some(cycle) = &plan.cycle {
+        return Err(cycle_error(cycle, root));
     }
 
     if work.is_empty() {
         // nothing to compile
-        for meta in graph.nodes.values() {
-            cache.update_file(meta, root);
-        }
-        return Ok(false);
-    }
-
-    // create a thread pool using rayon
-    let cpus = num_cpus::get();
-    let pool = rayon::ThreadPoolBuilder::new()
-        .num_threads(cpus)
-        .build()
-        .map_err(|e| e.to_string())?;
-
-    let built = Arc::new(Mutex::new(Vec::new()));
-    let error_flag = Arc::new(std::sync::atomic::AtomicBool::new(false));
-    pool.scope(|s| {
-        for meta in work {
-            let built_clone = built.clone();
-            let err_flag = error_flag.clone();
-            s.spawn(move |_| {
-                if err_flag.load(std::sync::atomic::Ordering::Relaxed) {
-                    // somebody already failed, bail out
-                    return;
-                }
-                if let Err(e) = compile_file(&meta, root, is_debug) {
-                    eprintln!("Error compiling {}: {}", meta.path.display(), e);
-                    err_flag.store(true, std::sync::atomic::Ordering::Relaxed);
-                    return;
-                }
-                built_clone.lock().unwrap().push(meta.path.clone());
-            });
+        record_clean(graph, cache, root);
+        return Ok(BuildReport::default());
+    }
+
+    let module_compiler = module_compiler(root, config, opts).map_err(BuildyError::compile)?;
+
+    // warning classes suppressed through `overrides`, with how many of the
+    // files being compiled suppress each, so they show up in the output
+    let mut suppressed: BTreeMap<String, usize> = BTreeMap::new();
+    let mut suppressing_files = 0;
+    for meta in &work {
+        let mut any = false;
+        for entry in config.overrides_for(&meta.path, root) {
+            for warning in &entry.suppress {
+                *suppressed.entry(warning.clone()).or_default() += 1;
+                any = true;
+            }
         }
-    });
+        suppressing_files += any as usize;
+    }
 
-    let built_obj_files = built.lock().unwrap();
-    if !built_obj_files.is_empty() {
-        need_link = true;
+    // validate the final flag set of every file before spawning any compiler;
+    // identical conflicts are reported once rather than once per file
+    let mut reported = HashSet::new();
+    let mut refused = false;
+    let speculating = speculate::staging_dir(root, opts.profile_name()).is_dir();
+    let store = ObjectStore::new(root);
+    let mut jobs: Vec<CompileJob> = Vec::new();
+    for meta in work {
+        let (command, flags) = compile_command(graph, &meta, root, config, opts, module_compiler)
+            .map_err(BuildyError::compile)?;
+        for conflict in flags.conflicts() {
+            let msg = conflict.to_string();
+            if reported.insert(msg.clone()) {
+                // a `[file]` override wins on purpose, so it only warns
+                let level = if opts.strict_flags && !conflict.is_override() {
+                    refused = true;
+                    "error"
+                } else {
+                    "warning"
+                };
+                messages::warn(format!("{}: {}", level, msg));
+            }
+        }
+        let is_module_unit = module_compiler.is_some() && graph.modules.unit(&meta.path).is_some();
+        let hash = plan.hashes.get(&meta.path);
+        let staged = hash
+            .filter(|_| speculating && !opts.force)
+            .and_then(|key| Staged::find(root, opts.profile_name(), key));
+        let key = hash
+            .filter(|_| !is_module_unit)
+            .map(|hash| (hash.clone(), graph.inputs(&meta.path)));
+        jobs.push(CompileJob {
+            meta,
+            command,
+            staged,
+            key,
+        });
+    }
+    if refused {
+        return Err(BuildyError::compile(
+            "conflicting compiler flags (--strict-flags)".into(),
+        ));
     }
 
-    if error_flag.load(std::sync::atomic::Ordering::Relaxed) {
-        // abort build, keep dirty flags as they were
-        return Err("compile failed".into());
+    start_order(&mut jobs, |job| &job.meta, cache, root, opts);
+    let total = jobs.len();
+    let reused = Reused::default();
+    let chaos = opts.chaos.map(Chaos::new);
+    if let Some(chaos) = &chaos {
+        messages::warn(format!("chaos mode: seed {}", chaos.seed()));
     }
+    let chaos = chaos.as_ref();
+    let progress = Progress::new(total, "compiling", opts.message_format, opts.verbose);
+    let warnings = AtomicUsize::new(0);
+    let batches = batch_jobs(&jobs, graph, cache, root, config.batch_size);
+    let (built, errors) =
+        run_batches(
+            &jobs,
+            &batches,
+            graph,
+            pool,
+            opts.keep_going,
+            |batch| match batch {
+                [job] => vec![run_job(job, root, opts, &progress, &warnings, || {
+                    compile_file(job, opts, &store, &reused, chaos)
+                })],
+                batch => run_batch(batch, root, opts, &progress, &warnings, || {
+                    compile_batch(batch, root, opts, &store, &reused, chaos)
+                }),
+            },
+        );
+    progress.finish();
 
-    // mark compiled metas as clean and update cache
-    for p in built_obj_files.iter() {
+    // mark compiled metas as clean and update cache, even if other files
+    // failed, so the next build only retries the failures; the object hash
+    // lets a recompile that produced identical bytes skip the link
+    for (p, duration) in &built {
         if let Some(m) = graph.nodes.get_mut(p) {
             m.dirty = false;
             cache.update_file(m, root);
+            if let Some(duration) = duration {
+                cache.record_duration(p, root, duration.as_millis() as u64);
+            }
+            let obj = object_path_for(p, root, opts.profile_name());
+            if let Ok(hash) = hash_file(&obj) {
+                cache.record_object(p, root, hash);
+            }
+            if let Some(hash) = plan.hashes.get(p) {
+                cache.record_command(p, root, hash.clone());
+            }
         }
     }
+    let compiled: Vec<PathBuf> = built.iter().map(|(p, _)| p.clone()).collect();
+    graph.reload_deps(&compiled, root, config, opts.profile_name());
+
+    record_clean(graph, cache, root);
+
+    if cancel::requested() {
+        return Err(BuildyError::Interrupted);
+    }
+    let promoted = reused.promoted.into_inner();
+    let report = compile_report(
+        built,
+        promoted + reused.stored.into_inner(),
+        warnings.into_inner(),
+        started,
+    );
+    if !errors.is_empty() {
+        return Err(compile_failure(errors, total, report, opts.keep_going));
+    }
+
+    let messages = opts.message_format;
+    if promoted > 0 {
+        messages.status(format!("{} object(s) pre-built speculatively", promoted));
+    }
+    if suppressing_files > 0 {
+        let classes: Vec<String> = suppressed
+            .iter()
+            .map(|(warning, files)| format!("{} ({})", warning, files))
+            .collect();
+        messages.status(format!(
+            "note: {} file(s) compiled with suppressed warnings: {}",
+            suppressing_files,
+            classes.join(", ")
+        ));
+    }
+    Ok(report)
+}
 
-    // also update cache for others (for example, header timestamps)
+/// The report of the jobs that succeeded, `reused` of them without running
+/// the compiler, and whose output had `warnings` warnings.
+fn compile_report(
+    built: Vec<(PathBuf, Option<Duration>)>,
+    reused: usize,
+    warnings: usize,
+    started: Instant,
+) -> BuildReport {
+    BuildReport {
+        compiled: built
+            .into_iter()
+            .filter_map(|(path, duration)| Some((path, duration?)))
+            .collect(),
+        from_cache: reused,
+        warnings,
+        duration: started.elapsed(),
+        ..BuildReport::default()
+    }
+}
+
+/// Update the cache for the files not compiled (for example, header
+/// timestamps), except for the inputs of sources that still have to be
+/// compiled.  Those sources are forgotten outright: they may have failed
+/// because of a flag change, which is only detected once, or been compiled
+/// into a unity file rather than their own object.
+fn record_clean(graph: &BuildGraph, cache: &mut BuildCache, root: &Path) {
+    let pending = pending_inputs(graph);
     for meta in graph.nodes.values() {
-        cache.update_file(meta, root);
+        if !pending.contains(&meta.path) {
+            cache.update_file(meta, root);
+        } else if meta.dirty && is_source(&meta.path) {
+            cache.invalidate(&meta.path, root);
+        }
+    }
+}
+
+/// Compile the buckets of a `--unity` build that changed since they were
+/// last compiled, after `build` has compiled the sources outside them:
+/// those whose members, the headers they include or the command line
+/// differ from the key recorded in `cache`, and those without an object.
+/// The report counts the buckets rather than their members.
+pub fn build_unity(
+    unity: &UnityBuild,
+    graph: &BuildGraph,
+    cache: &mut BuildCache,
+    root: &Path,
+    config: &Config,
+    opts: &BuildOptions,
+    pool: &rayon::ThreadPool,
+) -> Result<BuildReport, BuildyError> {
+    let started = Instant::now();
+    let mut jobs = Vec::new();
+    for bucket in &unity.buckets {
+        bucket
+            .write_source()
+            .map_err(|e| BuildyError::compile(format!("{}: {}", bucket.source.display(), e)))?;
+        let mut flags = compile_flags(&bucket.source, root, config, opts);
+        flags.dedup();
+        let toolchain = config.toolchain();
+        let command = CompileCommand {
+            program: config.compiler_for(&bucket.source),
+            wrapper: config.compiler_wrapper.clone(),
+            args: toolchain.compile_args(flags.args(), &bucket.source, &bucket.object),
+            toolchain,
+            directory: root.to_path_buf(),
+            source: bucket.source.clone(),
+            object: bucket.object.clone(),
+            dep_file: None,
+        };
+        let mut inputs: Vec<PathBuf> = bucket
+            .members
+            .iter()
+            .flat_map(|member| graph.inputs(member))
+            .chain([bucket.source.clone()])
+            .collect();
+        inputs.sort();
+        inputs.dedup();
+        let key = speculate::job_key(&command, &inputs).ok_or_else(|| {
+            BuildyError::compile(format!(
+                "could not read the members of {}",
+                bucket.source.display()
+            ))
+        })?;
+        if !opts.force && bucket.object.exists() && cache.unity.get(&bucket.name()) == Some(&key) {
+            continue;
+        }
+        let meta = FileMeta::new(bucket.source.clone())
+            .map_err(|e| BuildyError::compile(format!("{}: {}", bucket.source.display(), e)))?;
+        jobs.push(CompileJob {
+            meta,
+            command,
+            staged: None,
+            key: Some((key, inputs)),
+        });
+    }
+    // buckets of deleted sources, or of a build with more buckets
+    unity.remove_stale();
+    let names: HashSet<String> = unity.buckets.iter().map(|b| b.name()).collect();
+    cache.unity.retain(|name, _| names.contains(name));
+    if jobs.is_empty() || cancel::requested() {
+        return Ok(BuildReport::default());
+    }
+
+    let total = jobs.len();
+    let store = ObjectStore::new(root);
+    let reused = Reused::default();
+    let progress = Progress::new(total, "compiling", opts.message_format, opts.verbose);
+    let warnings = AtomicUsize::new(0);
+    let (built, errors) = run_ready(&jobs, graph, pool, opts.keep_going, |job| {
+        run_job(job, root, opts, &progress, &warnings, || {
+            compile_file(job, opts, &store, &reused, None)
+        })
+    });
+    progress.finish();
+    for job in &jobs {
+        if let Some((key, _)) = &job.key
+            && built.iter().any(|(path, _)| *path == job.meta.path)
+        {
+            let name = job.meta.path.file_name().unwrap_or_default();
+            cache
+                .unity
+                .insert(name.to_string_lossy().to_string(), key.clone());
+        }
+    }
+
+    if cancel::requested() {
+        return Err(BuildyError::Interrupted);
+    }
+    let report = compile_report(
+        built,
+        reused.stored.into_inner(),
+        warnings.into_inner(),
+        started,
+    );
+    if !errors.is_empty() {
+        let mut error = compile_failure(errors, total, report, opts.keep_going);
+        if let BuildyError::CompileFailed { message, .. } = &mut error {
+            message.push_str(
+                "\n\nnote: --unity compiles several sources as one, so static functions \
+                 and variables defined under the same name in two of them collide; \
+                 build without --unity to compile the sources apart",
+            );
+        }
+        return Err(error);
     }
+    Ok(report)
+}
 
-    Ok(need_link)
+/// The error of a dependency cycle among the files to compile.
+fn cycle_error(cycle: &[PathBuf], root: &Path) -> BuildyError {
+    let names: Vec<String> = cycle
+        .iter()
+        .map(|p| BuildCache::make_relative(p, root))
+        .collect();
+    BuildyError::ScanError(format!(
+        "dependency cycle, these files can't be compiled in any order: {}",
+        names.join(" -> ")
+    ))
 }
 
-/// compile a single source file into an object file using gcc/g++ based on
-/// extension.  The object file will reside next to the source with a .o
-/// extension.  Current simplistic command; flags and include paths should be
-/// provided by the graph/config.
-fn compile_file(meta: &FileMeta, root: &Path, is_debug: bool) -> Result<(), String> {
-    let profile_dir = if is_debug { "debug" } else { "release" };
-    let target_dir = root.join("target").join(profile_dir);
+/// The error of `total` jobs of which those in `report` succeeded and
+/// `errors` failed; with `keep_going` its message also counts the failed
+/// and skipped ones.
+fn compile_failure(
+    errors: Vec<Failure>,
+    total: usize,
+    mut report: BuildReport,
+    keep_going: bool,
+) -> BuildyError {
+    // jobs that were already running when the first one failed may have
+    // failed too
+    let (files, errors): (Vec<PathBuf>, Vec<String>) = errors.into_iter().unzip();
+    let skipped = total - report.objects() - errors.len();
+    report.failed = errors.len();
+    report.skipped = skipped;
+    let mut message = errors.join("\n\n");
+    if keep_going {
+        message.push_str(&format!("\n\n{} of {} files failed", errors.len(), total));
+        if skipped > 0 {
+            message.push_str(&format!(
+                ", {} not compiled because they depend on a failed one",
+                skipped
+            ));
+        }
+    }
+    BuildyError::CompileFailed {
+        files,
+        message,
+        report: Box::new(report),
+    }
+}
 
-    std::fs::create_dir_all(&target_dir).map_err(|e| e.to_string())?;
+/// Check the dirty sources of `plan` for `buildy check`: compile them in
+/// parallel with `-fsyntax-only`, so no object is written and nothing is
+/// linked.  The cache is left alone, so the next build still compiles them.
+/// Failures are reported like `build` reports them.  Returns how many
+/// sources were checked.
+pub fn check(
+    graph: &BuildGraph,
+    cache: &BuildCache,
+    root: &Path,
+    config: &Config,
+    opts: &BuildOptions,
+    pool: &rayon::ThreadPool,
+    plan: &DirtyPlan,
+) -> Result<usize, BuildyError> {
+    let started = Instant::now();
+    if cancel::requested() {
+        return Err(BuildyError::Interrupted);
+    }
+    if let Some(cycle) = &plan.cycle {
+        return Err(cycle_error(cycle, root));
+    }
+    let module_compiler = module_compiler(root, config, opts).map_err(BuildyError::compile)?;
+    let mut jobs: Vec<CompileJob> = Vec::new();
+    for path in &plan.order {
+        let Some(meta) = graph.nodes.get(path).cloned() else {
+            continue;
+        };
+        let (command, _) = compile_command(graph, &meta, root, config, opts, module_compiler)
+            .map_err(BuildyError::compile)?;
+        jobs.push(CompileJob {
+            meta,
+            command: command.syntax_only(),
+            staged: None,
+            key: None,
+        });
+    }
+    start_order(&mut jobs, |job| &job.meta, cache, root, opts);
+    let progress = Progress::new(jobs.len(), "checking", opts.message_format, opts.verbose);
+    let warnings = AtomicUsize::new(0);
+    let (checked, errors) = run_ready(&jobs, graph, pool, opts.keep_going, |job| {
+        run_job(job, root, opts, &progress, &warnings, || {
+            check_file(job, opts)
+        })
+    });
+    progress.finish();
+    if cancel::requested() {
+        return Err(BuildyError::Interrupted);
+    }
+    let count = checked.len();
+    if !errors.is_empty() {
+        let report = compile_report(checked, 0, warnings.into_inner(), started);
+        return Err(compile_failure(errors, jobs.len(), report, opts.keep_going));
+    }
+    Ok(count)
+}
 
-    let file_stem = meta.path.file_stem().ok_or("invalid file name")?;
-    let obj_path = target_dir.join(file_stem).with_extension("o");
+/// Order `jobs` so the ones that took longest to compile last time start
+/// first and a slow file doesn't finish alone at the end of the build.
+/// Files never compiled before are assumed to take the median time.  Ties
+/// go to files more others wait for, then to the path.
+fn longest_first<T>(
+    jobs: &mut [T],
+    meta: impl Fn(&T) -> &FileMeta,
+    cache: &BuildCache,
+    root: &Path,
+) {
+    let mut known: Vec<u64> = jobs
+        .iter()
+        .filter_map(|job| cache.compile_ms(&meta(job).path, root))
+        .collect();
+    known.sort_unstable();
+    let median = known.get(known.len() / 2).copied().unwrap_or(0);
+    jobs.sort_by_cached_key(|job| {
+        let meta = meta(job);
+        let ms = cache.compile_ms(&meta.path, root).unwrap_or(median);
+        (
+            std::cmp::Reverse(ms),
+            std::cmp::Reverse(meta.dependents.len()),
+            meta.path.clone(),
+        )
+    });
+}
 
-    let mut cmd = if meta.path.extension().and_then(|s| s.to_str()) == Some("c") {
-        Command::new("gcc")
+/// Put `jobs` in the order they are started: the slowest first, or by path
+/// with `--no-reorder`.
+fn start_order<T>(
+    jobs: &mut [T],
+    meta: impl Fn(&T) -> &FileMeta,
+    cache: &BuildCache,
+    root: &Path,
+    opts: &BuildOptions,
+) {
+    if opts.no_reorder {
+        // independent jobs start in path order, which makes `--jobs 1`
+        // deterministic
+        jobs.sort_by(|a, b| meta(a).path.cmp(&meta(b).path));
     } else {
-        Command::new("g++")
+        longest_first(jobs, meta, cache, root);
+    }
+}
+
+/// The commands `build` would run for the dirty sources of `plan`, in the
+/// order it would start them, without running anything (`build --dry-run`).
+/// Objects that `build` would take from the object store or the watch repl
+/// are listed as compiles too.
+pub fn planned_compiles(
+    graph: &BuildGraph,
+    cache: &BuildCache,
+    root: &Path,
+    config: &Config,
+    opts: &BuildOptions,
+    plan: &DirtyPlan,
+) -> Result<Vec<CompileCommand>, String> {
+    let module_compiler = module_compiler(root, config, opts)?;
+    let mut jobs: Vec<(&FileMeta, CompileCommand)> = Vec::new();
+    for path in &plan.order {
+        if let Some(meta) = graph.nodes.get(path) {
+            let (command, _) = compile_command(graph, meta, root, config, opts, module_compiler)?;
+            jobs.push((meta, command));
+        }
+    }
+    start_order(&mut jobs, |(meta, _)| meta, cache, root, opts);
+    Ok(jobs.into_iter().map(|(_, command)| command).collect())
+}
+
+/// Group `jobs`, in the order they start, into batches of at most
+/// `batch_size` compiled by one run of the compiler (see `batch::key`);
+/// the other jobs, and all of them without a `batch_size` above 1, are
+/// batches of their own.  Jobs depending on or awaited by another job,
+/// objects pre-built by the watch repl and sources that took longer than
+/// `batch::HEAVY_MS` to compile last time aren't batched.
+fn batch_jobs(
+    jobs: &[CompileJob],
+    graph: &BuildGraph,
+    cache: &BuildCache,
+    root: &Path,
+    batch_size: Option<usize>,
+) -> Vec<Vec<usize>> {
+    let size = batch_size.unwrap_or(1);
+    let paths: HashSet<&Path> = jobs.iter().map(|job| job.meta.path.as_path()).collect();
+    // jobs another job waits for, and those waiting for another
+    let mut awaited: HashSet<PathBuf> = HashSet::new();
+    let mut waiting: HashSet<&Path> = HashSet::new();
+    for job in jobs {
+        let path = &job.meta.path;
+        for input in graph.inputs(path) {
+            if input != *path && paths.contains(input.as_path()) {
+                awaited.insert(input);
+                waiting.insert(path);
+            }
+        }
+    }
+    let mut batches: Vec<Vec<usize>> = Vec::new();
+    // the batch being filled for each key, with the names in it
+    let mut open: HashMap<String, (usize, HashSet<String>)> = HashMap::new();
+    for (i, job) in jobs.iter().enumerate() {
+        let path = &job.meta.path;
+        let key = batch::key(&job.command).filter(|_| {
+            size > 1
+                && job.key.is_some()
+                && job.staged.is_none()
+                && !awaited.contains(path)
+                && !waiting.contains(path.as_path())
+                && cache
+                    .compile_ms(path, root)
+                    .is_none_or(|ms| ms <= batch::HEAVY_MS)
+        });
+        let Some(key) = key else {
+            batches.push(vec![i]);
+            continue;
+        };
+        match open.get_mut(&key) {
+            Some((b, names)) if batch::fits(names, path) => {
+                batches[*b].push(i);
+                names.insert(batch::stem(path));
+                if batches[*b].len() == size {
+                    open.remove(&key);
+                }
+            }
+            _ => {
+                open.insert(key, (batches.len(), HashSet::from([batch::stem(path)])));
+                batches.push(vec![i]);
+            }
+        }
+    }
+    batches
+}
+
+/// A source that failed to compile, with the error.
+type Failure = (PathBuf, String);
+
+/// Run `jobs` on `pool` through `run`, each once every job compiling one
+/// of its inputs (a module interface unit it imports) has succeeded.  Among
+/// the jobs that are ready, earlier ones start first.  After a failure the
+/// jobs depending on it are skipped, and without `keep_going` nothing new
+/// starts; after Ctrl-C nothing new starts either.  Returns the sources
+/// compiled with what `run` returned for them, and the sources that failed
+/// with their errors.
+fn run_ready<T, F>(
+    jobs: &[CompileJob],
+    graph: &BuildGraph,
+    pool: &rayon::ThreadPool,
+    keep_going: bool,
+    run: F,
+) -> (Vec<(PathBuf, T)>, Vec<Failure>)
+where
+    T: Send,
+    F: Fn(&CompileJob) -> Result<T, String> + Sync,
+{
+    let batches: Vec<Vec<usize>> = (0..jobs.len()).map(|i| vec![i]).collect();
+    run_batches(jobs, &batches, graph, pool, keep_going, |batch| {
+        batch.iter().map(|job| run(job)).collect()
+    })
+}
+
+/// `run_ready` handing `run` the jobs of one of `batches` at a time, which
+/// returns a result for each.  Every job is in one batch, and a batch
+/// starts once all of its jobs are ready; batches are ordered by their
+/// first job.
+fn run_batches<T, F>(
+    jobs: &[CompileJob],
+    batches: &[Vec<usize>],
+    graph: &BuildGraph,
+    pool: &rayon::ThreadPool,
+    keep_going: bool,
+    run: F,
+) -> (Vec<(PathBuf, T)>, Vec<Failure>)
+where
+    T: Send,
+    F: Fn(&[&CompileJob]) -> Vec<Result<T, String>> + Sync,
+{
+    let index: HashMap<&Path, usize> = jobs
+        .iter()
+        .enumerate()
+        .map(|(i, job)| (job.meta.path.as_path(), i))
+        .collect();
+    let mut batch_of = vec![0usize; jobs.len()];
+    for (b, batch) in batches.iter().enumerate() {
+        for &i in batch {
+            batch_of[i] = b;
+        }
+    }
+    // batches waiting for each job
+    let mut waiting = vec![0usize; batches.len()];
+    let mut dependents: Vec<Vec<usize>> = vec![Vec::new(); jobs.len()];
+    for (i, job) in jobs.iter().enumerate() {
+        for input in graph.inputs(&job.meta.path) {
+            if let Some(&dep) = index.get(input.as_path())
+                && dep != i
+            {
+                waiting[batch_of[i]] += 1;
+                dependents[dep].push(batch_of[i]);
+            }
+        }
+    }
+    let mut ready: BTreeSet<usize> = (0..batches.len()).filter(|&b| waiting[b] == 0).collect();
+    let mut started = vec![false; batches.len()];
+    let mut blocked = vec![false; batches.len()];
+    let mut built = Vec::new();
+    let mut errors = Vec::new();
+    let stop = AtomicBool::new(false);
+    let (tx, rx) = mpsc::channel();
+    // the loop runs on this thread, so even a single worker is free to
+    // compile while it waits for results
+    pool.in_place_scope_fifo(|s| {
+        let mut running = 0;
+        loop {
+            if !stop.load(Ordering::Relaxed) && !cancel::requested() {
+                while let Some(b) = ready.pop_first() {
+                    started[b] = true;
+                    running += 1;
+                    let members: Vec<&CompileJob> = batches[b].iter().map(|&i| &jobs[i]).collect();
+                    let (tx, run, stop) = (tx.clone(), &run, &stop);
+                    s.spawn_fifo(move |_| {
+                        // jobs queued before a failure don't start anymore
+                        let results = (!stop.load(Ordering::Relaxed) && !cancel::requested())
+                            .then(|| run(&members));
+                        let _ = tx.send((b, results));
+                    });
+                }
+            }
+            if running == 0 {
+                break;
+            }
+            let Ok((b, results)) = rx.recv() else {
+                break;
+            };
+            running -= 1;
+            for (&i, result) in batches[b].iter().zip(results.into_iter().flatten()) {
+                match result {
+                    Ok(value) => {
+                        built.push((jobs[i].meta.path.clone(), value));
+                        for &dependent in &dependents[i] {
+                            waiting[dependent] -= 1;
+                            if waiting[dependent] == 0 && !started[dependent] && !blocked[dependent]
+                            {
+                                ready.insert(dependent);
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        errors.push((jobs[i].meta.path.clone(), e));
+                        if !keep_going {
+                            stop.store(true, Ordering::Relaxed);
+                        }
+                        let mut queue = dependents[i].clone();
+                        while let Some(dependent) = queue.pop() {
+                            if !std::mem::replace(&mut blocked[dependent], true) {
+                                ready.remove(&dependent);
+                                for &j in &batches[dependent] {
+                                    queue.extend(&dependents[j]);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    });
+    (built, errors)
+}
+
+/// Sources still marked dirty after a build, plus everything they include
+/// directly or indirectly.  Their cache entries must stay outdated so the
+/// next build compiles those sources again.
+fn pending_inputs(graph: &BuildGraph) -> HashSet<PathBuf> {
+    let mut pending = HashSet::new();
+    let mut queue: Vec<PathBuf> = graph
+        .nodes
+        .iter()
+        .filter(|(path, meta)| meta.dirty && is_source(path))
+        .map(|(path, _)| path.clone())
+        .collect();
+    while let Some(path) = queue.pop() {
+        if pending.insert(path.clone())
+            && let Some(meta) = graph.nodes.get(&path)
+        {
+            queue.extend(meta.deps.iter().cloned());
+        }
+    }
+    pending
+}
+
+/// Assemble the flags used to compile a file, tagged with where each one came
+/// from so conflicts can be reported meaningfully.
+fn compile_flags(path: &Path, root: &Path, config: &Config, opts: &BuildOptions) -> FlagSet {
+    let mut flags = FlagSet::new();
+    let profile = FlagOrigin::Profile(opts.profile_name().to_string());
+    let toolchain = config.toolchain();
+    for f in opts.profile.compile_flags(toolchain) {
+        flags.push(f, profile.clone());
+    }
+
+    let language = Language::of(path).unwrap_or(Language::Cxx);
+    if language != Language::Asm {
+        for name in &config.warnings {
+            flags.push(
+                toolchain.warning_flag(name),
+                FlagOrigin::Config("warnings".into()),
+            );
+        }
+        let deny = match opts.deny_warnings {
+            Some(true) => Some(FlagOrigin::Cli("--deny-warnings".into())),
+            Some(false) => None,
+            None if config.deny_warnings => Some(FlagOrigin::Config("deny_warnings".into())),
+            None => None,
+        };
+        if let Some(origin) = deny {
+            flags.push(toolchain.deny_warnings_flag(), origin);
+        }
+    }
+    for f in config.common_flags_for(language) {
+        flags.push(f.clone(), FlagOrigin::Config("common_flags".into()));
+    }
+    let key = Config::language_key(path);
+    for f in config.language_flags(path) {
+        flags.push(f.clone(), FlagOrigin::Config(key.into()));
+    }
+    for (origin, f) in config.dir_flags(path, root) {
+        flags.push(f.clone(), FlagOrigin::Config(origin));
+    }
+    for f in config.include_args(root) {
+        flags.push(f, FlagOrigin::Config("include_dirs".into()));
+    }
+    for f in opts.include_args(root, toolchain) {
+        flags.push(f, FlagOrigin::Cli("--include".into()));
+    }
+    for define in &opts.profile.defines {
+        flags.push(
+            toolchain.define_flag(define),
+            FlagOrigin::Profile(opts.profile_name().to_string()),
+        );
+    }
+    for define in &opts.defines {
+        flags.push(
+            toolchain.define_flag(define),
+            FlagOrigin::Cli("--define".into()),
+        );
+    }
+    // sources are passed as canonical absolute paths, so strip the canonical
+    // root to make `__FILE__` root-relative; diagnostics keep full paths
+    let origin = match config.relative_file_macro {
+        Some(_) => FlagOrigin::Config("relative_file_macro".into()),
+        None => profile,
     };
+    if toolchain.is_gnu()
+        && config
+            .relative_file_macro
+            .unwrap_or(opts.profile.is_optimized())
+    {
+        let root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+        flags.push(
+            format!(
+                "-fmacro-prefix-map={}{}=",
+                root.display(),
+                std::path::MAIN_SEPARATOR
+            ),
+            origin,
+        );
+    }
+    if toolchain.is_gnu() && config.kind == TargetKind::Sharedlib {
+        flags.push("-fPIC", FlagOrigin::Config("kind".into()));
+    }
+    for entry in config.overrides_for(path, root) {
+        for f in entry.suppress_flags() {
+            flags.push(f, FlagOrigin::Config("overrides.suppress".into()));
+        }
+    }
+    // last, so they win over everything before
+    for (origin, f) in config.file_flags(path, root) {
+        flags.push(f.clone(), FlagOrigin::FileOverride(origin));
+    }
+    flags
+}
+
+/// Work out the module compiler flavour when modules are enabled, creating
+/// the BMI directory on the way.
+fn module_compiler(
+    root: &Path,
+    config: &Config,
+    opts: &BuildOptions,
+) -> Result<Option<ModuleCompiler>, String> {
+    if !config.modules {
+        return Ok(None);
+    }
+    let compiler = ModuleCompiler::detect(&config.cxx_compiler())?;
+    fs::create_dir_all(modules::cache_dir(root, opts.profile_name())).map_err(|e| e.to_string())?;
+    Ok(Some(compiler))
+}
 
-    cmd.arg("-c");
-    cmd.arg(&meta.path);
-    cmd.arg("-o");
-    cmd.arg(&obj_path);
+/// Extension of object files: `.obj` on Windows, whichever the toolchain.
+pub const OBJECT_EXTENSION: &str = if cfg!(windows) { "obj" } else { "o" };
 
-    if is_debug {
-        cmd.arg("-g");
-    } else {
-        cmd.arg("-O3");
+/// Directory of the object files of `profile`, which belongs to Buildy
+/// alone (see `remove_orphans`).
+pub fn object_dir(root: &Path, profile: &str) -> PathBuf {
+    root.join("target").join(profile).join("obj")
+}
+
+/// Object file produced for `source` in `profile`, the one path both the
+/// compile and the link use.  The path mirrors the source's location
+/// relative to `root` and keeps its extension (`src/net/util.cpp` becomes
+/// `target/<profile>/obj/src/net/util.cpp.o`), so no two sources share an
+/// object, not even `util.c` and `util.cpp`.  Sources outside the root go
+/// to a subdirectory named after a hash of their directory.
+pub fn object_path_for(source: &Path, root: &Path, profile: &str) -> PathBuf {
+    let out = object_dir(root, profile);
+    let relative = source
+        .strip_prefix(root)
+        .map(Path::to_path_buf)
+        .or_else(|_| {
+            // graph paths are canonical, `root` may not be
+            let root = root.canonicalize().map_err(|_| ())?;
+            source
+                .strip_prefix(root)
+                .map(Path::to_path_buf)
+                .map_err(|_| ())
+        });
+    let object = match relative {
+        Ok(relative) => out.join(relative),
+        Err(()) => {
+            let dir = source.parent().unwrap_or(Path::new(""));
+            let digest = Sha256::digest(dir.to_string_lossy().as_bytes());
+            let name = source.file_name().unwrap_or(source.as_os_str());
+            out.join("external")
+                .join(&format!("{:x}", digest)[..16])
+                .join(name)
+        }
+    };
+    let mut name = object.into_os_string();
+    name.push(".");
+    name.push(OBJECT_EXTENSION);
+    PathBuf::from(name)
+}
+
+/// Delete the object files under `object_dir` that none of `files` (the
+/// graph's nodes or the cache's entries) compiles to, such as those of
+/// deleted, renamed or excluded sources, together with their dependency
+/// files and the directories left empty.  Returns how many objects were
+/// removed.
+pub fn remove_orphans<P: AsRef<Path>>(
+    files: impl IntoIterator<Item = P>,
+    root: &Path,
+    profile: &str,
+) -> io::Result<usize> {
+    let dir = object_dir(root, profile);
+    if !dir.is_dir() {
+        return Ok(0);
     }
+    let expected: HashSet<PathBuf> = files
+        .into_iter()
+        .filter(|p| is_source(p.as_ref()))
+        .map(|p| object_path_for(p.as_ref(), root, profile))
+        .collect();
+    let mut removed = 0;
+    // children before their directory, so emptied directories can go too
+    for entry in WalkDir::new(&dir).min_depth(1).contents_first(true) {
+        let entry = entry.map_err(io::Error::other)?;
+        let path = entry.path();
+        if entry.file_type().is_dir() {
+            // fails unless empty, which is the point
+            let _ = fs::remove_dir(path);
+        } else if path.extension().is_some_and(|e| e == OBJECT_EXTENSION)
+            && !expected.contains(path)
+        {
+            fs::remove_file(path)?;
+            removed += 1;
+        } else if path.extension().is_some_and(|e| e == "d")
+            && !path.with_extension(OBJECT_EXTENSION).exists()
+        {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(removed)
+}
 
-    let status = cmd.status().map_err(|e| e.to_string())?;
-    if !status.success() {
-        Err(format!("compiler failed on {}", meta.path.display()))
-    } else {
-        Ok(())
+/// Build the command compiling `meta` into its object file using the C or
+/// C++ compiler based on extension.  Also returns the deduplicated flag set
+/// so callers can check it for conflicts.  Flags go before the source so
+/// that language selection such as `-x c++` applies to it.
+fn compile_command(
+    graph: &BuildGraph,
+    meta: &FileMeta,
+    root: &Path,
+    config: &Config,
+    opts: &BuildOptions,
+    module_compiler: Option<ModuleCompiler>,
+) -> Result<(CompileCommand, FlagSet), String> {
+    let mut flags = compile_flags(&meta.path, root, config, opts);
+    let mut directory = root.to_path_buf();
+    if let (Some(compiler), Some(unit)) = (module_compiler, graph.modules.unit(&meta.path)) {
+        let cache_dir = modules::cache_dir(root, opts.profile_name());
+        for f in compiler.flags(&meta.path, unit, &cache_dir) {
+            flags.push(f, FlagOrigin::Config("modules".into()));
+        }
+        if let Some(cwd) = compiler.working_dir(&cache_dir) {
+            directory = cwd;
+        }
     }
+    flags.dedup();
+
+    let object = object_path_for(&meta.path, root, opts.profile_name());
+    let toolchain = config.toolchain();
+    let mut args = toolchain.compile_args(flags.args(), &meta.path, &object);
+    // module compiles list BMIs in theirs, which the graph can't use
+    let dep_file = (toolchain.is_gnu() && module_compiler.is_none()).then(|| {
+        let dep_file = depfile::path_for(&object);
+        args.extend(toolchain.dep_file_args(&dep_file));
+        dep_file
+    });
+    let command = CompileCommand {
+        program: config.compiler_for(&meta.path),
+        wrapper: config.compiler_wrapper.clone(),
+        args,
+        toolchain,
+        directory,
+        source: meta.path.clone(),
+        object,
+        dep_file,
+    };
+    Ok((command, flags))
 }
 
-/// Link all object files produced by the graph into a single executable.
-/// The project name is the filename of the working directory, or provided
-/// explicitly by the caller.
-pub fn link(
+/// Compile commands for every source in the graph, sorted by path, whether
+/// or not the source is dirty.
+pub fn compile_commands(
     graph: &BuildGraph,
     root: &Path,
-    is_debug: bool,
-    output: &PathBuf,
-) -> Result<(), String> {
-    let profile_dir = if is_debug { "debug" } else { "release" };
-    let target_dir = root.join("target").join(profile_dir);
+    config: &Config,
+    opts: &BuildOptions,
+) -> Result<Vec<CompileCommand>, String> {
+    let module_compiler = module_compiler(root, config, opts)?;
+    let mut sources: Vec<&FileMeta> = graph
+        .nodes
+        .values()
+        .filter(|m| is_source(&m.path))
+        .collect();
+    sources.sort_by(|a, b| a.path.cmp(&b.path));
+    sources
+        .into_iter()
+        .map(|meta| {
+            compile_command(graph, meta, root, config, opts, module_compiler).map(|(c, _)| c)
+        })
+        .collect()
+}
 
-    let mut objs: Vec<PathBuf> = Vec::new();
+/// Compile `job` through `compile` and print what the compiler said as one
+/// block under the file's name, so the output of parallel jobs never
+/// interleaves.  Warnings are left out with `--quiet`.  A failure isn't
+/// printed here; its diagnostics are part of the returned error instead.
+/// In JSON mode the compile is also reported as messages, warnings
+/// included.  The warnings in the output, of a failed compile too, are
+/// added to `warnings`.  Returns how long the compiler ran, if it had to.
+fn run_job(
+    job: &CompileJob,
+    root: &Path,
+    opts: &BuildOptions,
+    progress: &Progress,
+    warnings: &AtomicUsize,
+    compile: impl FnOnce() -> Result<(String, Option<Duration>), String>,
+) -> Result<Option<Duration>, String> {
+    let started = start_job(job, root, opts, progress);
+    finish_job(job, root, opts, progress, warnings, started, compile())
+}
 
-    for (path, _) in &graph.nodes {
-        if let Some(ext) = path.extension().and_then(|s| s.to_str()) {
-            if ["c", "cpp", "cc", "cxx"].contains(&ext) {
-                let file_stem = path.file_stem().ok_or("invalid source filename")?;
+/// `run_job` for the jobs of a batch, which `compile` compiles together.
+fn run_batch(
+    batch: &[&CompileJob],
+    root: &Path,
+    opts: &BuildOptions,
+    progress: &Progress,
+    warnings: &AtomicUsize,
+    compile: impl FnOnce() -> Vec<Compiled>,
+) -> Vec<Result<Option<Duration>, String>> {
+    let started: Vec<Instant> = batch
+        .iter()
+        .map(|job| start_job(job, root, opts, progress))
+        .collect();
+    compile()
+        .into_iter()
+        .zip(batch.iter().zip(started))
+        .map(|(result, (job, started))| {
+            finish_job(job, root, opts, progress, warnings, started, result)
+        })
+        .collect()
+}
 
-                let obj_path = target_dir.join(file_stem).with_extension("o");
+/// Report `job` as started, returning when.
+fn start_job(job: &CompileJob, root: &Path, opts: &BuildOptions, progress: &Progress) -> Instant {
+    let name = job.meta.path.strip_prefix(root).unwrap_or(&job.meta.path);
+    let file = name.display().to_string();
+    opts.message_format
+        .emit(&Message::CompileStart { file: &file });
+    progress.start(&file);
+    Instant::now()
+}
 
-                if obj_path.exists() {
-                    objs.push(obj_path);
-                }
+/// Report the `result` of `job`, started at `started`, as `run_job`
+/// describes.
+fn finish_job(
+    job: &CompileJob,
+    root: &Path,
+    opts: &BuildOptions,
+    progress: &Progress,
+    warnings: &AtomicUsize,
+    started: Instant,
+    result: Result<(String, Option<Duration>), String>,
+) -> Result<Option<Duration>, String> {
+    let name = job.meta.path.strip_prefix(root).unwrap_or(&job.meta.path);
+    let file = name.display().to_string();
+    let messages = opts.message_format;
+    let (success, output) = match &result {
+        Ok((diagnostics, _)) => (true, diagnostics),
+        Err(e) => (false, e),
+    };
+    warnings.fetch_add(count_warnings(output), Ordering::Relaxed);
+    if !output.is_empty() {
+        messages.emit(&Message::Diagnostic {
+            file: &file,
+            stderr: output,
+        });
+    }
+    messages.emit(&Message::CompileFinish {
+        file: &file,
+        success,
+        duration_ms: started.elapsed().as_millis() as u64,
+    });
+    match result {
+        Ok((diagnostics, compiled)) => {
+            if !messages::quiet() && !diagnostics.is_empty() {
+                // a single call takes the stderr lock once for the block
+                progress.print_above(&format!("{}:\n{}", name.display(), diagnostics));
             }
+            Ok(compiled)
         }
+        Err(e) => Err(format!("failed to compile {}:\n{}", name.display(), e)),
     }
+}
 
-    if objs.is_empty() {
-        return Ok(()); // nothing to link
+/// How many warnings a compiler's output reports: the lines with
+/// `warning:`, as gcc and clang start each one.  Notes and the lines of
+/// source they quote aren't counted.
+fn count_warnings(output: &str) -> usize {
+    output
+        .lines()
+        .filter(|line| line.contains("warning:"))
+        .count()
+}
+
+/// Run a compile job, creating the object's directory first.  Returns the
+/// compiler's captured output, which on failure becomes the error.  A
+/// staged object or one from the object store is put in place instead when
+/// there is one, counted in `reused`; new objects are added to the store.
+/// Also returns how long the compiler ran, `None` for a reused object.
+fn compile_file(
+    job: &CompileJob,
+    opts: &BuildOptions,
+    store: &ObjectStore,
+    reused: &Reused,
+    chaos: Option<&Chaos>,
+) -> Result<(String, Option<Duration>), String> {
+    let object = &job.command.object;
+    if let Some(parent) = object.parent() {
+        fs::create_dir_all(parent).map_err(|e| e.to_string())?;
     }
+    if let Some((hash, _)) = &job.key
+        && !opts.force
+        && let Some(diagnostics) = store.fetch(hash, object)
+    {
+        reused.stored.fetch_add(1, Ordering::Relaxed);
+        return Ok((diagnostics, None));
+    }
+    let (diagnostics, compiled) = match job.staged.as_ref().and_then(|s| s.promote(object)) {
+        Some(diagnostics) => {
+            reused.promoted.fetch_add(1, Ordering::Relaxed);
+            (diagnostics, None)
+        }
+        None => {
+            if opts.verbose > 0 {
+                // one status line, so parallel compiles don't interleave
+                opts.message_format.status(format!(
+                    "+ {}\n  object: {}",
+                    job.command.shell_line(),
+                    object.display()
+                ));
+            }
+            let started = Instant::now();
+            let diagnostics = run_compiler(&job.command, chaos)?;
+            (diagnostics, Some(started.elapsed()))
+        }
+    };
+    store_object(job, store, &diagnostics);
+    Ok((diagnostics, compiled))
+}
 
-    let mut use_cpp = false;
+/// Add the object `job` compiled to the object store, but only if the
+/// inputs still have the contents the hash was computed from, so an edit
+/// during the compile can't poison it.
+fn store_object(job: &CompileJob, store: &ObjectStore, diagnostics: &str) {
+    if let Some((hash, inputs)) = &job.key
+        && speculate::job_key(&job.command, inputs).as_ref() == Some(hash)
+    {
+        store.insert(hash, &job.command.object, diagnostics);
+    }
+}
 
-    for (path, _) in &graph.nodes {
-        if let Some(ext) = path.extension().and_then(|s| s.to_str()) {
-            if ["cpp", "cc", "cxx"].contains(&ext) {
-                use_cpp = true;
-                break;
+/// What compiling a job gave: the compiler's output and how long it ran,
+/// or the error.
+type Compiled = Result<(String, Option<Duration>), String>;
+
+/// Compile the jobs of a batch (see `batch_jobs`) in a single run of
+/// `cl`, as `compile_file` compiles one.  Objects in the object store are
+/// taken from there first.  `cl` writes the objects to a directory of the
+/// batch, from where they are moved to their paths, and its output is split
+/// up by source; a source failed when it got no object.  Each compile is
+/// taken to have run for an equal share of the time.
+fn compile_batch(
+    batch: &[&CompileJob],
+    root: &Path,
+    opts: &BuildOptions,
+    store: &ObjectStore,
+    reused: &Reused,
+    chaos: Option<&Chaos>,
+) -> Vec<Compiled> {
+    let mut results: Vec<Option<Compiled>> = vec![None; batch.len()];
+    let mut pending = Vec::new();
+    for (i, job) in batch.iter().enumerate() {
+        let object = &job.command.object;
+        if let Some(parent) = object.parent()
+            && let Err(e) = fs::create_dir_all(parent)
+        {
+            results[i] = Some(Err(e.to_string()));
+        } else if let Some((hash, _)) = &job.key
+            && !opts.force
+            && let Some(diagnostics) = store.fetch(hash, object)
+        {
+            reused.stored.fetch_add(1, Ordering::Relaxed);
+            results[i] = Some(Ok((diagnostics, None)));
+        } else {
+            pending.push(i);
+        }
+    }
+    if let [i] = pending[..] {
+        results[i] = Some(compile_file(batch[i], opts, store, reused, chaos));
+    } else if !pending.is_empty() {
+        let jobs: Vec<&CompileJob> = pending.iter().map(|&i| batch[i]).collect();
+        for (i, result) in pending
+            .iter()
+            .zip(run_compiler_batch(&jobs, root, opts, chaos))
+        {
+            if let Ok((diagnostics, _)) = &result {
+                store_object(batch[*i], store, diagnostics);
             }
+            results[*i] = Some(result);
+        }
+    }
+    results.into_iter().flatten().collect()
+}
+
+/// Run `cl` once for all of `jobs`, leaving each object at its path.
+fn run_compiler_batch(
+    jobs: &[&CompileJob],
+    root: &Path,
+    opts: &BuildOptions,
+    chaos: Option<&Chaos>,
+) -> Vec<Compiled> {
+    let first = &jobs[0].command;
+    let name = first
+        .object
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy();
+    let out_dir = object_dir(root, opts.profile_name()).join(format!(".batch-{}", name));
+    let _ = fs::remove_dir_all(&out_dir);
+    if let Err(e) = fs::create_dir_all(&out_dir) {
+        return jobs.iter().map(|_| Err(e.to_string())).collect();
+    }
+    let commands: Vec<&CompileCommand> = jobs.iter().map(|job| &job.command).collect();
+    let command = batch::command(&commands, &out_dir);
+    if opts.verbose > 0 {
+        opts.message_format.status(format!(
+            "+ {}\n  objects: {}",
+            command.shell_line(),
+            out_dir.display()
+        ));
+    }
+    for job in jobs {
+        let _ = fs::remove_file(&job.command.object);
+    }
+    let started = Instant::now();
+    let mut cmd = command.to_command();
+    let output = match chaos {
+        Some(chaos) => chaos.output(&first.source.to_string_lossy(), &mut cmd, &command.object),
+        None => cancel::output(&mut cmd),
+    };
+    let output = match output {
+        Ok(output) => output,
+        Err(e) => {
+            let _ = fs::remove_dir_all(&out_dir);
+            let e = config::spawn_error(&command.program, &e);
+            return jobs.iter().map(|_| Err(e.clone())).collect();
+        }
+    };
+    let share = started.elapsed() / jobs.len() as u32;
+    let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+    let sources: Vec<&Path> = jobs
+        .iter()
+        .map(|job| job.command.source.as_path())
+        .collect();
+    let sections = batch::split_output(&text, &sources);
+    let results = jobs
+        .iter()
+        .zip(sections)
+        .map(|(job, diagnostics)| {
+            let built = batch::object_in(&out_dir, &job.command.source);
+            if !built.is_file() {
+                return Err(if diagnostics.is_empty() {
+                    format!("compiler exited with {}", output.status)
+                } else {
+                    diagnostics
+                });
+            }
+            fs::rename(&built, &job.command.object).map_err(|e| {
+                format!(
+                    "could not move {} to {}: {}",
+                    built.display(),
+                    job.command.object.display(),
+                    e
+                )
+            })?;
+            Ok((diagnostics, Some(share)))
+        })
+        .collect();
+    let _ = fs::remove_dir_all(&out_dir);
+    results
+}
+
+/// Run the syntax-only compile of `job` for `check`.
+fn check_file(job: &CompileJob, opts: &BuildOptions) -> Result<(String, Option<Duration>), String> {
+    let command = &job.command;
+    if opts.verbose > 0 {
+        opts.message_format
+            .status(format!("+ {}", command.shell_line()));
+    }
+    let output = cancel::output(&mut command.to_command())
+        .map_err(|e| config::spawn_error(&command.program, &e))?;
+    compiler_result(&output).map(|diagnostics| (diagnostics, None))
+}
+
+/// Run the compiler for `command`, returning its output.  The old object is
+/// removed first: it may be a hard link into the object store, which the
+/// compiler would otherwise overwrite.  After a failure whatever the
+/// compiler left behind is removed too, so a partial object is never linked.
+fn run_compiler(command: &CompileCommand, chaos: Option<&Chaos>) -> Result<String, String> {
+    let _ = fs::remove_file(&command.object);
+    let mut cmd = command.to_command();
+    let output = match chaos {
+        Some(chaos) => chaos.output(&command.source.to_string_lossy(), &mut cmd, &command.object),
+        None => cancel::output(&mut cmd),
+    }
+    .map_err(|e| config::spawn_error(&command.program, &e))?;
+    if !output.status.success() {
+        let _ = fs::remove_file(&command.object);
+        if let Some(dep_file) = &command.dep_file {
+            let _ = fs::remove_file(dep_file);
+        }
+    }
+    compiler_result(&output)
+}
+
+/// What the compiler printed, as the error if it failed.
+fn compiler_result(output: &std::process::Output) -> Result<String, String> {
+    let mut diagnostics = String::from_utf8_lossy(&output.stdout).into_owned();
+    diagnostics.push_str(&String::from_utf8_lossy(&output.stderr));
+    let diagnostics = diagnostics.trim_end().to_string();
+    if output.status.success() {
+        Ok(diagnostics)
+    } else if diagnostics.is_empty() {
+        Err(format!("compiler exited with {}", output.status))
+    } else {
+        Err(diagnostics)
+    }
+}
+
+/// One program or library to link, with the sources whose objects go into
+/// it.
+pub struct LinkTarget {
+    pub output: PathBuf,
+    pub sources: Vec<PathBuf>,
+}
+
+/// What the build links in `output_dir`.  A library, or a project with at
+/// most one source defining `main`, is a single target called after `name`
+/// holding every object.  Otherwise each source defining `main` becomes a
+/// program named after its file stem, linked with all the sources that
+/// don't define `main`.  The sources under `tests/` only go into the test
+/// programs (see `testing::link_targets`).
+pub fn link_targets(
+    graph: &BuildGraph,
+    root: &Path,
+    config: &Config,
+    output_dir: &Path,
+    name: &str,
+) -> Result<Vec<LinkTarget>, BuildyError> {
+    let mut sources: Vec<PathBuf> = graph
+        .nodes
+        .keys()
+        .filter(|p| is_source(p) && !testing::is_test(p, root))
+        .cloned()
+        .collect();
+    sources.sort();
+    let mains: Vec<&PathBuf> = graph
+        .mains
+        .iter()
+        .filter(|p| !testing::is_test(p, root))
+        .collect();
+    if config.kind != TargetKind::Executable || mains.len() <= 1 {
+        return Ok(vec![LinkTarget {
+            output: output_dir.join(config.kind.file_name(name, config.toolchain())),
+            sources,
+        }]);
+    }
+    let shared: Vec<PathBuf> = sources
+        .into_iter()
+        .filter(|p| !graph.mains.contains(p))
+        .collect();
+    let mut targets: Vec<LinkTarget> = Vec::new();
+    for main in mains {
+        let stem = main
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let output = output_dir.join(config.kind.file_name(&stem, config.toolchain()));
+        if let Some(other) = targets.iter().find(|t| t.output == output) {
+            return Err(BuildyError::LinkFailed(format!(
+                "{} and {} both define main() and would both be linked as `{}`",
+                other.sources[other.sources.len() - 1].display(),
+                main.display(),
+                stem
+            )));
+        }
+        let mut sources = shared.clone();
+        sources.push(main.clone());
+        targets.push(LinkTarget { output, sources });
+    }
+    Ok(targets)
+}
+
+/// A link as it will run: the compiler driver linking an executable or
+/// shared library, or `ar` archiving a static library.
+pub struct LinkCommand {
+    pub program: String,
+    pub args: Vec<String>,
+    pub output: PathBuf,
+    /// Where the program writes, see `staged_output`.
+    staged: PathBuf,
+    archive: bool,
+}
+
+impl LinkCommand {
+    /// The command line, quoted so it can be pasted into a shell.
+    pub fn shell_line(&self) -> String {
+        shell_words::join(std::iter::once(&self.program).chain(&self.args))
+    }
+
+    /// Run the link and move its output into place.
+    pub fn run(&self) -> Result<(), BuildyError> {
+        if self.archive {
+            // `ar` adds to an existing archive, which would keep the objects
+            // of deleted sources
+            let _ = fs::remove_file(&self.staged);
+        }
+        // e.g. `tests/`, which holds nothing else
+        if let Some(dir) = self.staged.parent() {
+            fs::create_dir_all(dir)
+                .map_err(|e| BuildyError::LinkFailed(format!("{}: {}", dir.display(), e)))?;
+        }
+        let status = Command::new(&self.program)
+            .args(&self.args)
+            .status()
+            .map_err(|e| BuildyError::LinkFailed(config::spawn_error(&self.program, &e)))?;
+        if !status.success() {
+            let what = if self.archive {
+                self.program.as_str()
+            } else {
+                "linker"
+            };
+            return Err(BuildyError::LinkFailed(format!(
+                "{} returned non-zero status",
+                what
+            )));
         }
+        install_staged(&self.staged, &self.output).map_err(BuildyError::LinkFailed)
+    }
+}
+
+/// The object files of `target`, sorted so that the link, and with it the
+/// output, doesn't depend on the order sources were found in.  In a unity
+/// build the members of a bucket are linked through the bucket's object.
+/// A missing object is an error: linking without it would at best fail
+/// with undefined references that don't point at the cause.
+pub fn target_objects(
+    target: &LinkTarget,
+    root: &Path,
+    opts: &BuildOptions,
+    unity: Option<&UnityBuild>,
+) -> Result<Vec<PathBuf>, BuildyError> {
+    let mut objects: Vec<PathBuf> = Vec::new();
+    let mut missing: Vec<String> = Vec::new();
+    for path in &target.sources {
+        let obj = match unity.and_then(|u| u.bucket(path)) {
+            Some(bucket) => bucket.object.clone(),
+            None => object_path_for(path, root, opts.profile_name()),
+        };
+        if !obj.exists() {
+            missing.push(format!(
+                "  {} (from {})",
+                BuildCache::make_relative(&obj, root),
+                BuildCache::make_relative(path, root)
+            ));
+        }
+        objects.push(obj);
+    }
+    if !missing.is_empty() {
+        missing.sort();
+        missing.dedup();
+        return Err(BuildyError::LinkFailed(format!(
+            "cannot link {}, object files are missing from target/:\n{}\nbuilding again recompiles them",
+            BuildCache::make_relative(&target.output, root),
+            missing.join("\n")
+        )));
+    }
+    objects.sort();
+    objects.dedup();
+    Ok(objects)
+}
+
+/// The command linking `objects` (and `extra_objects`) into `target`: with
+/// the compiler driver into an executable or shared library, or `ar` into
+/// a static library (`AR` in the environment overrides it).
+pub fn link_command(
+    target: &LinkTarget,
+    objects: &[PathBuf],
+    root: &Path,
+    config: &Config,
+    profile: &Profile,
+    extra_objects: &[PathBuf],
+) -> LinkCommand {
+    let output = target.output.clone();
+    let staged = staged_output(&output);
+    let paths = |paths: &[PathBuf]| -> Vec<String> {
+        paths.iter().map(|p| p.display().to_string()).collect()
+    };
+
+    let toolchain = config.toolchain();
+    if config.kind == TargetKind::Staticlib {
+        let (ar, mut args) = toolchain.archive_command(&staged);
+        args.extend(paths(objects));
+        args.extend(paths(extra_objects));
+        return LinkCommand {
+            program: ar,
+            args,
+            output,
+            staged,
+            archive: true,
+        };
     }
 
-    let mut cmd = if use_cpp {
-        Command::new("g++")
+    let use_cpp = target
+        .sources
+        .iter()
+        .any(|path| Language::of(path) == Some(Language::Cxx));
+    let driver = if use_cpp {
+        config.cxx_compiler()
     } else {
-        Command::new("gcc")
+        config.c_compiler().to_string()
     };
+    let (linker, mut args) = toolchain.linker(
+        driver,
+        config.kind == TargetKind::Sharedlib,
+        config.linker.as_deref(),
+    );
+    args.extend(paths(objects));
+    args.extend(paths(extra_objects));
+    args.extend(config.link_args.iter().cloned());
+    args.extend(config.lib_args(root));
+    args.extend(config.ldflags.iter().cloned());
+    args.extend(profile.ldflags.iter().cloned());
+    args.extend(toolchain.output_args(&staged));
+    LinkCommand {
+        program: linker,
+        args,
+        output,
+        staged,
+        archive: false,
+    }
+}
 
-    for obj in &objs {
-        cmd.arg(obj);
+/// Link the objects of `target` (see `link_command`).
+pub fn link(
+    target: &LinkTarget,
+    root: &Path,
+    config: &Config,
+    opts: &BuildOptions,
+    extra_objects: &[PathBuf],
+    unity: Option<&UnityBuild>,
+) -> Result<(), BuildyError> {
+    let objects = target_objects(target, root, opts, unity)?;
+    if objects.is_empty() {
+        return Ok(()); // nothing to link
+    }
+    let command = link_command(target, &objects, root, config, &opts.profile, extra_objects);
+    opts.message_format.status(format!(
+        "linking {}",
+        BuildCache::make_relative(&command.output, root)
+    ));
+    if opts.verbose > 0 {
+        opts.message_format
+            .status(format!("+ {}", command.shell_line()));
     }
+    command.run()
+}
 
-    cmd.arg("-o");
-    cmd.arg(output);
+/// Digest of everything linking `target` consumes: the compiler and flags,
+/// and the content of each of its object files.  The output only has to be
+/// relinked when it differs from the digest recorded at its last link, so
+/// recompiling a file back to identical bytes (e.g. after reverting an edit)
+/// doesn't relink.
+pub fn link_digest(
+    target: &LinkTarget,
+    cache: &BuildCache,
+    root: &Path,
+    opts: &BuildOptions,
+    unity: Option<&UnityBuild>,
+) -> Result<String, String> {
+    let mut inputs: Vec<String> = Vec::new();
+    inputs.push(format!(
+        "compiler={}",
+        cache.compiler.as_deref().unwrap_or("")
+    ));
+    inputs.extend(cache.flags.iter().cloned());
+    inputs.extend(cache.libs.iter().map(|l| format!("lib={}", l)));
+    inputs.extend(cache.link_settings.iter().cloned());
+    inputs.extend(opts.profile.ldflags.iter().map(|f| format!("ldflag={}", f)));
+    if let Some(stamp) = &cache.stamp {
+        inputs.push(format!("stamp={:?}", stamp));
+    }
+    let mut objects = Vec::new();
+    for path in &target.sources {
+        let (obj, recorded) = match unity.and_then(|u| u.bucket(path)) {
+            Some(bucket) => (bucket.object.clone(), None),
+            None => (
+                object_path_for(path, root, opts.profile_name()),
+                cache.object_hash(path, root),
+            ),
+        };
+        if !obj.exists() {
+            continue;
+        }
+        let hash = match recorded {
+            Some(hash) => hash.to_string(),
+            None => hash_file(&obj).map_err(|e| format!("{}: {}", obj.display(), e))?,
+        };
+        objects.push(format!("{}={}", obj.display(), hash));
+    }
+    objects.sort();
+    objects.dedup();
+    inputs.extend(objects);
+
+    let mut hasher = Sha256::new();
+    for input in &inputs {
+        hasher.update(input.as_bytes());
+        hasher.update(b"\n");
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
 
-    let status = cmd.status().map_err(|e| e.to_string())?;
-    if !status.success() {
-        Err("linker returned non-zero status".into())
+/// How often to retry swapping in a staged executable while the previous
+/// one is still locked by a process that is shutting down.
+const INSTALL_RETRIES: u32 = 20;
+const INSTALL_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Where the linker should write `output`.  Windows refuses to overwrite an
+/// executable that is running, so there we link to `<name>.new.exe` and
+/// swap it in with `install_staged`; elsewhere the linker replaces the file
+/// in place.
+fn staged_output(output: &Path) -> PathBuf {
+    if cfg!(windows) {
+        sibling_exe(output, "new")
     } else {
-        Ok(())
+        output.to_path_buf()
+    }
+}
+
+/// `<name>.<tag>.exe` next to `output`.
+fn sibling_exe(output: &Path, tag: &str) -> PathBuf {
+    let stem = output
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    output.with_file_name(format!("{}.{}{}", stem, tag, std::env::consts::EXE_SUFFIX))
+}
+
+/// Move a staged executable into place.  A running executable can be
+/// renamed but not replaced, so the current one is first moved aside to
+/// `<name>.old.exe`, which is deleted on the next cycle once released.
+fn install_staged(staged: &Path, output: &Path) -> Result<(), String> {
+    if staged == output {
+        return Ok(());
+    }
+    let old = sibling_exe(output, "old");
+    // still locked if the previous run hasn't exited; retried next cycle
+    let _ = fs::remove_file(&old);
+    if output.exists() {
+        retry_io(|| fs::rename(output, &old))
+            .map_err(|e| format!("could not move {} aside: {}", output.display(), e))?;
+    }
+    retry_io(|| fs::rename(staged, output))
+        .map_err(|e| format!("could not install {}: {}", output.display(), e))
+}
+
+/// Run `op` until it succeeds or `INSTALL_RETRIES` attempts have failed,
+/// giving a terminating process time to release its file handles.
+fn retry_io(mut op: impl FnMut() -> std::io::Result<()>) -> std::io::Result<()> {
+    let mut attempt = 1;
+    loop {
+        match op() {
+            Ok(()) => return Ok(()),
+            Err(_) if attempt < INSTALL_RETRIES => {
+                attempt += 1;
+                thread::sleep(INSTALL_RETRY_DELAY);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use std::sync::Mutex;
+
+    /// The graph of `units`, each a source with the sources it imports,
+    /// and a compile job for each in the order given.
+    fn fixture(units: &[(&str, &[&str])]) -> (BuildGraph, Vec<CompileJob>) {
+        let mut graph = BuildGraph::new();
+        let mut jobs = Vec::new();
+        for (name, imports) in units {
+            let path = PathBuf::from(format!("/p/src/{}.cppm", name));
+            let meta = FileMeta {
+                path: path.clone(),
+                hash: String::new(),
+                last_modified: Utc::now(),
+                size: 0,
+                deps: imports
+                    .iter()
+                    .map(|i| PathBuf::from(format!("/p/src/{}.cppm", i)))
+                    .collect(),
+                dependents: Vec::new(),
+                dirty: true,
+            };
+            graph.nodes.insert(path.clone(), meta.clone());
+            let object = PathBuf::from(format!("/p/target/debug/obj/src/{}.cppm.o", name));
+            jobs.push(CompileJob {
+                meta,
+                command: CompileCommand {
+                    program: "c++".into(),
+                    wrapper: None,
+                    args: Vec::new(),
+                    toolchain: Toolchain::Gnu,
+                    directory: PathBuf::from("/p"),
+                    source: path,
+                    object,
+                    dep_file: None,
+                },
+                staged: None,
+                key: None,
+            });
+        }
+        (graph, jobs)
+    }
+
+    const UNITS: &[(&str, &[&str])] = &[
+        ("slow", &[]),
+        ("fast", &[]),
+        ("after_slow", &["slow"]),
+        ("after_fast", &["fast"]),
+        ("alone", &[]),
+    ];
+
+    /// Run `jobs` on `threads` with a fake compiler taking `delays` by
+    /// name, failing those in `failing`.  Returns the log of starts and
+    /// ends.
+    fn run_fake(
+        threads: usize,
+        (graph, jobs): &(BuildGraph, Vec<CompileJob>),
+        delays: &[(&str, u64)],
+        failing: &[&str],
+    ) -> (Vec<String>, Vec<Failure>) {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+        let log = Mutex::new(Vec::new());
+        let (_, failures) = run_ready(jobs, graph, &pool, true, |job| {
+            let name = job.meta.path.file_stem().unwrap().to_string_lossy();
+            log.lock().unwrap().push(format!("start {}", name));
+            let ms = delays.iter().find(|(n, _)| *n == name).map_or(0, |d| d.1);
+            thread::sleep(Duration::from_millis(ms));
+            log.lock().unwrap().push(format!("end {}", name));
+            match failing.contains(&name.as_ref()) {
+                true => Err(format!("{} failed", name)),
+                false => Ok(()),
+            }
+        });
+        (log.into_inner().unwrap(), failures)
+    }
+
+    /// Where `event` is in `log`.
+    fn at(log: &[String], event: &str) -> usize {
+        log.iter()
+            .position(|e| e == event)
+            .unwrap_or_else(|| panic!("no `{}` in {:?}", event, log))
+    }
+
+    #[test]
+    fn a_job_starts_as_soon_as_its_imports_are_built() {
+        // `fast` is done long before `slow`, so `after_fast` mustn't wait
+        // for `slow` as it would if jobs ran in waves
+        let delays = [("slow", 300), ("fast", 10), ("alone", 100)];
+        let (log, failures) = run_fake(4, &fixture(UNITS), &delays, &[]);
+        assert!(failures.is_empty());
+        assert_eq!(log.len(), 10);
+        let starts = at(&log, "start after_fast");
+        assert!(starts > at(&log, "end fast"), "{:?}", log);
+        assert!(starts < at(&log, "end slow"), "{:?}", log);
+        assert!(
+            at(&log, "start after_slow") > at(&log, "end slow"),
+            "{:?}",
+            log
+        );
+    }
+
+    #[test]
+    fn ready_jobs_start_in_job_order() {
+        // one worker: a job that becomes ready queues up behind the others
+        let delays = [("slow", 30), ("fast", 10)];
+        let (log, _) = run_fake(1, &fixture(UNITS), &delays, &[]);
+        let starts: Vec<&str> = log
+            .iter()
+            .filter_map(|e| e.strip_prefix("start "))
+            .collect();
+        assert_eq!(
+            starts,
+            ["slow", "fast", "alone", "after_slow", "after_fast"]
+        );
+    }
+
+    #[test]
+    fn a_failure_skips_its_dependents_only() {
+        let units: &[(&str, &[&str])] = &[
+            ("base", &[]),
+            ("user", &["base"]),
+            ("transitive", &["user"]),
+            ("other", &[]),
+        ];
+        let (log, failures) = run_fake(4, &fixture(units), &[("other", 50)], &["base"]);
+        assert_eq!(failures.len(), 1);
+        assert!(failures[0].0.ends_with("base.cppm"));
+        assert!(!log.iter().any(|e| e.contains("user")), "{:?}", log);
+        assert!(!log.iter().any(|e| e.contains("transitive")), "{:?}", log);
+        assert!(log.contains(&"end other".to_string()));
+    }
+
+    /// A source at `/p/src/<name>` that `dependents` others include, last
+    /// compiled in `ms` (`None` if never) according to `cache`.
+    fn timed(cache: &mut BuildCache, name: &str, dependents: usize, ms: Option<u64>) -> FileMeta {
+        let meta = FileMeta {
+            path: PathBuf::from(format!("/p/src/{}", name)),
+            hash: String::new(),
+            last_modified: Utc::now(),
+            size: 0,
+            deps: Vec::new(),
+            dependents: (0..dependents)
+                .map(|i| PathBuf::from(format!("/p/src/user{}.c", i)))
+                .collect(),
+            dirty: true,
+        };
+        let root = Path::new("/p");
+        cache.update_file(&meta, root);
+        if let Some(ms) = ms {
+            cache.record_duration(&meta.path, root, ms);
+        }
+        meta
+    }
+
+    fn names(metas: &[FileMeta]) -> Vec<String> {
+        metas
+            .iter()
+            .map(|m| m.path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn the_slowest_compiles_start_first() {
+        let mut cache = BuildCache::load(Path::new("/p"), "debug");
+        let mut jobs = vec![
+            timed(&mut cache, "a.c", 0, Some(100)),
+            timed(&mut cache, "template_heavy.cpp", 0, Some(40_000)),
+            timed(&mut cache, "new.c", 0, None),
+            timed(&mut cache, "b.c", 0, Some(2_000)),
+            timed(&mut cache, "c.c", 0, Some(300)),
+        ];
+        longest_first(&mut jobs, |m| m, &cache, Path::new("/p"));
+        // the median of 100, 300, 2000 and 40000 is 2000, so the file never
+        // compiled ties with b.c and goes after it by path
+        assert_eq!(
+            names(&jobs),
+            ["template_heavy.cpp", "b.c", "new.c", "c.c", "a.c"]
+        );
+    }
+
+    #[test]
+    fn ties_go_to_the_file_with_more_dependents() {
+        let mut cache = BuildCache::load(Path::new("/p"), "debug");
+        let mut jobs = vec![
+            timed(&mut cache, "a.c", 0, Some(500)),
+            timed(&mut cache, "b.c", 3, Some(500)),
+            timed(&mut cache, "c.c", 1, Some(500)),
+            timed(&mut cache, "d.c", 0, Some(500)),
+        ];
+        longest_first(&mut jobs, |m| m, &cache, Path::new("/p"));
+        assert_eq!(names(&jobs), ["b.c", "c.c", "a.c", "d.c"]);
+    }
+
+    #[test]
+    fn no_reorder_starts_in_path_order() {
+        let mut cache = BuildCache::load(Path::new("/p"), "debug");
+        let mut jobs = vec![
+            timed(&mut cache, "c.c", 0, Some(10)),
+            timed(&mut cache, "a.c", 0, Some(1)),
+            timed(&mut cache, "b.c", 5, Some(9_000)),
+        ];
+        let opts = BuildOptions {
+            no_reorder: true,
+            ..BuildOptions::default()
+        };
+        start_order(&mut jobs, |m| m, &cache, Path::new("/p"), &opts);
+        assert_eq!(names(&jobs), ["a.c", "b.c", "c.c"]);
+        start_order(
+            &mut jobs,
+            |m| m,
+            &cache,
+            Path::new("/p"),
+            &BuildOptions::default(),
+        );
+        assert_eq!(names(&jobs), ["b.c", "c.c", "a.c"]);
+    }
+
+    #[test]
+    fn the_link_digest_changes_with_the_objects() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let opts = BuildOptions::default();
+        let cache = BuildCache::load(root, "debug");
+        let sources = [root.join("src/main.c"), root.join("src/extra.c")];
+        for source in &sources {
+            let object = object_path_for(source, root, "debug");
+            fs::create_dir_all(object.parent().unwrap()).unwrap();
+            fs::write(&object, "object of main and extra()").unwrap();
+        }
+        let target = LinkTarget {
+            output: root.join("target/debug/app"),
+            sources: sources.to_vec(),
+        };
+        let digest = || link_digest(&target, &cache, root, &opts, None).unwrap();
+        let linked = digest();
+        assert_eq!(digest(), linked);
+
+        // a symbol removed from an object
+        let extra = object_path_for(&sources[1], root, "debug");
+        fs::write(&extra, "object of main").unwrap();
+        let edited = digest();
+        assert_ne!(edited, linked);
+
+        // an object removed altogether
+        fs::remove_file(&extra).unwrap();
+        let removed = digest();
+        assert_ne!(removed, edited);
+        assert_ne!(removed, linked);
     }
 }
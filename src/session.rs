@@ -0,0 +1,147 @@
+use crate::cache::BuildCache;
+use crate::graph::BuildGraph;
+use rayon::ThreadPool;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// State that outlives a single build.  The watch repl keeps one session for
+/// its whole lifetime so consecutive builds reuse the thread pool, the loaded
+/// cache and the graph's canonical path table instead of rebuilding them;
+/// one-shot commands create a session, build once and flush it.
+pub struct BuildSession {
+    pub root: PathBuf,
+    /// Caches loaded so far, by profile name.
+    pub caches: HashMap<String, BuildCache>,
+    pub graph: BuildGraph,
+    /// Pool the scheduler runs compile jobs on, created by the first build
+    /// so commands that only read the project never start one.
+    pool: Option<Arc<ThreadPool>>,
+}
+
+impl BuildSession {
+    pub fn new(root: &Path) -> Self {
+        BuildSession {
+            root: root.to_path_buf(),
+            caches: HashMap::new(),
+            graph: BuildGraph::new(),
+            pool: None,
+        }
+    }
+
+    /// The pool running compile jobs on `jobs` threads, 0 meaning one per
+    /// CPU.  It is created on first use and only rebuilt when the number
+    /// changes.
+    pub fn pool(&mut self, jobs: usize) -> Result<Arc<ThreadPool>, String> {
+        let threads = if jobs == 0 { num_cpus::get() } else { jobs };
+        match &self.pool {
+            Some(pool) if pool.current_num_threads() == threads => Ok(pool.clone()),
+            _ => {
+                let pool = Arc::new(thread_pool(threads)?);
+                self.pool = Some(pool.clone());
+                Ok(pool)
+            }
+        }
+    }
+
+    /// Write every loaded cache to disk, except those another process saved
+    /// since, whose changes would be lost.  Builds hold the `TargetLock`
+    /// and `forget_changed` caches first, so this only skips caches the
+    /// session merely read.
+    pub fn flush(&mut self) -> io::Result<()> {
+        for cache in self.caches.values_mut() {
+            if !cache.changed_on_disk() {
+                cache.save()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop the caches another process saved since they were loaded, so
+    /// the next build loads them again with its changes.
+    pub fn forget_changed(&mut self) {
+        self.caches.retain(|_, cache| !cache.changed_on_disk());
+    }
+
+    /// Forget the in-memory caches, e.g. after `clean` removed them from
+    /// disk, so the next flush doesn't bring them back.
+    pub fn reset_caches(&mut self) {
+        self.caches.clear();
+    }
+
+    /// Whether `--root` given at the watch prompt names a project other
+    /// than the session's.  Relative paths are taken relative to the
+    /// session root, and symlinks are followed.
+    pub fn is_foreign(&self, root: &Path) -> bool {
+        if root.as_os_str() == "." {
+            return false;
+        }
+        let other = self.root.join(root);
+        match (other.canonicalize(), self.root.canonicalize()) {
+            (Ok(other), Ok(own)) => other != own,
+            _ => other != self.root,
+        }
+    }
+}
+
+fn thread_pool(threads: usize) -> Result<ThreadPool, String> {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn only_other_projects_are_foreign() {
+        let dir = tempfile::tempdir().unwrap();
+        let own = dir.path().join("app");
+        let other = dir.path().join("lib");
+        fs::create_dir_all(own.join("src")).unwrap();
+        fs::create_dir_all(&other).unwrap();
+        let session = BuildSession::new(&own);
+
+        assert!(!session.is_foreign(Path::new(".")));
+        assert!(!session.is_foreign(Path::new("src/..")));
+        assert!(!session.is_foreign(Path::new("../app")));
+        assert!(!session.is_foreign(&own));
+        assert!(session.is_foreign(Path::new("../lib")));
+        assert!(session.is_foreign(&other));
+        assert!(session.is_foreign(Path::new("src")));
+        // not there (yet): compared as written
+        assert!(session.is_foreign(Path::new("../missing")));
+    }
+
+    #[test]
+    fn the_pool_is_made_when_a_build_asks_for_it() {
+        let mut session = BuildSession::new(Path::new("app"));
+        assert!(session.pool.is_none());
+
+        let pool = session.pool(2).unwrap();
+        assert_eq!(pool.current_num_threads(), 2);
+        assert!(Arc::ptr_eq(&pool, &session.pool(2).unwrap()));
+        assert_eq!(session.pool(3).unwrap().current_num_threads(), 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_symlink_to_the_root_is_the_same_project() {
+        let dir = tempfile::tempdir().unwrap();
+        let own = dir.path().join("app");
+        fs::create_dir_all(&own).unwrap();
+        let link = dir.path().join("link");
+        std::os::unix::fs::symlink(&own, &link).unwrap();
+
+        let session = BuildSession::new(&own);
+        assert!(!session.is_foreign(&link));
+        assert!(!session.is_foreign(Path::new("../link")));
+        // and a session started through the link knows its target
+        let session = BuildSession::new(&link);
+        assert!(!session.is_foreign(&own));
+    }
+}
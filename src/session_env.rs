@@ -0,0 +1,196 @@
+use crate::config::Config;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// Environment overrides set from the watch repl with `env set`.  They are
+/// layered on top of the `[run.env]` table from `buildy.toml` (which is in
+/// turn layered on top of buildy's own environment) and only live as long as
+/// the watch session unless explicitly saved.
+#[derive(Debug, Default)]
+pub struct SessionEnv {
+    vars: BTreeMap<String, String>,
+}
+
+impl SessionEnv {
+    /// Handle the arguments following `env` in the repl.
+    pub fn command(&mut self, args: &[String], root: &Path) -> Result<(), String> {
+        match args {
+            [cmd, assignment] if cmd == "set" => {
+                let (key, value) = assignment
+                    .split_once('=')
+                    .filter(|(k, _)| !k.is_empty())
+                    .ok_or("usage: env set KEY=VALUE")?;
+                self.vars.insert(key.to_string(), value.to_string());
+                Ok(())
+            }
+            [cmd, key] if cmd == "unset" => match self.vars.remove(key) {
+                Some(_) => Ok(()),
+                None => Err(format!("{} is not set in this session", key)),
+            },
+            [cmd] if cmd == "show" => {
+                self.show(&Config::load(root));
+                Ok(())
+            }
+            [cmd] if cmd == "save" => {
+                self.save(root)?;
+                println!("saved {} variable(s) to [run.env]", self.vars.len());
+                Ok(())
+            }
+            _ => Err("usage: env set KEY=VALUE | env unset KEY | env show | env save".into()),
+        }
+    }
+
+    /// The variables to add to a launched program's environment: config
+    /// values overridden by session values.
+    pub fn resolve(&self, config: &Config) -> BTreeMap<String, String> {
+        let mut env = config.run.env.clone();
+        env.extend(self.vars.clone());
+        env
+    }
+
+    fn show(&self, config: &Config) {
+        let resolved = self.resolve(config);
+        if resolved.is_empty() {
+            println!("no environment overrides");
+            return;
+        }
+        for (key, value) in &resolved {
+            let source = if self.vars.contains_key(key) {
+                "session"
+            } else {
+                "buildy.toml"
+            };
+            println!("{}={} ({})", key, value, source);
+        }
+    }
+
+    /// Merge the session variables into the `[run.env]` table of
+    /// `buildy.toml`, keeping the rest of the file (comments included) intact.
+    fn save(&self, root: &Path) -> Result<(), String> {
+        let path = root.join(crate::config::CONFIG_FILENAME);
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(format!("could not read {}: {}", path.display(), e)),
+        };
+        let mut doc: toml_edit::DocumentMut = text
+            .parse()
+            .map_err(|e| format!("could not parse {}: {}", path.display(), e))?;
+
+        let run = doc
+            .entry("run")
+            .or_insert_with(|| {
+                // don't emit an empty `[run]` header above `[run.env]`
+                let mut table = toml_edit::Table::new();
+                table.set_implicit(true);
+                toml_edit::Item::Table(table)
+            })
+            .as_table_mut()
+            .ok_or("`run` in buildy.toml is not a table")?;
+        let env = run
+            .entry("env")
+            .or_insert_with(toml_edit::table)
+            .as_table_mut()
+            .ok_or("`run.env` in buildy.toml is not a table")?;
+        for (key, value) in &self.vars {
+            env.insert(key, toml_edit::value(value.as_str()));
+        }
+
+        fs::write(&path, doc.to_string())
+            .map_err(|e| format!("could not write {}: {}", path.display(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_string).collect()
+    }
+
+    /// A project whose `buildy.toml` sets `[run.env]`.
+    fn project() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("buildy.toml"),
+            "# keep me\nname = \"app\"\n\n[run.env]\nLEVEL = \"config\"\nMODE = \"fast\"\n",
+        )
+        .unwrap();
+        dir
+    }
+
+    #[test]
+    fn session_values_override_the_config() {
+        let dir = project();
+        let root = dir.path();
+        let mut env = SessionEnv::default();
+        env.command(&args("set LEVEL=session"), root).unwrap();
+        env.command(&args("set TRACE=a=b"), root).unwrap();
+        let resolved = env.resolve(&Config::load(root));
+        assert_eq!(
+            resolved,
+            BTreeMap::from([
+                ("LEVEL".to_string(), "session".to_string()),
+                ("MODE".to_string(), "fast".to_string()),
+                ("TRACE".to_string(), "a=b".to_string()),
+            ])
+        );
+
+        env.command(&args("unset LEVEL"), root).unwrap();
+        assert_eq!(env.resolve(&Config::load(root))["LEVEL"], "config");
+        assert!(env.command(&args("unset LEVEL"), root).is_err());
+        assert!(env.command(&args("set =x"), root).is_err());
+        assert!(env.command(&args("set novalue"), root).is_err());
+        assert!(env.command(&args("frobnicate"), root).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn the_overlay_reaches_the_child() {
+        use crate::executor::{self, ExecRequest};
+
+        let dir = project();
+        let root = dir.path();
+        let mut env = SessionEnv::default();
+        env.command(&args("set MYAPP_TRACE=1"), root).unwrap();
+        env.command(&args("set LEVEL=session"), root).unwrap();
+        let request = ExecRequest {
+            program: "/bin/sh".into(),
+            args: vec!["-c".into(), "echo $MYAPP_TRACE $LEVEL $MODE".into()],
+            env: env.resolve(&Config::load(root)),
+            capture: true,
+            ..ExecRequest::default()
+        };
+        let result = executor::execute(&request).unwrap();
+        assert_eq!(result.stdout, "1 session fast\n");
+    }
+
+    #[test]
+    fn save_merges_into_run_env() {
+        let dir = project();
+        let root = dir.path();
+        let mut env = SessionEnv::default();
+        env.command(&args("set LEVEL=saved"), root).unwrap();
+        env.command(&args("set EXTRA=1"), root).unwrap();
+        env.command(&args("save"), root).unwrap();
+        let text = fs::read_to_string(root.join("buildy.toml")).unwrap();
+        assert!(text.starts_with("# keep me\n"), "{}", text);
+        let config = Config::load(root);
+        assert_eq!(config.run.env["LEVEL"], "saved");
+        assert_eq!(config.run.env["MODE"], "fast");
+        assert_eq!(config.run.env["EXTRA"], "1");
+    }
+
+    #[test]
+    fn save_creates_the_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let mut env = SessionEnv::default();
+        env.command(&args("set X=1"), root).unwrap();
+        env.command(&args("save"), root).unwrap();
+        let text = fs::read_to_string(root.join("buildy.toml")).unwrap();
+        assert_eq!(text, "[run.env]\nX = \"1\"\n");
+    }
+}
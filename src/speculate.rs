@@ -0,0 +1,189 @@
+use crate::hasher::hash_file;
+use crate::scheduler::CompileCommand;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+
+/// Upper bound for the staged objects of one profile; the oldest ones are
+/// dropped beyond it.
+const MAX_STAGED_BYTES: u64 = 256 * 1024 * 1024;
+
+/// Directory holding objects compiled ahead of time for a profile.  Each is
+/// named after `job_key` of the compile that produced it, with the
+/// compiler's output next to it in a `.log` file.
+pub fn staging_dir(root: &Path, profile: &str) -> PathBuf {
+    root.join("target").join(profile).join("speculative")
+}
+
+/// Identity of a compile: the command plus the current contents of its
+/// inputs, the same hash `DirtyPlan` computes from the graph.  `None` when
+/// an input can't be read.
+pub fn job_key(command: &CompileCommand, inputs: &[PathBuf]) -> Option<String> {
+    let hashes = inputs
+        .iter()
+        .map(|input| hash_file(input).ok())
+        .collect::<Option<Vec<String>>>()?;
+    Some(
+        command.digest(
+            inputs
+                .iter()
+                .map(PathBuf::as_path)
+                .zip(hashes.iter().map(String::as_str)),
+        ),
+    )
+}
+
+/// A staged object whose key matches, with the compiler output that came
+/// with it.
+pub struct Staged {
+    pub object: PathBuf,
+    pub log: PathBuf,
+}
+
+impl Staged {
+    pub fn find(root: &Path, profile: &str, key: &str) -> Option<Self> {
+        let dir = staging_dir(root, profile);
+        let object = dir.join(format!("{}.o", key));
+        object.exists().then(|| Staged {
+            object,
+            log: dir.join(format!("{}.log", key)),
+        })
+    }
+
+    /// Move the object to `dest`.  Returns the compiler output recorded
+    /// when it was built, or `None` if the object is gone (e.g. evicted).
+    pub fn promote(&self, dest: &Path) -> Option<String> {
+        fs::rename(&self.object, dest).ok()?;
+        let diagnostics = fs::read_to_string(&self.log).unwrap_or_default();
+        let _ = fs::remove_file(&self.log);
+        Some(diagnostics)
+    }
+}
+
+/// One compile to run ahead of time.
+pub struct Job {
+    pub command: CompileCommand,
+    pub inputs: Vec<PathBuf>,
+    pub key: String,
+}
+
+/// Background compiles started by the watch repl while it is idle.  Only
+/// one batch runs at a time; starting a new one cancels the previous batch
+/// after its current compile.
+#[derive(Default)]
+pub struct Speculator {
+    cancel: Arc<AtomicBool>,
+    worker: Option<JoinHandle<()>>,
+    /// Staging directories used so far, removed by `stop`.
+    dirs: HashSet<PathBuf>,
+}
+
+impl Speculator {
+    /// Compile `jobs` one after another at low priority into `dir`.
+    pub fn start(&mut self, dir: PathBuf, jobs: Vec<Job>) {
+        self.cancel.store(true, Ordering::Relaxed);
+        if jobs.is_empty() || fs::create_dir_all(&dir).is_err() {
+            return;
+        }
+        self.dirs.insert(dir.clone());
+        let cancel = Arc::new(AtomicBool::new(false));
+        self.cancel = cancel.clone();
+        self.worker = Some(thread::spawn(move || {
+            for job in jobs {
+                if cancel.load(Ordering::Relaxed) {
+                    break;
+                }
+                stage(&dir, &job);
+                evict(&dir, MAX_STAGED_BYTES);
+            }
+        }));
+    }
+
+    /// Cancel the running batch, wait for it and remove everything staged.
+    pub fn stop(&mut self) {
+        self.cancel.store(true, Ordering::Relaxed);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+        for dir in self.dirs.drain() {
+            let _ = fs::remove_dir_all(dir);
+        }
+    }
+}
+
+/// Compile `job` into the staging directory.  The object is only kept if
+/// the inputs still have the contents `job.key` was computed from once the
+/// compiler is done, so an edit made during the compile can't be promoted.
+fn stage(dir: &Path, job: &Job) {
+    let tmp = dir.join(format!("{}.tmp.o", job.key));
+    let Some(args) = job
+        .command
+        .toolchain
+        .redirect_object(&job.command.args, &tmp)
+    else {
+        return;
+    };
+    let output = match run_nice(&job.command.program, &args, &job.command.directory) {
+        Ok(output) => output,
+        Err(_) => return,
+    };
+    if output.status.success() && job_key(&job.command, &job.inputs).as_ref() == Some(&job.key) {
+        let mut diagnostics = String::from_utf8_lossy(&output.stdout).into_owned();
+        diagnostics.push_str(&String::from_utf8_lossy(&output.stderr));
+        let diagnostics = diagnostics.trim_end();
+        if diagnostics.is_empty()
+            || fs::write(dir.join(format!("{}.log", job.key)), diagnostics).is_ok()
+        {
+            let _ = fs::rename(&tmp, dir.join(format!("{}.o", job.key)));
+            return;
+        }
+    }
+    let _ = fs::remove_file(&tmp);
+}
+
+/// Run the compiler under `nice` where available so speculative work
+/// doesn't slow down the editor or a real build.
+fn run_nice(program: &str, args: &[String], dir: &Path) -> io::Result<Output> {
+    if cfg!(unix) {
+        let niced = Command::new("nice")
+            .arg(program)
+            .args(args)
+            .current_dir(dir)
+            .output();
+        if !matches!(&niced, Err(e) if e.kind() == io::ErrorKind::NotFound) {
+            return niced;
+        }
+    }
+    Command::new(program).args(args).current_dir(dir).output()
+}
+
+/// Remove the oldest staged objects until the directory holds at most
+/// `limit` bytes.
+fn evict(dir: &Path, limit: u64) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    let mut objects: Vec<(std::time::SystemTime, u64, PathBuf)> = entries
+        .flatten()
+        .filter(|e| e.path().extension().is_some_and(|ext| ext == "o"))
+        .filter_map(|e| {
+            let meta = e.metadata().ok()?;
+            Some((meta.modified().ok()?, meta.len(), e.path()))
+        })
+        .collect();
+    let mut total: u64 = objects.iter().map(|(_, size, _)| size).sum();
+    objects.sort();
+    for (_, size, path) in objects {
+        if total <= limit {
+            break;
+        }
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(path.with_extension("log"));
+        total -= size;
+    }
+}
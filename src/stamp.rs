@@ -0,0 +1,127 @@
+use crate::cache::BuildCache;
+use crate::config::{self, Config};
+use crate::graph::BuildGraph;
+use crate::scheduler::{self, BuildOptions};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Name of the generated translation unit holding the stamp.
+const STAMP_SOURCE: &str = "buildy_version.c";
+
+/// Identity of a build, compiled into the program when `version_stamp` is
+/// enabled.  The values live in a generated source of their own rather than
+/// in a `-D` flag so a new stamp only recompiles that one file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stamp {
+    /// Short hash over the content hashes of every input file.
+    pub build_id: String,
+    /// `git describe --always --dirty` when the project is a git checkout.
+    pub git_describe: Option<String>,
+}
+
+impl Stamp {
+    /// Compute the stamp for the current state of `graph`, whose nodes must
+    /// already have up-to-date hashes.
+    pub fn compute(graph: &BuildGraph, root: &Path) -> Self {
+        let mut inputs: Vec<String> = graph
+            .nodes
+            .values()
+            .map(|m| format!("{}={}", BuildCache::make_relative(&m.path, root), m.hash))
+            .collect();
+        inputs.sort();
+        let mut hasher = Sha256::new();
+        for input in &inputs {
+            hasher.update(input.as_bytes());
+            hasher.update(b"\n");
+        }
+        let digest = format!("{:x}", hasher.finalize());
+
+        let git_describe = Command::new("git")
+            .arg("-C")
+            .arg(root)
+            .args(["describe", "--always", "--dirty"])
+            .output()
+            .ok()
+            .filter(|o| o.status.success())
+            .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string());
+
+        Stamp {
+            build_id: digest[..12].to_string(),
+            git_describe,
+        }
+    }
+
+    fn source(&self) -> String {
+        let describe = self.git_describe.as_deref().unwrap_or("");
+        format!(
+            "/* generated by buildy, do not edit */\n\
+             const char buildy_build_id[] = \"{}\";\n\
+             const char buildy_git_describe[] = \"{}\";\n",
+            c_escape(&self.build_id),
+            c_escape(describe)
+        )
+    }
+}
+
+fn c_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// The stamp source in the profile's output directory.
+fn source_path(root: &Path, opts: &BuildOptions) -> PathBuf {
+    root.join("target")
+        .join(opts.profile_name())
+        .join("generated")
+        .join(STAMP_SOURCE)
+}
+
+/// The object `prepare` compiles the stamp into.
+pub fn object_path(root: &Path, opts: &BuildOptions) -> PathBuf {
+    source_path(root, opts).with_extension(scheduler::OBJECT_EXTENSION)
+}
+
+/// Write the stamp source into the profile's output directory and compile
+/// it when its content changed.  Returns the object file to link.
+pub fn prepare(
+    stamp: &Stamp,
+    root: &Path,
+    config: &Config,
+    opts: &BuildOptions,
+) -> Result<PathBuf, String> {
+    let source = source_path(root, opts);
+    let object = object_path(root, opts);
+    if let Some(dir) = source.parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    }
+
+    let text = stamp.source();
+    let unchanged = fs::read_to_string(&source).ok().as_deref() == Some(text.as_str());
+    if unchanged && object.exists() {
+        return Ok(object);
+    }
+    fs::write(&source, text).map_err(|e| format!("{}: {}", source.display(), e))?;
+    // position independent so the same object also fits a shared library
+    let toolchain = config.toolchain();
+    let flags = if toolchain.is_gnu() {
+        vec!["-fPIC".to_string()]
+    } else {
+        Vec::new()
+    };
+    let mut command = Command::new(config.c_compiler());
+    command.args(toolchain.compile_args(flags, &source, &object));
+    if opts.verbose > 0 {
+        let args = command.get_args().map(|a| a.to_string_lossy());
+        let line = shell_words::join(std::iter::once(config.c_compiler().into()).chain(args));
+        opts.message_format.status(format!("+ {}", line));
+    }
+    let status = command
+        .status()
+        .map_err(|e| config::spawn_error(config.c_compiler(), &e))?;
+    if !status.success() {
+        return Err(format!("compiler failed on {}", source.display()));
+    }
+    Ok(object)
+}
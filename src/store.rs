@@ -0,0 +1,183 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Objects shared by every profile and branch of a project, named after the
+/// combined hash of the compile that produced them (see `DirtyPlan`), with
+/// the compiler's output next to each in a `.log` file.  A compile whose
+/// hash is already here is replaced by linking the stored object into
+/// place, so switching back to a branch doesn't recompile it.
+pub struct ObjectStore {
+    dir: PathBuf,
+}
+
+impl ObjectStore {
+    pub fn new(root: &Path) -> Self {
+        ObjectStore {
+            dir: root.join("target").join(".buildy").join("objects"),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn object(&self, hash: &str) -> PathBuf {
+        self.dir.join(format!("{}.o", hash))
+    }
+
+    fn log(&self, hash: &str) -> PathBuf {
+        self.dir.join(format!("{}.log", hash))
+    }
+
+    /// Where the output named like `dest` of the link `digest` is kept.
+    fn linked(&self, digest: &str, dest: &Path) -> PathBuf {
+        let name = dest.file_name().unwrap_or_default().to_string_lossy();
+        self.dir.join(format!("{}-{}.link", digest, name))
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.object(hash).is_file()
+    }
+
+    /// Put the object stored for `hash` at `dest`, hard-linked where
+    /// possible.  Returns the compiler output recorded with it, or `None` on
+    /// a miss.
+    pub fn fetch(&self, hash: &str, dest: &Path) -> Option<String> {
+        let object = self.object(hash);
+        if !object.is_file() {
+            return None;
+        }
+        let _ = fs::remove_file(dest);
+        fs::hard_link(&object, dest)
+            .or_else(|_| fs::copy(&object, dest).map(|_| ()))
+            .ok()?;
+        // the modification time orders entries for `prune`
+        let _ = fs::File::options()
+            .write(true)
+            .open(&object)
+            .and_then(|f| f.set_modified(SystemTime::now()));
+        Some(fs::read_to_string(self.log(hash)).unwrap_or_default())
+    }
+
+    /// Put the program or library linked before from the inputs `digest`
+    /// (see `scheduler::link_digest`) describes at `dest`, so going back to
+    /// those inputs doesn't link again.  Copied rather than hard-linked, as
+    /// `ar` updates an archive in place.  Returns whether it was there.
+    pub fn fetch_link(&self, digest: &str, dest: &Path) -> bool {
+        let linked = self.linked(digest, dest);
+        if !linked.is_file() {
+            return false;
+        }
+        let _ = fs::remove_file(dest);
+        if fs::copy(&linked, dest).is_err() {
+            return false;
+        }
+        let _ = fs::File::options()
+            .write(true)
+            .open(&linked)
+            .and_then(|f| f.set_modified(SystemTime::now()));
+        true
+    }
+
+    /// Remember `output`, just linked from the inputs `digest` describes.
+    /// Best effort, like `insert`.
+    pub fn insert_link(&self, digest: &str, output: &Path) {
+        if fs::create_dir_all(&self.dir).is_err() {
+            return;
+        }
+        let linked = self.linked(digest, output);
+        let tmp = linked.with_extension(format!("tmp.{}", std::process::id()));
+        let stored = fs::copy(output, &tmp).and_then(|_| fs::rename(&tmp, &linked));
+        if stored.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+    }
+
+    /// Remember `object`, just compiled with the combined hash `hash`.
+    /// Best effort: a store that can't be written only costs the reuse.
+    pub fn insert(&self, hash: &str, object: &Path, diagnostics: &str) {
+        if fs::create_dir_all(&self.dir).is_err() {
+            return;
+        }
+        if !diagnostics.is_empty() && fs::write(self.log(hash), diagnostics).is_err() {
+            return;
+        }
+        let tmp = self
+            .dir
+            .join(format!("{}.tmp.{}.o", hash, std::process::id()));
+        let _ = fs::remove_file(&tmp);
+        let stored = fs::hard_link(object, &tmp)
+            .or_else(|_| fs::copy(object, &tmp).map(|_| ()))
+            .and_then(|_| fs::rename(&tmp, self.object(hash)));
+        if stored.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+    }
+
+    /// Remove the least recently used objects and links until the store
+    /// holds at most `max_bytes`.  Returns how many were removed and the
+    /// bytes reclaimed.
+    pub fn prune(&self, max_bytes: u64) -> io::Result<(usize, u64)> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((0, 0)),
+            Err(e) => return Err(e),
+        };
+        let mut objects = Vec::new();
+        let mut total = 0;
+        for entry in entries {
+            let path = entry?.path();
+            let meta = fs::metadata(&path)?;
+            total += meta.len();
+            if path
+                .extension()
+                .is_some_and(|ext| ext == "o" || ext == "link")
+            {
+                let log = fs::metadata(path.with_extension("log")).map_or(0, |m| m.len());
+                objects.push((meta.modified()?, meta.len() + log, path));
+            }
+        }
+        objects.sort();
+        let (mut removed, mut freed) = (0, 0);
+        for (_, size, path) in objects {
+            if total <= max_bytes {
+                break;
+            }
+            fs::remove_file(&path)?;
+            let _ = fs::remove_file(path.with_extension("log"));
+            total -= size.min(total);
+            freed += size;
+            removed += 1;
+        }
+        Ok((removed, freed))
+    }
+}
+
+/// Parse a size such as `2G`, `512M`, `100K` or `4096` (bytes).  Units are
+/// powers of 1024 and may be followed by `B` or `iB`.
+pub fn parse_size(text: &str) -> Result<u64, String> {
+    let text = text.trim();
+    let split = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid size '{}', expected e.g. 2G", text))?;
+    let unit = unit.trim_end_matches("iB").trim_end_matches('B');
+    let shift = match unit.to_ascii_uppercase().as_str() {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => {
+            return Err(format!("invalid size unit in '{}', use K, M, G or T", text));
+        }
+    };
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("size '{}' is too large", text))
+}
@@ -0,0 +1,131 @@
+use colored::Colorize;
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a program gets to exit after SIGTERM before it is killed.
+const GRACE_PERIOD: Duration = Duration::from_secs(3);
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// The program the watch repl keeps running with `autorun on` (or
+/// `watch --exec`), restarted after every successful rebuild.  Its output
+/// is forwarded line by line, each line tagged with the program's name.
+#[derive(Default)]
+pub struct Supervisor {
+    running: Option<Running>,
+}
+
+struct Running {
+    name: String,
+    child: Child,
+}
+
+impl Supervisor {
+    /// Stop the running program, if any, and start `exe` with `args` and
+    /// `env` added to the inherited environment.
+    pub fn restart(
+        &mut self,
+        exe: &Path,
+        args: &[String],
+        env: &BTreeMap<String, String>,
+    ) -> io::Result<()> {
+        self.stop();
+        let name = exe
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let mut child = Command::new(exe)
+            .args(args)
+            .envs(env)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        // the threads end with the program, or with whatever it started
+        // that still holds the pipes
+        forward(child.stdout.take(), name.clone(), false);
+        forward(child.stderr.take(), name.clone(), true);
+        println!("started {} (pid {})", name, child.id());
+        self.running = Some(Running { name, child });
+        Ok(())
+    }
+
+    /// Stop the running program: SIGTERM first, SIGKILL if it hasn't exited
+    /// after `GRACE_PERIOD`.  Without Unix signals it is killed right away.
+    pub fn stop(&mut self) {
+        let Some(mut running) = self.running.take() else {
+            return;
+        };
+        let status = match running.child.try_wait() {
+            Ok(Some(status)) => Some(status),
+            _ => {
+                terminate(&mut running.child);
+                let deadline = Instant::now() + GRACE_PERIOD;
+                loop {
+                    match running.child.try_wait() {
+                        Ok(Some(_)) => break None,
+                        Ok(None) if Instant::now() < deadline => thread::sleep(POLL_INTERVAL),
+                        _ => {
+                            let _ = running.child.kill();
+                            let _ = running.child.wait();
+                            break None;
+                        }
+                    }
+                }
+            }
+        };
+        match status {
+            Some(status) => println!("{} had exited with {}", running.name, status),
+            None => println!("stopped {}", running.name),
+        }
+    }
+}
+
+impl Drop for Supervisor {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(unix)]
+fn terminate(child: &mut Child) {
+    let Ok(pid) = libc::pid_t::try_from(child.id()) else {
+        let _ = child.kill();
+        return;
+    };
+    // not waited for yet, so the pid can't belong to another process
+    if unsafe { libc::kill(pid, libc::SIGTERM) } != 0 {
+        let _ = child.kill();
+    }
+}
+
+#[cfg(not(unix))]
+fn terminate(child: &mut Child) {
+    let _ = child.kill();
+}
+
+/// Copy `pipe` to our stdout (or stderr) line by line, each line prefixed
+/// with `[name]` so it stands apart from Buildy's own output.
+fn forward(pipe: Option<impl Read + Send + 'static>, name: String, stderr: bool) {
+    thread::spawn(move || {
+        let Some(pipe) = pipe else {
+            return;
+        };
+        let tag = format!("[{}]", name).cyan();
+        for line in BufReader::new(pipe).split(b'\n') {
+            let Ok(line) = line else {
+                break;
+            };
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches('\r');
+            if stderr {
+                let _ = writeln!(io::stderr(), "{} {}", tag, line);
+            } else {
+                let _ = writeln!(io::stdout(), "{} {}", tag, line);
+            }
+        }
+    });
+}
@@ -9,11 +9,15 @@ use std::path::{Path, PathBuf};
 pub struct FileMeta {
     /// Absolute path to the source file.
     pub path: PathBuf,
-    /// Hash of the contents (sha256 of file plus dependencies when computed by graph).
+    /// sha256 of the file's own contents.  The hash a source is rebuilt on,
+    /// which covers its headers too, is computed by `DirtyPlan`.
     pub hash: String,
     /// Last modified time (stored as RFC3339 string because SystemTime doesn't
     /// serialize directly).
     pub last_modified: DateTime<Utc>,
+    /// Size in bytes, read with `last_modified`.
+    #[serde(default)]
+    pub size: u64,
     /// Direct dependencies (headers) that this file includes.
     pub deps: Vec<PathBuf>,
     /// Reverse dependencies: other files that depend on this one.
@@ -34,6 +38,7 @@ impl FileMeta {
             path,
             hash: String::new(),
             last_modified,
+            size: metadata.len(),
             deps: Vec::new(),
             dependents: Vec::new(),
             dirty: true,
@@ -44,12 +49,18 @@ impl FileMeta {
     where
         T: Fn(&Path) -> io::Result<String>,
     {
-        let metadata = fs::metadata(&self.path)?;
-        let modified = metadata.modified()?;
-        self.last_modified = modified.into();
-
+        self.stat()?;
         self.hash = hash_fn(&self.path)?; // always computes hash to ensure it reflects current content
 
         Ok(())
     }
+
+    /// Read the modification time and size of the file again, without
+    /// hashing it.
+    pub fn stat(&mut self) -> io::Result<()> {
+        let metadata = fs::metadata(&self.path)?;
+        self.last_modified = metadata.modified()?.into();
+        self.size = metadata.len();
+        Ok(())
+    }
 }
@@ -0,0 +1,217 @@
+use crate::cache::BuildCache;
+use crate::error::BuildyError;
+use crate::executor::{self, ExecError, ExecRequest};
+use crate::graph::BuildGraph;
+use crate::hasher::hash_file;
+use crate::lang::is_source;
+use crate::scheduler::LinkTarget;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+/// Directory of the test programs, relative to the project root.  Every
+/// source in it belongs to the tests: the ones defining `main` are test
+/// programs, the others are linked into each of them.
+pub const TESTS_DIR: &str = "tests";
+
+/// Whether `source` lives under `tests/` of `root`.
+pub fn is_test(source: &Path, root: &Path) -> bool {
+    let tests = root.join(TESTS_DIR);
+    // graph paths are canonical, `root` may not be
+    source.starts_with(&tests)
+        || tests
+            .canonicalize()
+            .is_ok_and(|tests| source.starts_with(tests))
+}
+
+/// Whether the test program `source` is selected by `filter`, a substring
+/// of its path below `tests/`.
+fn selected(source: &Path, root: &Path, filter: &str) -> bool {
+    let name = BuildCache::make_relative(source, &root.join(TESTS_DIR));
+    name.contains(filter)
+}
+
+/// Whether a build for the tests matching `filter` leaves `source` out:
+/// the program's own `main` and the test programs not selected.
+pub fn skips(graph: &BuildGraph, root: &Path, source: &Path, filter: &str) -> bool {
+    let is_main = graph.mains.contains(source);
+    match is_test(source, root) {
+        true => is_main && !selected(source, root, filter),
+        false => is_main,
+    }
+}
+
+/// The test programs matching `filter`, linked into `output_dir/tests/`:
+/// each test source defining `main` with the other sources of `tests/` and
+/// every source of the project except the ones defining `main`.
+pub fn link_targets(
+    graph: &BuildGraph,
+    root: &Path,
+    output_dir: &Path,
+    filter: &str,
+) -> Vec<LinkTarget> {
+    let mut shared: Vec<PathBuf> = graph
+        .nodes
+        .keys()
+        .filter(|p| is_source(p) && !graph.mains.contains(*p))
+        .cloned()
+        .collect();
+    shared.sort();
+    graph
+        .mains
+        .iter()
+        .filter(|main| is_test(main, root) && selected(main, root, filter))
+        .map(|main| {
+            let stem = main
+                .file_stem()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let mut sources = shared.clone();
+            sources.push(main.clone());
+            LinkTarget {
+                output: output_dir.join(TESTS_DIR).join(format!(
+                    "{}{}",
+                    stem,
+                    std::env::consts::EXE_SUFFIX
+                )),
+                sources,
+            }
+        })
+        .collect()
+}
+
+/// How running one test program went.
+enum Outcome {
+    Passed,
+    /// Passed before with a binary identical to this one, so it wasn't run.
+    Cached,
+    /// Failed, with what it printed (or why it couldn't run).
+    Failed(String),
+}
+
+/// Run the test programs `tests` from `root` with `env`, `jobs` at a time,
+/// reporting each as it finishes and a summary at the end.  A test that
+/// passed with the same binary before isn't run again unless `force`;
+/// `passed` records the binaries that passed, by test name.
+pub fn run(
+    tests: &[PathBuf],
+    root: &Path,
+    env: &BTreeMap<String, String>,
+    jobs: usize,
+    force: bool,
+    passed: &mut BTreeMap<String, String>,
+) -> Result<(), BuildyError> {
+    println!("running {} test(s)", tests.len());
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::new());
+    let previous = &*passed;
+    thread::scope(|s| {
+        for _ in 0..jobs.clamp(1, tests.len().max(1)) {
+            s.spawn(|| {
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(test) = tests.get(i) else {
+                        break;
+                    };
+                    let name = test_name(test);
+                    let hash = hash_file(test).ok();
+                    let outcome = match &hash {
+                        Some(hash) if !force && previous.get(&name) == Some(hash) => {
+                            Outcome::Cached
+                        }
+                        _ => run_one(test, root, env),
+                    };
+                    report(&name, &outcome);
+                    results.lock().unwrap().push((name, hash, outcome));
+                }
+            });
+        }
+    });
+
+    let results = results.into_inner().unwrap();
+    let mut failed: Vec<&str> = Vec::new();
+    let mut cached = 0;
+    for (name, hash, outcome) in &results {
+        match outcome {
+            Outcome::Failed(_) => {
+                passed.remove(name);
+                failed.push(name);
+            }
+            outcome => {
+                cached += matches!(outcome, Outcome::Cached) as usize;
+                if let Some(hash) = hash {
+                    passed.insert(name.clone(), hash.clone());
+                }
+            }
+        }
+    }
+    failed.sort();
+    println!(
+        "{} passed, {} failed{}",
+        results.len() - failed.len(),
+        failed.len(),
+        match cached {
+            0 => String::new(),
+            n => format!(" ({} unchanged, not run again)", n),
+        }
+    );
+    if failed.is_empty() {
+        return Ok(());
+    }
+    println!("failed: {}", failed.join(", "));
+    Err(BuildyError::TestsFailed {
+        failed: failed.len(),
+        total: results.len(),
+    })
+}
+
+/// Name of the test program `test` in reports and the cache: its path
+/// below the profile directory, e.g. `tests/parse`.
+fn test_name(test: &Path) -> String {
+    let stem = test
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    format!("{}/{}", TESTS_DIR, stem)
+}
+
+fn run_one(test: &Path, root: &Path, env: &BTreeMap<String, String>) -> Outcome {
+    let request = ExecRequest {
+        program: test.display().to_string(),
+        cwd: Some(root.to_path_buf()),
+        env: env.clone(),
+        capture: true,
+        ..ExecRequest::default()
+    };
+    match executor::execute(&request) {
+        Ok(_) => Outcome::Passed,
+        Err(ExecError::Failed {
+            status,
+            stdout,
+            stderr,
+            ..
+        }) => {
+            let mut output = format!("{}{}", stdout, stderr).trim_end().to_string();
+            if !output.is_empty() {
+                output.push('\n');
+            }
+            output.push_str(&format!("exited with {}", status));
+            Outcome::Failed(output)
+        }
+        Err(e) => Outcome::Failed(e.to_string()),
+    }
+}
+
+/// Print the outcome of the test `name` as one block.
+fn report(name: &str, outcome: &Outcome) {
+    match outcome {
+        Outcome::Passed => println!("test {} ... ok", name),
+        Outcome::Cached => println!("test {} ... ok (unchanged)", name),
+        Outcome::Failed(output) => {
+            let indented: Vec<String> = output.lines().map(|l| format!("  {}", l)).collect();
+            println!("test {} ... FAILED\n{}", name, indented.join("\n"));
+        }
+    }
+}
@@ -0,0 +1,195 @@
+use crate::cache::BuildCache;
+use crate::graph::BuildGraph;
+use crate::lang::is_source;
+use crate::messages::MessageFormat;
+pub use crate::protocol::Timings;
+use crate::protocol::{CompileTime, Versioned};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Compiles shown in the `--timings` table; the report has all of them.
+const SLOWEST_SHOWN: usize = 10;
+
+impl Timings {
+    /// Timings of a build that ran the `compiled` compiles and spent `link`
+    /// linking, `total` overall.  The cache holds the durations of earlier
+    /// compiles.
+    pub fn new(
+        compiled: &[(PathBuf, Duration)],
+        graph: &BuildGraph,
+        cache: &BuildCache,
+        root: &Path,
+        link: Duration,
+        total: Duration,
+    ) -> Self {
+        let mut compiles: Vec<CompileTime> = compiled
+            .iter()
+            .map(|(path, duration)| CompileTime {
+                file: BuildCache::make_relative(path, root),
+                ms: duration.as_millis() as u64,
+            })
+            .collect();
+        compiles.sort_by(|a, b| b.ms.cmp(&a.ms).then_with(|| a.file.cmp(&b.file)));
+        let ran: HashSet<&PathBuf> = compiled.iter().map(|(path, _)| path).collect();
+        let saved_ms = graph
+            .nodes
+            .keys()
+            .filter(|path| is_source(path) && !ran.contains(path))
+            .filter_map(|path| cache.compile_ms(path, root))
+            .sum();
+        Timings {
+            compiles,
+            link_ms: link.as_millis() as u64,
+            total_ms: total.as_millis() as u64,
+            saved_ms,
+        }
+    }
+
+    /// Print the slowest compiles and the totals.
+    pub fn print(&self, messages: MessageFormat) {
+        messages.status("timings:");
+        for compile in self.compiles.iter().take(SLOWEST_SHOWN) {
+            messages.status(format!("  {:>8}  {}", seconds(compile.ms), compile.file));
+        }
+        if self.compiles.len() > SLOWEST_SHOWN {
+            messages.status(format!("  ({} more)", self.compiles.len() - SLOWEST_SHOWN));
+        }
+        let compile_ms: u64 = self.compiles.iter().map(|c| c.ms).sum();
+        messages.status(format!(
+            "  {} compiling ({} file(s)), {} linking, {} in total",
+            seconds(compile_ms),
+            self.compiles.len(),
+            seconds(self.link_ms),
+            seconds(self.total_ms)
+        ));
+        if self.saved_ms > 0 {
+            messages.status(format!(
+                "  about {} of compiling saved by the cache",
+                seconds(self.saved_ms)
+            ));
+        }
+    }
+
+    /// Write the report for `profile` as JSON, replacing the previous one.
+    /// Returns the path written.
+    pub fn write(&self, root: &Path, profile: &str) -> io::Result<PathBuf> {
+        let dir = root.join("target").join(".buildy").join("timings");
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(format!("{}.json", profile));
+        let json = serde_json::to_string_pretty(&Versioned::new(self)).map_err(io::Error::other)?;
+        fs::write(&path, json + "\n")?;
+        Ok(path)
+    }
+}
+
+fn seconds(ms: u64) -> String {
+    format!("{:.2}s", ms as f64 / 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::SCHEMA_VERSION;
+    use crate::target::FileMeta;
+    use chrono::Utc;
+
+    /// A graph of `sources` below `root`, and a cache that remembers how long
+    /// each took to compile the last time.
+    fn compiled_before(root: &Path, sources: &[(&str, u64)]) -> (BuildGraph, BuildCache) {
+        let mut graph = BuildGraph::new();
+        let mut cache = BuildCache::load(root, "debug");
+        for (name, ms) in sources {
+            let meta = FileMeta {
+                path: root.join(name),
+                hash: String::new(),
+                last_modified: Utc::now(),
+                size: 0,
+                deps: Vec::new(),
+                dependents: Vec::new(),
+                dirty: false,
+            };
+            cache.update_file(&meta, root);
+            cache.record_duration(&meta.path, root, *ms);
+            graph.nodes.insert(meta.path.clone(), meta);
+        }
+        (graph, cache)
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn compiles_are_listed_slowest_first() {
+        let root = Path::new("/p");
+        let (graph, cache) = compiled_before(root, &[]);
+        let compiled = [
+            (root.join("src/b.c"), ms(120)),
+            (root.join("src/slow.cpp"), ms(4_000)),
+            (root.join("src/a.c"), ms(120)),
+            (root.join("src/main.c"), ms(30)),
+        ];
+        let timings = Timings::new(&compiled, &graph, &cache, root, ms(250), ms(4_500));
+        let order: Vec<(&str, u64)> = timings
+            .compiles
+            .iter()
+            .map(|c| (c.file.as_str(), c.ms))
+            .collect();
+        assert_eq!(
+            order,
+            [
+                ("src/slow.cpp", 4_000),
+                ("src/a.c", 120),
+                ("src/b.c", 120),
+                ("src/main.c", 30)
+            ]
+        );
+        assert_eq!((timings.link_ms, timings.total_ms), (250, 4_500));
+        assert_eq!(timings.saved_ms, 0);
+    }
+
+    #[test]
+    fn sources_not_compiled_count_as_saved() {
+        let root = Path::new("/p");
+        let (graph, cache) = compiled_before(
+            root,
+            &[
+                ("src/a.c", 300),
+                ("src/b.c", 700),
+                ("src/c.c", 50),
+                // headers aren't compiled, whatever the cache says
+                ("src/a.h", 1_000),
+            ],
+        );
+        let compiled = [(root.join("src/c.c"), ms(60))];
+        let timings = Timings::new(&compiled, &graph, &cache, root, ms(0), ms(100));
+        assert_eq!(timings.saved_ms, 1_000);
+        assert_eq!(timings.compiles.len(), 1);
+    }
+
+    #[test]
+    fn the_report_is_versioned_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let (graph, cache) = compiled_before(root, &[("src/util.c", 80)]);
+        let compiled = [(root.join("src/main.c"), ms(1_500))];
+        let timings = Timings::new(&compiled, &graph, &cache, root, ms(40), ms(1_600));
+        let path = timings.write(root, "release").unwrap();
+        assert_eq!(path, root.join("target/.buildy/timings/release.json"));
+        let report: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(
+            report,
+            serde_json::json!({
+                "schema_version": SCHEMA_VERSION,
+                "compiles": [{ "file": "src/main.c", "ms": 1_500 }],
+                "link_ms": 40,
+                "total_ms": 1_600,
+                "saved_ms": 80,
+            })
+        );
+    }
+}
@@ -0,0 +1,531 @@
+use crate::depfile;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// Marks an include in the `/showIncludes` output of `cl`.
+const SHOW_INCLUDES_PREFIX: &str = "Note: including file:";
+
+/// The compiler family, which decides how compile and link commands are
+/// spelled.  Detected from the compiler name unless `toolchain` is set in
+/// `buildy.toml`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Toolchain {
+    /// gcc, clang and anything else taking their flags, MinGW included.
+    Gnu,
+    /// `cl.exe` (or `clang-cl`) with `link.exe` and `lib.exe`.
+    Msvc,
+}
+
+impl Toolchain {
+    /// The toolchain `compiler` belongs to: `cl` and `clang-cl`, with or
+    /// without a directory or `.exe`, are MSVC, everything else GNU.
+    pub fn of_compiler(compiler: &str) -> Toolchain {
+        let stem = Path::new(compiler)
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_ascii_lowercase())
+            .unwrap_or_default();
+        if stem == "cl" || stem == "clang-cl" {
+            Toolchain::Msvc
+        } else {
+            Toolchain::Gnu
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Toolchain::Gnu => "gnu",
+            Toolchain::Msvc => "msvc",
+        }
+    }
+
+    /// The C compiler used when `buildy.toml` and `CC` don't name one.
+    pub fn default_compiler(self) -> &'static str {
+        match self {
+            Toolchain::Gnu => "gcc",
+            Toolchain::Msvc => "cl",
+        }
+    }
+
+    /// Flag asking for debug information.
+    pub fn debug_flag(self) -> &'static str {
+        match self {
+            Toolchain::Gnu => "-g",
+            Toolchain::Msvc => "/Zi",
+        }
+    }
+
+    /// Flag of the optimization `level` (`0` to `3`, `s`, ...): `-O<level>`,
+    /// or the nearest `/O` switch of MSVC.
+    pub fn opt_flag(self, level: &str) -> String {
+        match self {
+            Toolchain::Gnu => format!("-O{}", level),
+            Toolchain::Msvc => match level {
+                "0" => "/Od".into(),
+                "1" | "s" | "z" => "/O1".into(),
+                _ => "/O2".into(),
+            },
+        }
+    }
+
+    /// Flag defining the macro `define`, written `NAME` or `NAME=VALUE`.
+    pub fn define_flag(self, define: &str) -> String {
+        match self {
+            Toolchain::Gnu => format!("-D{}", define),
+            Toolchain::Msvc => format!("/D{}", define),
+        }
+    }
+
+    /// Flag adding `dir` to the include search path.
+    pub fn include_flag(self, dir: &Path) -> String {
+        match self {
+            Toolchain::Gnu => format!("-I{}", dir.display()),
+            Toolchain::Msvc => format!("/I{}", dir.display()),
+        }
+    }
+
+    /// The directory of an include flag written `-Idir` or `/Idir` (`cl`
+    /// takes both), or `None` for another flag or a bare `-I`.
+    pub fn include_dir(flag: &str) -> Option<&str> {
+        flag.strip_prefix("-I")
+            .or_else(|| flag.strip_prefix("/I"))
+            .filter(|dir| !dir.is_empty())
+    }
+
+    /// Flag enabling the warning group `name` of `warnings`: `-W<name>`, or
+    /// the nearest MSVC warning level (`/W4`, and `/Wall` for
+    /// `everything`).
+    pub fn warning_flag(self, name: &str) -> String {
+        match self {
+            Toolchain::Gnu => format!("-W{}", name),
+            Toolchain::Msvc if name == "everything" => "/Wall".into(),
+            Toolchain::Msvc => "/W4".into(),
+        }
+    }
+
+    /// Flag turning warnings into errors, for `deny_warnings`.
+    pub fn deny_warnings_flag(self) -> &'static str {
+        match self {
+            Toolchain::Gnu => "-Werror",
+            Toolchain::Msvc => "/WX",
+        }
+    }
+
+    /// Whether the GNU-only flags buildy adds itself (`-fPIC`,
+    /// `-fmacro-prefix-map`) apply.
+    pub fn is_gnu(self) -> bool {
+        self == Toolchain::Gnu
+    }
+
+    /// Arguments compiling `source` into `object` with `flags`.  The flags
+    /// go before the source so that language selection applies to it.
+    pub fn compile_args(self, flags: Vec<String>, source: &Path, object: &Path) -> Vec<String> {
+        let (compile, output) = match self {
+            Toolchain::Gnu => ("-c", vec!["-o".to_string(), object.display().to_string()]),
+            Toolchain::Msvc => ("/c", vec![format!("/Fo{}", object.display())]),
+        };
+        let mut args = vec![compile.to_string()];
+        if self == Toolchain::Msvc {
+            args.push("/nologo".into());
+        }
+        args.extend(flags);
+        args.push(source.display().to_string());
+        args.extend(output);
+        args
+    }
+
+    /// Arguments making a compile also write the headers it includes to
+    /// `dep_file` as a make rule, read back by the next scan.  MSVC has no
+    /// equivalent, so none.
+    pub fn dep_file_args(self, dep_file: &Path) -> Vec<String> {
+        match self {
+            Toolchain::Gnu => vec!["-MMD".into(), "-MF".into(), dep_file.display().to_string()],
+            Toolchain::Msvc => Vec::new(),
+        }
+    }
+
+    /// `args` (from `compile_args`) writing the object to `object` instead.
+    /// `None` when the arguments name no object.  A dependency file is
+    /// still written where it was: it describes the source, whichever
+    /// object is compiled from it.
+    pub fn redirect_object(self, args: &[String], object: &Path) -> Option<Vec<String>> {
+        let mut args = args.to_vec();
+        match self {
+            Toolchain::Gnu => {
+                let i = args.iter().position(|a| a == "-o")?;
+                *args.get_mut(i + 1)? = object.display().to_string();
+            }
+            Toolchain::Msvc => {
+                let arg = args.iter_mut().find(|a| a.starts_with("/Fo"))?;
+                *arg = format!("/Fo{}", object.display());
+            }
+        }
+        Some(args)
+    }
+
+    /// `args` (from `compile_args`) only checking the source, so nothing is
+    /// written.
+    pub fn syntax_only(self, args: &[String]) -> Vec<String> {
+        match self {
+            Toolchain::Gnu => {
+                let mut out = vec!["-fsyntax-only".to_string()];
+                let mut rest = args.iter();
+                while let Some(arg) = rest.next() {
+                    if arg == "-o" || arg == "-MF" {
+                        rest.next();
+                    } else if arg != "-MMD" {
+                        out.push(arg.clone());
+                    }
+                }
+                out
+            }
+            Toolchain::Msvc => std::iter::once("/Zs".to_string())
+                .chain(
+                    args.iter()
+                        .filter(|a| *a != "/c" && !a.starts_with("/Fo"))
+                        .cloned(),
+                )
+                .collect(),
+        }
+    }
+
+    /// Flag compiling a module interface unit as C++.
+    pub fn cxx_language_args(self) -> &'static [&'static str] {
+        match self {
+            Toolchain::Gnu => &["-x", "c++"],
+            Toolchain::Msvc => &["/TP"],
+        }
+    }
+
+    /// Arguments that make the compiler list the headers a source includes
+    /// without compiling it, read back by `parse_deps`.
+    pub fn dep_scan_args(self) -> &'static [&'static str] {
+        match self {
+            Toolchain::Gnu => &["-MM"],
+            Toolchain::Msvc => &["/nologo", "/Zs", "/showIncludes"],
+        }
+    }
+
+    /// The headers listed in the output of a `dep_scan_args` run: the make
+    /// rule printed by `-MM` (see `depfile::parse` for its escaping), or the
+    /// `Note: including file:` lines of `/showIncludes`.  The source itself
+    /// comes first in a make rule and is left to the caller to filter out.
+    pub fn parse_deps(self, output: &str) -> Vec<PathBuf> {
+        match self {
+            Toolchain::Gnu => depfile::prerequisites(output)
+                .into_iter()
+                .filter(|path| !path.starts_with("<"))
+                .collect(),
+            Toolchain::Msvc => output
+                .lines()
+                .filter_map(|line| line.strip_prefix(SHOW_INCLUDES_PREFIX))
+                .map(|path| PathBuf::from(path.trim()))
+                .collect(),
+        }
+    }
+
+    /// Arguments searching `dirs` for the `libs` to link.
+    pub fn lib_args(self, root: &Path, dirs: &[PathBuf], libs: &[String]) -> Vec<String> {
+        let dirs = dirs.iter().map(|dir| match self {
+            Toolchain::Gnu => format!("-L{}", root.join(dir).display()),
+            Toolchain::Msvc => format!("/LIBPATH:{}", root.join(dir).display()),
+        });
+        let libs = libs.iter().map(|lib| match self {
+            Toolchain::Gnu => format!("-l{}", lib),
+            Toolchain::Msvc => format!("{}.lib", lib),
+        });
+        dirs.chain(libs).collect()
+    }
+
+    /// The archiver and its arguments writing the static library `output`.
+    /// `AR` overrides `ar`.
+    pub fn archive_command(self, output: &Path) -> (String, Vec<String>) {
+        match self {
+            Toolchain::Gnu => {
+                let ar = std::env::var("AR")
+                    .ok()
+                    .filter(|ar| !ar.is_empty())
+                    .unwrap_or_else(|| "ar".into());
+                (ar, vec!["rcs".into(), output.display().to_string()])
+            }
+            Toolchain::Msvc => (
+                "lib.exe".into(),
+                vec!["/nologo".into(), format!("/OUT:{}", output.display())],
+            ),
+        }
+    }
+
+    /// The linker when `driver` is the compiler that would link the
+    /// objects, and the arguments that go before them.  `linker` picks
+    /// another linker: through `-fuse-ld=` of the driver, or as the program
+    /// run instead of `link.exe`.
+    pub fn linker(
+        self,
+        driver: String,
+        shared: bool,
+        linker: Option<&str>,
+    ) -> (String, Vec<String>) {
+        match self {
+            Toolchain::Gnu => {
+                let mut args: Vec<String> = shared.then(|| "-shared".into()).into_iter().collect();
+                if let Some(linker) = linker {
+                    args.push(format!("-fuse-ld={}", linker));
+                }
+                (driver, args)
+            }
+            Toolchain::Msvc => {
+                let mut args = vec!["/nologo".to_string()];
+                if shared {
+                    args.push("/DLL".into());
+                }
+                (linker.unwrap_or("link.exe").into(), args)
+            }
+        }
+    }
+
+    /// Linker arguments naming the output, after everything else.
+    pub fn output_args(self, output: &Path) -> Vec<String> {
+        match self {
+            Toolchain::Gnu => vec!["-o".into(), output.display().to_string()],
+            Toolchain::Msvc => vec![format!("/OUT:{}", output.display())],
+        }
+    }
+
+    /// File name of a static library called `name`: `lib<name>.a`, or
+    /// `<name>.lib` for MSVC.
+    pub fn staticlib_name(self, name: &str) -> String {
+        match self {
+            Toolchain::Gnu => format!("lib{}.a", name),
+            Toolchain::Msvc => format!("{}.lib", name),
+        }
+    }
+
+    /// File name of a shared library called `name`.  MSVC DLLs go without
+    /// the `lib` prefix.
+    pub fn sharedlib_name(self, name: &str) -> String {
+        match self {
+            Toolchain::Gnu => format!("lib{}{}", name, std::env::consts::DLL_SUFFIX),
+            Toolchain::Msvc => format!("{}.dll", name),
+        }
+    }
+}
+
+/// The toolchain to use when nothing is configured: on Windows, MSVC when
+/// only `cl` is on PATH (from a developer prompt) and GNU otherwise.
+#[cfg(windows)]
+pub fn detect() -> Toolchain {
+    if !on_path("gcc") && on_path("cl") {
+        Toolchain::Msvc
+    } else {
+        Toolchain::Gnu
+    }
+}
+
+/// The toolchain to use when nothing is configured.
+#[cfg(not(windows))]
+pub fn detect() -> Toolchain {
+    Toolchain::Gnu
+}
+
+/// Whether `program` (`program.exe` on Windows) is in one of the `PATH`
+/// directories.
+pub fn on_path(program: &str) -> bool {
+    let Some(path) = std::env::var_os("PATH") else {
+        return false;
+    };
+    let name = format!("{}{}", program, std::env::consts::EXE_SUFFIX);
+    std::env::split_paths(&path).any(|dir| dir.join(&name).is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn the_toolchain_follows_the_compiler_name() {
+        for (compiler, toolchain) in [
+            ("gcc", Toolchain::Gnu),
+            ("g++-13", Toolchain::Gnu),
+            ("clang", Toolchain::Gnu),
+            ("/usr/bin/clang++", Toolchain::Gnu),
+            ("x86_64-w64-mingw32-gcc.exe", Toolchain::Gnu),
+            ("cl", Toolchain::Msvc),
+            ("CL.EXE", Toolchain::Msvc),
+            ("clang-cl", Toolchain::Msvc),
+            ("C:/VS/bin/cl.exe", Toolchain::Msvc),
+        ] {
+            assert_eq!(Toolchain::of_compiler(compiler), toolchain, "{}", compiler);
+        }
+    }
+
+    #[test]
+    fn compile_commands() {
+        let source = Path::new("src/main.c");
+        let object = Path::new("obj/main.c.o");
+        let flags = || strings(&["-O2", "-Iinclude"]);
+        for (toolchain, expected) in [
+            (
+                Toolchain::Gnu,
+                &["-c", "-O2", "-Iinclude", "src/main.c", "-o", "obj/main.c.o"][..],
+            ),
+            (
+                Toolchain::Msvc,
+                &[
+                    "/c",
+                    "/nologo",
+                    "-O2",
+                    "-Iinclude",
+                    "src/main.c",
+                    "/Foobj/main.c.o",
+                ][..],
+            ),
+        ] {
+            let args = toolchain.compile_args(flags(), source, object);
+            assert_eq!(args, expected, "{:?}", toolchain);
+            let moved = toolchain
+                .redirect_object(&args, Path::new("tmp/x.o"))
+                .unwrap();
+            assert_eq!(moved.len(), args.len());
+            assert!(moved.iter().any(|a| a.ends_with("tmp/x.o")), "{:?}", moved);
+            assert!(!moved.iter().any(|a| a.ends_with("obj/main.c.o")));
+        }
+        assert_eq!(
+            Toolchain::Gnu.redirect_object(&strings(&["-c", "x.c"]), object),
+            None
+        );
+        assert_eq!(
+            Toolchain::Msvc.redirect_object(&strings(&["/c", "x.c"]), object),
+            None
+        );
+    }
+
+    /// `flag` spelled for GNU and for MSVC.
+    fn both(flag: impl Fn(Toolchain) -> String) -> (String, String) {
+        (flag(Toolchain::Gnu), flag(Toolchain::Msvc))
+    }
+
+    #[test]
+    fn flags_of_each_toolchain() {
+        for (flags, gnu, msvc) in [
+            (
+                both(|t| t.include_flag(Path::new("include"))),
+                "-Iinclude",
+                "/Iinclude",
+            ),
+            (both(|t| t.define_flag("NDEBUG")), "-DNDEBUG", "/DNDEBUG"),
+            (both(|t| t.define_flag("LEVEL=2")), "-DLEVEL=2", "/DLEVEL=2"),
+            (both(|t| t.opt_flag("0")), "-O0", "/Od"),
+            (both(|t| t.opt_flag("s")), "-Os", "/O1"),
+            (both(|t| t.opt_flag("3")), "-O3", "/O2"),
+            (both(|t| t.warning_flag("all")), "-Wall", "/W4"),
+            (
+                both(|t| t.warning_flag("everything")),
+                "-Weverything",
+                "/Wall",
+            ),
+            (both(|t| t.deny_warnings_flag().into()), "-Werror", "/WX"),
+            (both(|t| t.debug_flag().into()), "-g", "/Zi"),
+            (both(|t| t.staticlib_name("net")), "libnet.a", "net.lib"),
+            (both(|t| t.default_compiler().into()), "gcc", "cl"),
+        ] {
+            assert_eq!(flags, (gnu.to_string(), msvc.to_string()));
+        }
+    }
+
+    #[test]
+    fn include_dirs_of_either_spelling() {
+        assert_eq!(Toolchain::include_dir("-Iinclude"), Some("include"));
+        assert_eq!(Toolchain::include_dir("/Iinclude"), Some("include"));
+        assert_eq!(Toolchain::include_dir("-I"), None);
+        assert_eq!(Toolchain::include_dir("-DINCLUDE"), None);
+    }
+
+    #[test]
+    fn checking_a_source_writes_nothing() {
+        let gnu = strings(&["-c", "-O2", "x.c", "-o", "x.o", "-MMD", "-MF", "x.d"]);
+        assert_eq!(
+            Toolchain::Gnu.syntax_only(&gnu),
+            ["-fsyntax-only", "-c", "-O2", "x.c"]
+        );
+        let msvc = strings(&["/c", "/nologo", "/O2", "x.c", "/Fox.obj"]);
+        assert_eq!(
+            Toolchain::Msvc.syntax_only(&msvc),
+            ["/Zs", "/nologo", "/O2", "x.c"]
+        );
+    }
+
+    #[test]
+    fn dependency_scans() {
+        assert_eq!(Toolchain::Gnu.dep_scan_args(), ["-MM"]);
+        assert_eq!(
+            Toolchain::Msvc.dep_scan_args(),
+            ["/nologo", "/Zs", "/showIncludes"]
+        );
+        assert_eq!(
+            Toolchain::Gnu.dep_file_args(Path::new("x.d")),
+            ["-MMD", "-MF", "x.d"]
+        );
+        assert!(Toolchain::Msvc.dep_file_args(Path::new("x.d")).is_empty());
+
+        let make = "main.o: src/main.c src/util.h \\\n include/my\\ lib.h\n";
+        assert_eq!(
+            Toolchain::Gnu.parse_deps(make),
+            [
+                PathBuf::from("src/main.c"),
+                PathBuf::from("src/util.h"),
+                PathBuf::from("include/my lib.h")
+            ]
+        );
+        let shown = "main.c\n\
+                     Note: including file: C:\\p\\src\\util.h\n\
+                     Note: including file:  C:\\p\\include\\my lib.h\n\
+                     main.c(3): warning C4101: unused\n";
+        assert_eq!(
+            Toolchain::Msvc.parse_deps(shown),
+            [
+                PathBuf::from(r"C:\p\src\util.h"),
+                PathBuf::from(r"C:\p\include\my lib.h")
+            ]
+        );
+    }
+
+    #[test]
+    fn link_commands() {
+        let root = Path::new("/p");
+        let dirs = [PathBuf::from("lib")];
+        let libs = strings(&["m", "z"]);
+        assert_eq!(
+            Toolchain::Gnu.lib_args(root, &dirs, &libs),
+            ["-L/p/lib", "-lm", "-lz"]
+        );
+        assert_eq!(
+            Toolchain::Msvc.lib_args(root, &dirs, &libs),
+            ["/LIBPATH:/p/lib", "m.lib", "z.lib"]
+        );
+
+        let (program, args) = Toolchain::Gnu.linker("clang".into(), true, Some("lld"));
+        assert_eq!(
+            (program.as_str(), args),
+            ("clang", strings(&["-shared", "-fuse-ld=lld"]))
+        );
+        let (program, args) = Toolchain::Msvc.linker("cl".into(), true, None);
+        assert_eq!(
+            (program.as_str(), args),
+            ("link.exe", strings(&["/nologo", "/DLL"]))
+        );
+        let (program, _) = Toolchain::Msvc.linker("cl".into(), false, Some("lld-link"));
+        assert_eq!(program, "lld-link");
+
+        let output = Path::new("target/app");
+        assert_eq!(Toolchain::Gnu.output_args(output), ["-o", "target/app"]);
+        assert_eq!(Toolchain::Msvc.output_args(output), ["/OUT:target/app"]);
+        assert_eq!(
+            Toolchain::Msvc.archive_command(Path::new("net.lib")),
+            ("lib.exe".to_string(), strings(&["/nologo", "/OUT:net.lib"]))
+        );
+    }
+}
@@ -0,0 +1,78 @@
+use crate::executor::{self, ExecRequest};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::env;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Environment variables the compiler and linker read on their own, adding
+/// search paths that never show up on the command line.
+pub const COMPILER_ENV_VARS: [&str; 7] = [
+    "CPATH",
+    "C_INCLUDE_PATH",
+    "CPLUS_INCLUDE_PATH",
+    "OBJC_INCLUDE_PATH",
+    "LIBRARY_PATH",
+    "COMPILER_PATH",
+    "GCC_EXEC_PREFIX",
+];
+
+/// How long `compiler_version` waits for a compiler, so a wrapper that
+/// hangs doesn't hang every build.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// First line of `--version` of each compiler probed so far, see
+/// `compiler_version`.
+static VERSIONS: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+/// First line of `<program> --version`, such as `gcc (Debian 12.2.0-14)
+/// 12.2.0`, so upgrading the compiler in place recompiles everything (`cl`
+/// prints its banner on stderr).  Each program is only asked once per
+/// process.  Empty when the program can't be run; the compile itself
+/// reports that.
+pub fn compiler_version(program: &str) -> String {
+    let mut versions = VERSIONS.lock().unwrap_or_else(|e| e.into_inner());
+    versions
+        .entry(program.to_string())
+        .or_insert_with(|| {
+            let request = ExecRequest {
+                program: program.to_string(),
+                args: vec!["--version".into()],
+                timeout: Some(PROBE_TIMEOUT),
+                capture: true,
+                ..ExecRequest::default()
+            };
+            executor::execute(&request)
+                .ok()
+                .and_then(|output| {
+                    let text = if output.stdout.trim().is_empty() {
+                        output.stderr
+                    } else {
+                        output.stdout
+                    };
+                    text.lines().next().map(|line| line.trim().to_string())
+                })
+                .unwrap_or_default()
+        })
+        .clone()
+}
+
+/// The compiler-relevant variables that are currently set, with their values.
+pub fn snapshot() -> Vec<(&'static str, String)> {
+    COMPILER_ENV_VARS
+        .iter()
+        .filter_map(|name| env::var(name).ok().map(|value| (*name, value)))
+        .collect()
+}
+
+/// Fingerprint entries for `snapshot`.  Values are hashed so the cache
+/// doesn't record local paths.
+pub fn fingerprint() -> Vec<String> {
+    snapshot()
+        .into_iter()
+        .map(|(name, value)| {
+            let digest = format!("{:x}", Sha256::digest(value.as_bytes()));
+            format!("env:{}={}", name, &digest[..12])
+        })
+        .collect()
+}
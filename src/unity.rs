@@ -0,0 +1,153 @@
+use crate::config::Config;
+use crate::graph::BuildGraph;
+use crate::lang::Language;
+use crate::scheduler;
+use crate::testing;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The sources of a `--unity` build, partitioned into buckets that are each
+/// compiled as one generated file including its members.  Sources defining
+/// `main`, module units, assembly, the sources of `tests/` and those with
+/// `[dir]` or `[file]` flags keep their own objects.
+pub struct UnityBuild {
+    pub buckets: Vec<Bucket>,
+    /// Index into `buckets` of every member source.
+    bucket_of: HashMap<PathBuf, usize>,
+    /// Where the generated sources and their objects go.
+    dirs: [PathBuf; 2],
+}
+
+/// One generated unity file.  The members all have the same language.
+pub struct Bucket {
+    /// Member sources, sorted.
+    pub members: Vec<PathBuf>,
+    /// `target/.buildy/unity_<i>.c` or `.cpp`.
+    pub source: PathBuf,
+    /// `target/<profile>/unity/unity_<i>.o`.
+    pub object: PathBuf,
+}
+
+impl UnityBuild {
+    /// Split the sources of `graph` into about `count` buckets (at least one
+    /// per language present), each language getting a share of the buckets
+    /// proportional to its number of sources.  Members are assigned in path
+    /// order so the buckets stay the same from build to build.
+    pub fn new(
+        graph: &BuildGraph,
+        root: &Path,
+        config: &Config,
+        profile: &str,
+        count: usize,
+    ) -> Self {
+        let mut by_language: Vec<(Language, Vec<PathBuf>)> = Vec::new();
+        for language in [Language::C, Language::Cxx] {
+            let mut members: Vec<PathBuf> = graph
+                .nodes
+                .keys()
+                .filter(|p| Language::of(p) == Some(language))
+                .filter(|p| !graph.mains.contains(*p) && graph.modules.unit(p).is_none())
+                .filter(|p| !testing::is_test(p, root))
+                .filter(|p| !config.has_local_flags(p, root))
+                .cloned()
+                .collect();
+            members.sort();
+            if !members.is_empty() {
+                by_language.push((language, members));
+            }
+        }
+        let total: usize = by_language.iter().map(|(_, m)| m.len()).sum();
+        let dir = root.join("target").join(".buildy");
+        let objects = root.join("target").join(profile).join("unity");
+        let mut buckets = Vec::new();
+        let mut bucket_of = HashMap::new();
+        for (language, members) in by_language {
+            let share = (count * members.len() / total).max(1);
+            let size = members.len().div_ceil(share.clamp(1, members.len()));
+            for chunk in members.chunks(size) {
+                let name = format!("unity_{}", buckets.len());
+                let ext = match language {
+                    Language::C => "c",
+                    _ => "cpp",
+                };
+                for member in chunk {
+                    bucket_of.insert(member.clone(), buckets.len());
+                }
+                buckets.push(Bucket {
+                    members: chunk.to_vec(),
+                    source: dir.join(&name).with_extension(ext),
+                    object: objects
+                        .join(name)
+                        .with_extension(scheduler::OBJECT_EXTENSION),
+                });
+            }
+        }
+        UnityBuild {
+            buckets,
+            bucket_of,
+            dirs: [dir, objects],
+        }
+    }
+
+    pub fn is_member(&self, source: &Path) -> bool {
+        self.bucket_of.contains_key(source)
+    }
+
+    /// The bucket `source` is compiled in, if any.
+    pub fn bucket(&self, source: &Path) -> Option<&Bucket> {
+        self.bucket_of.get(source).map(|&i| &self.buckets[i])
+    }
+
+    /// Remove the generated sources and objects of buckets that no longer
+    /// exist, left by a build with more buckets or more sources.
+    pub fn remove_stale(&self) {
+        for dir in &self.dirs {
+            let Ok(entries) = fs::read_dir(dir) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let path = entry.path();
+                let name = entry.file_name().to_string_lossy().to_string();
+                if name.starts_with("unity_")
+                    && !self
+                        .buckets
+                        .iter()
+                        .any(|b| b.source == path || b.object == path)
+                {
+                    let _ = fs::remove_file(path);
+                }
+            }
+        }
+    }
+}
+
+impl Bucket {
+    /// Name the cache records the bucket under.
+    pub fn name(&self) -> String {
+        self.source
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default()
+    }
+
+    /// Write the generated source, unless it already has this content.
+    pub fn write_source(&self) -> io::Result<()> {
+        let mut text = String::from("/* generated by buildy --unity, do not edit */\n");
+        for member in &self.members {
+            let path = member.display().to_string();
+            text.push_str(&format!(
+                "#include \"{}\"\n",
+                path.replace('\\', "\\\\").replace('"', "\\\"")
+            ));
+        }
+        if fs::read_to_string(&self.source).ok().as_deref() == Some(text.as_str()) {
+            return Ok(());
+        }
+        if let Some(dir) = self.source.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&self.source, text)
+    }
+}
@@ -0,0 +1,191 @@
+//! Batched MSVC compiles, with stand-ins for `cl` and `link.exe` that
+//! compile and link with the system C compiler the way the real ones name
+//! their outputs.
+
+#![cfg(unix)]
+
+mod common;
+
+use buildy::BuildyError;
+use common::Project;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+/// `cl`: logs its arguments, prints the name of each source as it starts
+/// on it, and writes `<dir>/<stem>.o` for `/Fo<dir>/`.
+const FAKE_CL: &str = r#"#!/bin/sh
+[ "$1" = --version ] && { echo "fake cl 1.0"; exit 0; }
+echo "$*" >> "$(dirname "$0")/cl.log"
+out=; sources=; scan=
+for arg; do
+    case $arg in
+        *.c) sources="$sources $arg" ;;
+        /Zs) scan=1 ;;
+        /Fo*) out=${arg#/Fo} ;;
+    esac
+done
+status=0
+for source in $sources; do
+    basename "$source"
+    [ -n "$scan" ] && continue
+    case $out in
+        */) object=$out$(basename "${source%.*}").o ;;
+        *) object=$out ;;
+    esac
+    cc -c "$source" -o "$object" 2>&1 || status=2
+done
+exit $status
+"#;
+
+/// `link.exe`: links the objects into what `/OUT:` names.
+const FAKE_LINK: &str = r#"#!/bin/sh
+out=; objects=
+for arg; do
+    case $arg in
+        /OUT:*) out=${arg#/OUT:} ;;
+        *.o) objects="$objects $arg" ;;
+    esac
+done
+exec cc -o "$out" $objects
+"#;
+
+fn tool(dir: &Path, name: &str, script: &str) -> String {
+    let path = dir.join(name);
+    fs::write(&path, script).unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    path.display().to_string()
+}
+
+/// A project built with the fake MSVC tools, compiling up to `batch_size`
+/// sources per run.
+fn msvc_project(batch_size: usize) -> Project {
+    let project = Project::hello("app");
+    let tools = project.path("tools");
+    fs::create_dir_all(&tools).unwrap();
+    let cl = tool(&tools, "cl", FAKE_CL);
+    let link = tool(&tools, "link", FAKE_LINK);
+    project.write(
+        "buildy.toml",
+        &format!(
+            "toolchain = \"msvc\"\ncompiler = \"{}\"\nlinker = \"{}\"\nbatch_size = {}\n",
+            cl, link, batch_size
+        ),
+    );
+    project.write("src/extra.c", "int extra(void) { return 1; }\n");
+    project
+}
+
+/// The source lists of the compiles `cl` ran, scans left out.
+fn compiles(project: &Project) -> Vec<Vec<String>> {
+    let log = fs::read_to_string(project.path("tools/cl.log")).unwrap_or_default();
+    log.lines()
+        .filter(|line| !line.contains("/Zs"))
+        .map(|line| {
+            line.split(' ')
+                .filter(|arg| arg.ends_with(".c"))
+                .map(|arg| {
+                    Path::new(arg)
+                        .file_name()
+                        .unwrap()
+                        .to_string_lossy()
+                        .to_string()
+                })
+                .collect()
+        })
+        .collect()
+}
+
+#[test]
+fn compiles_the_sources_in_one_run() {
+    let project = msvc_project(8);
+    let report = project.build().expect("build");
+    assert_eq!(report.compiled.len(), 3);
+    assert_eq!(compiles(&project).len(), 1);
+    let mut batch = compiles(&project).remove(0);
+    batch.sort();
+    assert_eq!(batch, ["extra.c", "main.c", "util.c"]);
+    assert_eq!(project.run(), "42\n");
+    // every object is at its own path, and nothing of the batch is left
+    for source in ["main.c", "util.c", "extra.c"] {
+        assert!(
+            project
+                .path(&format!("target/debug/obj/src/{}.o", source))
+                .is_file()
+        );
+    }
+    let leftovers: Vec<_> = fs::read_dir(project.path("target/debug/obj"))
+        .unwrap()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_name().to_string_lossy().starts_with(".batch"))
+        .collect();
+    assert!(leftovers.is_empty());
+
+    // and the cache knows each of them
+    let report = project.build().expect("second build");
+    assert!(report.compiled.is_empty());
+    assert_eq!(report.clean, 3);
+}
+
+#[test]
+fn batches_hold_at_most_batch_size() {
+    let project = msvc_project(2);
+    project.build().expect("build");
+    let sizes: Vec<usize> = compiles(&project).iter().map(Vec::len).collect();
+    assert_eq!(sizes.iter().sum::<usize>(), 3);
+    assert!(sizes.iter().all(|&n| n <= 2), "{:?}", sizes);
+}
+
+#[test]
+fn without_batch_size_each_source_runs_alone() {
+    let project = msvc_project(1);
+    project.build().expect("build");
+    assert!(compiles(&project).iter().all(|run| run.len() == 1));
+    assert_eq!(compiles(&project).len(), 3);
+}
+
+#[test]
+fn sources_of_the_same_name_go_to_different_runs() {
+    let project = msvc_project(8);
+    project.write("src/net/util.c", "int net_util(void) { return 2; }\n");
+    let report = project.build().expect("build");
+    assert_eq!(report.compiled.len(), 4);
+    for run in compiles(&project) {
+        let utils = run.iter().filter(|s| *s == "util.c").count();
+        assert!(utils <= 1, "{:?}", run);
+    }
+    assert!(project.path("target/debug/obj/src/net/util.c.o").is_file());
+    assert!(project.path("target/debug/obj/src/util.c.o").is_file());
+}
+
+#[test]
+fn a_failure_is_reported_for_its_source() {
+    let project = msvc_project(8);
+    project.write("src/extra.c", "int extra(void) { return missing; }\n");
+    let err = project
+        .builder()
+        .keep_going(true)
+        .build()
+        .expect_err("extra.c doesn't compile");
+    let BuildyError::CompileFailed {
+        files,
+        message,
+        report,
+    } = err
+    else {
+        panic!("unexpected error: {}", err);
+    };
+    assert_eq!(files.len(), 1);
+    assert!(files[0].ends_with("src/extra.c"), "{:?}", files);
+    assert!(message.contains("missing"), "{}", message);
+    assert!(!message.contains("main.c"), "{}", message);
+    assert_eq!(report.compiled.len(), 2);
+    assert_eq!(report.failed, 1);
+    assert_eq!(compiles(&project).len(), 1, "compiled in one run");
+
+    // the others were recorded as compiled, so only extra.c is retried
+    project.write("src/extra.c", "int extra(void) { return 3; }\n");
+    let report = project.build().expect("fixed build");
+    assert_eq!(report.compiled.len(), 1);
+    assert!(report.compiled[0].0.ends_with("src/extra.c"));
+}
@@ -0,0 +1,97 @@
+//! Building fixture projects through the library API.
+
+mod common;
+
+use buildy::BuildCache;
+use buildy::build::{Goal, build_and_link};
+use buildy::scheduler::BuildOptions;
+use buildy::session::BuildSession;
+use common::Project;
+use std::fs::{self, File};
+
+#[test]
+fn builds_a_project_through_the_library() {
+    let project = Project::hello("app");
+
+    let report = project.build().expect("first build");
+    assert_eq!(report.compiled.len(), 2);
+    assert_eq!(report.from_cache, 0);
+    assert_eq!(report.failed, 0);
+    assert_eq!(report.linked, vec!["app".to_string()]);
+    assert!(project.program().is_file());
+    assert_eq!(project.run(), "42\n");
+
+    let report = project.build().expect("second build");
+    assert!(report.compiled.is_empty());
+    assert_eq!(report.clean, 2);
+    assert!(report.linked.is_empty());
+}
+
+#[test]
+fn rebuilds_what_an_edit_touches() {
+    let project = Project::hello("app");
+    project.build().expect("first build");
+
+    project.write(
+        "src/util.c",
+        "#include \"util.h\"\nint answer(void) { return 7; }\n",
+    );
+    let report = project.build().expect("build after the edit");
+    let compiled: Vec<_> = report
+        .compiled
+        .iter()
+        .map(|(path, _)| path.file_name().unwrap().to_string_lossy().to_string())
+        .collect();
+    assert_eq!(compiled, vec!["util.c"]);
+    assert_eq!(report.linked, vec!["app".to_string()]);
+    assert_eq!(project.run(), "7\n");
+}
+
+#[test]
+fn reports_compile_errors() {
+    let project = Project::hello("app");
+    project.write("src/util.c", "int answer(void) { return }\n");
+
+    match project.builder().keep_going(true).build() {
+        Err(buildy::BuildyError::CompileFailed { files, report, .. }) => {
+            assert_eq!(files.len(), 1);
+            assert!(files[0].ends_with("src/util.c"));
+            assert_eq!(report.failed, 1);
+            assert_eq!(report.compiled.len(), 1);
+        }
+        other => panic!(
+            "expected a compile failure, got {:?}",
+            other.map(|r| r.to_string())
+        ),
+    }
+    assert!(!project.program().exists());
+}
+
+#[test]
+fn a_session_builds_again_from_what_it_holds() {
+    let project = Project::hello("app");
+    let mut session = BuildSession::new(&project.root);
+    let opts = BuildOptions {
+        jobs: Some(2),
+        ..BuildOptions::default()
+    };
+    let built = build_and_link(&mut session, &opts, Goal::Project).expect("first build");
+    assert_eq!(built.report.compiled.len(), 2);
+
+    // loading the cache again would find garbage and compile everything,
+    // and a rescan would find the new source, which doesn't compile
+    let cache = BuildCache::path(&project.root, "debug");
+    let saved = fs::metadata(&cache).unwrap().modified().unwrap();
+    fs::write(&cache, "not a cache").unwrap();
+    File::options()
+        .write(true)
+        .open(&cache)
+        .unwrap()
+        .set_modified(saved)
+        .unwrap();
+    project.write("src/broken.c", "this is not C\n");
+
+    let built = build_and_link(&mut session, &opts, Goal::Project).expect("second build");
+    assert!(built.report.compiled.is_empty());
+    assert_eq!(built.report.clean, 2);
+}
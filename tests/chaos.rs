@@ -0,0 +1,72 @@
+//! Soak test of the caches under `--chaos`: whatever a build with injected
+//! faults leaves behind, the next build must compile everything that didn't
+//! compile, so the program always prints what the sources say.
+
+mod common;
+
+use common::Project;
+use std::process::Command;
+
+const PARTS: usize = 8;
+const ROUNDS: u64 = 12;
+
+/// Write `src/partN.c` returning `value`.
+fn write_part(project: &Project, n: usize, value: u32) {
+    project.write(
+        &format!("src/part{}.c", n),
+        &format!("int part{}(void) {{ return {}; }}\n", n, value),
+    );
+}
+
+#[test]
+fn the_cache_never_records_a_failed_compile() {
+    let project = Project::new("app");
+    let mut main = String::from("#include <stdio.h>\n");
+    for n in 0..PARTS {
+        main += &format!("int part{}(void);\n", n);
+    }
+    main += "int main(void) {\n    int sum = 0;\n";
+    for n in 0..PARTS {
+        main += &format!("    sum += part{}();\n", n);
+    }
+    main += "    printf(\"%d\\n\", sum);\n    return 0;\n}\n";
+    project.write("src/main.c", &main);
+    let mut values = [1u32; PARTS];
+    for (n, value) in values.iter().enumerate() {
+        write_part(&project, n, *value);
+    }
+    project.build().expect("first build");
+
+    let mut rng = fastrand::Rng::with_seed(521);
+    let mut faulted = 0;
+    for seed in 0..ROUNDS {
+        for (n, value) in values.iter_mut().enumerate() {
+            if rng.bool() {
+                *value = rng.u32(1..1000);
+                write_part(&project, n, *value);
+            }
+        }
+        // may well fail; what counts is what it leaves behind
+        let chaos = Command::new(env!("CARGO_BIN_EXE_Buildy"))
+            .args(["--quiet", "build", "--keep-going", "--chaos"])
+            .arg(seed.to_string())
+            .current_dir(&project.root)
+            .env("BUILDY_CHAOS_SPAWN", "0.1")
+            .env("BUILDY_CHAOS_EXIT", "0.2")
+            .env("BUILDY_CHAOS_DELAY", "0")
+            .env("BUILDY_CHAOS_TRUNCATE", "0.2")
+            .env("BUILDY_CHAOS_CANCEL", "0.05")
+            .output()
+            .expect("run Buildy");
+        if !chaos.status.success() {
+            faulted += 1;
+        }
+
+        project
+            .build()
+            .unwrap_or_else(|e| panic!("build after chaos seed {}: {}", seed, e));
+        let sum: u32 = values.iter().sum();
+        assert_eq!(project.run(), format!("{}\n", sum), "chaos seed {}", seed);
+    }
+    assert!(faulted > 0, "no chaos build failed");
+}
@@ -0,0 +1,275 @@
+//! The `Buildy` binary's command line.
+
+mod common;
+
+use common::Project;
+use std::process::{Command, Output};
+
+fn buildy(project: &Project, args: &[&str]) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_Buildy"))
+        .args(args)
+        .current_dir(&project.root)
+        .env_remove("BUILDY_VERBOSE")
+        .output()
+        .expect("run Buildy");
+    assert!(
+        output.status.success(),
+        "Buildy {:?}: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+/// Whether a build run with `args` printed its compile commands.
+fn prints_commands(args: &[&str]) -> bool {
+    let project = Project::hello("app");
+    let output = buildy(&project, args);
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .any(|line| line.starts_with("+ ") && line.contains(" -c "))
+}
+
+#[test]
+fn verbose_goes_before_or_after_the_subcommand() {
+    assert!(prints_commands(&["-v", "build"]));
+    assert!(prints_commands(&["build", "-v"]));
+    assert!(prints_commands(&["--verbose", "check"]));
+    assert!(!prints_commands(&["build"]));
+}
+
+#[test]
+fn twice_verbose_before_the_subcommand() {
+    let project = Project::hello("app");
+    project.build().expect("build");
+    project.write(
+        "src/util.c",
+        "#include \"util.h\"\nint answer(void) { return 7; }\n",
+    );
+    let output = buildy(&project, &["-vv", "build"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    // -vv also says why each source is compiled
+    assert!(stdout.contains("dirty: src/util.c"), "{}", stdout);
+}
+
+#[test]
+fn verbose_is_accepted_by_commands_that_dont_build() {
+    let project = Project::hello("app");
+    buildy(&project, &["-v", "clean"]);
+}
+
+#[test]
+fn the_bash_script_completes_the_subcommands() {
+    let project = Project::new("app");
+    let output = buildy(&project, &["completions", "bash"]);
+    let script = String::from_utf8_lossy(&output.stdout);
+    for subcommand in ["build", "check", "run", "clean", "graph", "cache", "schema"] {
+        assert!(
+            script.contains(&format!("buildy__subcmd__{}", subcommand)),
+            "no {} in\n{}",
+            subcommand,
+            script
+        );
+    }
+    assert!(script.contains("complete -F _buildy"), "{}", script);
+}
+
+#[test]
+fn profiles_complete_with_those_of_buildy_toml() {
+    let project = Project::hello("app");
+    project.write("buildy.toml", "[profiles.fast]\nopt = 2\n");
+    let output = Command::new(env!("CARGO_BIN_EXE_Buildy"))
+        .args(["--", "buildy", "build", "--profile", ""])
+        .current_dir(&project.root)
+        .env("COMPLETE", "bash")
+        .env("_CLAP_COMPLETE_INDEX", "3")
+        .output()
+        .expect("run Buildy");
+    let candidates = String::from_utf8_lossy(&output.stdout);
+    let candidates: Vec<&str> = candidates.split_whitespace().collect();
+    assert!(candidates.contains(&"fast"), "{:?}", candidates);
+    assert!(candidates.contains(&"release"), "{:?}", candidates);
+}
+
+/// The `files` recorded in the debug cache of `project`.
+fn cached_files(project: &Project) -> Vec<String> {
+    let text = std::fs::read_to_string(project.path("target/debug/.buildy_cache.json"))
+        .expect("debug cache");
+    let cache: serde_json::Value = serde_json::from_str(&text).unwrap();
+    let mut files: Vec<String> = cache["files"]
+        .as_object()
+        .unwrap()
+        .keys()
+        .cloned()
+        .collect();
+    files.sort();
+    files
+}
+
+#[test]
+fn a_build_of_another_root_at_the_prompt_leaves_the_session_alone() {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let project = Project::hello("app");
+    let other = Project::new("tool");
+    other.write("src/tool.c", "int main(void) { return 0; }\n");
+    let mut watch = Command::new(env!("CARGO_BIN_EXE_Buildy"))
+        .arg("watch")
+        .current_dir(&project.root)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("start the watch repl");
+    let script = format!("build\nbuild --root {}\nexit\n", other.root.display());
+    watch
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(script.as_bytes())
+        .unwrap();
+    let output = watch.wait_with_output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+    assert!(stdout.contains("the session stays in"), "{}", stdout);
+
+    assert!(other.path("target/debug/tool").is_file());
+    assert_eq!(cached_files(&other), ["src/tool.c"]);
+    // saved on exit, with nothing of the other project
+    assert_eq!(
+        cached_files(&project),
+        ["src/main.c", "src/util.c", "src/util.h"]
+    );
+}
+
+#[test]
+fn env_set_at_the_prompt_reaches_the_program_run() {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let project = Project::new("app");
+    project.write(
+        "src/main.c",
+        "#include <stdio.h>\n#include <stdlib.h>\n\
+         int main(void) { printf(\"trace=%s\\n\", getenv(\"MYAPP_TRACE\")); return 0; }\n",
+    );
+    project.write("buildy.toml", "[run.env]\nMYAPP_TRACE = \"config\"\n");
+    let mut watch = Command::new(env!("CARGO_BIN_EXE_Buildy"))
+        .arg("watch")
+        .current_dir(&project.root)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("start the watch repl");
+    watch
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"run\nenv set MYAPP_TRACE=1\nrun\nenv unset MYAPP_TRACE\nrun\nexit\n")
+        .unwrap();
+    let output = watch.wait_with_output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+    let runs: Vec<&str> = stdout
+        .lines()
+        .filter(|line| line.starts_with("trace="))
+        .collect();
+    assert_eq!(
+        runs,
+        ["trace=config", "trace=1", "trace=config"],
+        "{}",
+        stdout
+    );
+    // not saved unless asked to
+    assert_eq!(
+        std::fs::read_to_string(project.path("buildy.toml")).unwrap(),
+        "[run.env]\nMYAPP_TRACE = \"config\"\n"
+    );
+}
+
+/// The exit status and stderr of `Buildy args` in `project`.
+fn status(project: &Project, args: &[&str]) -> (Option<i32>, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_Buildy"))
+        .args(args)
+        .current_dir(&project.root)
+        .output()
+        .expect("run Buildy");
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    (output.status.code(), stderr)
+}
+
+#[test]
+fn strict_flags_lets_a_file_override_win() {
+    let project = Project::hello("app");
+    project.write(
+        "buildy.toml",
+        "[file.\"src/util.c\"]\nextra_flags = [\"-O0\"]\n",
+    );
+    let (code, stderr) = status(&project, &["build", "--release", "--strict-flags"]);
+    assert_eq!(code, Some(0), "{}", stderr);
+    assert!(
+        stderr.contains("warning: conflicting optimization level flags"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("from profile 'release'"), "{}", stderr);
+    assert!(
+        stderr.contains("-O0 from buildy.toml 'file.\"src/util.c\".extra_flags'"),
+        "{}",
+        stderr
+    );
+
+    project.write("buildy.toml", "cflags = [\"-O0\"]\n");
+    let (code, stderr) = status(&project, &["build", "--release", "--strict-flags"]);
+    assert_eq!(code, Some(101), "{}", stderr);
+    assert!(
+        stderr.contains("error: conflicting optimization level flags"),
+        "{}",
+        stderr
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn the_automatic_stale_clean_keeps_json_stdout_clean() {
+    use std::fs::File;
+    use std::time::{Duration, SystemTime};
+
+    let project = Project::hello("app");
+    project.write("buildy.toml", "auto_clean_stale = \"1d\"\n");
+    project.write("target/old-variant/app", "stale");
+    let old = SystemTime::now() - Duration::from_secs(3 * 24 * 60 * 60);
+    for path in ["target/old-variant/app", "target/old-variant"] {
+        File::open(project.path(path))
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+    }
+    let output = buildy(&project, &["build", "--message-format", "json"]);
+    assert!(!project.path("target/old-variant").exists());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    for line in stdout.lines() {
+        assert!(
+            serde_json::from_str::<serde_json::Value>(line).is_ok(),
+            "not JSON: {}",
+            line
+        );
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("removed "), "{}", stderr);
+    assert!(
+        stderr.contains("old-variant (5 B, last used 3 days ago)"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn run_reads_buildy_toml_once() {
+    let project = Project::hello("app");
+    project.write("buildy.toml", "auto_clean_stale = \"soon\"\n");
+    let output = buildy(&project, &["run", "--quiet"]);
+    assert!(String::from_utf8_lossy(&output.stdout).ends_with("42\n"));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(stderr.matches("auto_clean_stale").count(), 1, "{}", stderr);
+}
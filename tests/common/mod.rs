@@ -0,0 +1,86 @@
+//! Fixture projects for the integration tests: a directory in a fresh temp
+//! dir, named like the program it builds.
+
+#![allow(dead_code)]
+
+use buildy::{BuildReport, Builder, BuildyError};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+pub struct Project {
+    _dir: TempDir,
+    pub root: PathBuf,
+}
+
+impl Project {
+    /// An empty project called `name`.
+    pub fn new(name: &str) -> Self {
+        let dir = tempfile::tempdir().expect("temp dir");
+        let root = dir.path().join(name);
+        fs::create_dir_all(&root).expect("project dir");
+        Project { _dir: dir, root }
+    }
+
+    /// A C program of two sources and a header: `main` prints what
+    /// `answer()` in `src/util.c` returns.
+    pub fn hello(name: &str) -> Self {
+        let project = Project::new(name);
+        project.write(
+            "src/main.c",
+            "#include <stdio.h>\n#include \"util.h\"\n\
+             int main(void) { printf(\"%d\\n\", answer()); return 0; }\n",
+        );
+        project.write("src/util.h", "int answer(void);\n");
+        project.write(
+            "src/util.c",
+            "#include \"util.h\"\nint answer(void) { return 42; }\n",
+        );
+        project
+    }
+
+    /// Write `contents` to `path` below the root, creating directories.
+    pub fn write(&self, path: &str, contents: &str) {
+        let path = self.root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).expect("parent dir");
+        fs::write(&path, contents).expect("write fixture file");
+    }
+
+    pub fn path(&self, path: &str) -> PathBuf {
+        self.root.join(path)
+    }
+
+    /// A debug build with two compile jobs.
+    pub fn build(&self) -> Result<BuildReport, BuildyError> {
+        self.builder().build()
+    }
+
+    pub fn builder(&self) -> Builder {
+        Builder::new(&self.root).jobs(2)
+    }
+
+    /// The program a debug build links.
+    pub fn program(&self) -> PathBuf {
+        let name = self.root.file_name().unwrap().to_string_lossy().to_string();
+        self.root.join("target").join("debug").join(format!(
+            "{}{}",
+            name,
+            std::env::consts::EXE_SUFFIX
+        ))
+    }
+
+    /// Run the program a debug build linked, returning what it printed.
+    pub fn run(&self) -> String {
+        run(&self.program())
+    }
+}
+
+/// Run `program` and return its stdout, failing the test unless it exits
+/// with status 0.
+pub fn run(program: &Path) -> String {
+    let output = std::process::Command::new(program)
+        .output()
+        .expect("run the built program");
+    assert!(output.status.success(), "{} failed", program.display());
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
@@ -0,0 +1,69 @@
+//! The exit status of failed builds and of the programs `run` starts.
+
+mod common;
+
+use common::Project;
+use std::process::Command;
+
+/// The exit status of `Buildy` run with `args` in `project`.
+fn status(project: &Project, args: &[&str]) -> Option<i32> {
+    Command::new(env!("CARGO_BIN_EXE_Buildy"))
+        .args(args)
+        .current_dir(&project.root)
+        .output()
+        .expect("run Buildy")
+        .status
+        .code()
+}
+
+/// A program that exits with `code` after printing nothing.
+fn exiting(code: i32) -> Project {
+    let project = Project::new("app");
+    project.write(
+        "src/main.c",
+        &format!("int main(void) {{ return {}; }}\n", code),
+    );
+    project
+}
+
+#[test]
+fn a_compile_error_exits_with_101() {
+    let project = Project::hello("app");
+    project.write("src/util.c", "int answer(void) { return }\n");
+    assert_eq!(status(&project, &["build"]), Some(101));
+}
+
+#[test]
+fn a_link_error_exits_with_102() {
+    let project = Project::hello("app");
+    project.write("src/util.c", "int unrelated(void) { return 1; }\n");
+    assert_eq!(status(&project, &["build"]), Some(102));
+}
+
+#[test]
+fn run_exits_with_the_status_of_the_program() {
+    assert_eq!(status(&exiting(0), &["run"]), Some(0));
+    assert_eq!(status(&exiting(3), &["run"]), Some(3));
+    assert_eq!(status(&exiting(77), &["run"]), Some(77));
+}
+
+#[test]
+fn run_passes_the_arguments_to_the_program() {
+    let project = Project::new("app");
+    project.write(
+        "src/main.c",
+        "int main(int argc, char **argv) { return argc; }\n",
+    );
+    assert_eq!(status(&project, &["run", "--", "a", "b"]), Some(3));
+}
+
+#[cfg(unix)]
+#[test]
+fn a_program_killed_by_a_signal_exits_like_in_a_shell() {
+    let project = Project::new("app");
+    project.write(
+        "src/main.c",
+        "#include <signal.h>\nint main(void) { raise(SIGTERM); return 0; }\n",
+    );
+    assert_eq!(status(&project, &["run"]), Some(128 + libc::SIGTERM));
+}
@@ -0,0 +1,59 @@
+//! `__FILE__` in the programs Buildy builds, with `relative_file_macro`.
+
+#![cfg(unix)]
+
+mod common;
+
+use common::Project;
+use std::fs;
+
+/// A program printing the `__FILE__` of a source below `src/log/`.
+fn logging() -> Project {
+    let project = Project::new("app");
+    project.write(
+        "src/main.c",
+        "#include <stdio.h>\nconst char *where(void);\n\
+         int main(void) { printf(\"%s\\n\", where()); return 0; }\n",
+    );
+    project.write(
+        "src/log/where.c",
+        "const char *where(void) { return \"file=\" __FILE__; }\n",
+    );
+    project
+}
+
+/// Whether `needle` occurs in `haystack`.
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+#[test]
+fn release_builds_embed_root_relative_paths() {
+    let project = logging();
+    project.builder().profile("release").build().expect("build");
+    let program = project.path("target/release/app");
+    assert_eq!(common::run(&program), "file=src/log/where.c\n");
+
+    let binary = fs::read(&program).unwrap();
+    assert!(contains(&binary, b"file=src/log/where.c\0"));
+    let root = project.root.canonicalize().unwrap();
+    let root = root.to_string_lossy();
+    assert!(
+        !contains(&binary, root.as_bytes()),
+        "the binary names {}",
+        root
+    );
+}
+
+#[test]
+fn debug_builds_keep_absolute_paths_unless_configured() {
+    let project = logging();
+    project.build().expect("build");
+    let root = project.root.canonicalize().unwrap();
+    let absolute = root.join("src/log/where.c");
+    assert_eq!(project.run(), format!("file={}\n", absolute.display()));
+
+    project.write("buildy.toml", "relative_file_macro = true\n");
+    project.build().expect("build with relative_file_macro");
+    assert_eq!(project.run(), "file=src/log/where.c\n");
+}
@@ -0,0 +1,160 @@
+//! Public headers of library builds, staged in `target/<profile>/include/`.
+
+mod common;
+
+use buildy::BuildCache;
+use common::Project;
+use std::fs;
+use std::process::Command;
+
+/// A static library with a header at the top of `include/`, one below it
+/// and a private one next to the sources.
+fn library() -> Project {
+    let project = Project::new("net");
+    project.write(
+        "buildy.toml",
+        "kind = \"staticlib\"\ninclude_dirs = [\"include\"]\npublic_headers = [\"include/**\"]\n",
+    );
+    project.write("include/net.h", "int net_init(void);\n");
+    project.write("include/net/socket.h", "int net_socket(void);\n");
+    project.write("src/private.h", "int helper(void);\n");
+    project.write(
+        "src/net.c",
+        "#include \"net.h\"\n#include \"net/socket.h\"\n#include \"private.h\"\n\
+         int helper(void) { return 1; }\n\
+         int net_init(void) { return helper(); }\n\
+         int net_socket(void) { return 3; }\n",
+    );
+    project
+}
+
+#[test]
+fn stages_the_headers_below_their_include_dir() {
+    let project = library();
+    project.build().expect("build");
+    let staged = project.path("target/debug/include");
+    assert_eq!(
+        fs::read_to_string(staged.join("net.h")).unwrap(),
+        "int net_init(void);\n"
+    );
+    assert!(staged.join("net/socket.h").is_file());
+    assert!(!staged.join("private.h").exists());
+    assert!(!staged.join("include").exists());
+}
+
+#[test]
+fn copies_only_the_headers_that_changed() {
+    let project = library();
+    project.build().expect("build");
+    let staged = project.path("target/debug/include");
+    let modified = |name: &str| fs::metadata(staged.join(name)).unwrap().modified().unwrap();
+    let before = modified("net.h");
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    project.write("include/net/socket.h", "int net_socket(int flags);\n");
+    project.write(
+        "src/net.c",
+        "#include \"net.h\"\n#include \"net/socket.h\"\n#include \"private.h\"\n\
+         int helper(void) { return 1; }\n\
+         int net_init(void) { return helper(); }\n\
+         int net_socket(int flags) { return flags; }\n",
+    );
+    project.build().expect("rebuild");
+    assert_eq!(modified("net.h"), before);
+    assert_eq!(
+        fs::read_to_string(staged.join("net/socket.h")).unwrap(),
+        "int net_socket(int flags);\n"
+    );
+}
+
+#[test]
+fn removes_headers_no_longer_public() {
+    let project = library();
+    project.build().expect("build");
+    fs::remove_file(project.path("include/net/socket.h")).unwrap();
+    project.write(
+        "src/net.c",
+        "#include \"net.h\"\nint net_init(void) { return 1; }\n",
+    );
+    project.build().expect("rebuild");
+    let staged = project.path("target/debug/include");
+    assert!(staged.join("net.h").is_file());
+    assert!(!staged.join("net").exists(), "the empty directory goes too");
+
+    project.write(
+        "buildy.toml",
+        "kind = \"staticlib\"\ninclude_dirs = [\"include\"]\n",
+    );
+    project.build().expect("build without public headers");
+    assert!(!staged.exists());
+}
+
+#[test]
+fn programs_stage_nothing() {
+    let project = Project::hello("app");
+    project.write("buildy.toml", "public_headers = [\"src/**\"]\n");
+    project.build().expect("build");
+    assert!(!project.path("target/debug/include").exists());
+}
+
+#[test]
+fn the_cache_records_the_staged_headers() {
+    let project = library();
+    project.build().expect("build");
+    let recorded = || -> Vec<String> {
+        let cache = BuildCache::load(&project.root, "debug");
+        cache.headers.keys().cloned().collect()
+    };
+    assert_eq!(recorded(), vec!["net.h", "net/socket.h"]);
+
+    fs::remove_file(project.path("include/net/socket.h")).unwrap();
+    project.write(
+        "src/net.c",
+        "#include \"net.h\"\nint net_init(void) { return 1; }\n",
+    );
+    project.build().expect("rebuild");
+    assert_eq!(recorded(), vec!["net.h"]);
+}
+
+#[cfg(unix)]
+#[test]
+fn install_copies_the_library_and_its_headers_below_the_prefix() {
+    let project = library();
+    let prefix = tempfile::tempdir().unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_Buildy"))
+        .args(["install", "--prefix"])
+        .arg(prefix.path())
+        .current_dir(&project.root)
+        .output()
+        .expect("run Buildy");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+    assert!(prefix.path().join("lib/libnet.a").is_file());
+    let include = prefix.path().join("include/net");
+    assert_eq!(
+        fs::read_to_string(include.join("net.h")).unwrap(),
+        "int net_init(void);\n"
+    );
+    assert!(include.join("net/socket.h").is_file());
+    assert!(!include.join("private.h").exists());
+    assert_eq!(stdout.matches("installed ").count(), 3, "{}", stdout);
+}
+
+#[test]
+fn install_refuses_a_program() {
+    let project = Project::hello("app");
+    let prefix = tempfile::tempdir().unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_Buildy"))
+        .args(["install", "--prefix"])
+        .arg(prefix.path())
+        .current_dir(&project.root)
+        .output()
+        .expect("run Buildy");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("install only installs libraries"),
+        "{}",
+        stderr
+    );
+    assert!(!prefix.path().join("lib").exists());
+}
@@ -0,0 +1,159 @@
+//! What rebuilds after the tree changes, and what doesn't.
+
+mod common;
+
+use common::Project;
+
+const UTIL: &str = "#include \"util.h\"\nint answer(void) { return 42; }\n";
+
+#[test]
+fn reverting_an_edit_reuses_the_old_object_and_link() {
+    let project = Project::hello("app");
+    project.build().expect("build");
+    project.write(
+        "src/util.c",
+        "#include \"util.h\"\nint answer(void) { return 7; }\n",
+    );
+    let report = project.build().expect("build after the edit");
+    assert_eq!(report.compiled.len(), 1);
+    assert_eq!(project.run(), "7\n");
+
+    project.write("src/util.c", UTIL);
+    let report = project.build().expect("build after the revert");
+    assert!(report.compiled.is_empty(), "{:?}", report.compiled);
+    assert_eq!(report.from_cache, 1);
+    assert_eq!(report.clean, 1);
+    assert!(report.linked.is_empty(), "{:?}", report.linked);
+    assert_eq!(project.run(), "42\n");
+}
+
+#[test]
+fn sources_of_the_same_name_get_objects_of_their_own() {
+    let project = Project::new("app");
+    project.write(
+        "src/main.cpp",
+        "#include <cstdio>\nint net_port();\nint db_port();\n\
+         int main() { std::printf(\"%d %d\\n\", net_port(), db_port()); }\n",
+    );
+    project.write("src/net/util.cpp", "int net_port() { return 80; }\n");
+    project.write("src/db/util.cpp", "int db_port() { return 5432; }\n");
+    let report = project.build().expect("build");
+    assert_eq!(report.compiled.len(), 3);
+    let net = project.path("target/debug/obj/src/net/util.cpp.o");
+    let db = project.path("target/debug/obj/src/db/util.cpp.o");
+    assert!(net.is_file() && db.is_file());
+    assert_ne!(std::fs::read(&net).unwrap(), std::fs::read(&db).unwrap());
+    // both symbols made it into the program
+    assert_eq!(project.run(), "80 5432\n");
+
+    project.write("src/db/util.cpp", "int db_port() { return 3306; }\n");
+    let report = project.build().expect("build after the edit");
+    assert_eq!(report.compiled.len(), 1);
+    assert!(report.compiled[0].0.ends_with("src/db/util.cpp"));
+    assert_eq!(project.run(), "80 3306\n");
+}
+
+#[test]
+fn editing_a_header_in_a_subdirectory_recompiles_every_includer() {
+    let project = Project::new("app");
+    project.write("buildy.toml", "include_dirs = [\"include\"]\n");
+    project.write("include/sub/x.h", "#define X 1\n");
+    project.write(
+        "src/main.c",
+        "#include <stdio.h>\n#include \"sub/x.h\"\nint twice(void);\n\
+         int main(void) { printf(\"%d %d\\n\", X, twice()); return 0; }\n",
+    );
+    project.write(
+        "src/twice.c",
+        "#include \"../include/sub/x.h\"\nint twice(void) { return 2 * X; }\n",
+    );
+    project.write("src/other.c", "int other(void) { return 0; }\n");
+    project.build().expect("build");
+    assert_eq!(project.run(), "1 2\n");
+
+    project.write("include/sub/x.h", "#define X 5\n");
+    let report = project.build().expect("build after the edit");
+    let mut compiled: Vec<String> = report
+        .compiled
+        .iter()
+        .map(|(path, _)| path.file_name().unwrap().to_string_lossy().to_string())
+        .collect();
+    compiled.sort();
+    // reached through -I and through a relative path alike
+    assert_eq!(compiled, ["main.c", "twice.c"]);
+    assert_eq!(report.clean, 1);
+    assert_eq!(project.run(), "5 10\n");
+
+    // and the header is one node of the graph, not one per spelling
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_Buildy"))
+        .args(["graph", "--format", "json"])
+        .current_dir(&project.root)
+        .output()
+        .expect("run Buildy");
+    let graph: serde_json::Value = serde_json::from_slice(&output.stdout).expect("graph JSON");
+    let nodes = graph["nodes"].as_array().unwrap();
+    let headers: Vec<_> = nodes
+        .iter()
+        .filter(|n| n["path"].as_str().unwrap().ends_with("x.h"))
+        .collect();
+    assert_eq!(headers.len(), 1, "{:?}", headers);
+    assert_eq!(headers[0]["path"], "include/sub/x.h");
+}
+
+/// A program printing what `extra()` returns, or 0 when no source defines
+/// it (it is declared weak, so linking works without it).
+fn with_extra() -> Project {
+    let project = Project::new("app");
+    project.write(
+        "src/main.c",
+        "#include <stdio.h>\n__attribute__((weak)) int extra(void);\n\
+         int main(void) { printf(\"%d\\n\", extra ? extra() : 0); return 0; }\n",
+    );
+    project.write(
+        "src/extra.c",
+        "int extra(void) { return 7; }\nint spare(void) { return 1; }\n",
+    );
+    project
+}
+
+#[cfg(unix)]
+#[test]
+fn removing_a_source_removes_its_symbols_from_the_program() {
+    let project = with_extra();
+    project.build().expect("build");
+    assert_eq!(project.run(), "7\n");
+    let object = project.path("target/debug/obj/src/extra.c.o");
+    assert!(object.is_file());
+
+    std::fs::remove_file(project.path("src/extra.c")).unwrap();
+    let report = project.build().expect("build without src/extra.c");
+    assert!(report.compiled.is_empty(), "{:?}", report.compiled);
+    assert_eq!(report.linked, ["app"]);
+    assert!(!object.exists(), "the stale object is left behind");
+    assert_eq!(project.run(), "0\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn removing_a_symbol_relinks() {
+    let project = with_extra();
+    project.build().expect("build");
+    project.write("src/extra.c", "int spare(void) { return 1; }\n");
+    let report = project.build().expect("build without extra()");
+    assert_eq!(report.compiled.len(), 1);
+    assert_eq!(report.linked, ["app"]);
+    assert_eq!(project.run(), "0\n");
+}
+
+#[test]
+fn a_renamed_source_is_compiled_under_its_new_name() {
+    let project = Project::hello("app");
+    project.build().expect("build");
+    std::fs::rename(project.path("src/util.c"), project.path("src/answer.c")).unwrap();
+    let report = project.build().expect("build after the rename");
+    assert_eq!(report.compiled.len(), 1);
+    assert!(report.compiled[0].0.ends_with("src/answer.c"));
+    assert_eq!(report.linked.len(), 1);
+    assert!(!project.path("target/debug/obj/src/util.c.o").exists());
+    assert_eq!(project.run(), "42\n");
+}
@@ -0,0 +1,90 @@
+//! `init` scaffolds a project that builds and runs straight away.
+
+mod common;
+
+use common::Project;
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+
+fn buildy(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_Buildy"))
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .expect("run Buildy")
+}
+
+/// Run `Buildy args` in `dir`, failing the test unless it succeeds, and
+/// return its stdout.
+fn succeeds(dir: &Path, args: &[&str]) -> String {
+    let output = buildy(dir, args);
+    assert!(
+        output.status.success(),
+        "Buildy {:?}: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[test]
+fn a_new_c_project_builds_and_runs() {
+    let workspace = Project::new("work");
+    let stdout = succeeds(&workspace.root, &["init", "hello"]);
+    assert!(stdout.contains("buildy --root hello run"), "{}", stdout);
+    let project = workspace.root.join("hello");
+    assert!(project.join("src/main.c").is_file());
+    assert!(project.join("buildy.toml").is_file());
+    assert_eq!(
+        fs::read_to_string(project.join(".gitignore")).unwrap(),
+        "target/\n"
+    );
+
+    let stdout = succeeds(&workspace.root, &["--root", "hello", "run"]);
+    assert!(stdout.ends_with("\nHello, world!\n"), "{}", stdout);
+    let program = format!("target/debug/hello{}", std::env::consts::EXE_SUFFIX);
+    assert!(project.join(program).is_file());
+}
+
+#[test]
+fn a_new_cpp_project_in_the_current_directory() {
+    let project = Project::new("greeter");
+    project.write(".gitignore", "*.log");
+    succeeds(&project.root, &["init", "--cpp"]);
+    assert!(project.path("src/main.cpp").is_file());
+    assert!(!project.path("src/main.c").exists());
+    let config = fs::read_to_string(project.path("buildy.toml")).unwrap();
+    assert!(config.contains("cxxflags = [\"-std=c++17\"]"), "{}", config);
+    // added to, not replaced
+    assert_eq!(
+        fs::read_to_string(project.path(".gitignore")).unwrap(),
+        "*.log\ntarget/\n"
+    );
+    let stdout = succeeds(&project.root, &["run"]);
+    assert!(stdout.ends_with("\nHello, world!\n"), "{}", stdout);
+}
+
+#[test]
+fn an_existing_config_is_only_overwritten_with_force() {
+    let project = Project::new("app");
+    project.write("buildy.toml", "name = \"mine\"\n");
+    let output = buildy(&project.root, &["init"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("already has buildy.toml"), "{}", stderr);
+    assert!(stderr.contains("--force"), "{}", stderr);
+    // nothing at all was written
+    assert_eq!(
+        fs::read_to_string(project.path("buildy.toml")).unwrap(),
+        "name = \"mine\"\n"
+    );
+    assert!(!project.path("src").exists());
+    assert!(!project.path(".gitignore").exists());
+
+    succeeds(&project.root, &["init", "--force"]);
+    let config = fs::read_to_string(project.path("buildy.toml")).unwrap();
+    assert!(config.starts_with("# Buildy configuration"), "{}", config);
+    let stdout = succeeds(&project.root, &["run"]);
+    assert!(stdout.ends_with("\nHello, world!\n"), "{}", stdout);
+}
@@ -0,0 +1,153 @@
+//! Golden files of the JSON buildy writes for other programs.  A change to
+//! the shape of a message or document fails here until `SCHEMA_VERSION` is
+//! bumped and the files for the new version are written with
+//! `BUILDY_BLESS=1 cargo test --test protocol`.
+
+use buildy::BuildReport;
+use buildy::messages::MessageFormat;
+use buildy::protocol::{
+    CollapsedHeader, CompileTime, Document, Graph, GraphEdge, GraphNode, Message, NodeKind,
+    SCHEMA_VERSION, Timings, Versioned,
+};
+use clap::ValueEnum;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Compare `actual` with the golden file `name` of the current version.
+fn check(name: &str, actual: &str) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(format!("v{}", SCHEMA_VERSION))
+        .join(name);
+    if env::var_os("BUILDY_BLESS").is_some() {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, actual).unwrap();
+        return;
+    }
+    let expected = fs::read_to_string(&path).unwrap_or_else(|e| {
+        panic!(
+            "{}: {}; write the golden files of version {} with BUILDY_BLESS=1",
+            path.display(),
+            e,
+            SCHEMA_VERSION
+        )
+    });
+    assert!(
+        actual == expected,
+        "{} changed shape without a bump of SCHEMA_VERSION (now {})\n\
+         --- expected\n{}\n--- actual\n{}",
+        name,
+        SCHEMA_VERSION,
+        expected,
+        actual
+    );
+}
+
+#[test]
+fn messages() {
+    let report = BuildReport {
+        compiled: vec![(PathBuf::from("src/util.c"), Duration::from_millis(41))],
+        from_cache: 1,
+        clean: 12,
+        warnings: 1,
+        linked: vec!["app".into()],
+        duration: Duration::from_millis(230),
+        ..BuildReport::default()
+    };
+    let messages = [
+        Message::CompileStart { file: "src/util.c" },
+        Message::Diagnostic {
+            file: "src/util.c",
+            stderr: "src/util.c:3:5: warning: unused variable 'x'\n",
+        },
+        Message::CompileFinish {
+            file: "src/util.c",
+            success: true,
+            duration_ms: 41,
+        },
+        Message::BuildFinished {
+            success: true,
+            artifacts: vec!["/p/target/debug/app".into()],
+            report: Some(&report),
+        },
+        Message::BuildFinished {
+            success: false,
+            artifacts: Vec::new(),
+            report: None,
+        },
+    ];
+    let mut lines = String::new();
+    for message in &messages {
+        lines += &message.to_json().unwrap();
+        lines.push('\n');
+    }
+    check("messages.jsonl", &lines);
+}
+
+#[test]
+fn graph() {
+    let graph = Graph {
+        nodes: vec![
+            GraphNode {
+                path: "src/main.c".into(),
+                kind: NodeKind::Source,
+                dirty: true,
+                reason: Some("src/util.h changed".into()),
+            },
+            GraphNode {
+                path: "src/util.h".into(),
+                kind: NodeKind::Header,
+                dirty: false,
+                reason: None,
+            },
+        ],
+        edges: vec![GraphEdge {
+            from: "src/main.c".into(),
+            to: "src/util.h".into(),
+        }],
+        collapsed: vec![CollapsedHeader {
+            path: "include/config.h".into(),
+            dependents: 40,
+        }],
+    };
+    check(
+        "graph.json",
+        &(serde_json::to_string_pretty(&Versioned::new(&graph)).unwrap() + "\n"),
+    );
+}
+
+#[test]
+fn timings() {
+    let timings = Timings {
+        compiles: vec![CompileTime {
+            file: "src/main.c".into(),
+            ms: 120,
+        }],
+        link_ms: 30,
+        total_ms: 200,
+        saved_ms: 900,
+    };
+    check(
+        "timings.json",
+        &(serde_json::to_string_pretty(&Versioned::new(&timings)).unwrap() + "\n"),
+    );
+}
+
+#[test]
+fn schemas() {
+    for document in Document::value_variants() {
+        let name = document.to_possible_value().unwrap().get_name().to_string();
+        check(&format!("{}.schema.json", name), &document.schema_json());
+    }
+}
+
+#[test]
+fn the_current_version_can_be_asked_for() {
+    assert_eq!(
+        MessageFormat::from_str(&format!("json@{}", SCHEMA_VERSION), false),
+        Ok(MessageFormat::Json)
+    );
+    assert!(MessageFormat::from_str(&format!("json@{}", SCHEMA_VERSION + 1), false).is_err());
+}
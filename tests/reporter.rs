@@ -0,0 +1,120 @@
+//! A build through the library with a `Reporter` prints nothing on stdout
+//! or stderr.
+//!
+//! Runs without the test harness (see Cargo.toml), which would capture
+//! `println!` itself and print its own progress on stdout: stdout and
+//! stderr are pointed at files for the build and must stay empty.
+
+mod common;
+
+use buildy::Reporter;
+use buildy::messages::Message;
+use common::Project;
+use std::sync::{Arc, Mutex};
+
+#[derive(Default)]
+struct Recorder {
+    status: Mutex<Vec<String>>,
+    warnings: Mutex<Vec<String>>,
+    messages: Mutex<Vec<String>>,
+}
+
+/// Passes everything to the `Recorder` the test inspects afterwards.
+struct Shared(Arc<Recorder>);
+
+impl Reporter for Shared {
+    fn status(&self, text: &str) {
+        self.0.status.lock().unwrap().push(text.to_string());
+    }
+
+    fn warning(&self, text: &str) {
+        self.0.warnings.lock().unwrap().push(text.to_string());
+    }
+
+    fn message(&self, message: &Message) {
+        let line = serde_json::to_string(message).unwrap();
+        self.0.messages.lock().unwrap().push(line);
+    }
+}
+
+#[cfg(unix)]
+fn main() {
+    use std::io::{Read, Seek, Write};
+    use std::os::fd::AsRawFd;
+
+    let project = Project::hello("app");
+    project.write(
+        "src/util.c",
+        "#include \"util.h\"\n#warning \"answer is hard-coded\"\n\
+         int answer(void) { return 42; }\n",
+    );
+    // a setting the config warns about, and a cache that can't be read
+    project.write("buildy.toml", "auto_clean_stale = \"soon\"\n");
+    project.write("target/debug/.buildy_cache.json", "{ not json");
+    let recorder = Arc::new(Recorder::default());
+
+    let mut captured = [1, 2].map(|_| tempfile::tempfile().expect("capture file"));
+    std::io::stdout().flush().unwrap();
+    let saved = [1, 2].map(|fd| unsafe { libc::dup(fd) });
+    assert!(saved.iter().all(|fd| *fd >= 0));
+    for (fd, file) in [1, 2].iter().zip(&captured) {
+        unsafe { libc::dup2(file.as_raw_fd(), *fd) };
+    }
+    let result = project
+        .builder()
+        .reporter(Shared(recorder.clone()))
+        .jobs(1)
+        .build();
+    std::io::stdout().flush().unwrap();
+    for (fd, saved) in [1, 2].iter().zip(saved) {
+        unsafe {
+            libc::dup2(saved, *fd);
+            libc::close(saved);
+        }
+    }
+
+    let report = result.expect("build with a reporter");
+    assert_eq!(report.compiled.len(), 2);
+    for (file, name) in captured.iter_mut().zip(["stdout", "stderr"]) {
+        let mut printed = String::new();
+        file.rewind().unwrap();
+        file.read_to_string(&mut printed).unwrap();
+        assert_eq!(printed, "", "the build printed on {}", name);
+    }
+
+    let warnings = recorder.warnings.lock().unwrap();
+    assert!(
+        warnings
+            .iter()
+            .any(|w| w.contains("`auto_clean_stale`") && w.contains("ignoring it")),
+        "the config warning didn't reach the reporter: {:?}",
+        warnings
+    );
+    assert!(
+        warnings
+            .iter()
+            .any(|w| w.contains(".buildy_cache.json") && w.contains("rebuilding from scratch")),
+        "the cache warning didn't reach the reporter: {:?}",
+        warnings
+    );
+    assert!(
+        warnings.iter().any(|w| w.contains("answer is hard-coded")),
+        "the compiler's warning didn't reach the reporter: {:?}",
+        warnings
+    );
+    let messages = recorder.messages.lock().unwrap();
+    let starts = messages
+        .iter()
+        .filter(|m| m.contains("\"reason\":\"compile-start\""))
+        .count();
+    assert_eq!(starts, 2, "{:?}", messages);
+    assert!(
+        messages
+            .iter()
+            .any(|m| m.contains("\"reason\":\"diagnostic\"") && m.contains("src/util.c"))
+    );
+    println!("reporter: ok");
+}
+
+#[cfg(not(unix))]
+fn main() {}
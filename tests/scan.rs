@@ -0,0 +1,81 @@
+//! Dependency scans that are interrupted and resumed.
+
+#![cfg(unix)]
+
+mod common;
+
+use common::Project;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A compiler whose `-MM` scans log the source and take a while; compiles
+/// go to the system compiler.
+const SLOW_SCAN: &str = r#"#!/bin/sh
+for arg; do
+    case $arg in
+        -MM) for source; do :; done
+             echo "$source" >> "$(dirname "$0")/scans.log"
+             sleep 0.3
+             exec cc "$@" ;;
+    esac
+done
+exec cc "$@"
+"#;
+
+const SOURCES: usize = 12;
+
+fn scans(project: &Project) -> usize {
+    fs::read_to_string(project.path("tools/scans.log"))
+        .unwrap_or_default()
+        .lines()
+        .count()
+}
+
+#[test]
+fn an_interrupted_scan_resumes_where_it_stopped() {
+    let project = Project::hello("app");
+    for i in 0..SOURCES - 2 {
+        project.write(
+            &format!("src/part{:02}.c", i),
+            &format!(
+                "#include \"util.h\"\nint part{}(void) {{ return answer(); }}\n",
+                i
+            ),
+        );
+    }
+    let cc = project.path("tools/cc");
+    fs::create_dir_all(cc.parent().unwrap()).unwrap();
+    fs::write(&cc, SLOW_SCAN).unwrap();
+    fs::set_permissions(&cc, fs::Permissions::from_mode(0o755)).unwrap();
+    project.write("buildy.toml", &format!("compiler = \"{}\"\n", cc.display()));
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_Buildy"))
+        .arg("build")
+        .current_dir(&project.root)
+        .spawn()
+        .expect("start Buildy");
+    let started = Instant::now();
+    while scans(&project) < 4 {
+        assert!(started.elapsed() < Duration::from_secs(30), "no scans");
+        thread::sleep(Duration::from_millis(20));
+    }
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGINT) };
+    let status = child.wait().unwrap();
+    assert_eq!(status.code(), Some(130));
+    let interrupted = scans(&project);
+    assert!(interrupted < SOURCES, "the scan finished: {}", interrupted);
+
+    // the cache was saved whole, with the scans that finished
+    let text = fs::read_to_string(project.path("target/debug/.buildy_cache.json")).unwrap();
+    let cache: serde_json::Value = serde_json::from_str(&text).expect("cache JSON");
+    let recorded = cache["scans"].as_object().unwrap().len();
+    assert!((3..SOURCES).contains(&recorded), "{} recorded", recorded);
+
+    // the next build scans what wasn't recorded, and only that
+    project.build().expect("build");
+    assert_eq!(scans(&project) - interrupted, SOURCES - recorded);
+    assert_eq!(project.run(), "42\n");
+}
@@ -0,0 +1,63 @@
+//! Files the scan can't read are skipped with a warning naming them.
+
+#![cfg(unix)]
+
+mod common;
+
+use common::Project;
+use std::fs;
+use std::os::unix::fs::{PermissionsExt, symlink};
+use std::process::{Command, Output};
+
+fn build(project: &Project) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_Buildy"))
+        .arg("build")
+        .current_dir(&project.root)
+        .output()
+        .expect("run Buildy")
+}
+
+#[test]
+fn a_dangling_symlink_is_skipped_with_a_warning() {
+    let project = Project::hello("app");
+    symlink(project.path("src/removed.c"), project.path("src/old.c")).unwrap();
+    let output = build(&project);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(
+        stderr.contains("skipped 1 unreadable file(s)"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("  src/old.c: "), "{}", stderr);
+    assert_eq!(project.run(), "42\n");
+}
+
+#[test]
+fn without_a_readable_source_the_build_fails() {
+    let project = Project::new("app");
+    symlink(project.path("src/removed.c"), project.path("main.c")).unwrap();
+    let output = build(&project);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(103), "{}", stderr);
+    assert!(stderr.contains("no readable sources"), "{}", stderr);
+    assert!(stderr.contains("  main.c: "), "{}", stderr);
+}
+
+#[test]
+fn an_unreadable_source_is_skipped_with_a_warning() {
+    if unsafe { libc::geteuid() } == 0 {
+        eprintln!("skipped: root can read any file");
+        return;
+    }
+    let project = Project::hello("app");
+    project.write("src/secret.c", "int secret(void) { return 1; }\n");
+    let secret = project.path("src/secret.c");
+    fs::set_permissions(&secret, fs::Permissions::from_mode(0o000)).unwrap();
+    let output = build(&project);
+    fs::set_permissions(&secret, fs::Permissions::from_mode(0o644)).unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(stderr.contains("  src/secret.c: "), "{}", stderr);
+    assert_eq!(project.run(), "42\n");
+}